@@ -4,13 +4,22 @@
 #include <ostream>
 #include <new>
 
+/// One buffer of a scatter read, as used by arti_read_stream_vectored
+struct ArtiIoVec {
+  /// Start of the buffer
+  uint8_t *base;
+  /// Length of the buffer in bytes
+  uintptr_t len;
+};
+
 extern "C" {
 
 /// Initialize the Arti Tor client with a default configuration
 ///
 /// This function must be called before any other functions.
 ///
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
 int arti_init();
 
 /// Initialize the Arti Tor client with a custom configuration file
@@ -18,29 +27,79 @@ int arti_init();
 /// This function must be called before any other functions.
 ///
 /// @param config_path A null-terminated string containing the path to the configuration file
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
 int arti_init_with_config(const char *config_path);
 
+/// Initialize the Arti Tor client, retrying failed bootstraps with exponential backoff
+///
+/// Behaves like arti_init_with_config, but a failed bootstrap is retried up to
+/// max_attempts times in total. The wait before each retry starts at backoff_ms and
+/// doubles every attempt, up to one minute. A failed attempt leaves no client behind.
+/// Errors that retrying cannot fix, such as another process holding the state lock,
+/// are returned at once. Blocks the calling thread for the whole retry sequence.
+///
+/// @param config_path A null-terminated path to the configuration file, or null for the default
+/// @param max_attempts Maximum number of bootstrap attempts, at least 1
+/// @param backoff_ms Wait before the first retry in milliseconds
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) if max_attempts is not positive, otherwise
+///         the code from the last attempt as for arti_init_with_config
+int arti_init_with_retry(const char *config_path, int max_attempts, uint64_t backoff_ms);
+
 /// Creates a new Tor circuit with the given ID
 ///
+/// The Tor client must be initialized and bootstrapped first.
+///
 /// @param circuit_id A null-terminated string representing a unique circuit ID
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached,
+///         ERR_INTERNAL (-5) if internal state is broken
 int arti_create_circuit(const char *circuit_id);
 
+/// Creates a new Tor circuit with the given ID once the client is bootstrapped
+///
+/// Like arti_create_circuit, but waits for a concurrent arti_init to finish
+/// bootstrapping instead of failing straight away.
+///
+/// @param circuit_id A null-terminated string representing a unique circuit ID
+/// @param timeout_ms Maximum time to wait for bootstrap, in milliseconds
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if the client was not bootstrapped in time,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_create_circuit_wait(const char *circuit_id, uint64_t timeout_ms);
+
+/// Creates several isolated Tor circuits at once
+///
+/// Each circuit uses its own isolation group, so no two of them share a path.
+/// With warmup enabled, a throwaway stream is opened on every circuit in parallel
+/// so the paths are already built when the first real stream is opened.
+/// The generated circuit IDs are written as a JSON array of strings.
+///
+/// @param count The number of circuits to create
+/// @param warmup 1 to pre-build the circuit paths, 0 to build them on first use
+/// @param out_ids_json Output buffer that will receive the null-terminated JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_CIRCUIT_LIMIT (-12) if the circuits would exceed the
+///         limit set with arti_set_max_circuits (none are created), ERR_INTERNAL (-5) if internal
+///         state is broken
+int arti_create_circuits(int count, int warmup, char *out_ids_json, int out_len);
+
 /// Destroys an existing Tor circuit
 ///
+/// Every stream opened on the circuit, including TLS streams, is closed too.
+///
 /// @param circuit_id A null-terminated string representing a unique circuit ID
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 int arti_destroy_circuit(const char *circuit_id);
 
 /// Connects to the Tor network
 ///
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 int arti_connect();
 
 /// Disconnects from the Tor network
 ///
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 int arti_disconnect();
 
 /// Checks if connected to the Tor network
@@ -50,18 +109,198 @@ int arti_is_connected();
 
 /// Connect to a target through Tor and return a stream ID
 ///
+/// The host may be a hostname, an IPv4 address, or an IPv6 address with or
+/// without brackets (e.g. "::1" or "[::1]"). It must not include a scheme or a
+/// port: "https://example.com" and "example.com:443" are rejected with
+/// ERR_INVALID_PARAMS and a message saying what to pass instead.
+///
 /// @param circuit_id The circuit ID to use
 /// @param target_host The target hostname
 /// @param target_port The target port
 /// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
 /// @param stream_id_len Maximum length of the stream ID buffer
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
 int arti_connect_stream(const char *circuit_id,
                         const char *target_host,
                         int32_t target_port,
                         char *stream_id,
                         int stream_id_len);
 
+/// Connect to a target through Tor using an isolation token and return a stream ID
+///
+/// Streams opened with the same isolation token may share a circuit, while streams
+/// with different tokens never do. This mirrors Tor's SOCKS username/password isolation.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param isolation_token A null-terminated string naming the isolation group
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_stream_isolated(const char *circuit_id,
+                                 const char *target_host,
+                                 int32_t target_port,
+                                 const char *isolation_token,
+                                 char *stream_id,
+                                 int stream_id_len);
+
+/// Connect to a target through a Tor exit in a specific country and return a stream ID
+///
+/// Requires a build with the `geoip` feature; relays are mapped to countries with the
+/// database built into Arti. Fails if no suitable exit in that country exists. Arti
+/// can't yet be given other databases, so once arti_set_geoip_files has loaded its own
+/// this returns ERR_NOT_SUPPORTED rather than pick exits with a different database.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param country_code A null-terminated two-letter ISO 3166-1 country code, e.g. "DE"
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the country code or target is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small, ERR_NOT_SUPPORTED (-11)
+///         if built without the geoip feature or after arti_set_geoip_files
+int arti_connect_stream_country(const char *circuit_id,
+                                const char *target_host,
+                                int32_t target_port,
+                                const char *country_code,
+                                char *stream_id,
+                                int stream_id_len);
+
+/// Connect to an onion service through Tor and return a stream ID
+///
+/// Onion streams follow the same isolation rules as arti_connect_stream_isolated:
+/// streams with the same isolation token may share a circuit, while streams with
+/// different tokens never do. Requires a build with the `onion-client` feature.
+///
+/// @param circuit_id The circuit ID to use
+/// @param onion_host The .onion hostname
+/// @param target_port The target port
+/// @param isolation_token A null-terminated string naming the isolation group, or NULL for none
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host is not an onion
+///         address or the port is malformed, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small,
+///         ERR_NOT_SUPPORTED (-11) if built without the onion-client feature
+int arti_connect_onion_stream(const char *circuit_id,
+                              const char *onion_host,
+                              int32_t target_port,
+                              const char *isolation_token,
+                              char *stream_id,
+                              int stream_id_len);
+
+/// Check whether an onion service is reachable right now
+///
+/// Connects to the service and closes the stream straight away, so nothing is
+/// left open. Requires a build with the `onion-client` feature.
+///
+/// @param circuit_id The circuit ID to use
+/// @param onion_addr The .onion hostname
+/// @param port The port to probe
+/// @param timeout_ms Maximum time to wait for the connection, in milliseconds
+/// @return 1 if the service is reachable, 0 if it is not or on failure, ERR_TIMEOUT (-8) if
+///         the timeout expired, ERR_INVALID_PARAMS (-4) if the host is not an onion address
+///         or the port is malformed, ERR_NOT_SUPPORTED (-11) if built without the onion-client feature
+int arti_onion_reachable(const char *circuit_id,
+                         const char *onion_addr,
+                         int32_t port,
+                         uint64_t timeout_ms);
+
+/// Start connecting to a target through Tor in the background
+///
+/// Returns immediately with a cancel token. Use arti_connect_poll to collect the
+/// stream ID once the connect has finished, or arti_cancel to abort it.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @return A non-zero cancel token on success, 0 on failure
+uint64_t arti_connect_stream_cancelable(const char *circuit_id,
+                                        const char *target_host,
+                                        int32_t target_port);
+
+/// Check the progress of a connect started with arti_connect_stream_cancelable
+///
+/// Once a final result has been reported the token is released. Outcomes are kept
+/// for the 1024 most recent connects that have not been polled.
+///
+/// @param token The cancel token returned by arti_connect_stream_cancelable
+/// @param stream_id Output parameter that will receive a null-terminated string
+///        representing the stream ID once connected
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 if connected, 0 if still in progress, ERR_CONNECTION_FAILED (-2) if the
+///         connect failed, ERR_CANCELLED (-6) if it was cancelled, ERR_INVALID_PARAMS (-4)
+///         for an unknown token, ERR_BUFFER_TOO_SMALL (-9) if the stream ID doesn't fit
+///         (the token stays valid so the call can be repeated)
+int arti_connect_poll(uint64_t token, char *stream_id, int stream_id_len);
+
+/// Cancel a connect started with arti_connect_stream_cancelable
+///
+/// The connect is aborted promptly and no stream is stored. A following
+/// arti_connect_poll reports ERR_CANCELLED (-6).
+///
+/// @param token The cancel token returned by arti_connect_stream_cancelable
+/// @return 1 if the operation was cancelled, 0 if the token is unknown or already finished
+int arti_cancel(uint64_t token);
+
+/// Connect to a target through Tor, retrying on a fresh circuit after a failure
+///
+/// Each failed attempt retires the circuit's current path and builds a new one under
+/// the same circuit ID before retrying, waiting a little longer between each attempt.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param max_attempts Maximum number of connect attempts, at least 1
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param attempts_used Output parameter that will receive the number of attempts made (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_stream_retry(const char *circuit_id,
+                              const char *target_host,
+                              int32_t target_port,
+                              int max_attempts,
+                              char *stream_id,
+                              int stream_id_len,
+                              int *attempts_used);
+
+/// Open a tunnel to a target through an HTTP proxy reached over Tor
+///
+/// Connects to the proxy through the circuit and issues an HTTP CONNECT for the
+/// target. Once the proxy accepts, the returned stream carries raw bytes to and from
+/// the target and works with the normal stream read/write functions.
+///
+/// @param circuit_id The circuit ID to use
+/// @param proxy_host The proxy hostname
+/// @param proxy_port The proxy port
+/// @param target_host The hostname the proxy should connect to
+/// @param target_port The port the proxy should connect to
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if a host or port is malformed,
+///         ERR_CONNECTION_FAILED (-2) if the proxy refused the CONNECT,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_via_http_proxy(const char *circuit_id,
+                                const char *proxy_host,
+                                int32_t proxy_port,
+                                const char *target_host,
+                                int32_t target_port,
+                                char *stream_id,
+                                int stream_id_len);
+
+/// Get the stream ID buffer length that is always large enough for a circuit
+///
+/// Stream IDs are derived from the circuit ID, so pass the circuit the stream will be
+/// opened on. Use this to size the stream_id buffer of the arti_connect_stream family.
+///
+/// @param circuit_id The circuit ID
+/// @return The required buffer length including the null terminator, or 0 on failure
+int arti_stream_id_len(const char *circuit_id);
+
 /// Write data to a stream
 ///
 /// @param stream_id The stream ID
@@ -85,12 +324,79 @@ int arti_flush_stream(const char *stream_id);
 /// @return 1 on success, 0 on failure
 int arti_read_stream(const char *stream_id, char *buffer, int buffer_len, int *bytes_read);
 
-/// Close and destroy a stream
+/// Peek at data from a stream without consuming it
+///
+/// The peeked data is kept in an internal buffer and served first by the
+/// next reads, so a following arti_read_stream returns the same bytes. Blocks
+/// only while nothing is buffered; if some data is already buffered, that is
+/// returned straight away even when it is less than buffer_len.
 ///
 /// @param stream_id The stream ID
+/// @param buffer The buffer to store the data
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_peeked Output parameter that will receive the number of bytes peeked
 /// @return 1 on success, 0 on failure
+int arti_peek_stream(const char *stream_id, char *buffer, int buffer_len, int *bytes_peeked);
+
+/// Close and destroy a stream
+///
+/// Closing a stream that is already closed, or was never opened, returns
+/// ERR_STREAM_NOT_FOUND rather than 0, so callers can treat a repeated close as
+/// harmless.
+///
+/// @param stream_id The stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if there is no such stream,
+///         ERR_INTERNAL (-5) if internal state is broken
 int arti_close_stream(const char *stream_id);
 
+/// Split a stream into independent read and write halves
+///
+/// Each half gets its own stream ID and can be used from a different thread at the
+/// same time: reads with arti_read_stream on the read half, writes and flushes with
+/// arti_write_stream and arti_flush_stream on the write half. The original stream ID
+/// is no longer valid. The underlying stream closes once both halves are closed.
+///
+/// @param stream_id The stream ID
+/// @param out_read_id Output buffer that will receive the null-terminated read half ID
+/// @param out_write_id Output buffer that will receive the null-terminated write half ID
+/// @param id_len Length of each output buffer
+/// @return 1 on success, 0 on failure
+int arti_split_stream(const char *stream_id, char *out_read_id, char *out_write_id, int id_len);
+
+/// Gracefully close a stream
+///
+/// Flushes pending writes, shuts down the write side so the peer sees EOF, and
+/// discards incoming data until the peer closes its side or the timeout expires.
+/// The stream is removed in every case.
+///
+/// @param stream_id The stream ID
+/// @param timeout_ms Maximum time to spend closing, in milliseconds
+/// @return 1 on a clean close, ERR_TIMEOUT (-8) if the timeout expired first, 0 on failure,
+///         ERR_STREAM_NOT_FOUND (-13) if the stream is already closed or unknown
+int arti_close_stream_graceful(const char *stream_id, uint64_t timeout_ms);
+
+/// Make an HTTP or HTTPS request through Tor
+///
+/// The response is written as a null-terminated JSON object with a stable schema:
+/// `{"status": number, "url": string, "headers": [[name, value], ...],
+///   "content_encoding": string | null, "body_encoding": "utf-8" | "base64", "body": string}`.
+/// `url` is the final URL after redirects. `headers` keeps the order the server sent
+/// them in, including repeated headers such as Set-Cookie. The body is delivered
+/// exactly as received; `content_encoding` is always null. A body that is valid UTF-8
+/// is returned as text with `body_encoding` "utf-8"; any other body is base64-encoded
+/// and `body_encoding` is "base64".
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit (use arti_http_request_sized
+///         to learn the required length), ERR_BODY_TOO_LARGE (-10) if the body exceeds
+///         the limit set with arti_set_max_http_body
 int arti_http_request(const char *circuit_id,
                       const char *url,
                       const char *method,
@@ -99,23 +405,155 @@ int arti_http_request(const char *circuit_id,
                       char *response,
                       int response_len);
 
+/// Make an HTTP or HTTPS request through Tor, reporting the buffer size needed for the response
+///
+/// Behaves like arti_http_request. When the response does not fit, the required
+/// buffer length, including the null terminator, is written to required_len so the
+/// caller can retry with a larger buffer.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @param required_len Output parameter that will receive the required buffer length (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10)
+///         if the body exceeds the limit set with arti_set_max_http_body
+int arti_http_request_sized(const char *circuit_id,
+                            const char *url,
+                            const char *method,
+                            const char *headers,
+                            const char *body,
+                            char *response,
+                            int response_len,
+                            int *required_len);
+
+/// Make an HTTP or HTTPS request through Tor with control over response decompression
+///
+/// When decompress is non-zero, the request advertises gzip, deflate and br (unless an
+/// Accept-Encoding header is supplied) and the body is decoded before it is returned.
+/// `content_encoding` in the response JSON then names the encoding that was removed,
+/// and the Content-Encoding/Content-Length headers are adjusted to match the delivered
+/// body. When decompress is zero this behaves exactly like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param decompress 1 to decode compressed bodies, 0 to return them untouched
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10)
+///         if the body exceeds the limit set with arti_set_max_http_body
+int arti_http_request_decompress(const char *circuit_id,
+                                 const char *url,
+                                 const char *method,
+                                 const char *headers,
+                                 const char *body,
+                                 int decompress,
+                                 char *response,
+                                 int response_len);
+
+/// Make an HTTP or HTTPS request through Tor with its own isolation token and timeout
+///
+/// Requests with the same isolation token may share a circuit of the given circuit's
+/// client, and so usually share an exit; requests with different tokens never share a
+/// circuit. A null or empty token behaves like arti_http_request. Otherwise this
+/// behaves like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param isolation_token A null-terminated isolation token, or null for none
+/// @param timeout_ms Limit on the whole request in milliseconds, or 0 for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_TIMEOUT (-8) if the timeout expired, ERR_BUFFER_TOO_SMALL (-9) if the response
+///         does not fit, ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with
+///         arti_set_max_http_body
+int arti_http_request_ex(const char *circuit_id,
+                         const char *url,
+                         const char *method,
+                         const char *headers,
+                         const char *body,
+                         const char *isolation_token,
+                         uint64_t timeout_ms,
+                         char *response,
+                         int response_len);
+
+/// Make an HTTP or HTTPS request through Tor to a given IP address
+///
+/// The exit connects to connect_ip instead of resolving the URL's host, but the
+/// request is otherwise made to the host in the URL: it is sent in the Host header
+/// and, for HTTPS, used for SNI and to validate the server's certificate, which
+/// must be valid for that host. Useful for reaching one server behind a load
+/// balancer or CDN. Otherwise this behaves like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request, naming the host the server must present a certificate for
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param connect_ip The IPv4 or IPv6 address to connect to
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if connect_ip is not an IP
+///         address or response_len is not positive, ERR_BUFFER_TOO_SMALL (-9) if the response
+///         does not fit, ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with
+///         arti_set_max_http_body
+int arti_http_request_to_ip(const char *circuit_id,
+                            const char *url,
+                            const char *method,
+                            const char *headers,
+                            const char *body,
+                            const char *connect_ip,
+                            char *response,
+                            int response_len);
+
 /// Connect to a target through Tor with TLS (HTTPS)
 ///
 /// @param circuit_id The circuit ID to use
-/// @param host The target hostname
+/// @param host The target hostname, without a scheme or port
 /// @param port The target port
 /// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
 int arti_connect_tls_stream(const char *circuit_id,
                             const char *host,
                             int port,
                             const char *stream_id);
 
+/// Connect to a target through Tor with TLS, WITHOUT verifying the server's certificate
+///
+/// DANGEROUS: any certificate is accepted, whoever issued it and whatever name it
+/// is for, so the connection is open to interception by anyone on the path after
+/// the exit, including the exit itself. Only use this for development servers or
+/// onion services with self-signed certificates, where the address itself
+/// authenticates the server. Always uses the default TLS versions, ignoring
+/// arti_set_tls_min_version. Otherwise behaves like arti_connect_tls_stream.
+///
+/// @param circuit_id The circuit ID to use
+/// @param host The target hostname, without a scheme or port
+/// @param port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+int arti_connect_tls_stream_insecure(const char *circuit_id,
+                                     const char *host,
+                                     int port,
+                                     const char *stream_id);
+
 /// Write data to a TLS stream
 ///
 /// @param stream_id A null-terminated string representing the stream ID
 /// @param data Pointer to the data to write
-/// @param data_len Length of the data
+/// @param data_len Length of the data, between 1 and INT_MAX
 /// @return 1 on success, 0 on failure
 int arti_tls_write(const char *stream_id, const uint8_t *data, uintptr_t data_len);
 
@@ -129,15 +567,1174 @@ int arti_flush_tls_stream(const char *stream_id);
 ///
 /// @param stream_id A null-terminated string representing the stream ID
 /// @param buffer Pointer to the buffer to store the read data
-/// @param buffer_len Length of the buffer
-/// @param bytes_read Output parameter that will receive the number of bytes read
-/// @return 1 on success, 0 on failure
+/// @param buffer_len Length of the buffer, between 1 and INT_MAX
+/// @return The number of bytes read (0 at end of stream), or -1 on failure
 int arti_tls_read(const char *stream_id, uint8_t *buffer, uintptr_t buffer_len);
 
-/// Close a TLS stream
+/// Get details of the certificate presented by the peer of a TLS stream
+///
+/// Writes a JSON object with the leaf certificate's "subject", "issuer", "sans" (DNS
+/// names and IP addresses) and "not_before"/"not_after" as Unix timestamps in seconds.
+/// The certificate has already been validated against the web PKI roots for the host
+/// given to arti_connect_tls_stream; use this for additional checks of your own.
 ///
 /// @param stream_id A null-terminated string representing the stream ID
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
 /// @return 1 on success, 0 on failure
+int arti_tls_peer_cert_info(const char *stream_id, char *out_json, int out_len);
+
+/// Get the parameters negotiated on a TLS stream
+///
+/// Writes a JSON object with the protocol "version" (e.g. "TLSv1.3"), the
+/// "cipher_suite" (e.g. "TLS13_AES_256_GCM_SHA384") and the "alpn" protocol, which
+/// is null when none was negotiated.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure or if the stream is not a TLS stream,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+int arti_tls_connection_info(const char *stream_id, char *out_json, int out_len);
+
+/// Close a TLS stream
+///
+/// As with arti_close_stream, closing a stream that is already closed returns
+/// ERR_STREAM_NOT_FOUND.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if there is no such stream,
+///         ERR_INTERNAL (-5) if internal state is broken
 int arti_close_tls_stream(const char *stream_id);
 
+/// Set a callback that receives log messages from torpc and Arti
+///
+/// Once a callback is installed, diagnostics are no longer printed to the console.
+/// Messages above the level set with arti_set_log_level are dropped. Passing NULL
+/// removes the callback and restores console output.
+///
+/// @param cb Function called with the level (1 = error ... 5 = trace) and a null-terminated message
+/// @return 1 on success, 0 on failure
+int arti_set_log_callback(void (*cb)(int level, const char *msg));
+
+/// Set a callback that is notified when circuits and streams are created or closed
+///
+/// The callback runs on a dedicated background thread, never while torpc holds
+/// its internal locks, so it may call other arti_* functions. Events are
+/// delivered in the order they happened. Passing NULL removes the callback.
+///
+/// @param cb Function called with the event type (1 = circuit created, 2 = circuit destroyed,
+///         3 = stream opened, 4 = stream closed) and the null-terminated circuit or stream ID
+/// @return 1 on success, 0 on failure
+int arti_set_event_callback(void (*cb)(int event_type, const char *id));
+
+/// Set the maximum level of messages delivered to the log callback
+///
+/// @param level 0 = off, 1 = error, 2 = warn, 3 = info (default), 4 = debug, 5 = trace
+/// @return 1 on success, 0 on failure
+int arti_set_log_level(int level);
+
+/// Set how much diagnostic output is printed when no log callback is installed
+///
+/// Errors and warnings are always printed to stderr. Informational and debug
+/// messages are printed to stderr only up to the given level. Nothing is written
+/// to stdout. When a log callback is installed, use arti_set_log_level instead.
+///
+/// @param level 0 = off (default), 3 = info, 4 = debug, 5 = trace
+/// @return 1 on success, 0 on failure
+int arti_set_verbose(int level);
+
+/// Get the number of bytes read from and written to a stream
+///
+/// Counts are cumulative since the stream was opened. Peeked data is counted
+/// once it has been consumed by a read.
+///
+/// @param stream_id The stream ID
+/// @param out_read Output parameter that will receive the number of bytes read
+/// @param out_written Output parameter that will receive the number of bytes written
+/// @return 1 on success, 0 on failure
+int arti_stream_stats(const char *stream_id, uint64_t *out_read, uint64_t *out_written);
+
+/// Describe a stream ID, including whether it still exists
+///
+/// The result is a null-terminated JSON object:
+/// `{"exists": bool, "type": "plain" | "split" | "tls" | null, "circuit": string | null,
+///   "bytes_in": number | null, "bytes_out": number | null, "age_ms": number | null}`.
+/// An unknown ID is reported with `"exists": false` rather than as an error. Byte
+/// counts and age are only tracked for plain streams and are null for the others.
+///
+/// @param stream_id The stream ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_stream_info(const char *stream_id, char *out_json, int out_len);
+
+/// Limit the combined throughput of all stream reads and writes
+///
+/// The limit is a token bucket shared by every plain and TLS stream, with a
+/// burst allowance of one second's worth of data.
+///
+/// @param bytes_per_sec Maximum bytes per second, or 0 to disable the limit
+/// @return 1 on success, 0 on failure
+int arti_set_rate_limit(uint64_t bytes_per_sec);
+
+/// Report which optional capabilities this library was built with
+///
+/// Writes a JSON object such as
+/// `{"onion_client": false, "onion_service": false, "pt_bridges": false,
+///   "experimental_apis": false, "geoip": false}`.
+/// Arti features are chosen at compile time, so callers can use this to fail fast
+/// with a clear message instead of attempting an unsupported operation.
+///
+/// @param out_json Output buffer that will receive the null-terminated JSON
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure
+int arti_features(char *out_json, int out_len);
+
+/// Connect to a target, send a request, and read the whole response in one call
+///
+/// Opens a stream on the circuit, writes and flushes the request, reads until the
+/// peer closes the connection, then closes the stream. If the response is larger
+/// than the output buffer, it is truncated to out_len bytes.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param request The request bytes to send
+/// @param request_len The length of the request
+/// @param out_buf The buffer to store the response
+/// @param out_len The maximum length of the response buffer
+/// @param bytes_written Output parameter that will receive the number of response bytes stored
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+int arti_request_once(const char *circuit_id,
+                      const char *target_host,
+                      int32_t target_port,
+                      const char *request,
+                      int request_len,
+                      char *out_buf,
+                      int out_len,
+                      int *bytes_written);
+
+/// Start an HTTP request whose body is streamed with arti_http_upload_write
+///
+/// The request is sent as soon as it starts; body chunks are passed on as they are
+/// written, so large bodies never need to be held in memory at once.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (POST, PUT or PATCH are typical)
+/// @param headers A JSON object of request headers
+/// @param content_length The total body length, or -1 to use chunked transfer encoding
+/// @return A non-zero upload handle on success, 0 on failure
+uint64_t arti_http_upload_begin(const char *circuit_id,
+                                const char *url,
+                                const char *method,
+                                const char *headers,
+                                int64_t content_length);
+
+/// Write a chunk of the request body for an HTTP upload
+///
+/// Blocks while earlier chunks are still waiting to be sent, so the caller is
+/// paced by the speed of the connection.
+///
+/// @param handle The upload handle returned by arti_http_upload_begin
+/// @param data The body data to send
+/// @param data_len The length of the data
+/// @return 1 on success, 0 on failure
+int arti_http_upload_write(uint64_t handle, const char *data, int data_len);
+
+/// Finish an HTTP upload and wait for the response
+///
+/// Ends the request body, waits for the server's response, and releases the handle.
+/// The response is written as the same JSON object returned by arti_http_request.
+///
+/// @param handle The upload handle returned by arti_http_upload_begin
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_BODY_TOO_LARGE (-10) if the response body exceeds
+///         the limit set with arti_set_max_http_body
+int arti_http_upload_finish(uint64_t handle, char *response, int response_len);
+
+/// Start an HTTP or HTTPS request through Tor and read its response body in pieces
+///
+/// Returns once the response headers have arrived, without waiting for the body.
+/// Read the body with arti_http_stream_read and release the response with
+/// arti_http_stream_close, which may be called at any time to abandon the download.
+/// Response bodies read this way are not subject to arti_set_max_http_body.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param stream_id Output buffer that will receive the null-terminated HTTP stream ID
+/// @param stream_id_len Length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+///         (no request is sent), ERR_TIMEOUT (-8) if the request timed out, ERR_INTERNAL (-5)
+///         if internal state is broken
+int arti_http_stream_open(const char *circuit_id,
+                          const char *url,
+                          const char *method,
+                          const char *headers,
+                          const char *body,
+                          char *stream_id,
+                          int stream_id_len);
+
+/// Read the next piece of a response body opened with arti_http_stream_open
+///
+/// Blocks until some of the body is available. A read of 0 bytes marks the end
+/// of the body.
+///
+/// @param stream_id The HTTP stream ID
+/// @param buffer The buffer to store the data
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_read Output parameter that will receive the number of bytes read
+/// @return 1 on success, 0 on failure, ERR_CANCELLED (-6) if the stream was closed
+///         with arti_http_stream_close while reading
+int arti_http_stream_read(const char *stream_id, char *buffer, int buffer_len, int *bytes_read);
+
+/// Close an HTTP stream opened with arti_http_stream_open
+///
+/// The rest of the body is abandoned and the connection and its Tor stream are
+/// released straight away. A read blocked on the stream in another thread
+/// returns ERR_CANCELLED (-6).
+///
+/// @param stream_id The HTTP stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if the stream is already
+///         closed or unknown
+int arti_http_stream_close(const char *stream_id);
+
+/// Report whether datagram (UDP) traffic can be carried over Tor
+///
+/// Tor only carries TCP streams, so this currently always returns 0. For DNS,
+/// use arti_resolve, which performs the lookup at the exit relay.
+///
+/// @return 1 if UDP is supported, 0 if not
+int arti_supports_udp();
+
+/// Resolve a hostname through Tor
+///
+/// The lookup is performed by an exit relay, so it does not leak to the local
+/// resolver. The addresses are written as a JSON array of strings, e.g.
+/// `["93.184.216.34", "2606:2800:220:1::"]`. At most 32 addresses are returned.
+/// The lookup gives up after the timeout set with arti_set_resolve_timeout.
+///
+/// @param circuit_id The circuit ID to use
+/// @param hostname The hostname to resolve
+/// @param out_json Output buffer that will receive the null-terminated JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if the lookup timed out
+int arti_resolve(const char *circuit_id, const char *hostname, char *out_json, int out_len);
+
+/// Set how long arti_resolve waits for an answer
+///
+/// @param timeout_ms The timeout in milliseconds; the default is 30 seconds
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) if timeout_ms is 0
+int arti_set_resolve_timeout(uint64_t timeout_ms);
+
+/// Set the directories Arti uses for its persistent state and directory cache
+///
+/// Arti holds a lock on its state directory while running, so two processes using
+/// the same directory conflict and initialization returns ERR_STATE_LOCKED (-7).
+/// Give each process its own state directory to run several side by side.
+/// Must be called before arti_init.
+///
+/// @param state_dir A null-terminated path for the state directory, or NULL for the default
+/// @param cache_dir A null-terminated path for the cache directory, or NULL for the default
+/// @return 1 on success, 0 on failure
+int arti_set_storage_dirs(const char *state_dir, const char *cache_dir);
+
+/// Set the circuit build timeout
+///
+/// This maps to Arti's circuit request timeout (`circuit_timing.request_timeout`):
+/// the total time spent building and retrying circuits for one request, so slow
+/// guards fail fast instead of stalling for tens of seconds. Arti does not let the
+/// timeout of a single build attempt be set; that one adapts to the observed
+/// network speed and still applies within this limit.
+/// Must be called before arti_init.
+///
+/// @param timeout_ms The timeout in milliseconds, or 0 to restore Arti's default
+/// @return 1 on success, 0 on failure
+int arti_set_circuit_build_timeout_ms(uint64_t timeout_ms);
+
+/// Set the target used by arti_check_connectivity
+///
+/// Defaults to www.torproject.org:443.
+///
+/// @param host The target hostname
+/// @param port The target port
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+int arti_set_connectivity_target(const char *host, int32_t port);
+
+/// Check that the Tor network is actually reachable
+///
+/// Opens a short-lived stream to the connectivity target over a fresh circuit and
+/// closes it again. Unlike arti_is_connected, this verifies end-to-end reachability.
+///
+/// @param timeout_ms Maximum time to wait for the stream, in milliseconds
+/// @return 1 if the target was reached, 0 on failure, ERR_NOT_INITIALIZED (-1) if the
+///         client is not initialized, ERR_TIMEOUT (-8) if the timeout expired
+int arti_check_connectivity(uint64_t timeout_ms);
+
+/// Set which IP address family exit connections use
+///
+/// Applies to every stream opened by the client, including those on circuits and
+/// isolated clients created later. Arti chooses guard and relay addresses itself;
+/// this only controls how exits connect to targets.
+/// Must be called before arti_init.
+///
+/// @param preference 0 = auto (prefer IPv4), 1 = IPv4 only, 2 = IPv6 only, 3 = prefer IPv6
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown preference
+int arti_set_address_family_preference(int preference);
+
+/// Get the validity window of the client's current network consensus
+///
+/// Writes a JSON object with "valid_after", "fresh_until" and "valid_until" as Unix
+/// timestamps in seconds, and "live" telling whether the consensus is currently valid.
+/// Requires a build with the `experimental-api` feature.
+///
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not
+///         initialized or has no usable directory yet, ERR_NOT_SUPPORTED (-11) if built without
+///         the experimental-api feature
+int arti_get_directory_info(char *out_json, int out_len);
+
+/// Close streams that have been idle for too long
+///
+/// A background task closes any stream with no reads or writes for longer than the
+/// timeout, so streams the caller forgot to close don't leak. Applies to streams
+/// opened with the arti_connect_stream family.
+///
+/// @param timeout_ms The idle timeout in milliseconds, or 0 to disable reaping
+/// @return 1 on success, 0 on failure
+int arti_set_idle_timeout_ms(uint64_t timeout_ms);
+
+/// Write as much data to a stream as it accepts right now
+///
+/// Unlike arti_write_stream, this makes a single write attempt and reports how many
+/// bytes were accepted, which may be fewer than data_len or 0 if the stream cannot
+/// take more data at the moment. Retry with the remainder for custom flow control.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @param bytes_written Output parameter that will receive the number of bytes accepted
+/// @return 1 on success, 0 on failure
+int arti_write_stream_partial(const char *stream_id,
+                              const char *data,
+                              int data_len,
+                              int *bytes_written);
+
+/// Start a SOCKS5 proxy backed by the initialized Tor client
+///
+/// Lets external tools such as curl or browsers use the same bootstrapped client.
+/// The proxy listens on 127.0.0.1 only and accepts SOCKS5 CONNECT requests. As with
+/// Tor's SocksPort, a username and password are accepted but not checked: connections
+/// with different credentials are isolated onto different circuits, while those with
+/// the same credentials may share one. Only one proxy can run at a time.
+///
+/// @param port The port to listen on, or 0 to pick a free port
+/// @return The port the proxy is listening on, 0 on failure, ERR_NOT_INITIALIZED (-1) if
+///         the client is not initialized, ERR_INVALID_PARAMS (-4) for an invalid port
+int arti_start_socks_proxy(int port);
+
+/// Stop the SOCKS5 proxy started with arti_start_socks_proxy
+///
+/// Connections already relayed through the proxy are not interrupted.
+///
+/// @return 1 if a proxy was stopped, 0 if none was running
+int arti_stop_socks_proxy();
+
+/// Put the Tor client to sleep or wake it up
+///
+/// In soft dormant mode the client stops building circuits preemptively and
+/// reduces background activity, but streams can still be opened on demand.
+/// Call with normal mode to resume. The mode applies to every circuit.
+///
+/// @param mode 0 = normal, 1 = soft dormant, 2 = hard dormant (not supported by arti-client 0.10)
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not
+///         initialized, ERR_INVALID_PARAMS (-4) for an unknown or unsupported mode
+int arti_set_dormant(int mode);
+
+/// Write data to a stream, waiting for the peer to make room, up to a timeout
+///
+/// Tor flow control stops accepting data while the receiver isn't reading. This
+/// waits for the data to be accepted and flushed, reporting how much got through
+/// if the timeout expires first, so callers don't buffer without bound.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @param timeout_ms Maximum time to wait, in milliseconds
+/// @param bytes_written Output parameter that will receive the number of bytes accepted
+/// @return 1 if all data was written, 0 on failure, ERR_TIMEOUT (-8) if the timeout expired first
+int arti_write_stream_blocking(const char *stream_id,
+                               const char *data,
+                               int data_len,
+                               uint64_t timeout_ms,
+                               int *bytes_written);
+
+/// Create an additional, independent Tor client
+///
+/// Each client has its own configuration, guards and circuits, so an application
+/// can run e.g. a bridged and a direct client side by side. Clients must not share
+/// a state directory. Create circuits on a client with arti_client_create_circuit;
+/// the stream functions then work on those circuits as usual. Handle 0 always
+/// refers to the global client set up by arti_init.
+///
+/// @param config_path Path to an Arti TOML configuration file, or NULL for the settings
+///                    made with the arti_set_* configuration functions
+/// @return A non-zero client handle on success, 0 on failure
+uint64_t arti_client_new(const char *config_path);
+
+/// Creates a new Tor circuit with the given ID on a specific client
+///
+/// Circuit IDs share one namespace across all clients.
+///
+/// @param client_handle A handle from arti_client_new, or 0 for the global client
+/// @param circuit_id A null-terminated string representing a unique circuit ID
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client does not exist,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached
+int arti_client_create_circuit(uint64_t client_handle, const char *circuit_id);
+
+/// Release a client created with arti_client_new
+///
+/// Circuits created on the client keep working until they are destroyed.
+///
+/// @param client_handle A handle from arti_client_new
+/// @return 1 on success, 0 if the handle is unknown
+int arti_client_free(uint64_t client_handle);
+
+/// Load country databases from files
+///
+/// The files use Tor's legacy geoip format (the `geoip` and `geoip6` files shipped
+/// with Tor). They are parsed and validated immediately, and used by
+/// arti_geoip_lookup. The Arti version this library is built on can't take them for
+/// exit selection, so arti_connect_stream_country returns ERR_NOT_SUPPORTED once they
+/// are loaded. Requires a build with the `geoip` feature.
+///
+/// @param ipv4_path Path to the IPv4 geoip file
+/// @param ipv6_path Path to the IPv6 geoip file
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if a file is missing or malformed,
+///         ERR_NOT_SUPPORTED (-11) if built without the geoip feature
+int arti_set_geoip_files(const char *ipv4_path, const char *ipv6_path);
+
+/// Look up the country of an IP address in the databases loaded with arti_set_geoip_files
+///
+/// @param ip A null-terminated IPv4 or IPv6 address
+/// @param out_country Output buffer that will receive the null-terminated two-letter country code
+/// @param out_len Length of the output buffer
+/// @return 1 if a country was found, 0 if not found or on failure, ERR_INVALID_PARAMS (-4) for
+///         a malformed address, ERR_NOT_INITIALIZED (-1) if no databases are loaded,
+///         ERR_NOT_SUPPORTED (-11) if built without the geoip feature
+int arti_geoip_lookup(const char *ip, char *out_country, int out_len);
+
+/// Get the most recent error message reported on the calling thread
+///
+/// Every failure that is logged at error level, including a caught internal panic,
+/// is also kept as the thread's last error. The message stays until a newer error
+/// replaces it.
+///
+/// @param out_buf Output buffer that will receive the null-terminated message
+/// @param out_len Length of the output buffer
+/// @return 1 if a message was written, 0 if there is no error to report,
+///         ERR_BUFFER_TOO_SMALL (-9) if the message does not fit
+int arti_last_error(char *out_buf, int out_len);
+
+/// Limit how many HTTP requests run at the same time
+///
+/// Requests beyond the limit wait for a running one to finish; the wait counts
+/// towards the request's timeout. Applies to every HTTP function, including
+/// uploads. Requests already waiting when the limit changes keep the old limit.
+///
+/// @param max_concurrent Maximum number of requests in flight, or 0 for no limit
+/// @return 1 on success, 0 on failure
+int arti_set_http_max_concurrency(uint32_t max_concurrent);
+
+/// Limit the size of HTTP response bodies
+///
+/// Bodies are read in chunks and the request fails with ERR_BODY_TOO_LARGE (-10) as
+/// soon as the limit is exceeded, so a huge response can't exhaust memory. When a
+/// body is decompressed, the limit also applies to the decoded size.
+///
+/// @param max_bytes The largest body accepted, in bytes, or 0 for no limit
+/// @return 1 on success
+int arti_set_max_http_body(uint64_t max_bytes);
+
+/// Connect to a target through a circuit and tag the stream with a label
+///
+/// Every stream opened on a circuit belongs to that circuit's group; the label only
+/// identifies the stream in arti_get_stream_group. Labels need not be unique.
+///
+/// @param circuit_id The circuit ID to use
+/// @param label A null-terminated label for the stream
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_circuit_open_labeled_stream(const char *circuit_id,
+                                     const char *label,
+                                     const char *target_host,
+                                     int32_t target_port,
+                                     char *stream_id,
+                                     int stream_id_len);
+
+/// Get the open streams of a circuit and their labels
+///
+/// The result is a null-terminated JSON object mapping stream IDs to labels, with
+/// an empty label for streams opened without one, e.g. `{"c-stream-1": "video"}`.
+///
+/// @param circuit_id The circuit ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_get_stream_group(const char *circuit_id, char *out_json, int out_len);
+
+/// Close every stream opened on a circuit
+///
+/// Closes plain, split and TLS streams alike. The circuit itself stays usable.
+///
+/// @param circuit_id The circuit ID
+/// @return The number of streams closed (0 if there were none), or ERR_INTERNAL (-5)
+///         if internal state is broken
+int arti_close_stream_group(const char *circuit_id);
+
+/// Read data from a stream into a buffer allocated by the library
+///
+/// Saves the caller from sizing a buffer up front. On success, *out_ptr receives a
+/// buffer of exactly *out_len bytes that belongs to the caller and must be released
+/// with arti_free_bytes, passing the same length; it must not be freed with free()
+/// or any other allocator. At end of stream *out_ptr is null and *out_len is 0.
+///
+/// @param stream_id The stream ID
+/// @param max_len The maximum number of bytes to read
+/// @param out_ptr Output parameter that will receive the buffer
+/// @param out_len Output parameter that will receive the number of bytes read
+/// @return 1 on success, 0 on failure
+int arti_read_stream_owned(const char *stream_id,
+                           uintptr_t max_len,
+                           uint8_t **out_ptr,
+                           uintptr_t *out_len);
+
+/// Free a buffer returned by arti_read_stream_owned
+///
+/// @param ptr The buffer, or null (which is ignored)
+/// @param len The length reported alongside the buffer
+void arti_free_bytes(uint8_t *ptr, uintptr_t len);
+
+/// Close all streams and circuits but keep the Tor client
+///
+/// Closes every plain, split and TLS stream, cancels pending background connects and
+/// destroys every circuit. The client stays bootstrapped, so new circuits and
+/// streams can be created right away. The SOCKS proxy started with
+/// arti_start_socks_proxy keeps running.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+int arti_reset_streams_and_circuits();
+
+/// Get connection and traffic counters
+///
+/// The result is a null-terminated JSON object:
+/// `{"attempts": n, "successes": n, "failures": n, "timeouts": n, "bytes_in": n,
+///   "bytes_out": n, "active_streams": n}`.
+/// The connect counters cover every stream opened through Tor, including those made
+/// for HTTP requests and by the SOCKS proxy; timeouts are also counted as failures.
+/// The byte counters cover all stream traffic, as described for arti_total_bandwidth,
+/// and active_streams counts plain streams, split halves and TLS streams. Counters
+/// are never reset.
+///
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+int arti_get_metrics(char *out_json, int out_len);
+
+/// Get the total number of bytes sent and received over Tor since the library was loaded
+///
+/// Covers plain, split and TLS streams, HTTP requests and traffic relayed by the
+/// SOCKS proxy. Only stream payload is counted: TLS handshakes and record overhead,
+/// Tor cell overhead and the client's own directory traffic are not included, since
+/// Arti does not report them. The totals are never reset, so compare two readings
+/// to measure the traffic in between.
+///
+/// @param out_sent Output parameter that will receive the number of bytes sent
+/// @param out_received Output parameter that will receive the number of bytes received
+/// @return 1 on success, 0 on failure
+int arti_total_bandwidth(uint64_t *out_sent, uint64_t *out_received);
+
+/// Set the headers sent with every HTTP request
+///
+/// Replaces the default headers, which initially hold only a User-Agent matching
+/// Tor Browser so requests are harder to tell apart from its traffic. Headers passed
+/// to an individual request take precedence over these, compared case-insensitively.
+/// Passing "{}" sends no default headers at all, not even a User-Agent.
+///
+/// @param headers A JSON object mapping header names to string values
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is not an
+///         object of valid header names and string values
+int arti_set_default_http_headers(const char *headers);
+
+/// Connect to an IP address through Tor and return a stream ID
+///
+/// Unlike arti_connect_stream, the host must be an IPv4 or IPv6 literal (IPv6 with or
+/// without brackets), so the exit connects to exactly that address without a DNS
+/// lookup. This pins the server, but the exit still sees the address, and skipping
+/// DNS does not hide which service is reached: an IP often identifies it as well as
+/// a hostname does. Resolving the address yourself outside Tor would leak it, so
+/// obtain it through arti_resolve or a trusted source.
+///
+/// @param circuit_id The circuit ID to use
+/// @param ip The target IP address
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the IP address or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_stream_ip(const char *circuit_id,
+                           const char *ip,
+                           int32_t target_port,
+                           char *stream_id,
+                           int stream_id_len);
+
+/// Set the oldest TLS version accepted by arti_connect_tls_stream
+///
+/// Applies to connections made after the call. TLS 1.0 and 1.1 are insecure and are
+/// never accepted.
+///
+/// @param version "1.2" to accept TLS 1.2 and 1.3 (the default), or "1.3" to require TLS 1.3
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for any other version
+int arti_set_tls_min_version(const char *version);
+
+/// Check whether a read from a stream would return without blocking
+///
+/// Useful for event-loop integration: polls the stream once without waiting. A
+/// stream that has reached end of stream counts as readable, since a read returns
+/// 0 immediately. Split halves are not supported.
+///
+/// @param stream_id The stream ID
+/// @return 1 if data (or end of stream) is ready, 0 if a read would block, -1 on error
+int arti_stream_readable(const char *stream_id);
+
+/// Close a TLS stream cleanly, sending the TLS close_notify alert
+///
+/// arti_close_tls_stream drops the connection without close_notify, which some
+/// servers treat as a truncation attack. This flushes pending data and sends
+/// close_notify before the stream is released. The stream is removed even if
+/// sending fails.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @return 1 on a clean close, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if the stream is
+///         already closed or unknown
+int arti_close_tls_stream_graceful(const char *stream_id);
+
+/// Set how much connection padding the client sends to its guards
+///
+/// Padding cells hide when the client is idle and make traffic analysis on the
+/// link to the guard harder. Normal padding costs a few hundred bytes per minute
+/// for each open channel; reduced padding cuts that at the price of weaker
+/// protection, which suits mobile or metered connections. Disabling padding makes
+/// the client stand out and is not recommended.
+/// Must be called before arti_init.
+///
+/// @param mode 0 = normal (the default), 1 = reduced, 2 = none
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown mode
+int arti_set_padding(int mode);
+
+/// Set the directory sources used to bootstrap, for private or test networks
+///
+/// Replaces the built-in fallback directory caches and directory authorities
+/// with the ones given. The JSON follows the [tor_network] section of Arti's
+/// configuration file:
+/// {"fallback_caches": [{"rsa_identity": "...", "ed_identity": "...", "orports": ["127.0.0.1:5000"]}],
+///  "authorities": [{"name": "test000a", "v3ident": "..."}]}
+/// Either key may be left out to keep the defaults for that list. For a chutney
+/// network, the arti.toml it generates can instead be passed to arti_init_with_config.
+/// Must be called before arti_init; settings loaded from a config file are not affected.
+///
+/// @param network_json JSON object describing the directory sources, or NULL to restore the defaults
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is malformed
+int arti_set_fallback_dirs(const char *network_json);
+
+/// Start bootstrapping the Tor client without waiting for it to finish
+///
+/// An alternative to arti_init for UIs that show progress: returns straight away
+/// while the client bootstraps on the runtime, and arti_bootstrap_progress reports
+/// how far it got. The client is only installed, and arti_is_connected only
+/// reports 1, once bootstrap has completed. Calling this while a bootstrap is
+/// already running does nothing. Settings made before arti_init apply here too.
+///
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_begin_bootstrap();
+
+/// Get the progress of a bootstrap started with arti_begin_bootstrap
+///
+/// @param out_percent Output parameter that will receive the progress, from 0 to 100.
+///         100 means the client is bootstrapped and ready to use.
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if no bootstrap was started,
+///         ERR_CONNECTION_FAILED (-2) if the bootstrap failed (see arti_last_error),
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_bootstrap_progress(int *out_percent);
+
+/// Connect to the host and port named by a URL, using TLS when the scheme calls for it
+///
+/// Supported schemes are http (plain, default port 80), https (TLS, default port
+/// 443) and tcp (plain, port required). Only the scheme, host and port are used;
+/// no request is sent. A TLS stream must be used with the arti_tls_* functions and
+/// arti_close_tls_stream, a plain one with arti_read_stream and friends.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to connect to, e.g. "https://example.com" or "tcp://example.com:7000"
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param is_tls Output parameter that will receive 1 for a TLS stream, 0 for a plain one (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the URL is malformed or its scheme
+///         unsupported, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_url(const char *circuit_id,
+                     const char *url,
+                     char *stream_id,
+                     int stream_id_len,
+                     int *is_tls);
+
+/// Write data to a stream and flush it in one call
+///
+/// Equivalent to arti_write_stream followed by arti_flush_stream, saving a call
+/// per message. Works on plain streams and split write halves.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @return 1 on success, 0 on failure
+int arti_write_stream_flush(const char *stream_id, const char *data, int data_len);
+
+/// Set the bridges the client connects through, replacing any set before
+///
+/// Requires a build with the `pt-bridges` feature. Each entry is a bridge line as
+/// used in torrc, without the leading "Bridge", e.g.
+/// "obfs4 192.0.2.1:443 <fingerprint> cert=... iat-mode=0". Lines that can't be
+/// parsed are skipped and logged. An empty array removes all bridges.
+/// Must be called before arti_init.
+///
+/// @param bridges_json A JSON array of bridge lines
+/// @return The number of bridges accepted, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is not
+///         an array of strings or no line could be parsed, ERR_NOT_SUPPORTED (-11) if built without
+///         the pt-bridges feature
+int arti_set_bridges(const char *bridges_json);
+
+/// Shut down the library completely
+///
+/// Closes every stream, destroys all circuits, stops the SOCKS proxy and the idle
+/// stream reaper, drops the global client and every client from arti_client_new,
+/// and shuts down the async runtime, waiting up to 5 seconds for its tasks. The
+/// library can then be unloaded safely. To use it again, call arti_init (or
+/// arti_begin_bootstrap) and re-apply arti_set_idle_timeout_ms if needed.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+int arti_shutdown_all();
+
+/// Get how long a circuit took to build and its current round-trip time
+///
+/// The build time is how long the first stream on the circuit took to open, which
+/// includes building the path. The round-trip time is measured now by opening and
+/// closing a probe stream to the connectivity target (see
+/// arti_set_connectivity_target); since the path already exists, this is roughly
+/// one round trip to the exit plus the exit's connection to the target.
+///
+/// @param circuit_id The circuit ID
+/// @param out_build_ms Output parameter that will receive the build time in milliseconds
+/// @param out_rtt_ms Output parameter that will receive the round-trip time in milliseconds
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if no stream has been opened on
+///         the circuit yet, ERR_CONNECTION_FAILED (-2) if the probe stream failed
+int arti_circuit_latency_ms(const char *circuit_id, uint64_t *out_build_ms, uint64_t *out_rtt_ms);
+
+/// Check whether any exit relay in the current consensus accepts a port
+///
+/// Most exits only allow common ports, so streams to unusual ports often fail.
+/// Call this to warn before trying. Relays flagged as bad exits are not counted.
+/// Requires a build with the `experimental-api` feature.
+///
+/// @param port The target port
+/// @return 1 if at least one exit allows the port over IPv4 or IPv6, 0 if none does,
+///         ERR_INVALID_PARAMS (-4) for an invalid port, ERR_NOT_INITIALIZED (-1) if no
+///         directory is available (client not initialized or not bootstrapped),
+///         ERR_NOT_SUPPORTED (-11) if built without the experimental-api feature
+int arti_exit_allows_port(int port);
+
+/// Send a raw HTTP/1.1 request and read the response, without reqwest
+///
+/// The request bytes are sent exactly as given, so the request line and headers
+/// are fully under the caller's control; nothing is added, reordered or encoded.
+/// The response is returned as the status line and headers exactly as received,
+/// followed by the body. A chunked body is de-chunked (trailers are dropped) but
+/// the head still says so; compressed bodies are left as they are. The body ends
+/// where Content-Length says, or when the server closes the connection.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param request The raw request bytes, including the blank line ending the headers
+/// @param request_len The length of the request
+/// @param out_buf The buffer to store the response
+/// @param out_len The length of the response buffer
+/// @param bytes_written Output parameter that will receive the response length, or the
+///         required buffer length if the buffer is too small
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10) if
+///         the body exceeds the limit set with arti_set_max_http_body
+int arti_http1_request(const char *circuit_id,
+                       const char *target_host,
+                       int32_t target_port,
+                       const char *request,
+                       int request_len,
+                       char *out_buf,
+                       int out_len,
+                       int *bytes_written);
+
+/// Make an HTTP or HTTPS request on whichever of several circuits answers first
+///
+/// The request is sent on every circuit at once. The first successful response is
+/// returned and the requests still in flight on the other circuits are cancelled.
+/// The response JSON has the same schema as arti_http_request. Note that a request
+/// with side effects may reach the server more than once.
+///
+/// @param circuit_ids_json A JSON array of circuit IDs to race, e.g. ["a", "b"]
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @param used_circuit Output buffer that will receive the ID of the circuit that won
+/// @param used_circuit_len Length of the used_circuit buffer
+/// @return 1 on success, 0 on failure or if every circuit failed, ERR_INVALID_PARAMS (-4)
+///         if the circuit list is empty or a buffer length is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response or circuit ID does not fit,
+///         ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with arti_set_max_http_body
+int arti_http_request_fastest(const char *circuit_ids_json,
+                              const char *url,
+                              const char *method,
+                              const char *headers,
+                              const char *body,
+                              char *response,
+                              int response_len,
+                              char *used_circuit,
+                              int used_circuit_len);
+
+/// Export the IDs of the open circuits so they can be recreated after a restart
+///
+/// The result is a null-terminated JSON object listing every circuit with its
+/// isolation, e.g. `{"circuits": [{"id": "session-1", "isolation": "client"}]}`.
+/// Every circuit is currently its own isolated client, so isolation is always
+/// "client". Only the logical grouping is exported; the paths themselves are not,
+/// and circuits recreated with arti_import_circuits get new ones.
+///
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_export_circuits(char *out_json, int out_len);
+
+/// Recreate circuits exported with arti_export_circuits
+///
+/// Each circuit is created as a new isolated client of the bootstrapped Tor client,
+/// so it gets a fresh path. Circuits that already exist are left untouched. The
+/// whole list is validated before any circuit is created.
+///
+/// @param json A null-terminated JSON object as produced by arti_export_circuits
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_INVALID_PARAMS (-4) if the JSON is malformed, ERR_CIRCUIT_LIMIT (-12) if the
+///         limit set with arti_set_max_circuits is reached (circuits imported before that are
+///         kept), ERR_INTERNAL (-5) if internal state is broken
+int arti_import_circuits(const char *json);
+
+/// Upgrade an open plain stream to TLS, as in STARTTLS
+///
+/// For protocols such as SMTP and IMAP that negotiate TLS after a plaintext
+/// exchange. The stream is taken out of the plain stream map and a TLS handshake
+/// is performed over it with the settings used by arti_connect_tls_stream. On
+/// success the TLS stream is stored under a new ID, belonging to the same circuit,
+/// and the plain stream ID is no longer valid. The plain stream is closed if the
+/// handshake fails. Data peeked but not yet read prevents the upgrade, since it
+/// would be lost; read it first.
+///
+/// @param stream_id The plain stream to upgrade
+/// @param sni_host The server name to send and verify the certificate against
+/// @param out_tls_stream_id Output buffer that will receive the new TLS stream ID
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the stream has unread
+///         peeked data, ERR_BUFFER_TOO_SMALL (-9) if the output buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_upgrade_stream_to_tls(const char *stream_id,
+                               const char *sni_host,
+                               char *out_tls_stream_id,
+                               int out_len);
+
+/// Build several isolated circuits and report the exit IP addresses they use
+///
+/// Each circuit asks check.torproject.org for the address its request came from.
+/// The result is a null-terminated JSON array of the distinct addresses with the
+/// number of circuits that used each, most common first, e.g.
+/// `[{"ip": "185.220.101.1", "count": 2}, {"ip": "192.42.116.16", "count": 1}]`.
+/// Circuits that fail or time out are left out, so the counts may add up to less
+/// than count. The temporary circuits are destroyed before returning.
+///
+/// @param count Number of circuits to build, between 1 and 64
+/// @param timeout_ms Time each circuit has to report its exit IP, in milliseconds
+/// @param out_json Output buffer that will receive the JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_INVALID_PARAMS (-4) if count or timeout_ms is out of range,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+int arti_sample_exit_ips(int count, uint64_t timeout_ms, char *out_json, int out_len);
+
+/// Start writing data to a stream in the background
+///
+/// Returns immediately with an operation ID; use arti_write_poll to learn when
+/// the write has finished. The data is copied, so the caller may free it as soon
+/// as this returns. Writes started on the same stream are sent one after another,
+/// in the order they were started.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @return A non-zero operation ID on success, 0 on failure
+uint64_t arti_write_stream_start(const char *stream_id, const char *data, int data_len);
+
+/// Check whether a write started with arti_write_stream_start has finished
+///
+/// Once the write is reported done the operation ID is released.
+///
+/// @param op_id The operation ID returned by arti_write_stream_start
+/// @param out_done Output parameter set to 1 if the write has finished, 0 if it is in progress
+/// @param out_err Output parameter set to 0 if the finished write succeeded, or to
+///         ERR_CONNECTION_FAILED (-2) if it failed
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) for an unknown operation ID or null pointers,
+///         ERR_INTERNAL (-5) if internal state is broken
+int arti_write_poll(uint64_t op_id, int *out_done, int *out_err);
+
+/// Route Tor's own connections to the network through an upstream proxy
+///
+/// Meant for networks where Tor can only get out through a proxy. arti-client
+/// 0.10 cannot connect to relays through a proxy, so after validating the URL
+/// this always returns ERR_NOT_SUPPORTED for now and nothing is configured. Must
+/// be called before arti_init.
+///
+/// @param socks_url The proxy, e.g. "socks5://127.0.0.1:1080" (socks4, socks4a and socks5 are accepted)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the URL is malformed,
+///         ERR_NOT_SUPPORTED (-11) if upstream proxies are not supported
+int arti_set_upstream_proxy(const char *socks_url);
+
+/// Connect to a target through Tor and describe how the connect went
+///
+/// Behaves like arti_connect_stream and also writes a null-terminated JSON object
+/// such as `{"stream_id": "c-stream-1", "connect_ms": 840, "reused_circuit": false,
+/// "exit_nickname": null}`. reused_circuit is true when an earlier stream on the same
+/// circuit ID had already built a path, so this connect didn't have to wait for one.
+/// Arti doesn't report relay nicknames, so exit_nickname is always null for now.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if either buffer is too small
+int arti_connect_stream_detailed(const char *circuit_id,
+                                 const char *target_host,
+                                 int32_t target_port,
+                                 char *stream_id,
+                                 int stream_id_len,
+                                 char *out_json,
+                                 int out_len);
+
+/// Connect to a target through Tor over a given IP address family and return a stream ID
+///
+/// Overrides arti_set_address_family_preference for this stream only. When the
+/// target has no address in the requested family, the exit refuses the stream and
+/// the connect fails.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param family 4 for IPv4 only, 6 for IPv6 only, or 0 for the global preference
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown family or a
+///         malformed host or port, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+int arti_connect_stream_family(const char *circuit_id,
+                               const char *target_host,
+                               int32_t target_port,
+                               int family,
+                               char *stream_id,
+                               int stream_id_len);
+
+/// Read one line from a stream
+///
+/// Reads up to and including the next newline, so CRLF-terminated lines end in
+/// "\r\n". Data received past the line is kept for the next read, so lines split
+/// across network reads, or several lines in one, are handled. If the line doesn't
+/// fit, the buffer is filled and the rest of the line is returned by the next call.
+/// At the end of the stream, whatever is left is returned without a newline, and
+/// then 0 bytes. Not available for streams split with arti_split_stream. Closing
+/// the stream from another thread ends the wait.
+///
+/// @param stream_id The stream ID
+/// @param buffer The buffer to store the line
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_read Output parameter that will receive the length of the line
+/// @param timeout_ms Maximum time to wait for a complete line in milliseconds, or 0 for none
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if no complete line arrived in time;
+///         any partial line stays buffered for the next read
+int arti_read_line(const char *stream_id,
+                   char *buffer,
+                   int buffer_len,
+                   int *bytes_read,
+                   uint64_t timeout_ms);
+
+/// Read and discard the data waiting on a stream
+///
+/// Keeps reading until max_bytes have been discarded, the stream ends, or no new
+/// data arrives for timeout_ms; with a timeout of 0 only data that has already
+/// arrived is discarded. Data buffered by arti_peek_stream is discarded too. Useful
+/// to reset a protocol or to clean up before closing.
+///
+/// @param stream_id The stream ID
+/// @param max_bytes Most bytes to discard, or 0 for no limit
+/// @param timeout_ms How long to wait for more data before stopping, in milliseconds
+/// @param out_drained Output parameter that will receive the number of bytes discarded
+/// @return 1 on success, 0 on failure; out_drained is set even when a read fails
+int arti_drain_stream(const char *stream_id,
+                      uint64_t max_bytes,
+                      uint64_t timeout_ms,
+                      uint64_t *out_drained);
+
+/// Keep a long-lived stream from being treated as idle, and notice when it dies
+///
+/// Tor has no stream-level keepalive message and sending bytes would corrupt the
+/// application protocol, so nothing is sent. Instead, every interval_ms a background
+/// task probes the stream without consuming data, as arti_stream_readable does, and
+/// counts the probe as activity so arti_set_idle_timeout_ms leaves the stream open.
+/// When the probe finds the stream closed or failed, the stream is removed and the
+/// stream closed event is sent to the arti_set_event_callback callback. Data that
+/// arrives during a probe stays buffered for the next read. Streams that are busy in
+/// a blocking read or write are skipped until the next interval. Applies to streams
+/// opened with the arti_connect_stream family.
+///
+/// @param stream_id The stream ID
+/// @param interval_ms How often to probe the stream, in milliseconds, or 0 to stop probing
+/// @return 1 on success, 0 on failure
+int arti_set_stream_keepalive(const char *stream_id, uint64_t interval_ms);
+
+/// Close several streams in one call
+///
+/// Closes plain, split and TLS streams alike. IDs that don't name an open stream
+/// are skipped, so one stale ID doesn't stop the rest of the batch.
+///
+/// @param stream_ids_json A JSON array of stream IDs, e.g. ["c-stream-1", "c-stream-2"]
+/// @return The number of streams closed, ERR_INVALID_PARAMS (-4) if the JSON is not an
+///         array of strings, ERR_INTERNAL (-5) if internal state is broken
+int arti_close_streams(const char *stream_ids_json);
+
+/// Set how long idle HTTP connections are kept for reuse
+///
+/// HTTP requests keep their connection open after the response has been read, so
+/// the next request on the same circuit to the same host and port can skip opening
+/// a new Tor stream and, for HTTPS, the TLS handshake. Connections are pooled per
+/// circuit and isolation token, never shared between circuits, and are dropped
+/// with their circuit. A connection is only reused after a complete, successful
+/// response; when a request fails, the circuit's pool is discarded. Connections
+/// already pooled are dropped when the setting changes. The default is 30 seconds.
+///
+/// @param ttl_ms How long an idle connection is kept, in milliseconds, or 0 to open a
+///        new connection for every request
+/// @return 1 on success, 0 on failure
+int arti_set_http_pool_ttl_ms(uint64_t ttl_ms);
+
+/// Check that a configuration file can be used, without bootstrapping
+///
+/// Reads and parses the file the same way arti_init_with_config does and builds
+/// the client configuration from it, catching both TOML syntax errors and settings
+/// Arti rejects. No client is created, nothing is written to disk and the network
+/// is not touched. Paths in the file are not checked for existence.
+///
+/// @param config_path A null-terminated path to the configuration file
+/// @param out_error Output buffer that will receive a null-terminated description of
+///        what is wrong with the file, or NULL if not needed; left untouched if valid
+/// @param out_len Length of the output buffer
+/// @return 1 if the configuration is valid, 0 if it is not or on failure,
+///         ERR_BUFFER_TOO_SMALL (-9) if the file is invalid and the description doesn't fit
+int arti_validate_config(const char *config_path, char *out_error, int out_len);
+
+/// Read data from a stream into several buffers at once
+///
+/// Makes a single read of up to the combined length of the buffers, but at most
+/// 64 KiB, and spreads the data across them in order, each one filled before the
+/// next is used, so a caller can keep e.g. a fixed-size header apart from the
+/// body. Like arti_read_stream this returns whatever data is available, which may
+/// fill only the first buffers or part of one; buffers past out_total_read are
+/// untouched.
+/// Works on plain streams and split read halves.
+///
+/// @param stream_id The stream ID
+/// @param iovecs Array of count buffers; zero-length entries are skipped
+/// @param count Number of entries in iovecs
+/// @param out_total_read Output parameter that will receive the number of bytes read, 0 at end of stream
+/// @return 1 on success, 0 on failure
+int arti_read_stream_vectored(const char *stream_id,
+                              const ArtiIoVec *iovecs,
+                              uintptr_t count,
+                              uintptr_t *out_total_read);
+
+/// Initialize the Arti Tor client with its state and cache in a temporary directory
+///
+/// Nothing is kept once the client shuts down, but this is not in-memory storage:
+/// arti-client 0.10 cannot hold its state and directory cache in memory and writes
+/// them to disk while the client runs. This puts both in a new directory under the
+/// system temp directory that only the current user can access, and deletes it
+/// when the client is shut down with arti_disconnect or arti_shutdown_all, or when
+/// initialization fails. Every session therefore starts with a fresh guard set and
+/// downloads the directory again, which makes bootstrap slower. For nothing to
+/// reach a disk at all, point TMPDIR (TMP on Windows) at a RAM-backed filesystem
+/// such as tmpfs. Storage directories set with arti_set_storage_dirs are ignored
+/// for the session and apply again afterwards; other settings made before init
+/// still apply.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+int arti_init_temporary();
+
+/// Limit how many circuits can exist at once
+///
+/// Once the limit is reached, creating another circuit fails with ERR_CIRCUIT_LIMIT
+/// (-12) until one is destroyed with arti_destroy_circuit; each destroyed circuit
+/// frees a slot. Circuits on every client count towards the limit. Lowering the
+/// limit below the current number of circuits doesn't destroy any; it only stops
+/// new ones from being created.
+///
+/// @param max_circuits The most circuits allowed, or 0 for no limit (the default)
+/// @return 1 on success
+int arti_set_max_circuits(uint32_t max_circuits);
+
+/// Get the status and headers of a response opened with arti_http_stream_open
+///
+/// The headers are available as soon as arti_http_stream_open returns, before
+/// any of the body has been read, so a caller can look at the status, length or
+/// type and close the stream without downloading the body. Reading the body
+/// doesn't change the result, and it can be called while a read is in progress.
+/// The result is a null-terminated JSON object:
+/// `{"status": 200, "url": "<final url>", "headers": [["name", "value"], ...]}`,
+/// with headers in wire order and duplicates such as Set-Cookie kept.
+///
+/// @param stream_id The HTTP stream ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure or if the stream is unknown,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+int arti_http_stream_headers(const char *stream_id, char *out_json, int out_len);
+
 } // extern "C"
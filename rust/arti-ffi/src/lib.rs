@@ -4,17 +4,21 @@
 // from JavaScript/TypeScript through Bun's FFI capabilities.
 
 use std::ffi::{CStr, CString};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, Once, RwLock};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::os::raw::{c_char, c_int};
 use std::sync::Arc;
 use std::path::Path;
 use std::fs::File;
 use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
 
-use arti_client::{TorClient, TorClientConfig, DataStream};
+use arti_client::{TorClient, TorClientConfig, DataStream, StreamPrefs, IsolationToken, ErrorKind, HasKind};
+use arti_client::config::CfgPath;
 use tokio::runtime::{Runtime, Builder};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tor_rtcompat::PreferredRuntime;
 use anyhow::{Result, anyhow};
 use lazy_static::lazy_static;
@@ -28,7 +32,12 @@ use std::str::FromStr;
 use rustls::RootCertStore;
 use webpki_roots::TLS_SERVER_ROOTS;
 use std::cell::RefCell;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
 use reqwest;
+use serde::Serialize;
+use base64::Engine;
 use serde_json;
 
 // Constants
@@ -42,26 +51,209 @@ const ERR_CONNECTION_FAILED: c_int = -2;
 const ERR_CIRCUIT_FAILED: c_int = -3;
 const ERR_INVALID_PARAMS: c_int = -4;
 const ERR_INTERNAL: c_int = -5;
+const ERR_CANCELLED: c_int = -6;
+const ERR_STATE_LOCKED: c_int = -7;
+const ERR_TIMEOUT: c_int = -8;
+const ERR_BUFFER_TOO_SMALL: c_int = -9;
+const ERR_BODY_TOO_LARGE: c_int = -10;
+const ERR_NOT_SUPPORTED: c_int = -11;
+const ERR_CIRCUIT_LIMIT: c_int = -12;
+const ERR_STREAM_NOT_FOUND: c_int = -13;
+
+// User-Agent sent by default, matching Tor Browser so requests blend in with its users
+const TOR_BROWSER_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; rv:128.0) Gecko/20100101 Firefox/128.0";
 
 // Default SOCKS port used by the Tor client
 const TOR_SOCKS_PORT: u16 = 9050;
 
+// Most circuits arti_sample_exit_ips builds in one call
+const MAX_EXIT_IP_SAMPLES: c_int = 64;
+
+// Most addresses arti_resolve returns for one name
+const MAX_RESOLVED_ADDRESSES: usize = 32;
+
+// Longest wait between attempts in arti_init_with_retry
+const MAX_BOOTSTRAP_BACKOFF: Duration = Duration::from_secs(60);
+
+// Most bytes arti_read_stream_vectored reads in one call, whatever the buffer sizes
+const MAX_VECTORED_READ: usize = 64 * 1024;
+
+// How long arti_shutdown_all waits for runtime tasks to finish
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Service that reports the address a request came from, used to learn exit IPs
+const EXIT_IP_CHECK_URL: &str = "https://check.torproject.org/api/ip";
+
+// Target used to make Arti build a circuit's path ahead of the first real stream
+const WARMUP_TARGET: &str = "www.torproject.org:443";
+
+// Address family preferences understood by arti_set_address_family_preference
+const ADDRESS_FAMILY_AUTO: c_int = 0;
+const ADDRESS_FAMILY_IPV4_ONLY: c_int = 1;
+const ADDRESS_FAMILY_IPV6_ONLY: c_int = 2;
+const ADDRESS_FAMILY_PREFER_IPV6: c_int = 3;
+
+// Padding levels understood by arti_set_padding
+const PADDING_NORMAL: c_int = 0;
+const PADDING_REDUCED: c_int = 1;
+const PADDING_NONE: c_int = 2;
+
+// Lifecycle events passed to the callback set with arti_set_event_callback
+const EVENT_CIRCUIT_CREATED: c_int = 1;
+const EVENT_CIRCUIT_DESTROYED: c_int = 2;
+const EVENT_STREAM_OPENED: c_int = 3;
+const EVENT_STREAM_CLOSED: c_int = 4;
+
+// Dormant modes understood by arti_set_dormant
+const DORMANT_NORMAL: c_int = 0;
+const DORMANT_SOFT: c_int = 1;
+const DORMANT_HARD: c_int = 2;
+
+// Log levels understood by arti_set_log_level and passed to the log callback
+const LOG_LEVEL_OFF: c_int = 0;
+const LOG_LEVEL_ERROR: c_int = 1;
+const LOG_LEVEL_WARN: c_int = 2;
+const LOG_LEVEL_INFO: c_int = 3;
+const LOG_LEVEL_DEBUG: c_int = 4;
+const LOG_LEVEL_TRACE: c_int = 5;
+
+// Maximum level delivered to the log callback
+static LOG_LEVEL: AtomicI32 = AtomicI32::new(LOG_LEVEL_INFO);
+
+// Maximum level of info/debug messages printed to stderr when no log callback is
+// installed. Off by default so the library stays quiet unless asked.
+static VERBOSE_LEVEL: AtomicI32 = AtomicI32::new(LOG_LEVEL_OFF);
+
+// Route a diagnostic message through the logging bridge
+macro_rules! log_error {
+    ($($arg:tt)*) => { log_message(LOG_LEVEL_ERROR, &format!($($arg)*)) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log_message(LOG_LEVEL_WARN, &format!($($arg)*)) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { log_message(LOG_LEVEL_INFO, &format!($($arg)*)) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log_message(LOG_LEVEL_DEBUG, &format!($($arg)*)) };
+}
+
 // Global state to manage TorClient instances and circuits
 lazy_static! {
     static ref CLIENT: Mutex<Option<TorClient<PreferredRuntime>>> = Mutex::new(None);
     static ref CIRCUITS: Mutex<HashMap<String, Arc<TorClient<PreferredRuntime>>>> = Mutex::new(HashMap::new());
     static ref RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
-    static ref STREAMS: Mutex<HashMap<String, DataStream>> = Mutex::new(HashMap::new());
-    static ref TLS_CLIENT_CONFIG: StdArc<ClientConfig> = create_tls_config();
+    // Handle to RUNTIME, so spawning and blocking on it never waits on the RUNTIME lock
+    static ref RUNTIME_HANDLE: RwLock<Option<tokio::runtime::Handle>> = RwLock::new(None);
+    static ref STREAMS: Mutex<HashMap<String, BufferedStream>> = Mutex::new(HashMap::new());
+    // Plain streams taken out of STREAMS by a call that waits on the network, with a
+    // signal that fires if the stream is closed meanwhile
+    static ref TAKEN_STREAMS: Mutex<HashMap<String, StdArc<tokio::sync::Notify>>> = Mutex::new(HashMap::new());
+    static ref TLS_STREAMS: Mutex<HashMap<String, StdArc<Mutex<TlsStream<DataStream>>>>> = Mutex::new(HashMap::new());
+    static ref TLS_CLIENT_CONFIG: Mutex<Option<StdArc<ClientConfig>>> = Mutex::new(None);
+    static ref ISOLATION_TOKENS: Mutex<HashMap<String, IsolationToken>> = Mutex::new(HashMap::new());
+    static ref LOG_CALLBACK: Mutex<Option<extern "C" fn(c_int, *const c_char)>> = Mutex::new(None);
+    static ref EVENT_CALLBACK: Mutex<Option<extern "C" fn(c_int, *const c_char)>> = Mutex::new(None);
+    // Queue feeding the thread that delivers events, started with the first event
+    static ref EVENT_SENDER: Mutex<Option<std::sync::mpsc::Sender<(c_int, String)>>> = Mutex::new(None);
+    static ref RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::new(0));
+    static ref HTTP_UPLOADS: Mutex<HashMap<u64, HttpUpload>> = Mutex::new(HashMap::new());
+    static ref HTTP_STREAMS: Mutex<HashMap<String, HttpStreamHandle>> = Mutex::new(HashMap::new());
+    static ref CONFIG_OVERRIDES: Mutex<ConfigOverrides> = Mutex::new(ConfigOverrides::default());
+    // Temporary storage set up by arti_init_temporary, removed at shutdown
+    static ref TEMPORARY_STORAGE: Mutex<Option<TemporaryStorage>> = Mutex::new(None);
+    static ref SPLIT_READERS: Mutex<HashMap<String, SplitHalf<tokio::io::ReadHalf<BufferedStream>>>> = Mutex::new(HashMap::new());
+    static ref SPLIT_WRITERS: Mutex<HashMap<String, SplitHalf<tokio::io::WriteHalf<BufferedStream>>>> = Mutex::new(HashMap::new());
+    static ref CIRCUIT_PROXIES: Mutex<HashMap<CircuitProxyKey, CircuitProxy>> = Mutex::new(HashMap::new());
+    static ref IDLE_REAPER: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+    static ref SOCKS_PROXY: Mutex<Option<CircuitProxy>> = Mutex::new(None);
+    static ref CLIENTS: Mutex<HashMap<u64, TorClient<PreferredRuntime>>> = Mutex::new(HashMap::new());
+    static ref BACKGROUND_BOOTSTRAP: Mutex<Option<BackgroundBootstrap>> = Mutex::new(None);
+    // How long the first stream on each circuit took to open, path build included
+    static ref CIRCUIT_BUILD_TIMES: Mutex<HashMap<String, Duration>> = Mutex::new(HashMap::new());
+    static ref GEOIP_DB: Mutex<Option<Arc<GeoipDbHandle>>> = Mutex::new(None);
+    static ref CONNECTIVITY_TARGET: Mutex<String> = Mutex::new(WARMUP_TARGET.to_string());
+    // Connects still running, removed by each connect task when it ends
+    static ref PENDING_CONNECTS: Mutex<HashMap<u64, tokio::task::AbortHandle>> = Mutex::new(HashMap::new());
+    // Outcome of finished connects not yet collected with arti_connect_poll
+    static ref FINISHED_CONNECTS: Mutex<BTreeMap<u64, ConnectOutcome>> = Mutex::new(BTreeMap::new());
+    static ref PENDING_WRITES: Mutex<HashMap<u64, tokio::task::JoinHandle<Result<()>>>> = Mutex::new(HashMap::new());
+    // Last background write started on each stream and a signal that fires once it is done
+    static ref WRITE_QUEUES: Mutex<HashMap<String, (u64, tokio::sync::oneshot::Receiver<()>)>> = Mutex::new(HashMap::new());
+    static ref DEFAULT_HTTP_HEADERS: Mutex<Vec<(String, String)>> = Mutex::new(vec![
+        ("User-Agent".to_string(), TOR_BROWSER_USER_AGENT.to_string()),
+    ]);
+    // Streams opened on each circuit, mapped to their label ("" if unlabeled)
+    static ref STREAM_GROUPS: Mutex<HashMap<String, HashMap<String, String>>> = Mutex::new(HashMap::new());
+    // Bounds the number of HTTP requests in flight; None means unlimited
+    static ref HTTP_CONCURRENCY: Mutex<Option<StdArc<tokio::sync::Semaphore>>> = Mutex::new(None);
 }
 
-// Define TLS_STREAMS as a thread-local HashMap of Mutex-protected TLS streams
+// Counters reported by arti_get_metrics
+static CONNECT_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static CONNECT_SUCCESSES: AtomicU64 = AtomicU64::new(0);
+static CONNECT_FAILURES: AtomicU64 = AtomicU64::new(0);
+static CONNECT_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+// Largest HTTP response body accepted, in bytes; 0 means unlimited
+static MAX_HTTP_BODY: AtomicU64 = AtomicU64::new(0);
+
+// How long arti_resolve waits for an answer, in milliseconds
+static RESOLVE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(30_000);
+
+// Source of handles for HTTP uploads; 0 is never handed out
+static NEXT_UPLOAD_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+// Streams idle for longer than this many milliseconds are closed; 0 disables reaping
+static IDLE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+// Most circuits that may exist at once; 0 means unlimited
+static MAX_CIRCUITS: AtomicU64 = AtomicU64::new(0);
+
+// Idle HTTP connections are kept for reuse this many milliseconds; 0 disables pooling
+static HTTP_POOL_TTL_MS: AtomicU64 = AtomicU64::new(30_000);
+
+// Country database loaded with arti_set_geoip_files
+#[cfg(feature = "geoip")]
+type GeoipDbHandle = tor_geoip::GeoipDb;
+#[cfg(not(feature = "geoip"))]
+type GeoipDbHandle = ();
+
+// Source of handles for additional Tor clients; 0 is the global client
+static NEXT_CLIENT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+// Source of cancel tokens for background connects; 0 is never handed out
+static NEXT_CANCEL_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+// Source of the numbers in circuit IDs generated by arti_create_circuits
+static NEXT_CIRCUIT: AtomicU64 = AtomicU64::new(1);
+
+// Source of the numbers in stream IDs, so two streams never share an ID
+static NEXT_STREAM: AtomicU64 = AtomicU64::new(1);
+
+// Source of the numbers in HTTP response stream IDs
+static NEXT_HTTP_STREAM: AtomicU64 = AtomicU64::new(1);
+
+// Source of operation IDs for background writes; 0 is never handed out
+static NEXT_WRITE_OP: AtomicU64 = AtomicU64::new(1);
+
+// Most recent error message reported on each thread, for arti_last_error
 thread_local! {
-    static TLS_STREAMS: RefCell<HashMap<String, StdArc<Mutex<TlsStream<DataStream>>>>> = RefCell::new(HashMap::new());
+    static LAST_ERROR: RefCell<Option<String>> = RefCell::new(None);
 }
 
-// Create TLS configuration with system root certificates
-fn create_tls_config() -> StdArc<ClientConfig> {
+// Installs the tracing subscriber that forwards Arti's events to the log callback
+static TRACING_INIT: Once = Once::new();
+
+
+// Create TLS configuration with system root certificates, accepting only the given
+// protocol versions
+fn create_tls_config(versions: &[&'static rustls::SupportedProtocolVersion]) -> Result<StdArc<ClientConfig>> {
     let mut root_store = RootCertStore::empty();
     
     // Add Mozilla's root certificates
@@ -79,28 +271,228 @@ fn create_tls_config() -> StdArc<ClientConfig> {
     );
     
     let tls_config = ClientConfig::builder()
-        .with_safe_defaults()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
         .with_root_certificates(root_store)
         .with_no_client_auth();
     
-    StdArc::new(tls_config)
+    Ok(StdArc::new(tls_config))
+}
+
+// The TLS configuration for new connections, created with the default protocol
+// versions on first use
+fn tls_client_config() -> Result<StdArc<ClientConfig>> {
+    let mut config = TLS_CLIENT_CONFIG.lock()
+        .map_err(|_| anyhow!("Failed to lock TLS config mutex"))?;
+    if let Some(c) = config.as_ref() {
+        return Ok(StdArc::clone(c));
+    }
+
+    let created = create_tls_config(rustls::DEFAULT_VERSIONS)?;
+    *config = Some(StdArc::clone(&created));
+    Ok(created)
+}
+
+// A DataStream with a read-ahead buffer, so incoming data can be peeked
+// without being consumed. Reads are served from the buffer first.
+// Also counts the bytes delivered to and accepted from the caller, and when
+// data last moved in either direction. Generic only so tests can wrap an
+// in-memory stream.
+struct BufferedStream<S = DataStream> {
+    inner: S,
+    read_ahead: Vec<u8>,
+    bytes_read: u64,
+    bytes_written: u64,
+    opened_at: Instant,
+    last_activity: Instant,
+    // Task probing the stream for arti_set_stream_keepalive; it stops by itself
+    // once the stream is gone
+    keepalive: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl<S: AsyncRead + Unpin> BufferedStream<S> {
+    fn new(inner: S) -> Self {
+        BufferedStream {
+            inner,
+            read_ahead: Vec::new(),
+            bytes_read: 0,
+            bytes_written: 0,
+            opened_at: Instant::now(),
+            last_activity: Instant::now(),
+            keepalive: None,
+        }
+    }
+
+    // Copy up to buf.len() bytes of incoming data into buf without consuming it.
+    // Like a read, this only waits on the underlying stream when nothing is
+    // buffered, so it may return fewer bytes than requested.
+    async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.read_ahead.is_empty() && !buf.is_empty() {
+            let mut chunk = vec![0u8; buf.len()];
+            let n = self.inner.read(&mut chunk).await?;
+            self.read_ahead.extend_from_slice(&chunk[..n]);
+        }
+
+        let n = std::cmp::min(buf.len(), self.read_ahead.len());
+        buf[..n].copy_from_slice(&self.read_ahead[..n]);
+        Ok(n)
+    }
+
+    // Read up to and including the next '\n' into buf, or as much as fits if the
+    // line is longer than buf. Data read past the line stays buffered for the next
+    // read. Returns what is left, possibly nothing, at the end of the stream.
+    async fn read_line(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let line_end = self.read_ahead.iter().position(|&b| b == b'\n').map(|pos| pos + 1);
+            let n = match line_end {
+                Some(end) if end <= buf.len() => end,
+                _ if self.read_ahead.len() >= buf.len() => buf.len(),
+                _ => {
+                    let mut chunk = [0u8; 4096];
+                    let n = self.inner.read(&mut chunk).await?;
+                    if n > 0 {
+                        self.read_ahead.extend_from_slice(&chunk[..n]);
+                        continue;
+                    }
+                    self.read_ahead.len()
+                }
+            };
+
+            buf[..n].copy_from_slice(&self.read_ahead[..n]);
+            self.read_ahead.drain(..n);
+            self.bytes_read += n as u64;
+            BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+            self.last_activity = Instant::now();
+            return Ok(n);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for BufferedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let n = std::cmp::min(buf.remaining(), this.read_ahead.len());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.drain(..n);
+            this.bytes_read += n as u64;
+            BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+            this.last_activity = Instant::now();
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = (buf.filled().len() - filled_before) as u64;
+            this.bytes_read += n;
+            BYTES_IN.fetch_add(n, Ordering::Relaxed);
+            this.last_activity = Instant::now();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for BufferedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.bytes_written += n as u64;
+            BYTES_OUT.fetch_add(n as u64, Ordering::Relaxed);
+            this.last_activity = Instant::now();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Token bucket shared by all stream reads and writes. A rate of 0 means unlimited.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take `bytes` tokens from the bucket, going into debt if needed, and return
+    // how long the caller must wait before its bytes are within the limit.
+    // The bucket holds at most one second's worth of tokens.
+    fn reserve(&mut self, bytes: usize) -> Option<Duration> {
+        if self.bytes_per_sec == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let rate = self.bytes_per_sec as f64;
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            Some(Duration::from_secs_f64(-self.tokens / rate))
+        } else {
+            None
+        }
+    }
+}
+
+// Wait until `bytes` fit within the global rate limit. Bandwidth is reserved in
+// the order callers arrive, so concurrent streams share the limit fairly.
+async fn throttle(bytes: usize) {
+    let wait = match RATE_LIMITER.lock() {
+        Ok(mut limiter) => limiter.reserve(bytes),
+        Err(_) => None,
+    };
+
+    if let Some(delay) = wait {
+        tokio::time::sleep(delay).await;
+    }
 }
 
 /// Initialize the Arti Tor client with a default configuration
 /// 
 /// This function must be called before any other functions.
 /// 
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_init() -> c_int {
-    let result = initialize_tor_client(None);
-    match result {
-        Ok(_) => 1,
-        Err(e) => {
-            eprintln!("Failed to initialize Tor client: {:?}", e);
-            0
-        },
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        let result = initialize_tor_client(None);
+        match result {
+            Ok(_) => 1,
+            Err(e) => {
+                log_error!("Failed to initialize Tor client: {:?}", e);
+                init_error_code(&e)
+            },
+        }
+    })
 }
 
 /// Initialize the Arti Tor client with a custom configuration file
@@ -108,98 +500,265 @@ pub extern "C" fn arti_init() -> c_int {
 /// This function must be called before any other functions.
 /// 
 /// @param config_path A null-terminated string containing the path to the configuration file
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_init_with_config(config_path: *const c_char) -> c_int {
-    if config_path.is_null() {
-        return arti_init();
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        if config_path.is_null() {
+            return arti_init();
+        }
 
-    let c_str = unsafe { CStr::from_ptr(config_path) };
-    let config_path_str = match c_str.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to convert config path to string");
-            return 0;
+        let c_str = unsafe { CStr::from_ptr(config_path) };
+        let config_path_str = match c_str.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to convert config path to string");
+                return 0;
+            }
+        };
+
+        let result = initialize_tor_client(Some(config_path_str));
+        match result {
+            Ok(_) => 1,
+            Err(e) => {
+                log_error!("Failed to initialize Tor client with config: {:?}", e);
+                init_error_code(&e)
+            },
         }
-    };
+    })
+}
 
-    let result = initialize_tor_client(Some(config_path_str));
-    match result {
-        Ok(_) => 1,
-        Err(e) => {
-            eprintln!("Failed to initialize Tor client with config: {:?}", e);
-            0
-        },
-    }
+/// Initialize the Arti Tor client, retrying failed bootstraps with exponential backoff
+///
+/// Behaves like arti_init_with_config, but a failed bootstrap is retried up to
+/// max_attempts times in total. The wait before each retry starts at backoff_ms and
+/// doubles every attempt, up to one minute. A failed attempt leaves no client behind.
+/// Errors that retrying cannot fix, such as another process holding the state lock,
+/// are returned at once. Blocks the calling thread for the whole retry sequence.
+///
+/// @param config_path A null-terminated path to the configuration file, or null for the default
+/// @param max_attempts Maximum number of bootstrap attempts, at least 1
+/// @param backoff_ms Wait before the first retry in milliseconds
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) if max_attempts is not positive, otherwise
+///         the code from the last attempt as for arti_init_with_config
+#[no_mangle]
+pub extern "C" fn arti_init_with_retry(config_path: *const c_char, max_attempts: c_int, backoff_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if max_attempts <= 0 {
+            log_error!("Invalid max_attempts: {}", max_attempts);
+            return ERR_INVALID_PARAMS;
+        }
+
+        let config_path_str = if config_path.is_null() {
+            None
+        } else {
+            match unsafe { CStr::from_ptr(config_path) }.to_str() {
+                Ok(s) => Some(s),
+                Err(_) => {
+                    log_error!("Failed to convert config path to string");
+                    return 0;
+                }
+            }
+        };
+
+        let mut backoff = Duration::from_millis(backoff_ms);
+        for attempt in 1..=max_attempts {
+            let code = match initialize_tor_client(config_path_str) {
+                Ok(_) => return 1,
+                Err(e) => {
+                    log_warn!("Bootstrap attempt {} of {} failed: {:?}", attempt, max_attempts, e);
+                    init_error_code(&e)
+                }
+            };
+
+            // Retrying won't help with a held state lock or broken internal state
+            if attempt == max_attempts || code == ERR_STATE_LOCKED || code == ERR_INTERNAL {
+                log_error!("Failed to initialize Tor client after {} attempts", attempt);
+                return code;
+            }
+
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BOOTSTRAP_BACKOFF);
+        }
+        0
+    })
 }
 
 /// Creates a new Tor circuit with the given ID
 ///
+/// The Tor client must be initialized and bootstrapped first.
+///
 /// @param circuit_id A null-terminated string representing a unique circuit ID
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached,
+///         ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_create_circuit(circuit_id: *const c_char) -> c_int {
-    if circuit_id.is_null() {
-        return 0;
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() {
+            return 0;
+        }
 
-    let circuit_id_str = unsafe {
-        match CStr::from_ptr(circuit_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+        let circuit_id_str = unsafe {
+            match CStr::from_ptr(circuit_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+
+        if !client_ready() {
+            log_error!("Cannot create circuit {}: the Tor client is not bootstrapped, call arti_init first", circuit_id_str);
+            return ERR_NOT_INITIALIZED;
         }
-    };
 
-    match create_circuit(circuit_id_str) {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+        match create_circuit(circuit_id_str) {
+            Ok(_) => 1,
+            Err(e) => error_code(&e, 0),
+        }
+    })
+}
+
+/// Creates a new Tor circuit with the given ID once the client is bootstrapped
+///
+/// Like arti_create_circuit, but waits for a concurrent arti_init to finish
+/// bootstrapping instead of failing straight away.
+///
+/// @param circuit_id A null-terminated string representing a unique circuit ID
+/// @param timeout_ms Maximum time to wait for bootstrap, in milliseconds
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if the client was not bootstrapped in time,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_create_circuit_wait(circuit_id: *const c_char, timeout_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() {
+            return 0;
+        }
+
+        let circuit_id_str = unsafe {
+            match CStr::from_ptr(circuit_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+
+        // Poll rather than block on the runtime, which arti_init holds while bootstrapping
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        while !client_ready() {
+            if Instant::now() >= deadline {
+                log_error!("Timed out waiting for bootstrap to create circuit {}", circuit_id_str);
+                return ERR_TIMEOUT;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        match create_circuit(circuit_id_str) {
+            Ok(_) => 1,
+            Err(e) => error_code(&e, 0),
+        }
+    })
+}
+
+/// Creates several isolated Tor circuits at once
+///
+/// Each circuit uses its own isolation group, so no two of them share a path.
+/// With warmup enabled, a throwaway stream is opened on every circuit in parallel
+/// so the paths are already built when the first real stream is opened.
+/// The generated circuit IDs are written as a JSON array of strings.
+///
+/// @param count The number of circuits to create
+/// @param warmup 1 to pre-build the circuit paths, 0 to build them on first use
+/// @param out_ids_json Output buffer that will receive the null-terminated JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_CIRCUIT_LIMIT (-12) if the circuits would exceed the
+///         limit set with arti_set_max_circuits (none are created), ERR_INTERNAL (-5) if internal
+///         state is broken
+#[no_mangle]
+pub extern "C" fn arti_create_circuits(
+    count: c_int,
+    warmup: c_int,
+    out_ids_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if count <= 0 || out_ids_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_create_circuits");
+            return 0;
+        }
+
+        let ids = match create_circuits(count as usize, warmup != 0) {
+            Ok(ids) => ids,
+            Err(e) => {
+                log_error!("Failed to create circuits: {:?}", e);
+                return error_code(&e, 0);
+            }
+        };
+
+        let json = serde_json::to_string(&ids).unwrap_or_else(|_| "[]".to_string());
+        if !write_c_buffer(&json, out_ids_json, out_len) {
+            log_error!("Output buffer too small in arti_create_circuits");
+            // Don't leave behind circuits the caller can't address
+            for id in ids {
+                let _ = destroy_circuit(id);
+            }
+            return 0;
+        }
+        1
+    })
 }
 
 /// Destroys an existing Tor circuit
 ///
+/// Every stream opened on the circuit, including TLS streams, is closed too.
+///
 /// @param circuit_id A null-terminated string representing a unique circuit ID
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_destroy_circuit(circuit_id: *const c_char) -> c_int {
-    if circuit_id.is_null() {
-        return 0;
-    }
-
-    let circuit_id_str = unsafe {
-        match CStr::from_ptr(circuit_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() {
+            return 0;
         }
-    };
 
-    match destroy_circuit(circuit_id_str) {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+        let circuit_id_str = unsafe {
+            match CStr::from_ptr(circuit_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+
+        match destroy_circuit(circuit_id_str) {
+            Ok(_) => 1,
+            Err(e) => error_code(&e, 0),
+        }
+    })
 }
 
 /// Connects to the Tor network
 ///
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_connect() -> c_int {
-    match bootstrap_tor() {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        match bootstrap_tor() {
+            Ok(_) => 1,
+            Err(e) => error_code(&e, 0),
+        }
+    })
 }
 
 /// Disconnects from the Tor network
 ///
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
 #[no_mangle]
 pub extern "C" fn arti_disconnect() -> c_int {
-    match shutdown_tor() {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        match shutdown_tor() {
+            Ok(_) => 1,
+            Err(e) => error_code(&e, 0),
+        }
+    })
 }
 
 /// Checks if connected to the Tor network
@@ -207,20 +766,28 @@ pub extern "C" fn arti_disconnect() -> c_int {
 /// @return 1 if connected, 0 if not connected
 #[no_mangle]
 pub extern "C" fn arti_is_connected() -> c_int {
-    match is_connected() {
-        Ok(true) => 1,
-        _ => 0,
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        match is_connected() {
+            Ok(true) => 1,
+            _ => 0,
+        }
+    })
 }
 
 /// Connect to a target through Tor and return a stream ID
 ///
+/// The host may be a hostname, an IPv4 address, or an IPv6 address with or
+/// without brackets (e.g. "::1" or "[::1]"). It must not include a scheme or a
+/// port: "https://example.com" and "example.com:443" are rejected with
+/// ERR_INVALID_PARAMS and a message saying what to pass instead.
+///
 /// @param circuit_id The circuit ID to use
 /// @param target_host The target hostname
 /// @param target_port The target port
 /// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
 /// @param stream_id_len Maximum length of the stream ID buffer
-/// @return 1 on success, 0 on failure
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
 #[no_mangle]
 pub extern "C" fn arti_connect_stream(
     circuit_id: *const c_char,
@@ -229,1054 +796,9684 @@ pub extern "C" fn arti_connect_stream(
     stream_id: *mut c_char,
     stream_id_len: c_int,
 ) -> c_int {
-    if circuit_id.is_null() || target_host.is_null() || stream_id.is_null() || target_port <= 0 {
-        eprintln!("Invalid parameters in arti_connect_stream");
-        return 0;
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        connect_stream_with_prefs(circuit_id, target_host, target_port, stream_id, stream_id_len, &default_stream_prefs())
+    })
+}
 
-    let c_str_circuit = unsafe { CStr::from_ptr(circuit_id) };
-    let circuit_id_str = match c_str_circuit.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Invalid circuit ID string");
+/// Connect to a target through Tor using an isolation token and return a stream ID
+///
+/// Streams opened with the same isolation token may share a circuit, while streams
+/// with different tokens never do. This mirrors Tor's SOCKS username/password isolation.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param isolation_token A null-terminated string naming the isolation group
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_isolated(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    isolation_token: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if isolation_token.is_null() {
+            log_error!("Invalid parameters in arti_connect_stream_isolated");
             return 0;
         }
-    };
 
-    let c_str_host = unsafe { CStr::from_ptr(target_host) };
-    let host_str = match c_str_host.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Invalid host string");
-            return 0;
+        let token_str = match unsafe { CStr::from_ptr(isolation_token) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid isolation token string");
+                return 0;
+            }
+        };
+
+        let mut prefs = default_stream_prefs();
+        match isolation_token_for(token_str) {
+            Ok(token) => {
+                prefs.set_isolation(token);
+            },
+            Err(e) => {
+                log_error!("Failed to get isolation token: {:?}", e);
+                return 0;
+            }
         }
-    };
 
-    // Generate a unique stream ID
-    let stream_id_str = format!("{}-stream-{}", circuit_id_str, std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis());
+        connect_stream_with_prefs(circuit_id, target_host, target_port, stream_id, stream_id_len, &prefs)
+    })
+}
 
-    // Convert the stream ID to a C string and copy it to the output parameter
-    let stream_id_cstring = match CString::new(stream_id_str.clone()) {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to create stream ID C string");
+/// Connect to a target through a Tor exit in a specific country and return a stream ID
+///
+/// Requires a build with the `geoip` feature; relays are mapped to countries with the
+/// database built into Arti. Fails if no suitable exit in that country exists. Arti
+/// can't yet be given other databases, so once arti_set_geoip_files has loaded its own
+/// this returns ERR_NOT_SUPPORTED rather than pick exits with a different database.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param country_code A null-terminated two-letter ISO 3166-1 country code, e.g. "DE"
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the country code or target is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small, ERR_NOT_SUPPORTED (-11)
+///         if built without the geoip feature or after arti_set_geoip_files
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_country(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    country_code: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if country_code.is_null() {
+            log_error!("Invalid parameters in arti_connect_stream_country");
             return 0;
         }
-    };
 
-    let stream_id_bytes = stream_id_cstring.as_bytes_with_nul();
-    if stream_id_bytes.len() > stream_id_len as usize {
-        eprintln!("Stream ID buffer too small");
-        return 0;
-    }
+        let country_str = match unsafe { CStr::from_ptr(country_code) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid country code string");
+                return 0;
+            }
+        };
 
-    unsafe {
-        std::ptr::copy_nonoverlapping(
-            stream_id_bytes.as_ptr(),
-            stream_id as *mut u8,
-            stream_id_bytes.len(),
-        );
-    }
+        #[cfg(feature = "geoip")]
+        {
+            let country = match tor_geoip::CountryCode::from_str(country_str) {
+                Ok(c) => c,
+                Err(e) => {
+                    log_error!("Invalid country code {:?}: {}", country_str, e);
+                    return ERR_INVALID_PARAMS;
+                }
+            };
 
-    // Connect to the target
-    println!("DEBUG - Connecting to {}:{} through Tor", host_str, target_port);
-    
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to get runtime: {:?}", e);
-            return 0;
-        }
-    };
-    let runtime_guard = match runtime_mutex.lock() {
-        Ok(g) => g,
-        Err(_) => {
-            eprintln!("Failed to lock runtime mutex");
-            return 0;
-        }
-    };
-    
-    let runtime = match &*runtime_guard {
-        Some(r) => r,
-        None => {
-            eprintln!("Runtime not initialized");
-            return 0;
+            if GEOIP_DB.lock().map(|db| db.is_some()).unwrap_or(false) {
+                log_error!("Cannot select exit country {}: exits can't be chosen with databases from arti_set_geoip_files", country_str);
+                return ERR_NOT_SUPPORTED;
+            }
+
+            let mut prefs = default_stream_prefs();
+            prefs.exit_country(country);
+            connect_stream_with_prefs(circuit_id, target_host, target_port, stream_id, stream_id_len, &prefs)
         }
-    };
 
-    // Get the circuit
-    let circuits = match CIRCUITS.lock() {
-        Ok(c) => c,
-        Err(_) => {
-            eprintln!("Failed to lock circuits mutex");
-            return 0;
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = (circuit_id, target_host, target_port, stream_id, stream_id_len);
+            log_error!("Cannot select exit country {}: built without the geoip feature", country_str);
+            ERR_NOT_SUPPORTED
         }
-    };
+    })
+}
 
-    let circuit = match circuits.get(circuit_id_str) {
-        Some(c) => c.clone(),
-        None => {
-            eprintln!("Circuit not found: {}", circuit_id_str);
+/// Connect to an onion service through Tor and return a stream ID
+///
+/// Onion streams follow the same isolation rules as arti_connect_stream_isolated:
+/// streams with the same isolation token may share a circuit, while streams with
+/// different tokens never do. Requires a build with the `onion-client` feature.
+///
+/// @param circuit_id The circuit ID to use
+/// @param onion_host The .onion hostname
+/// @param target_port The target port
+/// @param isolation_token A null-terminated string naming the isolation group, or NULL for none
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host is not an onion
+///         address or the port is malformed, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small,
+///         ERR_NOT_SUPPORTED (-11) if built without the onion-client feature
+#[no_mangle]
+pub extern "C" fn arti_connect_onion_stream(
+    circuit_id: *const c_char,
+    onion_host: *const c_char,
+    target_port: i32,
+    isolation_token: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if onion_host.is_null() {
+            log_error!("Invalid parameters in arti_connect_onion_stream");
             return 0;
         }
-    };
 
-    // Connect to the target and store the stream
-    let target = format!("{}:{}", host_str, target_port);
-    let connect_result = runtime.block_on(async {
-        circuit.connect(target).await
-    });
+        let host_str = match unsafe { CStr::from_ptr(onion_host) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid host string");
+                return 0;
+            }
+        };
 
-    let stream = match connect_result {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to connect to target: {:?}", e);
-            return 0;
+        if !host_str.to_ascii_lowercase().ends_with(".onion") {
+            log_error!("Not an onion address: {:?}", host_str);
+            return ERR_INVALID_PARAMS;
         }
-    };
 
-    println!("DEBUG - Connected to target through Tor");
+        #[cfg(feature = "onion-client")]
+        {
+            let mut prefs = default_stream_prefs();
+            prefs.connect_to_onion_services(arti_client::config::BoolOrAuto::Explicit(true));
 
-    // Store the stream
-    let mut streams = match STREAMS.lock() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to lock streams mutex");
-            return 0;
+            if !isolation_token.is_null() {
+                let token_str = match unsafe { CStr::from_ptr(isolation_token) }.to_str() {
+                    Ok(s) => s,
+                    Err(_) => {
+                        log_error!("Invalid isolation token string");
+                        return 0;
+                    }
+                };
+
+                match isolation_token_for(token_str) {
+                    Ok(token) => {
+                        prefs.set_isolation(token);
+                    },
+                    Err(e) => {
+                        log_error!("Failed to get isolation token: {:?}", e);
+                        return 0;
+                    }
+                }
+            }
+
+            connect_stream_with_prefs(circuit_id, onion_host, target_port, stream_id, stream_id_len, &prefs)
         }
-    };
 
-    streams.insert(stream_id_str, stream);
-    
-    1
+        #[cfg(not(feature = "onion-client"))]
+        {
+            let _ = (circuit_id, target_port, isolation_token, stream_id, stream_id_len);
+            log_error!("Cannot connect to {}: built without the onion-client feature", host_str);
+            ERR_NOT_SUPPORTED
+        }
+    })
 }
 
-/// Write data to a stream
+/// Check whether an onion service is reachable right now
 ///
-/// @param stream_id The stream ID
-/// @param data The data to write
-/// @param data_len The length of the data
-/// @return 1 on success, 0 on failure
+/// Connects to the service and closes the stream straight away, so nothing is
+/// left open. Requires a build with the `onion-client` feature.
+///
+/// @param circuit_id The circuit ID to use
+/// @param onion_addr The .onion hostname
+/// @param port The port to probe
+/// @param timeout_ms Maximum time to wait for the connection, in milliseconds
+/// @return 1 if the service is reachable, 0 if it is not or on failure, ERR_TIMEOUT (-8) if
+///         the timeout expired, ERR_INVALID_PARAMS (-4) if the host is not an onion address
+///         or the port is malformed, ERR_NOT_SUPPORTED (-11) if built without the onion-client feature
 #[no_mangle]
-pub extern "C" fn arti_write_stream(
-    stream_id: *const c_char,
-    data: *const c_char,
-    data_len: c_int,
+pub extern "C" fn arti_onion_reachable(
+    circuit_id: *const c_char,
+    onion_addr: *const c_char,
+    port: i32,
+    timeout_ms: u64,
 ) -> c_int {
-    if stream_id.is_null() || data.is_null() || data_len <= 0 {
-        eprintln!("Invalid parameters in arti_write_stream");
-        return 0;
-    }
-
-    let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
-    let stream_id_str = match c_str_stream_id.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Invalid stream ID string");
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || onion_addr.is_null() || timeout_ms == 0 {
+            log_error!("Invalid parameters in arti_onion_reachable");
             return 0;
         }
-    };
 
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to get runtime: {:?}", e);
-            return 0;
-        }
-    };
-    let runtime_guard = match runtime_mutex.lock() {
-        Ok(g) => g,
-        Err(_) => {
-            eprintln!("Failed to lock runtime mutex");
-            return 0;
-        }
-    };
-    
-    let runtime = match &*runtime_guard {
-        Some(r) => r,
-        None => {
-            eprintln!("Runtime not initialized");
-            return 0;
-        }
-    };
+        let (circuit_id_str, host_str) = match (unsafe { CStr::from_ptr(circuit_id) }.to_str(), unsafe { CStr::from_ptr(onion_addr) }.to_str()) {
+            (Ok(c), Ok(h)) => (c, h),
+            _ => {
+                log_error!("Invalid string in arti_onion_reachable");
+                return 0;
+            }
+        };
 
-    // Get the stream
-    let mut streams = match STREAMS.lock() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to lock streams mutex");
-            return 0;
+        if !host_str.to_ascii_lowercase().ends_with(".onion") || format_target(host_str, port).is_none() {
+            log_error!("Not an onion address and port: {:?}, {}", host_str, port);
+            return ERR_INVALID_PARAMS;
         }
-    };
 
-    let stream = match streams.get_mut(stream_id_str) {
-        Some(s) => s,
-        None => {
-            eprintln!("Stream not found: {}", stream_id_str);
-            return 0;
-        }
-    };
+        #[cfg(feature = "onion-client")]
+        {
+            let circuit = match get_tor_client_by_circuit(circuit_id_str) {
+                Some(c) => c,
+                None => {
+                    log_error!("Circuit not found: {}", circuit_id_str);
+                    return 0;
+                }
+            };
 
-    // Get the data as a slice
-    let data_slice = unsafe {
-        std::slice::from_raw_parts(data as *const u8, data_len as usize)
-    };
+            let mut prefs = default_stream_prefs();
+            prefs.connect_to_onion_services(arti_client::config::BoolOrAuto::Explicit(true));
 
-    println!("DEBUG - Writing {} bytes to stream", data_len);
-    
-    // Write the data to the stream
-    let write_result = runtime.block_on(async {
-        stream.write_all(data_slice).await
-    });
+            let probe_result = block_on_runtime(tokio::time::timeout(
+                Duration::from_millis(timeout_ms),
+                circuit.connect_with_prefs((host_str, port as u16), &prefs),
+            ));
 
-    match write_result {
-        Ok(_) => 1,
-        Err(e) => {
-            eprintln!("Failed to write to stream: {:?}", e);
-            0
+            // The stream, if any, is dropped here, closing it
+            match probe_result {
+                Ok(Ok(Ok(_))) => 1,
+                Ok(Ok(Err(e))) => {
+                    log_warn!("Onion service {}:{} is not reachable: {:?}", host_str, port, e);
+                    0
+                },
+                Ok(Err(_)) => {
+                    log_warn!("Probe of onion service {}:{} timed out", host_str, port);
+                    ERR_TIMEOUT
+                },
+                Err(e) => {
+                    log_error!("Failed to run onion probe: {:?}", e);
+                    0
+                }
+            }
         }
-    }
+
+        #[cfg(not(feature = "onion-client"))]
+        {
+            let _ = circuit_id_str;
+            log_error!("Cannot probe {}: built without the onion-client feature", host_str);
+            ERR_NOT_SUPPORTED
+        }
+    })
 }
 
-/// Flush a stream
+/// Start connecting to a target through Tor in the background
 ///
-/// @param stream_id The stream ID
-/// @return 1 on success, 0 on failure
+/// Returns immediately with a cancel token. Use arti_connect_poll to collect the
+/// stream ID once the connect has finished, or arti_cancel to abort it.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @return A non-zero cancel token on success, 0 on failure
 #[no_mangle]
-pub extern "C" fn arti_flush_stream(
-    stream_id: *const c_char,
-) -> c_int {
-    if stream_id.is_null() {
-        eprintln!("Invalid parameters in arti_flush_stream");
-        return 0;
-    }
+pub extern "C" fn arti_connect_stream_cancelable(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+) -> u64 {
+    ffi_boundary(0, || {
+        let args = match parse_stream_connect(circuit_id, target_host, target_port) {
+            Ok(a) => a,
+            Err(_) => return 0,
+        };
 
-    let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
-    let stream_id_str = match c_str_stream_id.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Invalid stream ID string");
-            return 0;
-        }
-    };
+        let circuit = match get_tor_client_by_circuit(&args.circuit_id) {
+            Some(c) => c,
+            None => {
+                log_error!("Circuit not found: {}", args.circuit_id);
+                return 0;
+            }
+        };
 
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to get runtime: {:?}", e);
-            return 0;
-        }
-    };
-    let runtime_guard = match runtime_mutex.lock() {
-        Ok(g) => g,
-        Err(_) => {
-            eprintln!("Failed to lock runtime mutex");
-            return 0;
-        }
-    };
-    
-    let runtime = match &*runtime_guard {
-        Some(r) => r,
-        None => {
-            eprintln!("Runtime not initialized");
-            return 0;
-        }
-    };
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
 
-    // Get the stream
-    let mut streams = match STREAMS.lock() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to lock streams mutex");
-            return 0;
-        }
-    };
+        // Hold the lock until the task is recorded, so a connect that ends at once
+        // can't remove its entry before it is added
+        let mut pending = match PENDING_CONNECTS.lock() {
+            Ok(p) => p,
+            Err(_) => {
+                log_error!("Failed to lock pending connects mutex");
+                return 0;
+            }
+        };
 
-    let stream = match streams.get_mut(stream_id_str) {
-        Some(s) => s,
-        None => {
-            eprintln!("Stream not found: {}", stream_id_str);
-            return 0;
-        }
-    };
+        let token = NEXT_CANCEL_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let guard = PendingConnect { token, outcome: ConnectOutcome::Cancelled };
 
-    println!("DEBUG - Flushing stream");
-    
-    // Flush the stream
-    let flush_result = runtime.block_on(async {
-        stream.flush().await
-    });
+        log_debug!("Connecting to {}:{} through Tor in the background", args.host, args.port);
+        let task = handle.spawn(async move {
+            let mut guard = guard;
+            guard.outcome = match record_connect(circuit.connect(args.target).await) {
+                Ok(stream) => match STREAMS.lock() {
+                    Ok(mut streams) => {
+                        track_stream(&args.circuit_id, &args.stream_id, "");
+                        streams.insert(args.stream_id.clone(), BufferedStream::new(stream));
+                        ConnectOutcome::Connected(args.stream_id)
+                    },
+                    Err(_) => ConnectOutcome::Failed("Failed to lock streams mutex".to_string()),
+                },
+                Err(e) => ConnectOutcome::Failed(format!("{:?}", e)),
+            };
+        });
 
-    match flush_result {
-        Ok(_) => 1,
-        Err(e) => {
-            eprintln!("Failed to flush stream: {:?}", e);
-            0
+        pending.insert(token, task.abort_handle());
+        token
+    })
+}
+
+// How a connect started with arti_connect_stream_cancelable ended
+enum ConnectOutcome {
+    Connected(String),
+    Failed(String),
+    Cancelled,
+}
+
+// Finished connects whose outcome is kept for arti_connect_poll; the oldest are
+// forgotten first
+const MAX_FINISHED_CONNECTS: usize = 1024;
+
+// Owned by a background connect task. Dropping it, whether the task finished or
+// was aborted, moves the connect from PENDING_CONNECTS to FINISHED_CONNECTS.
+struct PendingConnect {
+    token: u64,
+    outcome: ConnectOutcome,
+}
+
+impl Drop for PendingConnect {
+    fn drop(&mut self) {
+        // Record the outcome before removing the pending entry, so a poll in between
+        // never finds neither
+        if let Ok(mut finished) = FINISHED_CONNECTS.lock() {
+            let outcome = std::mem::replace(&mut self.outcome, ConnectOutcome::Cancelled);
+            finished.insert(self.token, outcome);
+            while finished.len() > MAX_FINISHED_CONNECTS {
+                finished.pop_first();
+            }
+        }
+        if let Ok(mut pending) = PENDING_CONNECTS.lock() {
+            pending.remove(&self.token);
         }
     }
 }
 
-/// Read data from a stream
+/// Check the progress of a connect started with arti_connect_stream_cancelable
 ///
-/// @param stream_id The stream ID
-/// @param buffer The buffer to store the data
-/// @param buffer_len The maximum length of the buffer
-/// @param bytes_read Output parameter that will receive the number of bytes read
-/// @return 1 on success, 0 on failure
-#[no_mangle]
-pub extern "C" fn arti_read_stream(
-    stream_id: *const c_char,
-    buffer: *mut c_char,
-    buffer_len: c_int,
-    bytes_read: *mut c_int,
+/// Once a final result has been reported the token is released. Outcomes are kept
+/// for the 1024 most recent connects that have not been polled.
+///
+/// @param token The cancel token returned by arti_connect_stream_cancelable
+/// @param stream_id Output parameter that will receive a null-terminated string
+///        representing the stream ID once connected
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 if connected, 0 if still in progress, ERR_CONNECTION_FAILED (-2) if the
+///         connect failed, ERR_CANCELLED (-6) if it was cancelled, ERR_INVALID_PARAMS (-4)
+///         for an unknown token, ERR_BUFFER_TOO_SMALL (-9) if the stream ID doesn't fit
+///         (the token stays valid so the call can be repeated)
+#[no_mangle]
+pub extern "C" fn arti_connect_poll(token: u64, stream_id: *mut c_char, stream_id_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_connect_poll");
+            return ERR_INVALID_PARAMS;
+        }
+
+        match PENDING_CONNECTS.lock() {
+            Ok(pending) if pending.contains_key(&token) => return 0,
+            Ok(_) => {},
+            Err(_) => {
+                log_error!("Failed to lock pending connects mutex");
+                return ERR_INTERNAL;
+            }
+        }
+
+        let mut finished = match FINISHED_CONNECTS.lock() {
+            Ok(f) => f,
+            Err(_) => {
+                log_error!("Failed to lock finished connects mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        if let Some(ConnectOutcome::Connected(id)) = finished.get(&token) {
+            if !write_c_buffer(id, stream_id, stream_id_len) {
+                log_error!("Stream ID buffer too small: {} bytes needed", id.len() + 1);
+                return ERR_BUFFER_TOO_SMALL;
+            }
+        }
+
+        match finished.remove(&token) {
+            Some(ConnectOutcome::Connected(_)) => 1,
+            Some(ConnectOutcome::Failed(e)) => {
+                log_error!("Failed to connect to target: {}", e);
+                ERR_CONNECTION_FAILED
+            },
+            Some(ConnectOutcome::Cancelled) => ERR_CANCELLED,
+            None => {
+                log_error!("Unknown connect token: {}", token);
+                ERR_INVALID_PARAMS
+            }
+        }
+    })
+}
+
+/// Cancel a connect started with arti_connect_stream_cancelable
+///
+/// The connect is aborted promptly and no stream is stored. A following
+/// arti_connect_poll reports ERR_CANCELLED (-6).
+///
+/// @param token The cancel token returned by arti_connect_stream_cancelable
+/// @return 1 if the operation was cancelled, 0 if the token is unknown or already finished
+#[no_mangle]
+pub extern "C" fn arti_cancel(token: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let task = match PENDING_CONNECTS.lock() {
+            Ok(pending) => pending.get(&token).cloned(),
+            Err(_) => {
+                log_error!("Failed to lock pending connects mutex");
+                return 0;
+            }
+        };
+
+        // Abort outside the lock; the task takes it to remove itself
+        match task {
+            Some(task) => {
+                task.abort();
+                1
+            },
+            None => 0,
+        }
+    })
+}
+
+/// Connect to a target through Tor, retrying on a fresh circuit after a failure
+///
+/// Each failed attempt retires the circuit's current path and builds a new one under
+/// the same circuit ID before retrying, waiting a little longer between each attempt.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param max_attempts Maximum number of connect attempts, at least 1
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param attempts_used Output parameter that will receive the number of attempts made (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_retry(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    max_attempts: c_int,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+    attempts_used: *mut c_int,
 ) -> c_int {
-    if stream_id.is_null() || buffer.is_null() || buffer_len <= 0 || bytes_read.is_null() {
-        eprintln!("Invalid parameters in arti_read_stream");
-        return 0;
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        if max_attempts <= 0 {
+            log_error!("Invalid parameters in arti_connect_stream_retry");
+            return 0;
+        }
 
-    let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
-    let stream_id_str = match c_str_stream_id.to_str() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Invalid stream ID string");
+        let args = match prepare_stream_connect(circuit_id, target_host, target_port, stream_id, stream_id_len) {
+            Ok(a) => a,
+            Err(code) => return code,
+        };
+
+        let mut attempt = 0;
+        let result = loop {
+            attempt += 1;
+
+            let circuit = match get_tor_client_by_circuit(&args.circuit_id) {
+                Some(c) => c,
+                None => {
+                    log_error!("Circuit not found: {}", args.circuit_id);
+                    break 0;
+                }
+            };
+
+            log_debug!("Connecting to {}:{} through Tor (attempt {}/{})", args.host, args.port, attempt, max_attempts);
+            let connect_result = match block_on_runtime(async { record_connect(circuit.connect(args.target.as_str()).await) }) {
+                Ok(r) => r,
+                Err(e) => {
+                    log_error!("Failed to run connect: {:?}", e);
+                    break 0;
+                }
+            };
+
+            match connect_result {
+                Ok(stream) => {
+                    match STREAMS.lock() {
+                        Ok(mut streams) => {
+                            track_stream(&args.circuit_id, &args.stream_id, "");
+                            streams.insert(args.stream_id.clone(), BufferedStream::new(stream));
+                            break 1;
+                        },
+                        Err(_) => {
+                            log_error!("Failed to lock streams mutex");
+                            break 0;
+                        }
+                    }
+                },
+                Err(e) if attempt < max_attempts => {
+                    log_warn!("Connect attempt {} to {} failed, retrying on a new circuit: {:?}", attempt, args.target, e);
+                    if let Err(e) = rebuild_circuit(&args.circuit_id) {
+                        log_error!("Failed to rebuild circuit: {:?}", e);
+                        break 0;
+                    }
+                    // Back off 500ms, 1s, 2s, ... capped at 8s
+                    let delay = Duration::from_millis(500) * 2u32.pow((attempt as u32 - 1).min(4));
+                    std::thread::sleep(delay);
+                },
+                Err(e) => {
+                    log_error!("Failed to connect to target after {} attempts: {:?}", attempt, e);
+                    break 0;
+                }
+            }
+        };
+
+        if !attempts_used.is_null() {
+            unsafe { *attempts_used = attempt };
+        }
+        result
+    })
+}
+
+/// Open a tunnel to a target through an HTTP proxy reached over Tor
+///
+/// Connects to the proxy through the circuit and issues an HTTP CONNECT for the
+/// target. Once the proxy accepts, the returned stream carries raw bytes to and from
+/// the target and works with the normal stream read/write functions.
+///
+/// @param circuit_id The circuit ID to use
+/// @param proxy_host The proxy hostname
+/// @param proxy_port The proxy port
+/// @param target_host The hostname the proxy should connect to
+/// @param target_port The port the proxy should connect to
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if a host or port is malformed,
+///         ERR_CONNECTION_FAILED (-2) if the proxy refused the CONNECT,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_via_http_proxy(
+    circuit_id: *const c_char,
+    proxy_host: *const c_char,
+    proxy_port: i32,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if target_host.is_null() {
+            log_error!("Invalid parameters in arti_connect_via_http_proxy");
             return 0;
         }
+
+        let target_host_str = match unsafe { CStr::from_ptr(target_host) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid target host string");
+                return 0;
+            }
+        };
+
+        let tunnel_target = match format_target(target_host_str, target_port) {
+            Some(t) => t,
+            None => {
+                log_error!("Invalid tunnel target: {}", describe_invalid_target(target_host_str, target_port));
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let args = match prepare_stream_connect(circuit_id, proxy_host, proxy_port, stream_id, stream_id_len) {
+            Ok(a) => a,
+            Err(code) => return code,
+        };
+
+        let circuit = match get_tor_client_by_circuit(&args.circuit_id) {
+            Some(c) => c,
+            None => {
+                log_error!("Circuit not found: {}", args.circuit_id);
+                return 0;
+            }
+        };
+
+        log_debug!("Tunneling to {} through proxy {}:{}", tunnel_target, args.host, args.port);
+        let connect_result = block_on_runtime(async {
+            let stream = record_connect(circuit.connect(args.target).await)
+                .map_err(|e| (0, anyhow!("Failed to connect to proxy: {:?}", e)))?;
+            let mut stream = BufferedStream::new(stream);
+            http_connect_handshake(&mut stream, &tunnel_target).await
+                .map_err(|e| (ERR_CONNECTION_FAILED, e))?;
+            Ok::<BufferedStream, (c_int, anyhow::Error)>(stream)
+        });
+
+        let stream = match connect_result {
+            Ok(Ok(s)) => s,
+            Ok(Err((code, e))) => {
+                log_error!("Failed to open tunnel: {:?}", e);
+                return code;
+            },
+            Err(e) => {
+                log_error!("Failed to run tunnel connect: {:?}", e);
+                return 0;
+            }
+        };
+
+        match STREAMS.lock() {
+            Ok(mut streams) => {
+                track_stream(&args.circuit_id, &args.stream_id, "");
+                streams.insert(args.stream_id, stream);
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                0
+            }
+        }
+    })
+}
+
+// Validated arguments of an arti_connect_stream-style call. The stream ID has
+// already been copied to the caller's buffer.
+struct StreamConnectArgs {
+    circuit_id: String,
+    host: String,
+    port: i32,
+    target: String,
+    stream_id: String,
+}
+
+// Shared implementation of the arti_connect_stream family: connects to the
+// target with the given stream preferences and stores the resulting stream.
+fn connect_stream_with_prefs(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+    prefs: &StreamPrefs,
+) -> c_int {
+    connect_stream_labeled(circuit_id, target_host, target_port, stream_id, stream_id_len, prefs, "")
+}
+
+// Like connect_stream_with_prefs, recording the stream in its circuit's group under a label
+fn connect_stream_labeled(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+    prefs: &StreamPrefs,
+    label: &str,
+) -> c_int {
+    let args = match prepare_stream_connect(circuit_id, target_host, target_port, stream_id, stream_id_len) {
+        Ok(a) => a,
+        Err(code) => return code,
     };
+    let StreamConnectArgs { circuit_id: circuit_id_str, host: host_str, port: target_port, target, stream_id: stream_id_str } = args;
 
+    // Connect to the target
+    log_debug!("Connecting to {}:{} through Tor", host_str, target_port);
+    
     // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
+    let handle = match runtime_handle() {
+        Ok(h) => h,
         Err(e) => {
-            eprintln!("Failed to get runtime: {:?}", e);
+            log_error!("Failed to get runtime: {:?}", e);
             return 0;
         }
     };
-    let runtime_guard = match runtime_mutex.lock() {
-        Ok(g) => g,
-        Err(_) => {
-            eprintln!("Failed to lock runtime mutex");
+
+    // Get the circuit; the circuits lock is released before connecting
+    let circuit = match get_tor_client_by_circuit(&circuit_id_str) {
+        Some(c) => c,
+        None => {
+            log_error!("Circuit not found: {}", circuit_id_str);
             return 0;
         }
     };
-    
-    let runtime = match &*runtime_guard {
-        Some(r) => r,
-        None => {
-            eprintln!("Runtime not initialized");
+
+    // Connect to the target and store the stream
+    let started = Instant::now();
+    let connect_result = handle.block_on(async {
+        record_connect(circuit.connect_with_prefs(target, prefs).await)
+    });
+    if connect_result.is_ok() {
+        record_first_stream(&circuit_id_str, started.elapsed());
+    }
+
+    let stream = match connect_result {
+        Ok(s) => s,
+        Err(e) => {
+            log_error!("Failed to connect to target: {:?}", e);
             return 0;
         }
     };
 
-    // Get the stream
+    log_debug!("Connected to target through Tor");
+
+    // Store the stream
     let mut streams = match STREAMS.lock() {
         Ok(s) => s,
         Err(_) => {
-            eprintln!("Failed to lock streams mutex");
+            log_error!("Failed to lock streams mutex");
             return 0;
         }
     };
 
-    let stream = match streams.get_mut(stream_id_str) {
-        Some(s) => s,
-        None => {
-            eprintln!("Stream not found: {}", stream_id_str);
-            return 0;
+    track_stream(&circuit_id_str, &stream_id_str, label);
+    streams.insert(stream_id_str, BufferedStream::new(stream));
+    
+    1
+}
+
+// Remember how long the first stream on a circuit took to open
+fn record_first_stream(circuit_id: &str, elapsed: Duration) {
+    if let Ok(mut times) = CIRCUIT_BUILD_TIMES.lock() {
+        times.entry(circuit_id.to_string()).or_insert(elapsed);
+    }
+}
+
+// Generate an ID for a new stream on a circuit
+fn new_stream_id(circuit_id: &str) -> String {
+    format!("{}-stream-{}", circuit_id, NEXT_STREAM.fetch_add(1, Ordering::Relaxed))
+}
+
+// Record a newly opened stream in its circuit's group
+fn track_stream(circuit_id: &str, stream_id: &str, label: &str) {
+    if let Ok(mut groups) = STREAM_GROUPS.lock() {
+        groups.entry(circuit_id.to_string())
+            .or_default()
+            .insert(stream_id.to_string(), label.to_string());
+    }
+    emit_event(EVENT_STREAM_OPENED, stream_id);
+}
+
+// Close every plain, split and TLS stream opened on a circuit, returning how many
+// were closed
+fn close_circuit_streams(circuit_id: &str) -> Result<usize> {
+    let group = lock(&STREAM_GROUPS, "stream groups")?
+        .remove(circuit_id)
+        .unwrap_or_default();
+
+    let mut streams = lock(&STREAMS, "streams")?;
+    let mut split_readers = lock(&SPLIT_READERS, "split readers")?;
+    let mut split_writers = lock(&SPLIT_WRITERS, "split writers")?;
+    let mut tls_streams = lock(&TLS_STREAMS, "TLS streams")?;
+
+    let mut closed = 0;
+    for stream_id in group.keys() {
+        let removed = streams.remove(stream_id).is_some()
+            | close_taken_stream(stream_id)
+            | split_readers.remove(stream_id).is_some()
+            | split_writers.remove(stream_id).is_some()
+            | tls_streams.remove(stream_id).is_some();
+        if removed {
+            closed += 1;
+            emit_event(EVENT_STREAM_CLOSED, stream_id);
         }
-    };
+    }
+    Ok(closed)
+}
+
+// Close the given streams, whatever kind they are, skipping unknown IDs. Returns
+// how many were closed.
+fn close_streams(stream_ids: &[String]) -> Result<usize> {
+    let mut streams = lock(&STREAMS, "streams")?;
+    let mut split_readers = lock(&SPLIT_READERS, "split readers")?;
+    let mut split_writers = lock(&SPLIT_WRITERS, "split writers")?;
+    let mut tls_streams = lock(&TLS_STREAMS, "TLS streams")?;
+
+    let mut closed = 0;
+    for stream_id in stream_ids {
+        let removed = streams.remove(stream_id).is_some()
+            | close_taken_stream(stream_id)
+            | split_readers.remove(stream_id).is_some()
+            | split_writers.remove(stream_id).is_some()
+            | tls_streams.remove(stream_id).is_some();
+        if removed {
+            closed += 1;
+            untrack_stream(stream_id);
+        }
+    }
+    Ok(closed)
+}
+
+// Forget a closed stream. Never called while holding STREAM_GROUPS, and callers
+// holding STREAMS take the locks in that order.
+fn untrack_stream(stream_id: &str) {
+    if let Ok(mut groups) = STREAM_GROUPS.lock() {
+        groups.retain(|_, group| {
+            group.remove(stream_id);
+            !group.is_empty()
+        });
+    }
+    emit_event(EVENT_STREAM_CLOSED, stream_id);
+}
 
-    // Prepare the buffer
-    let buffer_slice = unsafe {
-        std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+// Take a plain stream out of STREAMS for a call that waits on the network, so the
+// lock isn't held while it waits. Closing the stream meanwhile fires the returned
+// signal; other calls on the stream report it as not found until it is put back.
+fn take_stream(stream_id: &str) -> Result<Option<(BufferedStream, StdArc<tokio::sync::Notify>)>> {
+    let mut streams = lock(&STREAMS, "streams")?;
+    let mut taken = lock(&TAKEN_STREAMS, "taken streams")?;
+    let stream = match streams.remove(stream_id) {
+        Some(s) => s,
+        None => return Ok(None),
     };
+    let closed = StdArc::new(tokio::sync::Notify::new());
+    taken.insert(stream_id.to_string(), closed.clone());
+    Ok(Some((stream, closed)))
+}
 
-    println!("DEBUG - Reading from stream (max {} bytes)", buffer_len);
-    
-    // Read from the stream
-    let read_result = runtime.block_on(async {
-        stream.read(buffer_slice).await
-    });
+// Put back a stream taken with take_stream, unless it was closed meanwhile
+fn return_stream(stream_id: &str, stream: BufferedStream) {
+    if let Ok(mut streams) = STREAMS.lock() {
+        let still_open = TAKEN_STREAMS.lock()
+            .map(|mut taken| taken.remove(stream_id).is_some())
+            .unwrap_or(false);
+        if still_open {
+            streams.insert(stream_id.to_string(), stream);
+        }
+    }
+}
 
-    match read_result {
-        Ok(n) => {
-            println!("DEBUG - Read {} bytes from stream", n);
-            unsafe {
-                *bytes_read = n as c_int;
-            }
-            1
+// Close a stream taken with take_stream, waking the call holding it. Returns
+// whether the stream was taken.
+fn close_taken_stream(stream_id: &str) -> bool {
+    match TAKEN_STREAMS.lock().map(|mut taken| taken.remove(stream_id)) {
+        Ok(Some(closed)) => {
+            closed.notify_one();
+            true
         },
-        Err(e) => {
-            eprintln!("Failed to read from stream: {:?}", e);
-            0
-        }
+        _ => false,
     }
 }
 
-/// Close and destroy a stream
-///
-/// @param stream_id The stream ID
-/// @return 1 on success, 0 on failure
-#[no_mangle]
-pub extern "C" fn arti_close_stream(
-    stream_id: *const c_char,
-) -> c_int {
+// Parse and validate the arguments shared by the arti_connect_stream family, and
+// copy a newly generated stream ID to the caller's buffer. On error, returns the
+// code the FFI function should return.
+fn prepare_stream_connect(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> std::result::Result<StreamConnectArgs, c_int> {
     if stream_id.is_null() {
-        eprintln!("Invalid parameters in arti_close_stream");
-        return 0;
+        log_error!("Invalid parameters in arti_connect_stream");
+        return Err(0);
     }
+    let args = parse_stream_connect(circuit_id, target_host, target_port)?;
 
-    let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
-    let stream_id_str = match c_str_stream_id.to_str() {
+    // Convert the stream ID to a C string and copy it to the output parameter
+    let stream_id_cstring = match CString::new(args.stream_id.clone()) {
         Ok(s) => s,
         Err(_) => {
-            eprintln!("Invalid stream ID string");
-            return 0;
+            log_error!("Failed to create stream ID C string");
+            return Err(0);
         }
     };
 
-    // Remove the stream
-    let mut streams = match STREAMS.lock() {
+    // Fail before connecting, so no stream is opened that the caller can't address
+    let stream_id_bytes = stream_id_cstring.as_bytes_with_nul();
+    if stream_id_len <= 0 || stream_id_bytes.len() > stream_id_len as usize {
+        log_error!("Stream ID buffer too small: {} bytes needed", stream_id_bytes.len());
+        return Err(ERR_BUFFER_TOO_SMALL);
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            stream_id_bytes.as_ptr(),
+            stream_id as *mut u8,
+            stream_id_bytes.len(),
+        );
+    }
+
+    Ok(args)
+}
+
+// Parse and validate the circuit and target of a connect, and generate the ID the
+// new stream will get
+fn parse_stream_connect(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+) -> std::result::Result<StreamConnectArgs, c_int> {
+    if circuit_id.is_null() || target_host.is_null() || target_port <= 0 {
+        log_error!("Invalid parameters in arti_connect_stream");
+        return Err(0);
+    }
+
+    let c_str_circuit = unsafe { CStr::from_ptr(circuit_id) };
+    let circuit_id_str = match c_str_circuit.to_str() {
         Ok(s) => s,
         Err(_) => {
-            eprintln!("Failed to lock streams mutex");
-            return 0;
+            log_error!("Invalid circuit ID string");
+            return Err(0);
         }
     };
 
-    if streams.remove(stream_id_str).is_some() {
-        println!("DEBUG - Stream closed: {}", stream_id_str);
-        1
-    } else {
-        eprintln!("Stream not found: {}", stream_id_str);
-        0
-    }
-}
+    let c_str_host = unsafe { CStr::from_ptr(target_host) };
+    let host_str = match c_str_host.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            log_error!("Invalid host string");
+            return Err(0);
+        }
+    };
 
-// Implement a more straightforward HTTP/HTTPS request function using reqwest
-fn http_request(circuit_id: String, url: String, method: String, headers: String, body: String) -> Result<String> {
-    // Get the Tor client for this circuit
-    let tor_client = match get_tor_client_by_circuit(&circuit_id) {
-        Some(client) => client,
-        None => return Err(anyhow!("Circuit not found")),
-    };
-    
-    // Configure the reqwest client to use the Tor SOCKS proxy
-    // We'll use the default SOCKS port 9050 since we can't easily get it from the TorClient
-    let proxy_url = "socks5://127.0.0.1:9050";
-    
-    // Create a reqwest client with the SOCKS proxy
-    let client_builder = reqwest::Client::builder()
-        .proxy(reqwest::Proxy::all(proxy_url)?)
-        .danger_accept_invalid_certs(false);  // Enforce certificate validation for HTTPS
-        
-    // Build the client
-    let client = client_builder.build()?;
-    
-    // Parse the headers
-    let headers_map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&headers)?;
-    
-    // Create the request
-    let mut request_builder = match method.to_uppercase().as_str() {
-        "GET" => client.get(&url),
-        "POST" => client.post(&url),
-        "PUT" => client.put(&url),
-        "DELETE" => client.delete(&url),
-        "HEAD" => client.head(&url),
-        "PATCH" => client.patch(&url),
-        _ => return Err(anyhow!("Unsupported HTTP method: {}", method)),
-    };
-    
-    // Add headers
-    for (key, value) in headers_map.iter() {
-        if let Some(value_str) = value.as_str() {
-            request_builder = request_builder.header(key, value_str);
-        }
-    }
-    
-    // Add body if present
-    if !body.is_empty() {
-        request_builder = request_builder.body(body);
-    }
-    
-    // Send the request and get the response using a new runtime to avoid MutexGuard issues
-    let runtime = tokio::runtime::Runtime::new()?;
-    
-    // Execute the request in the runtime
-    let response = runtime.block_on(async {
-        request_builder.send().await
-            .map_err(|e| anyhow!("Request failed: {}", e))
-    })?;
-    
-    // Get the status code
-    let status = response.status().as_u16();
-    
-    // Get the response headers
-    let response_headers = response.headers().iter()
-        .map(|(name, value)| {
-            let name_str = name.as_str();
-            let value_str = value.to_str().unwrap_or("");
-            (name_str.to_string(), value_str.to_string())
-        })
-        .collect::<std::collections::HashMap<String, String>>();
-    
-    // Read the response body
-    let response_body = runtime.block_on(async {
-        response.text().await
-            .map_err(|e| anyhow!("Failed to read response body: {}", e))
-    })?;
-    
-    // Create the response JSON
-    let response_json = serde_json::json!({
-        "status": status,
-        "headers": response_headers,
-        "body": response_body
-    });
-    
-    Ok(response_json.to_string())
-}
-
-#[no_mangle]
-pub extern "C" fn arti_http_request(
-    circuit_id: *const c_char,
-    url: *const c_char,
-    method: *const c_char,
-    headers: *const c_char,
-    body: *const c_char,
-    response: *mut c_char,
-    response_len: c_int,
-) -> c_int {
-    // Validate parameters
-    if circuit_id.is_null() || url.is_null() || method.is_null() || headers.is_null() || body.is_null() || response.is_null() {
-        return 0;
-    }
-    
-    // Convert parameters to Rust strings
-    let circuit_id_str = unsafe { CStr::from_ptr(circuit_id).to_str().unwrap_or("") }.to_string();
-    let url_str = unsafe { CStr::from_ptr(url).to_str().unwrap_or("") }.to_string();
-    let method_str = unsafe { CStr::from_ptr(method).to_str().unwrap_or("") }.to_string();
-    let headers_str = unsafe { CStr::from_ptr(headers).to_str().unwrap_or("{}") }.to_string();
-    let body_str = unsafe { CStr::from_ptr(body).to_str().unwrap_or("") }.to_string();
-    
-    // Make the HTTP request
-    match http_request(circuit_id_str, url_str, method_str, headers_str, body_str) {
-        Ok(response_str) => {
-            // Copy the response to the provided buffer
-            let response_bytes = response_str.as_bytes();
-            let max_len = response_len as usize - 1; // Leave space for null terminator
-            let copy_len = std::cmp::min(response_bytes.len(), max_len);
-            
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    response_bytes.as_ptr() as *const c_char,
-                    response,
-                    copy_len,
-                );
-                // Add null terminator
-                *response.add(copy_len) = 0;
-            }
-            
-            1
-        },
-        Err(e) => {
-            eprintln!("HTTP request failed: {:?}", e);
-            0
+    // Validate the target before doing any work
+    let target = match format_target(host_str, target_port) {
+        Some(t) => t,
+        None => {
+            log_error!("Invalid target address: {}", describe_invalid_target(host_str, target_port));
+            return Err(ERR_INVALID_PARAMS);
         }
-    }
-}
+    };
 
-// Rust implementation functions
+    // Generate a unique stream ID
+    let stream_id_str = new_stream_id(circuit_id_str);
 
-fn initialize_tor_client(config_path: Option<&str>) -> Result<()> {
-    // Get or create the runtime
-    let runtime_mutex = get_or_create_runtime()?;
-    let runtime_guard = runtime_mutex.lock().unwrap();
-    
-    if let Some(runtime) = &*runtime_guard {
-        // Create the base Tor client configuration 
-        let config = TorClientConfig::default();
-        
-        eprintln!("Using default TorClientConfig");
-        
-        // We'll print some debug info about the configuration file if provided
-        if let Some(path) = config_path {
-            eprintln!("Note: Configuration file specified at: {}", path);
-            if !Path::new(path).exists() {
-                eprintln!("Warning: Configuration file not found: {}", path);
-            } else {
-                // Just read the file to print its contents for debugging
-                match File::open(path) {
-                    Ok(mut file) => {
-                        let mut contents = String::new();
-                        if file.read_to_string(&mut contents).is_ok() {
-                            eprintln!("Configuration file content (for reference only):");
-                            eprintln!("{}", contents);
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Warning: Failed to read configuration file: {}", e);
-                    }
-                }
-            }
-        } else {
-            // Check if we have a default config file in the current directory
-            let default_config_path = "arti.toml";
-            if Path::new(default_config_path).exists() {
-                eprintln!("Found default configuration file at: {}", default_config_path);
-                // Just read the file to print its contents for debugging
-                match File::open(default_config_path) {
-                    Ok(mut file) => {
-                        let mut contents = String::new();
-                        if file.read_to_string(&mut contents).is_ok() {
-                            eprintln!("Default configuration file content (for reference only):");
-                            eprintln!("{}", contents);
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Warning: Failed to read default configuration file: {}", e);
-                    }
-                }
-            }
-        }
-        
-        // Bootstrap the Tor client
-        eprintln!("Bootstrapping Tor client...");
-        let tor_client = runtime.block_on(TorClient::create_bootstrapped(config))?;
-        eprintln!("Tor client bootstrapped successfully");
-        
-        // Drop the runtime guard before acquiring another lock
-        drop(runtime_guard);
-        
-        // Store the client
-        let mut client = CLIENT.lock().unwrap();
-        *client = Some(tor_client);
-        
-        Ok(())
-    } else {
-        Err(anyhow!("Failed to get Tokio runtime"))
-    }
+    Ok(StreamConnectArgs {
+        circuit_id: circuit_id_str.to_string(),
+        host: host_str.to_string(),
+        port: target_port,
+        target,
+        stream_id: stream_id_str,
+    })
 }
 
-fn bootstrap_tor() -> Result<()> {
-    let client = CLIENT.lock().unwrap();
-    if client.is_none() {
-        return Err(anyhow::anyhow!("Tor client not initialized"));
-    }
-    
-    // Client is already bootstrapped when created
-    Ok(())
-}
+// Largest CONNECT response header accepted from an HTTP proxy
+const HTTP_CONNECT_MAX_HEADER: usize = 16 * 1024;
 
-fn shutdown_tor() -> Result<()> {
-    // First, destroy all circuits
-    let mut circuits = CIRCUITS.lock().unwrap();
-    circuits.clear();
-    
-    // Then clear the client
-    let mut client = CLIENT.lock().unwrap();
-    *client = None;
-    
-    Ok(())
-}
+// Ask an HTTP proxy on the other end of the stream to open a tunnel to target, and
+// wait for its response. Any bytes the proxy sent after the response header belong
+// to the tunnel, so they are kept in the stream's read-ahead buffer.
+async fn http_connect_handshake(stream: &mut BufferedStream, target: &str) -> Result<()> {
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
 
-fn is_connected() -> Result<bool> {
-    let client = CLIENT.lock().unwrap();
-    Ok(client.is_some())
-}
+    let mut response = Vec::new();
+    let header_end = loop {
+        if let Some(pos) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if response.len() > HTTP_CONNECT_MAX_HEADER {
+            return Err(anyhow!("Proxy response header too large"));
+        }
 
-fn create_circuit(circuit_id: String) -> Result<()> {
-    // Get the Tor client from the global state
-    let tor_client = match CLIENT.lock().unwrap().clone() {
-        Some(client) => Arc::new(client),
-        None => return Err(anyhow!("Tor client not initialized")),
+        let mut chunk = [0u8; 1024];
+        let n = stream.inner.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Proxy closed the connection during CONNECT"));
+        }
+        response.extend_from_slice(&chunk[..n]);
     };
-    
-    // Store the circuit ID and associated client
-    let mut circuits = CIRCUITS.lock().unwrap();
-    circuits.insert(circuit_id.clone(), tor_client);
-    
-    Ok(())
-}
+    // Only the header is consumed here; the rest is counted when read from read_ahead
+    BYTES_IN.fetch_add(header_end as u64, Ordering::Relaxed);
 
-fn destroy_circuit(circuit_id: String) -> Result<()> {
-    // Remove the circuit ID from the registry
-    let mut circuits = CIRCUITS.lock().unwrap();
-    if circuits.remove(&circuit_id).is_none() {
-        return Err(anyhow!("Circuit not found: {}", circuit_id));
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let status_line = header.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status_line.starts_with("HTTP/1.") || status != "200" {
+        return Err(anyhow!("Proxy refused CONNECT to {}: {}", target, status_line));
     }
+
+    stream.read_ahead.extend_from_slice(&response[header_end..]);
     Ok(())
 }
 
-// Helper function to get or create the runtime
-fn get_or_create_runtime() -> Result<&'static Mutex<Option<Runtime>>> {
-    // Check if runtime exists
-    let runtime = RUNTIME.lock().unwrap();
-    if runtime.is_none() {
-        // Release lock before modifying
-        drop(runtime);
-        
-        // Get lock again and check once more (double-check locking pattern)
-        let mut runtime = RUNTIME.lock().unwrap();
-        if runtime.is_none() {
-            // Create a new runtime
-            *runtime = Some(
-                Builder::new_multi_thread()
-                    .enable_all()
-                    .build()
-                    .map_err(|e| anyhow!("Failed to create Tokio runtime: {}", e))?
-            );
+/// Get the stream ID buffer length that is always large enough for a circuit
+///
+/// Stream IDs are derived from the circuit ID, so pass the circuit the stream will be
+/// opened on. Use this to size the stream_id buffer of the arti_connect_stream family.
+///
+/// @param circuit_id The circuit ID
+/// @return The required buffer length including the null terminator, or 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_stream_id_len(circuit_id: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() {
+            log_error!("Invalid parameters in arti_stream_id_len");
+            return 0;
         }
-    }
-    
-    Ok(&RUNTIME)
+
+        match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => stream_id_capacity(s).min(c_int::MAX as usize) as c_int,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                0
+            }
+        }
+    })
 }
 
-// Helper function to get the Tor client from a circuit ID
-fn get_tor_client_by_circuit(circuit_id: &str) -> Option<Arc<TorClient<PreferredRuntime>>> {
-    let circuits = CIRCUITS.lock().unwrap();
-    circuits.get(circuit_id).cloned()
+// Longest stream ID generated for a circuit, plus the null terminator. Matches the
+// "{circuit}-stream-{n}" format with the widest possible counter value.
+fn stream_id_capacity(circuit_id: &str) -> usize {
+    circuit_id.len() + "-stream-".len() + u64::MAX.to_string().len() + 1
 }
 
-/// Connect to a target through Tor with TLS (HTTPS)
+/// Write data to a stream
 ///
-/// @param circuit_id The circuit ID to use
-/// @param host The target hostname
-/// @param port The target port
-/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
 /// @return 1 on success, 0 on failure
 #[no_mangle]
-pub extern "C" fn arti_connect_tls_stream(
-    circuit_id: *const c_char,
-    host: *const c_char,
-    port: c_int,
-    stream_id: *const c_char
+pub extern "C" fn arti_write_stream(
+    stream_id: *const c_char,
+    data: *const c_char,
+    data_len: c_int,
 ) -> c_int {
-    if circuit_id.is_null() || host.is_null() || stream_id.is_null() || port <= 0 || port > 65535 {
-        eprintln!("Invalid parameters in arti_connect_tls_stream");
-        return 0;
-    }
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || data.is_null() || data_len <= 0 {
+            log_error!("Invalid parameters in arti_write_stream");
+            return 0;
+        }
 
-    let circuit_id_str = unsafe {
-        match CStr::from_ptr(circuit_id).to_str() {
-            Ok(s) => s.to_string(),
+        let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
+        let stream_id_str = match c_str_stream_id.to_str() {
+            Ok(s) => s,
             Err(_) => {
-                eprintln!("Invalid circuit ID string");
+                log_error!("Invalid stream ID string");
                 return 0;
             }
+        };
+
+        // Split write halves run without the global locks, alongside reads on the other half
+        if let Some(writer) = split_half(&SPLIT_WRITERS, stream_id_str) {
+            let data_slice = unsafe {
+                std::slice::from_raw_parts(data as *const u8, data_len as usize)
+            };
+            return match write_split_half(&writer, data_slice) {
+                Ok(()) => 1,
+                Err(e) => {
+                    log_error!("Failed to write to stream: {:?}", e);
+                    0
+                }
+            };
         }
-    };
-    
-    let host_str = unsafe {
-        match CStr::from_ptr(host).to_str() {
-            Ok(s) => s.to_string(),
+
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
             Err(_) => {
-                eprintln!("Invalid host string");
+                log_error!("Failed to lock runtime mutex");
                 return 0;
             }
-        }
-    };
+        };
     
-    let stream_id_str = unsafe {
-        match CStr::from_ptr(stream_id).to_str() {
-            Ok(s) => s.to_string(),
+        let runtime = match &*runtime_guard {
+            Some(r) => r,
+            None => {
+                log_error!("Runtime not initialized");
+                return 0;
+            }
+        };
+
+        // Get the stream
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
             Err(_) => {
-                eprintln!("Invalid stream ID string");
+                log_error!("Failed to lock streams mutex");
                 return 0;
             }
-        }
-    };
-    
-    let target_port = port as u16;
+        };
 
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to get runtime for TLS connection: {:?}", e);
-            return 0;
-        }
-    };
-    let runtime_guard = runtime_mutex.lock().unwrap();
-    
-    if let Some(runtime) = &*runtime_guard {
-        // Get the circuit
-        let client = match get_tor_client_by_circuit(&circuit_id_str) {
-            Some(c) => c,
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
             None => {
-                eprintln!("Circuit not found: {}", circuit_id_str);
+                log_error!("Stream not found: {}", stream_id_str);
                 return 0;
             }
         };
 
-        // Connect to the target through Tor
-        println!("DEBUG - Connecting to {}:{} through Tor with TLS", host_str, target_port);
-        
-        let result = runtime.block_on(async {
-            // First establish the basic Tor connection
-            let target = format!("{}:{}", host_str, target_port);
-            let stream = match client.connect(&target).await {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Failed to connect to target through Tor: {:?}", e);
-                    return Err(anyhow!("Connection failed"));
-                }
-            };
-            
-            // Now establish TLS connection over the Tor stream
-            let server_name = match rustls::ServerName::try_from(host_str.as_str()) {
-                Ok(n) => n,
-                Err(e) => {
-                    eprintln!("Invalid server name for TLS: {:?}", e);
-                    return Err(anyhow!("Invalid server name"));
-                }
-            };
-            
-            let connector = TlsConnector::from(StdArc::clone(&TLS_CLIENT_CONFIG));
-            
-            match connector.connect(server_name, stream).await {
-                Ok(tls_stream) => {
-                    // Store the TLS stream in thread-local storage
-                    TLS_STREAMS.with(|streams| {
-                        let mut streams_ref = streams.borrow_mut();
-                        streams_ref.insert(circuit_id_str.clone(), StdArc::new(Mutex::new(tls_stream)));
-                    });
-                    Ok(())
-                },
-                Err(e) => {
-                    eprintln!("TLS handshake failed: {:?}", e);
-                    Err(anyhow!("TLS handshake failed"))
-                }
-            }
+        // Get the data as a slice
+        let data_slice = unsafe {
+            std::slice::from_raw_parts(data as *const u8, data_len as usize)
+        };
+
+        log_debug!("Writing {} bytes to stream", data_len);
+    
+        // Write the data to the stream
+        let write_result = runtime.block_on(async {
+            throttle(data_slice.len()).await;
+            stream.write_all(data_slice).await
         });
 
-        match result {
-            Ok(_) => {
-                println!("TLS connection established: {}", stream_id_str);
-                1
-            },
+        match write_result {
+            Ok(_) => 1,
             Err(e) => {
-                eprintln!("TLS connection failed: {:?}", e);
+                log_error!("Failed to write to stream: {:?}", e);
                 0
             }
         }
-    } else {
-        eprintln!("Runtime not initialized");
-        0
-    }
+    })
 }
 
-/// Write data to a TLS stream
+/// Flush a stream
 ///
-/// @param stream_id A null-terminated string representing the stream ID
-/// @param data Pointer to the data to write
-/// @param data_len Length of the data
+/// @param stream_id The stream ID
 /// @return 1 on success, 0 on failure
 #[no_mangle]
-pub extern "C" fn arti_tls_write(
+pub extern "C" fn arti_flush_stream(
     stream_id: *const c_char,
-    data: *const u8,
-    data_len: usize
 ) -> c_int {
-    let stream_id_str = unsafe {
-        match CStr::from_ptr(stream_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_flush_stream");
+            return 0;
         }
-    };
-    
-    let data_slice = unsafe { std::slice::from_raw_parts(data, data_len) };
-    
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(_) => return 0,
-    };
-    let runtime_guard = runtime_mutex.lock().unwrap();
-    
-    if let Some(runtime) = &*runtime_guard {
-        // Get the TLS stream from thread-local storage
-        let stream_arc_option = TLS_STREAMS.with(|streams| {
-            let streams_ref = streams.borrow();
-            streams_ref.get(&stream_id_str).map(StdArc::clone)
-        });
-        
-        if let Some(stream_arc) = stream_arc_option {
-            let result = runtime.block_on(async {
-                // Get a lock on the TLS stream
-                let mut stream = stream_arc.lock().unwrap();
-                
-                // Write the data to the stream
-                match stream.write_all(data_slice).await {
-                    Ok(_) => Ok(()),
-                    Err(e) => {
-                        eprintln!("Failed to write to TLS stream: {:?}", e);
-                        Err(anyhow!("Write failed"))
-                    }
-                }
-            });
-            
-            match result {
-                Ok(_) => 1,
-                Err(_) => 0,
+
+        let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
+        let stream_id_str = match c_str_stream_id.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
             }
-        } else {
-            0
-        }
-    } else {
-        0
-    }
-}
+        };
 
-/// Flush a TLS stream
-///
-/// @param stream_id A null-terminated string representing the stream ID
-/// @return 1 on success, 0 on failure
-#[no_mangle]
-pub extern "C" fn arti_flush_tls_stream(
-    stream_id: *const c_char
-) -> c_int {
-    let stream_id_str = unsafe {
-        match CStr::from_ptr(stream_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+        if let Some(writer) = split_half(&SPLIT_WRITERS, stream_id_str) {
+            return match flush_split_half(&writer) {
+                Ok(()) => 1,
+                Err(e) => {
+                    log_error!("Failed to flush stream: {:?}", e);
+                    0
+                }
+            };
         }
-    };
+
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => {
+                log_error!("Failed to lock runtime mutex");
+                return 0;
+            }
+        };
     
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(_) => return 0,
-    };
-    let runtime_guard = runtime_mutex.lock().unwrap();
+        let runtime = match &*runtime_guard {
+            Some(r) => r,
+            None => {
+                log_error!("Runtime not initialized");
+                return 0;
+            }
+        };
+
+        // Get the stream
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        log_debug!("Flushing stream");
     
-    if let Some(runtime) = &*runtime_guard {
-        // Get the TLS stream from thread-local storage
-        let stream_arc_option = TLS_STREAMS.with(|streams| {
-            let streams_ref = streams.borrow();
-            streams_ref.get(&stream_id_str).map(StdArc::clone)
+        // Flush the stream
+        let flush_result = runtime.block_on(async {
+            stream.flush().await
         });
-        
-        if let Some(stream_arc) = stream_arc_option {
-            let result = runtime.block_on(async {
-                // Get a lock on the TLS stream
-                let mut stream = stream_arc.lock().unwrap();
-                
-                // Flush the stream
-                match stream.flush().await {
-                    Ok(_) => Ok(()),
-                    Err(e) => {
-                        eprintln!("Failed to flush TLS stream: {:?}", e);
-                        Err(anyhow!("Flush failed"))
-                    }
-                }
-            });
-            
-            match result {
-                Ok(_) => 1,
-                Err(_) => 0,
+
+        match flush_result {
+            Ok(_) => 1,
+            Err(e) => {
+                log_error!("Failed to flush stream: {:?}", e);
+                0
             }
-        } else {
-            0
         }
-    } else {
-        0
-    }
+    })
 }
 
-/// Read data from a TLS stream
+/// Read data from a stream
 ///
-/// @param stream_id A null-terminated string representing the stream ID
-/// @param buffer Pointer to the buffer to store the read data
-/// @param buffer_len Length of the buffer
+/// @param stream_id The stream ID
+/// @param buffer The buffer to store the data
+/// @param buffer_len The maximum length of the buffer
 /// @param bytes_read Output parameter that will receive the number of bytes read
 /// @return 1 on success, 0 on failure
 #[no_mangle]
-pub extern "C" fn arti_tls_read(
+pub extern "C" fn arti_read_stream(
     stream_id: *const c_char,
-    buffer: *mut u8,
-    buffer_len: usize
+    buffer: *mut c_char,
+    buffer_len: c_int,
+    bytes_read: *mut c_int,
 ) -> c_int {
-    let stream_id_str = unsafe {
-        match CStr::from_ptr(stream_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return -1,
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || buffer.is_null() || buffer_len <= 0 || bytes_read.is_null() {
+            log_error!("Invalid parameters in arti_read_stream");
+            return 0;
         }
-    };
-    
-    let buffer_slice = unsafe { std::slice::from_raw_parts_mut(buffer, buffer_len) };
+
+        let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
+        let stream_id_str = match c_str_stream_id.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Split read halves run without the global locks, alongside writes on the other half
+        if let Some(reader) = split_half(&SPLIT_READERS, stream_id_str) {
+            let buffer_slice = unsafe {
+                std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+            };
+            return match read_split_half(&reader, buffer_slice) {
+                Ok(n) => {
+                    unsafe {
+                        *bytes_read = n as c_int;
+                    }
+                    1
+                },
+                Err(e) => {
+                    log_error!("Failed to read from stream: {:?}", e);
+                    0
+                }
+            };
+        }
+
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => {
+                log_error!("Failed to lock runtime mutex");
+                return 0;
+            }
+        };
     
-    // Get the runtime
-    let runtime_mutex = match get_or_create_runtime() {
-        Ok(r) => r,
-        Err(_) => return -1,
-    };
-    let runtime_guard = runtime_mutex.lock().unwrap();
+        let runtime = match &*runtime_guard {
+            Some(r) => r,
+            None => {
+                log_error!("Runtime not initialized");
+                return 0;
+            }
+        };
+
+        // Get the stream
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        // Prepare the buffer
+        let buffer_slice = unsafe {
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+        };
+
+        log_debug!("Reading from stream (max {} bytes)", buffer_len);
     
-    if let Some(runtime) = &*runtime_guard {
-        // Get the TLS stream from thread-local storage
-        let stream_arc_option = TLS_STREAMS.with(|streams| {
-            let streams_ref = streams.borrow();
-            streams_ref.get(&stream_id_str).map(StdArc::clone)
+        // Read from the stream
+        let read_result = runtime.block_on(async {
+            let n = stream.read(buffer_slice).await?;
+            throttle(n).await;
+            Ok::<usize, std::io::Error>(n)
         });
-        
-        if let Some(stream_arc) = stream_arc_option {
-            let result = runtime.block_on(async {
-                // Get a lock on the TLS stream
-                let mut stream = stream_arc.lock().unwrap();
-                
-                // Read data into the buffer
-                match stream.read(buffer_slice).await {
-                    Ok(n) => Ok(n),
-                    Err(e) => {
-                        eprintln!("Failed to read from TLS stream: {:?}", e);
-                        Err(anyhow!("Read failed"))
-                    }
+
+        match read_result {
+            Ok(n) => {
+                log_debug!("Read {} bytes from stream", n);
+                unsafe {
+                    *bytes_read = n as c_int;
                 }
-            });
-            
-            match result {
-                Ok(bytes_read) => bytes_read as c_int,
-                Err(_) => -1,
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to read from stream: {:?}", e);
+                0
             }
-        } else {
-            -1
         }
-    } else {
-        -1
-    }
+    })
 }
 
-/// Close a TLS stream
+/// Peek at data from a stream without consuming it
 ///
-/// @param stream_id A null-terminated string representing the stream ID
+/// The peeked data is kept in an internal buffer and served first by the
+/// next reads, so a following arti_read_stream returns the same bytes. Blocks
+/// only while nothing is buffered; if some data is already buffered, that is
+/// returned straight away even when it is less than buffer_len.
+///
+/// @param stream_id The stream ID
+/// @param buffer The buffer to store the data
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_peeked Output parameter that will receive the number of bytes peeked
 /// @return 1 on success, 0 on failure
 #[no_mangle]
-pub extern "C" fn arti_close_tls_stream(
-    stream_id: *const c_char
+pub extern "C" fn arti_peek_stream(
+    stream_id: *const c_char,
+    buffer: *mut c_char,
+    buffer_len: c_int,
+    bytes_peeked: *mut c_int,
 ) -> c_int {
-    let stream_id_str = unsafe {
-        match CStr::from_ptr(stream_id).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || buffer.is_null() || buffer_len <= 0 || bytes_peeked.is_null() {
+            log_error!("Invalid parameters in arti_peek_stream");
+            return 0;
         }
-    };
 
-    // Remove the stream from the map
-    let removed = TLS_STREAMS.with(|streams| {
-        let mut streams_mut = streams.borrow_mut();
-        streams_mut.remove(&stream_id_str).is_some()
-    });
-    
-    if removed {
-        println!("TLS Stream closed: {}", stream_id_str);
-        1
-    } else {
-        println!("TLS Stream not found: {}", stream_id_str);
-        0
+        let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
+        let stream_id_str = match c_str_stream_id.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => {
+                log_error!("Failed to lock runtime mutex");
+                return 0;
+            }
+        };
+
+        let runtime = match &*runtime_guard {
+            Some(r) => r,
+            None => {
+                log_error!("Runtime not initialized");
+                return 0;
+            }
+        };
+
+        // Get the stream
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        // Prepare the buffer
+        let buffer_slice = unsafe {
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+        };
+
+        // Peek at the stream
+        let peek_result = runtime.block_on(async {
+            stream.peek(buffer_slice).await
+        });
+
+        match peek_result {
+            Ok(n) => {
+                unsafe {
+                    *bytes_peeked = n as c_int;
+                }
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to peek stream: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Close and destroy a stream
+///
+/// Closing a stream that is already closed, or was never opened, returns
+/// ERR_STREAM_NOT_FOUND rather than 0, so callers can treat a repeated close as
+/// harmless.
+///
+/// @param stream_id The stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if there is no such stream,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_close_stream(
+    stream_id: *const c_char,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_close_stream");
+            return 0;
+        }
+
+        let c_str_stream_id = unsafe { CStr::from_ptr(stream_id) };
+        let stream_id_str = match c_str_stream_id.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Remove the stream
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let removed = streams.remove(stream_id_str).is_some()
+            || close_taken_stream(stream_id_str)
+            || SPLIT_READERS.lock().map(|mut r| r.remove(stream_id_str).is_some()).unwrap_or(false)
+            || SPLIT_WRITERS.lock().map(|mut w| w.remove(stream_id_str).is_some()).unwrap_or(false);
+
+        if removed {
+            untrack_stream(stream_id_str);
+            log_debug!("Stream closed: {}", stream_id_str);
+            1
+        } else {
+            log_debug!("Stream already closed or unknown: {}", stream_id_str);
+            ERR_STREAM_NOT_FOUND
+        }
+    })
+}
+
+// Response returned by arti_http_request, serialized as:
+// {"status": 200, "url": "<final url>", "headers": [["name", "value"], ...],
+//  "content_encoding": "gzip" | null, "body_encoding": "utf-8" | "base64", "body": "..."}
+#[derive(Serialize)]
+struct HttpResponse {
+    status: u16,
+    url: String,
+    headers: Vec<(String, String)>,
+    content_encoding: Option<String>,
+    body_encoding: &'static str,
+    body: String,
+}
+
+// Put a response body into a JSON string: as is if it is UTF-8 text, otherwise
+// base64-encoded so binary bodies survive intact. Returns the body and its
+// body_encoding.
+fn encode_body(body: Vec<u8>) -> (String, &'static str) {
+    match String::from_utf8(body) {
+        Ok(text) => (text, "utf-8"),
+        Err(e) => (base64::engine::general_purpose::STANDARD.encode(e.into_bytes()), "base64"),
+    }
+}
+
+// Per-request options for http_request
+#[derive(Default, Clone)]
+struct HttpOptions {
+    // Ask for compressed content and decode gzip/deflate/br bodies before returning them
+    decompress: bool,
+    // Isolation token for the request's streams; requests sharing a token may share a circuit
+    isolation: Option<String>,
+    // Limit on the whole request, from sending to reading the last byte of the body
+    timeout: Option<Duration>,
+    // Address the exit connects to instead of resolving the URL's host; TLS still
+    // uses the host for SNI and certificate validation
+    connect_ip: Option<IpAddr>,
+}
+
+// Decode a response body according to its Content-Encoding. Returns the decoded bytes
+// and the encoding that was removed, or the body untouched if the encoding is unknown.
+fn decode_body(encoding: Option<&str>, body: Vec<u8>, max_len: u64) -> Result<(Vec<u8>, Option<String>)> {
+    let encoding = match encoding {
+        Some(e) => e.trim().to_ascii_lowercase(),
+        None => return Ok((body, None)),
+    };
+
+    let decoded = match encoding.as_str() {
+        "gzip" | "x-gzip" => read_limited(flate2::read::GzDecoder::new(&body[..]), max_len)?,
+        "deflate" => read_limited(flate2::read::ZlibDecoder::new(&body[..]), max_len)?,
+        "br" => read_limited(brotli::Decompressor::new(&body[..], 4096), max_len)?,
+        _ => return Ok((body, None)),
+    };
+
+    Ok((decoded, Some(encoding)))
+}
+
+// Read a decoder to the end, failing with BodyTooLarge rather than inflating past
+// max_len bytes (0 means unlimited)
+fn read_limited(mut reader: impl Read, max_len: u64) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    if max_len == 0 {
+        reader.read_to_end(&mut decoded)?;
+        return Ok(decoded);
+    }
+
+    reader.take(max_len + 1).read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > max_len {
+        return Err(BodyTooLarge(max_len).into());
+    }
+    Ok(decoded)
+}
+
+// An HTTP response body exceeded the limit set with arti_set_max_http_body
+#[derive(Debug)]
+struct BodyTooLarge(u64);
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Response body exceeds the limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+// Map an HTTP failure to the FFI return code
+fn http_error_code(e: &anyhow::Error) -> c_int {
+    if e.is::<BodyTooLarge>() {
+        ERR_BODY_TOO_LARGE
+    } else if e.is::<tokio::time::error::Elapsed>() {
+        ERR_TIMEOUT
+    } else {
+        error_code(e, 0)
+    }
+}
+
+/// Split a stream into independent read and write halves
+///
+/// Each half gets its own stream ID and can be used from a different thread at the
+/// same time: reads with arti_read_stream on the read half, writes and flushes with
+/// arti_write_stream and arti_flush_stream on the write half. The original stream ID
+/// is no longer valid. The underlying stream closes once both halves are closed.
+///
+/// @param stream_id The stream ID
+/// @param out_read_id Output buffer that will receive the null-terminated read half ID
+/// @param out_write_id Output buffer that will receive the null-terminated write half ID
+/// @param id_len Length of each output buffer
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_split_stream(
+    stream_id: *const c_char,
+    out_read_id: *mut c_char,
+    out_write_id: *mut c_char,
+    id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_read_id.is_null() || out_write_id.is_null() || id_len <= 0 {
+            log_error!("Invalid parameters in arti_split_stream");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let read_id = format!("{}-read", stream_id_str);
+        let write_id = format!("{}-write", stream_id_str);
+        if !write_c_buffer(&read_id, out_read_id, id_len) || !write_c_buffer(&write_id, out_write_id, id_len) {
+            log_error!("Stream ID buffer too small");
+            return 0;
+        }
+
+        let stream = match STREAMS.lock() {
+            Ok(mut streams) => match streams.remove(stream_id_str) {
+                Some(s) => s,
+                None => {
+                    log_error!("Stream not found: {}", stream_id_str);
+                    return 0;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        // The halves take the original stream's place in its circuit's group
+        let group = STREAM_GROUPS.lock().ok().and_then(|groups| {
+            groups.iter().find_map(|(circuit_id, group)| {
+                group.get(stream_id_str).map(|label| (circuit_id.clone(), label.clone()))
+            })
+        });
+
+        let (reader, writer) = tokio::io::split(stream);
+        match (SPLIT_READERS.lock(), SPLIT_WRITERS.lock()) {
+            (Ok(mut readers), Ok(mut writers)) => {
+                readers.insert(read_id.clone(), Arc::new(tokio::sync::Mutex::new(reader)));
+                writers.insert(write_id.clone(), Arc::new(tokio::sync::Mutex::new(writer)));
+            },
+            _ => {
+                log_error!("Failed to lock split stream mutexes");
+                return 0;
+            }
+        }
+
+        untrack_stream(stream_id_str);
+        if let Some((circuit_id, label)) = group {
+            track_stream(&circuit_id, &read_id, &label);
+            track_stream(&circuit_id, &write_id, &label);
+        }
+        log_debug!("Stream split: {}", stream_id_str);
+        1
+    })
+}
+
+// One half of a split stream. Each half has its own lock, so a read and a write
+// can be in progress at once.
+type SplitHalf<T> = Arc<tokio::sync::Mutex<T>>;
+
+// Look up a split stream half by ID
+fn split_half<T>(halves: &Mutex<HashMap<String, SplitHalf<T>>>, stream_id: &str) -> Option<SplitHalf<T>> {
+    halves.lock().ok().and_then(|h| h.get(stream_id).cloned())
+}
+
+// The helpers below lock only their own half while blocking on the runtime, so a
+// read and a write on the two halves don't serialize.
+fn read_split_half(reader: &SplitHalf<tokio::io::ReadHalf<BufferedStream>>, buf: &mut [u8]) -> Result<usize> {
+    runtime_handle()?.block_on(async {
+        let n = reader.lock().await.read(buf).await?;
+        throttle(n).await;
+        Ok(n)
+    })
+}
+
+fn write_split_half(writer: &SplitHalf<tokio::io::WriteHalf<BufferedStream>>, data: &[u8]) -> Result<()> {
+    runtime_handle()?.block_on(async {
+        throttle(data.len()).await;
+        writer.lock().await.write_all(data).await?;
+        Ok(())
+    })
+}
+
+// Write all of `data` to a plain or split stream, blocking until it is written
+fn write_stream_all(stream_id: &str, data: &[u8]) -> Result<()> {
+    if let Some(writer) = split_half(&SPLIT_WRITERS, stream_id) {
+        return write_split_half(&writer, data);
+    }
+
+    let handle = runtime_handle()?;
+    let mut streams = lock(&STREAMS, "streams")?;
+    let stream = streams.get_mut(stream_id)
+        .ok_or_else(|| anyhow!("Stream not found: {}", stream_id))?;
+    handle.block_on(async {
+        throttle(data.len()).await;
+        stream.write_all(data).await
+    })?;
+    Ok(())
+}
+
+fn flush_split_half(writer: &SplitHalf<tokio::io::WriteHalf<BufferedStream>>) -> Result<()> {
+    runtime_handle()?.block_on(async {
+        writer.lock().await.flush().await?;
+        Ok(())
+    })
+}
+
+/// Gracefully close a stream
+///
+/// Flushes pending writes, shuts down the write side so the peer sees EOF, and
+/// discards incoming data until the peer closes its side or the timeout expires.
+/// The stream is removed in every case.
+///
+/// @param stream_id The stream ID
+/// @param timeout_ms Maximum time to spend closing, in milliseconds
+/// @return 1 on a clean close, ERR_TIMEOUT (-8) if the timeout expired first, 0 on failure,
+///         ERR_STREAM_NOT_FOUND (-13) if the stream is already closed or unknown
+#[no_mangle]
+pub extern "C" fn arti_close_stream_graceful(
+    stream_id: *const c_char,
+    timeout_ms: u64,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_close_stream_graceful");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Take the stream out of the map so it is gone whatever happens below
+        let mut stream = match STREAMS.lock() {
+            Ok(mut streams) => match streams.remove(stream_id_str) {
+                Some(s) => {
+                    untrack_stream(stream_id_str);
+                    s
+                },
+                None => {
+                    log_debug!("Stream already closed or unknown: {}", stream_id_str);
+                    return ERR_STREAM_NOT_FOUND;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let close_result = block_on_runtime(tokio::time::timeout(Duration::from_millis(timeout_ms), async {
+            stream.flush().await?;
+            stream.shutdown().await?;
+
+            let mut discard = [0u8; 4096];
+            while stream.read(&mut discard).await? > 0 {}
+            Ok::<(), std::io::Error>(())
+        }));
+
+        match close_result {
+            Ok(Ok(Ok(()))) => {
+                log_debug!("Stream closed gracefully: {}", stream_id_str);
+                1
+            },
+            Ok(Ok(Err(e))) => {
+                log_error!("Failed to close stream {}: {:?}", stream_id_str, e);
+                0
+            },
+            Ok(Err(_)) => {
+                log_debug!("Timed out closing stream: {}", stream_id_str);
+                ERR_TIMEOUT
+            },
+            Err(e) => {
+                log_error!("Failed to run graceful close: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+// Implement a more straightforward HTTP/HTTPS request function using reqwest
+fn http_request(circuit_id: String, url: String, method: String, headers: String, body: String, options: &HttpOptions) -> Result<String> {
+    let mut request_builder = build_http_request(&circuit_id, &url, &method, &headers, options)?;
+    
+    // Add body if present
+    if !body.is_empty() {
+        request_builder = request_builder.body(body);
+    }
+    
+    // Run on the shared runtime, which also keeps the pooled connections alive
+    // between requests. Only a handle is taken, so the runtime lock isn't held.
+    let response = runtime_handle()?.block_on(async {
+        let exchange = async {
+            let _permit = http_permit().await;
+            let response = send_http_request(&circuit_id, request_builder).await?;
+            read_http_response(response, options).await
+        };
+        match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange).await?,
+            None => exchange.await,
+        }
+    })?;
+    
+    Ok(serde_json::to_string(&response)?)
+}
+
+// Send the same request on every circuit at once and return the first successful
+// response with the circuit that carried it. The slower requests are dropped, which
+// cancels them.
+fn http_request_fastest(circuit_ids: &[String], url: &str, method: &str, headers: &str, body: &str, options: &HttpOptions) -> Result<(String, String)> {
+    let mut exchanges = Vec::with_capacity(circuit_ids.len());
+    for circuit_id in circuit_ids {
+        let mut request_builder = build_http_request(circuit_id, url, method, headers, options)?;
+        if !body.is_empty() {
+            request_builder = request_builder.body(body.to_string());
+        }
+
+        let circuit_id = circuit_id.clone();
+        exchanges.push(Box::pin(async move {
+            let exchange = async {
+                let _permit = http_permit().await;
+                let response = send_http_request(&circuit_id, request_builder).await?;
+                read_http_response(response, options).await
+            };
+            let response = match options.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, exchange).await?,
+                None => exchange.await,
+            };
+            response
+                .map(|r| (circuit_id.clone(), r))
+                .map_err(|e| e.context(format!("Request on circuit {} failed", circuit_id)))
+        }));
+    }
+
+    // Same as http_request: the shared runtime keeps pooled connections alive
+    let ((circuit_id, response), _) = runtime_handle()?.block_on(futures::future::select_ok(exchanges))?;
+
+    Ok((circuit_id, serde_json::to_string(&response)?))
+}
+
+// Wait for a slot under the limit set with arti_set_http_max_concurrency. The
+// request may run while the returned permit is held; None means no limit.
+async fn http_permit() -> Option<tokio::sync::OwnedSemaphorePermit> {
+    let semaphore = HTTP_CONCURRENCY.lock().ok()?.clone()?;
+    semaphore.acquire_owned().await.ok()
+}
+
+// Build `count` temporary circuits, ask each one for its exit's IP address and
+// return the addresses seen with how many circuits used each, most common first.
+// Circuits that fail or don't answer within the timeout are left out.
+fn sample_exit_ips(count: usize, timeout: Duration) -> Result<Vec<(IpAddr, usize)>> {
+    let circuit_ids = create_circuits(count, false)?;
+
+    let lookups = circuit_ids.iter().map(|circuit_id| async move {
+        let request_builder = build_http_request(circuit_id, EXIT_IP_CHECK_URL, "GET", "{}", &HttpOptions::default())?;
+        let exchange = async {
+            let _permit = http_permit().await;
+            let response = send_http_request(circuit_id, request_builder).await?;
+            let body: serde_json::Value = response.json().await?;
+            body.get("IP")
+                .and_then(|ip| ip.as_str())
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .ok_or_else(|| anyhow!("Unexpected response from {}: {}", EXIT_IP_CHECK_URL, body))
+        };
+        tokio::time::timeout(timeout, exchange).await?
+    });
+
+    // Same as http_request: run on the shared runtime without holding its lock
+    let results = runtime_handle()
+        .map(|handle| handle.block_on(futures::future::join_all(lookups)));
+
+    // Clean up before looking at the results, so the circuits never outlive the call
+    for circuit_id in &circuit_ids {
+        let _ = destroy_circuit(circuit_id.clone());
+    }
+
+    let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+    for (circuit_id, result) in circuit_ids.iter().zip(results?) {
+        match result {
+            Ok(ip) => *counts.entry(ip).or_default() += 1,
+            Err(e) => log_warn!("Failed to learn the exit IP of circuit {}: {:?}", circuit_id, e),
+        }
+    }
+
+    let mut counts: Vec<(IpAddr, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(counts)
+}
+
+// Build a reqwest request for the given circuit, without a body
+fn build_http_request(circuit_id: &str, url: &str, method: &str, headers: &str, options: &HttpOptions) -> Result<reqwest::RequestBuilder> {
+    // Make sure the circuit exists
+    if get_tor_client_by_circuit(circuit_id).is_none() {
+        return Err(anyhow!("Circuit not found"));
+    }
+    
+    // Pin the URL's host to the requested address. reqwest still sees the hostname,
+    // so TLS verifies the certificate against it; only the proxy swaps in the IP.
+    let address_override = match options.connect_ip {
+        Some(ip) => {
+            let host = url::Url::parse(url)?.host_str()
+                .ok_or_else(|| anyhow!("URL has no host: {}", url))?
+                .to_string();
+            Some((host, ip))
+        },
+        None => None,
+    };
+
+    // Route the request through this circuit's own client via its loopback SOCKS proxy
+    let client = circuit_http_client(circuit_id, options.isolation.as_deref(), address_override)?;
+    
+    // Parse the headers
+    let headers_map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(headers)?;
+    
+    // Create the request
+    let mut request_builder = match method.to_uppercase().as_str() {
+        "GET" => client.get(url),
+        "POST" => client.post(url),
+        "PUT" => client.put(url),
+        "DELETE" => client.delete(url),
+        "HEAD" => client.head(url),
+        "PATCH" => client.patch(url),
+        _ => return Err(anyhow!("Unsupported HTTP method: {}", method)),
+    };
+    
+    // Add headers
+    for (key, value) in headers_map.iter() {
+        if let Some(value_str) = value.as_str() {
+            request_builder = request_builder.header(key, value_str);
+        }
+    }
+    
+    // Add the default headers the caller didn't set for this request
+    let defaults = DEFAULT_HTTP_HEADERS.lock()
+        .map_err(|_| anyhow!("Failed to lock default HTTP headers mutex"))?;
+    for (key, value) in defaults.iter() {
+        if !headers_map.keys().any(|k| k.eq_ignore_ascii_case(key)) {
+            request_builder = request_builder.header(key, value);
+        }
+    }
+    
+    // Advertise the encodings we can decode unless the caller chose their own
+    if options.decompress && !headers_map.keys().any(|k| k.eq_ignore_ascii_case("accept-encoding")) {
+        request_builder = request_builder.header("Accept-Encoding", "gzip, deflate, br");
+    }
+    
+    Ok(request_builder)
+}
+
+// A loopback SOCKS5 listener that connects through one circuit's client. reqwest
+// 0.11 has no hook for supplying its own connections, so it can only reach Tor
+// through a proxy, and each circuit used for HTTP gets its own. Those proxies only
+// accept their own random credentials, so other local processes can't use them.
+// Also used for the public proxy started by arti_start_socks_proxy.
+struct CircuitProxy {
+    port: u16,
+    // Username and password clients must present, None for the public proxy
+    credentials: Option<(String, String)>,
+    task: tokio::task::JoinHandle<()>,
+    // HTTP client whose connection pool goes through this proxy, created on first use
+    http_client: Option<reqwest::Client>,
+}
+
+// A host name and the address a proxy connects to in its place
+type AddressOverride = (String, IpAddr);
+
+// Circuit proxies are shared by requests with the same circuit, isolation token and
+// address override
+type CircuitProxyKey = (String, Option<String>, Option<AddressOverride>);
+
+// socks5h URL of a proxy, with its credentials. socks5h leaves name resolution to
+// the exit, so hostnames never hit local DNS.
+fn socks_proxy_url(proxy: &CircuitProxy) -> String {
+    match &proxy.credentials {
+        Some((username, password)) => format!("socks5h://{}:{}@127.0.0.1:{}", username, password, proxy.port),
+        None => format!("socks5h://127.0.0.1:{}", proxy.port),
+    }
+}
+
+// Get the URL of the circuit's SOCKS proxy for an isolation token, starting the proxy
+// on first use. Each token gets its own proxy so its streams are isolated from the rest,
+// and so does each address override.
+fn circuit_proxy_url(circuit_id: &str, isolation: Option<&str>, address_override: Option<AddressOverride>) -> Result<String> {
+    let mut proxies = CIRCUIT_PROXIES.lock()
+        .map_err(|_| anyhow!("Failed to lock circuit proxies mutex"))?;
+    let key = (circuit_id.to_string(), isolation.map(|t| t.to_string()), address_override.clone());
+    if let Some(proxy) = proxies.get(&key) {
+        return Ok(socks_proxy_url(proxy));
+    }
+
+    // Fresh random credentials per proxy; callers never get to choose them
+    let hex = |bytes: [u8; 16]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let auth = SocksAuth::Required {
+        username: hex(rand::random()),
+        password: hex(rand::random()),
+    };
+
+    let token = isolation.map(isolation_token_for).transpose()?;
+    let proxy = start_socks_proxy(0, Some(circuit_id.to_string()), token, address_override, auth)?;
+    let url = socks_proxy_url(&proxy);
+    log_debug!("Started SOCKS proxy for circuit {} (isolation {:?}) on port {}", circuit_id, isolation, proxy.port);
+    proxies.insert(key, proxy);
+    Ok(url)
+}
+
+// Get the HTTP client for a circuit's SOCKS proxy. Clients are kept with the proxy,
+// so idle connections are pooled per circuit, isolation token and host:port, and
+// dropped along with the circuit. With pooling disabled, every request gets a fresh
+// client and so a fresh connection.
+fn circuit_http_client(circuit_id: &str, isolation: Option<&str>, address_override: Option<AddressOverride>) -> Result<reqwest::Client> {
+    let proxy_url = circuit_proxy_url(circuit_id, isolation, address_override.clone())?;
+    let ttl_ms = HTTP_POOL_TTL_MS.load(Ordering::Relaxed);
+
+    let client_builder = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_url)?)
+        .danger_accept_invalid_certs(false);  // Enforce certificate validation for HTTPS
+    if ttl_ms == 0 {
+        return Ok(client_builder.pool_max_idle_per_host(0).build()?);
+    }
+
+    let mut proxies = CIRCUIT_PROXIES.lock()
+        .map_err(|_| anyhow!("Failed to lock circuit proxies mutex"))?;
+    let key = (circuit_id.to_string(), isolation.map(|t| t.to_string()), address_override);
+    let proxy = proxies.get_mut(&key)
+        .ok_or_else(|| anyhow!("SOCKS proxy for circuit {} was stopped", circuit_id))?;
+    if let Some(client) = &proxy.http_client {
+        return Ok(client.clone());
+    }
+
+    let client = client_builder.pool_idle_timeout(Duration::from_millis(ttl_ms)).build()?;
+    proxy.http_client = Some(client.clone());
+    Ok(client)
+}
+
+// Forget the pooled HTTP connections of a circuit, or of every circuit if None.
+// Requests in flight keep their connection; later ones start afresh.
+fn discard_pooled_connections(circuit_id: Option<&str>) {
+    if let Ok(mut proxies) = CIRCUIT_PROXIES.lock() {
+        for ((circuit, _, _), proxy) in proxies.iter_mut() {
+            if circuit_id.map_or(true, |id| id == circuit) {
+                proxy.http_client = None;
+            }
+        }
+    }
+}
+
+// Send a request built by build_http_request. A failed request may have left its
+// connection in a bad state, so the circuit's pool is discarded rather than reused.
+async fn send_http_request(circuit_id: &str, request_builder: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    match request_builder.send().await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            discard_pooled_connections(Some(circuit_id));
+            Err(anyhow!("Request failed: {}", e))
+        }
+    }
+}
+
+// How a SOCKS proxy treats the username and password a client sends
+#[derive(Clone)]
+enum SocksAuth {
+    // The public proxy: as with Tor's SocksPort, any credentials are accepted and each
+    // distinct pair gets its own isolation token
+    IsolateByCredentials,
+    // A proxy used internally for HTTP: only this username and password are accepted,
+    // and they never affect isolation, which stays the proxy's own
+    Required { username: String, password: String },
+}
+
+// Start a loopback SOCKS5 listener on the given port (0 for any free port) that
+// connects through a circuit's client, or through the main client if circuit_id is None.
+// Streams are opened with the isolation token if one is given, and connections to the
+// overridden host go to its address instead.
+fn start_socks_proxy(
+    port: u16,
+    circuit_id: Option<String>,
+    isolation: Option<IsolationToken>,
+    address_override: Option<AddressOverride>,
+    auth: SocksAuth,
+) -> Result<CircuitProxy> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    let credentials = match &auth {
+        SocksAuth::Required { username, password } => Some((username.clone(), password.clone())),
+        SocksAuth::IsolateByCredentials => None,
+    };
+
+    let task = runtime_handle()?.spawn(async move {
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+            Ok(l) => l,
+            Err(e) => {
+                log_error!("Failed to start SOCKS proxy on port {}: {:?}", port, e);
+                return;
+            }
+        };
+
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    log_warn!("SOCKS proxy on port {} failed to accept: {:?}", port, e);
+                    continue;
+                }
+            };
+
+            let circuit = circuit_id.clone();
+            let address_override = address_override.clone();
+            let auth = auth.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_socks_connection(circuit.as_deref(), isolation, address_override.as_ref(), &auth, socket).await {
+                    log_debug!("SOCKS connection on port {} failed: {:?}", port, e);
+                }
+            });
+        }
+    });
+
+    Ok(CircuitProxy { port, credentials, task, http_client: None })
+}
+
+// Stop the SOCKS proxies of the given circuit, or of every circuit if None
+fn stop_circuit_proxies(circuit_id: Option<&str>) {
+    if let Ok(mut proxies) = CIRCUIT_PROXIES.lock() {
+        match circuit_id {
+            Some(id) => {
+                proxies.retain(|(circuit, _, _), proxy| {
+                    if circuit == id {
+                        proxy.task.abort();
+                        false
+                    } else {
+                        true
+                    }
+                });
+            },
+            None => {
+                for (_, proxy) in proxies.drain() {
+                    proxy.task.abort();
+                }
+            }
+        }
+    }
+}
+
+// SOCKS5 reply with the given status code and an unspecified bound address
+fn socks_reply(code: u8) -> [u8; 10] {
+    [5, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+// Handle one SOCKS5 CONNECT (RFC 1928) by connecting to the requested target through
+// the circuit's current client, or the main client if circuit_id is None, and relaying
+// both ways. A username/password (RFC 1929) is handled as `auth` says.
+async fn serve_socks_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    circuit_id: Option<&str>,
+    mut isolation: Option<IsolationToken>,
+    address_override: Option<&AddressOverride>,
+    auth: &SocksAuth,
+    mut socket: S,
+) -> Result<()> {
+    let mut greeting = [0u8; 2];
+    socket.read_exact(&mut greeting).await?;
+    if greeting[0] != 5 {
+        return Err(anyhow!("Unsupported SOCKS version {}", greeting[0]));
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if methods.contains(&2) {
+        socket.write_all(&[5, 2]).await?;
+        let (username, password) = read_socks_credentials(&mut socket).await?;
+        match auth {
+            SocksAuth::IsolateByCredentials => {
+                socket.write_all(&[1, 0]).await?;
+                isolation = Some(isolation_token_for(&socks_isolation_key(&username, &password))?);
+            },
+            SocksAuth::Required { username: expected_username, password: expected_password } => {
+                if username != expected_username.as_bytes() || password != expected_password.as_bytes() {
+                    socket.write_all(&[1, 1]).await?;
+                    return Err(anyhow!("SOCKS client presented the wrong credentials"));
+                }
+                socket.write_all(&[1, 0]).await?;
+            },
+        }
+    } else if methods.contains(&0) && matches!(auth, SocksAuth::IsolateByCredentials) {
+        socket.write_all(&[5, 0]).await?;
+    } else {
+        socket.write_all(&[5, 0xff]).await?;
+        return Err(anyhow!("SOCKS client offered no supported authentication method"));
+    }
+
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[1] != 1 {
+        socket.write_all(&socks_reply(7)).await?;
+        return Err(anyhow!("Unsupported SOCKS command {}", request[1]));
+    }
+
+    let host = match request[3] {
+        1 => {
+            let mut addr = [0u8; 4];
+            socket.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        },
+        3 => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        },
+        4 => {
+            let mut addr = [0u8; 16];
+            socket.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        },
+        atyp => {
+            socket.write_all(&socks_reply(8)).await?;
+            return Err(anyhow!("Unsupported SOCKS address type {}", atyp));
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await?;
+
+    let host = match address_override {
+        Some((name, ip)) if name.eq_ignore_ascii_case(&host) => ip.to_string(),
+        _ => host,
+    };
+
+    let target = match format_target(&host, u16::from_be_bytes(port) as i32) {
+        Some(t) => t,
+        None => {
+            socket.write_all(&socks_reply(1)).await?;
+            return Err(anyhow!("Invalid SOCKS target {:?}", host));
+        }
+    };
+
+    // Look the client up per connection, so a rebuilt circuit takes effect immediately
+    let client = match circuit_id {
+        Some(id) => get_tor_client_by_circuit(id),
+        None => CLIENT.lock().ok().and_then(|c| c.as_ref().map(|client| Arc::new(client.clone()))),
+    };
+    let client = match client {
+        Some(c) => c,
+        None => {
+            socket.write_all(&socks_reply(1)).await?;
+            return Err(anyhow!("No Tor client for SOCKS connection (circuit {:?})", circuit_id));
+        }
+    };
+
+    let mut prefs = default_stream_prefs();
+    if let Some(token) = isolation {
+        prefs.set_isolation(token);
+    }
+
+    let mut stream = match record_connect(client.connect_with_prefs(target.as_str(), &prefs).await) {
+        Ok(s) => s,
+        Err(e) => {
+            socket.write_all(&socks_reply(4)).await?;
+            return Err(anyhow!("Failed to connect to {}: {:?}", target, e));
+        }
+    };
+    socket.write_all(&socks_reply(0)).await?;
+
+    // Wrapped so proxied traffic, including HTTP requests, shows up in the byte counters
+    let mut stream = BufferedStream::new(stream);
+    tokio::io::copy_bidirectional(&mut socket, &mut stream).await?;
+    Ok(())
+}
+
+// Read a SOCKS5 username/password sub-negotiation (RFC 1929)
+async fn read_socks_credentials<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).await?;
+    if header[0] != 1 {
+        socket.write_all(&[1, 1]).await?;
+        return Err(anyhow!("Unsupported SOCKS authentication version {}", header[0]));
+    }
+    let mut username = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut username).await?;
+
+    let mut password_len = [0u8; 1];
+    socket.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    socket.read_exact(&mut password).await?;
+
+    Ok((username, password))
+}
+
+// Isolation key for SOCKS credentials, kept apart from tokens passed through the FFI
+fn socks_isolation_key(username: &[u8], password: &[u8]) -> String {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("socks-auth:{}:{}", hex(username), hex(password))
+}
+
+// Read a reqwest response into the HttpResponse returned to callers
+async fn read_http_response(mut response: reqwest::Response, options: &HttpOptions) -> Result<HttpResponse> {
+    // Get the status code and the final URL after any redirects
+    let status = response.status().as_u16();
+    let final_url = response.url().to_string();
+    
+    // Get the response headers in wire order, keeping duplicates such as Set-Cookie
+    let mut response_headers = response.headers().iter()
+        .map(|(name, value)| {
+            let name_str = name.as_str();
+            // Values may carry non-ASCII bytes; keep them rather than dropping the value
+            let value_str = String::from_utf8_lossy(value.as_bytes());
+            (name_str.to_string(), value_str.into_owned())
+        })
+        .collect::<Vec<(String, String)>>();
+    
+    let content_encoding = response.headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    
+    // Read the response body in chunks, giving up as soon as it exceeds the limit
+    let max_body = MAX_HTTP_BODY.load(Ordering::Relaxed);
+    let mut raw_body = Vec::new();
+    while let Some(chunk) = response.chunk().await
+        .map_err(|e| anyhow!("Failed to read response body: {}", e))? {
+        if max_body > 0 && (raw_body.len() + chunk.len()) as u64 > max_body {
+            return Err(BodyTooLarge(max_body).into());
+        }
+        raw_body.extend_from_slice(&chunk);
+    }
+    
+    // Decode the body if requested, and make the headers describe what is delivered
+    let (body_bytes, applied_encoding) = if options.decompress {
+        decode_body(content_encoding.as_deref(), raw_body, max_body)?
+    } else {
+        (raw_body, None)
+    };
+    
+    if applied_encoding.is_some() {
+        response_headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-encoding"));
+        for (name, value) in response_headers.iter_mut() {
+            if name.eq_ignore_ascii_case("content-length") {
+                *value = body_bytes.len().to_string();
+            }
+        }
+    }
+    
+    let (body, body_encoding) = encode_body(body_bytes);
+    Ok(HttpResponse {
+        status,
+        url: final_url,
+        headers: response_headers,
+        content_encoding: applied_encoding,
+        body_encoding,
+        body,
+    })
+}
+
+/// Make an HTTP or HTTPS request through Tor
+///
+/// The response is written as a null-terminated JSON object with a stable schema:
+/// `{"status": number, "url": string, "headers": [[name, value], ...],
+///   "content_encoding": string | null, "body_encoding": "utf-8" | "base64", "body": string}`.
+/// `url` is the final URL after redirects. `headers` keeps the order the server sent
+/// them in, including repeated headers such as Set-Cookie. The body is delivered
+/// exactly as received; `content_encoding` is always null. A body that is valid UTF-8
+/// is returned as text with `body_encoding` "utf-8"; any other body is base64-encoded
+/// and `body_encoding` is "base64".
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit (use arti_http_request_sized
+///         to learn the required length), ERR_BODY_TOO_LARGE (-10) if the body exceeds
+///         the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    response: *mut c_char,
+    response_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        http_request_ffi(circuit_id, url, method, headers, body, &HttpOptions::default(), response, response_len, std::ptr::null_mut())
+    })
+}
+
+/// Make an HTTP or HTTPS request through Tor, reporting the buffer size needed for the response
+///
+/// Behaves like arti_http_request. When the response does not fit, the required
+/// buffer length, including the null terminator, is written to required_len so the
+/// caller can retry with a larger buffer.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @param required_len Output parameter that will receive the required buffer length (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10)
+///         if the body exceeds the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request_sized(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    response: *mut c_char,
+    response_len: c_int,
+    required_len: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        http_request_ffi(circuit_id, url, method, headers, body, &HttpOptions::default(), response, response_len, required_len)
+    })
+}
+
+/// Make an HTTP or HTTPS request through Tor with control over response decompression
+///
+/// When decompress is non-zero, the request advertises gzip, deflate and br (unless an
+/// Accept-Encoding header is supplied) and the body is decoded before it is returned.
+/// `content_encoding` in the response JSON then names the encoding that was removed,
+/// and the Content-Encoding/Content-Length headers are adjusted to match the delivered
+/// body. When decompress is zero this behaves exactly like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param decompress 1 to decode compressed bodies, 0 to return them untouched
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10)
+///         if the body exceeds the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request_decompress(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    decompress: c_int,
+    response: *mut c_char,
+    response_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let options = HttpOptions {
+            decompress: decompress != 0,
+            ..HttpOptions::default()
+        };
+        http_request_ffi(circuit_id, url, method, headers, body, &options, response, response_len, std::ptr::null_mut())
+    })
+}
+
+/// Make an HTTP or HTTPS request through Tor with its own isolation token and timeout
+///
+/// Requests with the same isolation token may share a circuit of the given circuit's
+/// client, and so usually share an exit; requests with different tokens never share a
+/// circuit. A null or empty token behaves like arti_http_request. Otherwise this
+/// behaves like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param isolation_token A null-terminated isolation token, or null for none
+/// @param timeout_ms Limit on the whole request in milliseconds, or 0 for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if response_len is not positive,
+///         ERR_TIMEOUT (-8) if the timeout expired, ERR_BUFFER_TOO_SMALL (-9) if the response
+///         does not fit, ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with
+///         arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request_ex(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    isolation_token: *const c_char,
+    timeout_ms: u64,
+    response: *mut c_char,
+    response_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let isolation = if isolation_token.is_null() {
+            None
+        } else {
+            match unsafe { CStr::from_ptr(isolation_token) }.to_str() {
+                Ok("") => None,
+                Ok(s) => Some(s.to_string()),
+                Err(_) => {
+                    log_error!("Invalid isolation token string");
+                    return 0;
+                }
+            }
+        };
+
+        let options = HttpOptions {
+            isolation,
+            timeout: if timeout_ms > 0 { Some(Duration::from_millis(timeout_ms)) } else { None },
+            ..HttpOptions::default()
+        };
+        http_request_ffi(circuit_id, url, method, headers, body, &options, response, response_len, std::ptr::null_mut())
+    })
+}
+
+/// Make an HTTP or HTTPS request through Tor to a given IP address
+///
+/// The exit connects to connect_ip instead of resolving the URL's host, but the
+/// request is otherwise made to the host in the URL: it is sent in the Host header
+/// and, for HTTPS, used for SNI and to validate the server's certificate, which
+/// must be valid for that host. Useful for reaching one server behind a load
+/// balancer or CDN. Otherwise this behaves like arti_http_request.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request, naming the host the server must present a certificate for
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param connect_ip The IPv4 or IPv6 address to connect to
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if connect_ip is not an IP
+///         address or response_len is not positive, ERR_BUFFER_TOO_SMALL (-9) if the response
+///         does not fit, ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with
+///         arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request_to_ip(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    connect_ip: *const c_char,
+    response: *mut c_char,
+    response_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if connect_ip.is_null() {
+            log_error!("Invalid parameters in arti_http_request_to_ip");
+            return 0;
+        }
+
+        let ip = match unsafe { CStr::from_ptr(connect_ip) }.to_str().ok().and_then(|s| s.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => {
+                log_error!("Invalid connect IP address");
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let options = HttpOptions {
+            connect_ip: Some(ip),
+            ..HttpOptions::default()
+        };
+        http_request_ffi(circuit_id, url, method, headers, body, &options, response, response_len, std::ptr::null_mut())
+    })
+}
+
+// Shared implementation of the arti_http_request family
+#[allow(clippy::too_many_arguments)]
+fn http_request_ffi(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    options: &HttpOptions,
+    response: *mut c_char,
+    response_len: c_int,
+    required_len: *mut c_int,
+) -> c_int {
+    // Validate parameters
+    if circuit_id.is_null() || url.is_null() || method.is_null() || headers.is_null() || body.is_null() || response.is_null() {
+        return 0;
+    }
+    if response_len <= 0 {
+        log_error!("Invalid response buffer length: {}", response_len);
+        return ERR_INVALID_PARAMS;
+    }
+    
+    // Convert parameters to Rust strings
+    let circuit_id_str = unsafe { CStr::from_ptr(circuit_id).to_str().unwrap_or("") }.to_string();
+    let url_str = unsafe { CStr::from_ptr(url).to_str().unwrap_or("") }.to_string();
+    let method_str = unsafe { CStr::from_ptr(method).to_str().unwrap_or("") }.to_string();
+    let headers_str = unsafe { CStr::from_ptr(headers).to_str().unwrap_or("{}") }.to_string();
+    let body_str = unsafe { CStr::from_ptr(body).to_str().unwrap_or("") }.to_string();
+    
+    // Make the HTTP request
+    match http_request(circuit_id_str, url_str, method_str, headers_str, body_str, options) {
+        Ok(response_str) => {
+            // Copy the response to the provided buffer, never a truncated JSON document
+            if !write_c_buffer(&response_str, response, response_len) {
+                let needed = response_str.len() + 1;
+                log_error!("Response buffer too small: {} bytes needed, {} available", needed, response_len);
+                unsafe {
+                    *response = 0;
+                    if !required_len.is_null() {
+                        *required_len = needed.min(c_int::MAX as usize) as c_int;
+                    }
+                }
+                return ERR_BUFFER_TOO_SMALL;
+            }
+            
+            1
+        },
+        Err(e) => {
+            log_error!("HTTP request failed: {:?}", e);
+            http_error_code(&e)
+        }
+    }
+}
+
+// Rust implementation functions
+
+// Client settings made through the FFI before initialization
+#[derive(Default)]
+struct ConfigOverrides {
+    state_dir: Option<String>,
+    cache_dir: Option<String>,
+    circuit_request_timeout: Option<Duration>,
+    address_family: c_int,
+    padding: Option<tor_config::PaddingLevel>,
+    #[cfg_attr(not(feature = "pt-bridges"), allow(dead_code))]
+    bridges: Vec<String>,
+    tor_network: Option<arti_client::config::dir::NetworkConfigBuilder>,
+}
+
+// Build the TorClientConfig, applying any settings made before initialization
+fn build_client_config() -> Result<TorClientConfig> {
+    let overrides = CONFIG_OVERRIDES.lock()
+        .map_err(|_| anyhow!("Failed to lock config overrides mutex"))?;
+    let mut builder = TorClientConfig::builder();
+    
+    if let Some(dir) = &overrides.state_dir {
+        builder.storage().state_dir(CfgPath::new(dir.clone()));
+    }
+    if let Some(dir) = &overrides.cache_dir {
+        builder.storage().cache_dir(CfgPath::new(dir.clone()));
+    }
+    if let Some(timeout) = overrides.circuit_request_timeout {
+        builder.circuit_timing().request_timeout(timeout);
+    }
+    if let Some(padding) = overrides.padding {
+        builder.channel().padding(padding);
+    }
+    #[cfg(feature = "pt-bridges")]
+    for line in &overrides.bridges {
+        builder.bridges().bridges().push(line.parse()?);
+    }
+    if let Some(network) = &overrides.tor_network {
+        *builder.tor_network() = network.clone();
+    }
+    
+    Ok(builder.build()?)
+}
+
+// A private directory holding Arti's state and cache for one temporary session,
+// and the storage settings it temporarily replaced
+struct TemporaryStorage {
+    dir: std::path::PathBuf,
+    previous_state_dir: Option<String>,
+    previous_cache_dir: Option<String>,
+}
+
+// Create a fresh directory only the current user can access under the system
+// temp dir, and point the storage settings at it
+fn create_temporary_storage() -> Result<()> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!("torpc-temporary-{}-{}", std::process::id(), nanos));
+
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+
+    let mut overrides = lock(&CONFIG_OVERRIDES, "config overrides")?;
+    let storage = TemporaryStorage {
+        dir: dir.clone(),
+        previous_state_dir: overrides.state_dir.replace(dir.join("state").to_string_lossy().into_owned()),
+        previous_cache_dir: overrides.cache_dir.replace(dir.join("cache").to_string_lossy().into_owned()),
+    };
+    *lock(&TEMPORARY_STORAGE, "temporary storage")? = Some(storage);
+    Ok(())
+}
+
+// Delete the temporary session's directory, if any, and restore the storage settings
+fn remove_temporary_storage() {
+    let storage = match TEMPORARY_STORAGE.lock() {
+        Ok(mut storage) => storage.take(),
+        Err(_) => None,
+    };
+    let storage = match storage {
+        Some(s) => s,
+        None => return,
+    };
+
+    if let Ok(mut overrides) = CONFIG_OVERRIDES.lock() {
+        overrides.state_dir = storage.previous_state_dir;
+        overrides.cache_dir = storage.previous_cache_dir;
+    }
+    match std::fs::remove_dir_all(&storage.dir) {
+        Ok(()) => log_debug!("Removed temporary storage {}", storage.dir.display()),
+        Err(e) => log_warn!("Failed to remove temporary storage {}: {}", storage.dir.display(), e),
+    }
+}
+
+// Load a client configuration from an Arti TOML file
+fn load_client_config(path: &str) -> Result<TorClientConfig> {
+    let contents = std::fs::read_to_string(path)?;
+    let builder: arti_client::config::TorClientConfigBuilder = toml::from_str(&contents)?;
+    Ok(builder.build()?)
+}
+
+// Stream preferences every connect starts from, reflecting the configured address family
+fn default_stream_prefs() -> StreamPrefs {
+    let family = CONFIG_OVERRIDES.lock()
+        .map(|o| o.address_family)
+        .unwrap_or(ADDRESS_FAMILY_AUTO);
+
+    let mut prefs = StreamPrefs::new();
+    match family {
+        ADDRESS_FAMILY_IPV4_ONLY => { prefs.ipv4_only(); },
+        ADDRESS_FAMILY_IPV6_ONLY => { prefs.ipv6_only(); },
+        ADDRESS_FAMILY_PREFER_IPV6 => { prefs.ipv6_preferred(); },
+        _ => {},
+    }
+    prefs
+}
+
+// Map an initialization error to the code returned by the arti_init family
+fn init_error_code(e: &anyhow::Error) -> c_int {
+    e.downcast_ref::<arti_client::Error>()
+        .and_then(|err| init_kind_code(err.kind()))
+        .unwrap_or_else(|| error_code(e, 0))
+}
+
+// The specific code for an Arti error of this kind during initialization, if any.
+// Another process holding the state lock shows up as LocalResourceAlreadyInUse.
+fn init_kind_code(kind: ErrorKind) -> Option<c_int> {
+    match kind {
+        ErrorKind::LocalResourceAlreadyInUse => Some(ERR_STATE_LOCKED),
+        _ => None,
+    }
+}
+
+// A global mutex was poisoned by a panic on another thread while it was held
+#[derive(Debug)]
+struct LockPoisoned(&'static str);
+
+impl std::fmt::Display for LockPoisoned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to lock {} mutex", self.0)
+    }
+}
+
+impl std::error::Error for LockPoisoned {}
+
+// Lock a global mutex, reporting a poisoned lock as an error instead of panicking
+fn lock<'a, T>(mutex: &'a Mutex<T>, name: &'static str) -> Result<MutexGuard<'a, T>> {
+    mutex.lock().map_err(|_| LockPoisoned(name).into())
+}
+
+// Run the body of an FFI function, turning a panic into the `on_panic` return value.
+// Unwinding across the C ABI is undefined behavior, so every exported function goes
+// through here. The panic message is recorded as the thread's last error.
+fn ffi_boundary<R>(on_panic: R, body: impl FnOnce() -> R) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            log_error!("Internal error: {}", msg);
+            on_panic
+        }
+    }
+}
+
+// Map an error from a Rust implementation function to an FFI return code:
+// ERR_INTERNAL for broken internal state, `default` for anything else
+fn error_code(e: &anyhow::Error, default: c_int) -> c_int {
+    if e.is::<LockPoisoned>() {
+        ERR_INTERNAL
+    } else if e.is::<CircuitLimitReached>() {
+        ERR_CIRCUIT_LIMIT
+    } else {
+        default
+    }
+}
+
+fn initialize_tor_client(config_path: Option<&str>) -> Result<()> {
+    // Get or create the runtime
+    let runtime_mutex = get_or_create_runtime()?;
+    let runtime_guard = lock(runtime_mutex, "runtime")?;
+    
+    if let Some(runtime) = &*runtime_guard {
+        // Create the base Tor client configuration, with any settings made before init
+        let config = build_client_config()?;
+        
+        // We'll print some debug info about the configuration file if provided
+        if let Some(path) = config_path {
+            log_info!("Note: Configuration file specified at: {}", path);
+            if !Path::new(path).exists() {
+                log_warn!("Configuration file not found: {}", path);
+            } else {
+                // Just read the file to print its contents for debugging
+                match File::open(path) {
+                    Ok(mut file) => {
+                        let mut contents = String::new();
+                        if file.read_to_string(&mut contents).is_ok() {
+                            log_info!("Configuration file content (for reference only):");
+                            log_info!("{}", contents);
+                        }
+                    },
+                    Err(e) => {
+                        log_warn!("Failed to read configuration file: {}", e);
+                    }
+                }
+            }
+        } else {
+            // Check if we have a default config file in the current directory
+            let default_config_path = "arti.toml";
+            if Path::new(default_config_path).exists() {
+                log_info!("Found default configuration file at: {}", default_config_path);
+                // Just read the file to print its contents for debugging
+                match File::open(default_config_path) {
+                    Ok(mut file) => {
+                        let mut contents = String::new();
+                        if file.read_to_string(&mut contents).is_ok() {
+                            log_info!("Default configuration file content (for reference only):");
+                            log_info!("{}", contents);
+                        }
+                    },
+                    Err(e) => {
+                        log_warn!("Failed to read default configuration file: {}", e);
+                    }
+                }
+            }
+        }
+        
+        // Bootstrap the Tor client
+        log_info!("Bootstrapping Tor client...");
+        let mut tor_client = runtime.block_on(TorClient::create_bootstrapped(config))?;
+        tor_client.set_stream_prefs(default_stream_prefs());
+        log_info!("Tor client bootstrapped successfully");
+        
+        // Drop the runtime guard before acquiring another lock
+        drop(runtime_guard);
+        
+        // Store the client
+        let mut client = lock(&CLIENT, "client")?;
+        *client = Some(tor_client);
+        
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to get Tokio runtime"))
+    }
+}
+
+// A bootstrap started by arti_begin_bootstrap. The client moves to CLIENT once it
+// has bootstrapped, so it is never used before it is ready.
+enum BackgroundBootstrap {
+    Running {
+        client: TorClient<PreferredRuntime>,
+        task: tokio::task::JoinHandle<()>,
+    },
+    Failed(String),
+}
+
+// Create an unbootstrapped client and bootstrap it on the runtime in the background
+fn begin_bootstrap() -> Result<()> {
+    let mut state = lock(&BACKGROUND_BOOTSTRAP, "background bootstrap")?;
+    if let Some(BackgroundBootstrap::Running { .. }) = &*state {
+        return Ok(());
+    }
+
+    let config = build_client_config()?;
+    let handle = runtime_handle()?;
+    let client = {
+        let _entered = handle.enter();
+        TorClient::builder()
+            .config(config)
+            .bootstrap_behavior(arti_client::BootstrapBehavior::Manual)
+            .create_unbootstrapped()?
+    };
+
+    let bootstrapping = client.clone();
+    let task = handle.spawn(async move {
+        let result = bootstrapping.bootstrap().await;
+
+        // Only publish the client if the bootstrap wasn't abandoned meanwhile
+        let mut state = match BACKGROUND_BOOTSTRAP.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock background bootstrap mutex");
+                return;
+            }
+        };
+        if !matches!(&*state, Some(BackgroundBootstrap::Running { .. })) {
+            return;
+        }
+
+        match result {
+            Ok(()) => {
+                let mut client = bootstrapping;
+                client.set_stream_prefs(default_stream_prefs());
+                match CLIENT.lock() {
+                    Ok(mut c) => {
+                        *c = Some(client);
+                        *state = None;
+                        log_info!("Tor client bootstrapped successfully");
+                    },
+                    Err(_) => {
+                        *state = Some(BackgroundBootstrap::Failed("Failed to lock client mutex".to_string()));
+                    }
+                }
+            },
+            Err(e) => {
+                log_error!("Background bootstrap failed: {:?}", e);
+                *state = Some(BackgroundBootstrap::Failed(e.to_string()));
+            }
+        }
+    });
+
+    log_info!("Bootstrapping Tor client in the background...");
+    *state = Some(BackgroundBootstrap::Running { client, task });
+    Ok(())
+}
+
+// Abandon a background bootstrap, if any
+fn cancel_background_bootstrap() {
+    if let Ok(mut state) = BACKGROUND_BOOTSTRAP.lock() {
+        if let Some(BackgroundBootstrap::Running { task, .. }) = state.take() {
+            task.abort();
+        }
+    }
+}
+
+fn bootstrap_tor() -> Result<()> {
+    let client = lock(&CLIENT, "client")?;
+    if client.is_none() {
+        return Err(anyhow::anyhow!("Tor client not initialized"));
+    }
+    
+    // Client is already bootstrapped when created
+    Ok(())
+}
+
+fn shutdown_tor() -> Result<()> {
+    cancel_background_bootstrap();
+
+    // First, destroy all circuits
+    let mut circuits = lock(&CIRCUITS, "circuits")?;
+    for circuit_id in circuits.drain().map(|(id, _)| id) {
+        emit_event(EVENT_CIRCUIT_DESTROYED, &circuit_id);
+    }
+    stop_circuit_proxies(None);
+    arti_stop_socks_proxy();
+    
+    // Then clear the client
+    let mut client = lock(&CLIENT, "client")?;
+    *client = None;
+    drop(client);
+    remove_temporary_storage();
+    
+    Ok(())
+}
+
+// Count the outcome of a connect through Tor for arti_get_metrics
+fn record_connect<T>(result: std::result::Result<T, arti_client::Error>) -> std::result::Result<T, arti_client::Error> {
+    CONNECT_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    match &result {
+        Ok(_) => {
+            CONNECT_SUCCESSES.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(e) => {
+            CONNECT_FAILURES.fetch_add(1, Ordering::Relaxed);
+            if matches!(e.kind(), ErrorKind::TorNetworkTimeout | ErrorKind::RemoteNetworkTimeout) {
+                CONNECT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    result
+}
+
+// Close every stream and circuit, leaving the client bootstrapped
+fn reset_streams_and_circuits() -> Result<()> {
+    // Abort outside the lock; each connect task takes it to remove itself
+    let connects: Vec<_> = match PENDING_CONNECTS.lock() {
+        Ok(pending) => pending.values().cloned().collect(),
+        Err(_) => Vec::new(),
+    };
+    for task in connects {
+        task.abort();
+    }
+    if let Ok(mut finished) = FINISHED_CONNECTS.lock() {
+        finished.clear();
+    }
+    // Background writes can't be aborted; forget them and let any still queued fail
+    lock(&PENDING_WRITES, "pending writes")?.clear();
+    lock(&WRITE_QUEUES, "write queues")?.clear();
+
+    lock(&STREAMS, "streams")?.clear();
+    for (_, closed) in lock(&TAKEN_STREAMS, "taken streams")?.drain() {
+        closed.notify_one();
+    }
+    lock(&SPLIT_READERS, "split readers")?.clear();
+    lock(&SPLIT_WRITERS, "split writers")?.clear();
+    lock(&TLS_STREAMS, "TLS streams")?.clear();
+    for group in lock(&STREAM_GROUPS, "stream groups")?.drain().map(|(_, group)| group) {
+        for stream_id in group.keys() {
+            emit_event(EVENT_STREAM_CLOSED, stream_id);
+        }
+    }
+
+    for circuit_id in lock(&CIRCUITS, "circuits")?.drain().map(|(id, _)| id) {
+        emit_event(EVENT_CIRCUIT_DESTROYED, &circuit_id);
+    }
+    lock(&CIRCUIT_BUILD_TIMES, "circuit build times")?.clear();
+    stop_circuit_proxies(None);
+
+    Ok(())
+}
+
+// Tear down everything, including the runtime, so the library can be unloaded
+fn shutdown_all() -> Result<()> {
+    cancel_background_bootstrap();
+    reset_streams_and_circuits()?;
+    arti_stop_socks_proxy();
+
+    if let Some(reaper) = lock(&IDLE_REAPER, "idle reaper")?.take() {
+        reaper.abort();
+    }
+    lock(&HTTP_UPLOADS, "HTTP uploads")?.clear();
+    for (_, http_stream) in lock(&HTTP_STREAMS, "HTTP streams")?.drain() {
+        http_stream.closed.notify_one();
+    }
+    lock(&CLIENTS, "clients")?.clear();
+    *lock(&CLIENT, "client")? = None;
+
+    // Take the runtime out first; shutting it down while holding the lock could
+    // deadlock with tasks that need it
+    let runtime = lock(&RUNTIME, "runtime")?.take();
+    if let Ok(mut handle) = RUNTIME_HANDLE.write() {
+        *handle = None;
+    }
+    if let Some(runtime) = runtime {
+        runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+    }
+    remove_temporary_storage();
+
+    Ok(())
+}
+
+fn is_connected() -> Result<bool> {
+    let client = lock(&CLIENT, "client")?;
+    Ok(client.is_some())
+}
+
+// Whether the Tor client exists and has bootstrapped enough to carry traffic
+fn client_ready() -> bool {
+    match CLIENT.lock() {
+        Ok(client) => client.as_ref()
+            .map(|c| c.bootstrap_status().ready_for_traffic())
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+// Creating a circuit would exceed the limit set with arti_set_max_circuits
+#[derive(Debug)]
+struct CircuitLimitReached(u64);
+
+impl std::fmt::Display for CircuitLimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Circuit limit of {} reached; destroy a circuit first", self.0)
+    }
+}
+
+impl std::error::Error for CircuitLimitReached {}
+
+// Check that adding the given circuit IDs stays within the circuit limit. IDs that
+// already exist are replaced rather than added, so they don't count.
+fn check_circuit_limit<'a, T>(circuits: &HashMap<String, T>, ids: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    let max = MAX_CIRCUITS.load(Ordering::Relaxed);
+    if max == 0 {
+        return Ok(());
+    }
+
+    let added = ids.into_iter().filter(|id| !circuits.contains_key(*id)).count() as u64;
+    if circuits.len() as u64 + added > max {
+        return Err(CircuitLimitReached(max).into());
+    }
+    Ok(())
+}
+
+fn create_circuit(circuit_id: String) -> Result<()> {
+    // Get the Tor client from the global state. Each circuit gets its own isolated
+    // client so that streams and HTTP requests on different circuits use different paths.
+    let tor_client = match lock(&CLIENT, "client")?.as_ref() {
+        Some(client) => Arc::new(client.isolated_client()),
+        None => return Err(anyhow!("Tor client not initialized")),
+    };
+    
+    // Store the circuit ID and associated client
+    let mut circuits = lock(&CIRCUITS, "circuits")?;
+    check_circuit_limit(&circuits, [&circuit_id])?;
+    circuits.insert(circuit_id.clone(), tor_client);
+    drop(circuits);
+    emit_event(EVENT_CIRCUIT_CREATED, &circuit_id);
+    
+    Ok(())
+}
+
+fn create_circuits(count: usize, warmup: bool) -> Result<Vec<String>> {
+    // Get the Tor client from the global state
+    let base_client = match lock(&CLIENT, "client")?.clone() {
+        Some(client) => client,
+        None => return Err(anyhow!("Tor client not initialized")),
+    };
+    
+    // Number the circuits from a shared counter, skipping IDs the caller already chose
+    let ids: Vec<String> = {
+        let circuits = lock(&CIRCUITS, "circuits")?;
+        let ids: Vec<String> = std::iter::repeat_with(|| format!("circuit-{}", NEXT_CIRCUIT.fetch_add(1, Ordering::Relaxed)))
+            .filter(|id| !circuits.contains_key(id))
+            .take(count)
+            .collect();
+        // Fail before spending time on warmup; checked again when the circuits are stored
+        check_circuit_limit(&circuits, &ids)?;
+        ids
+    };
+
+    // Each circuit gets its own isolated client so they never share a path
+    let new_circuits: Vec<(String, Arc<TorClient<PreferredRuntime>>)> = ids.iter()
+        .map(|id| (id.clone(), Arc::new(base_client.isolated_client())))
+        .collect();
+    
+    if warmup {
+        // Open and drop a throwaway stream on every circuit in parallel so each path is built
+        let results = block_on_runtime(futures::future::join_all(
+            new_circuits.iter().map(|(_, client)| client.connect(WARMUP_TARGET))
+        ))?;
+        for ((circuit_id, _), result) in new_circuits.iter().zip(results) {
+            if let Err(e) = result {
+                log_warn!("Failed to warm up circuit {}: {:?}", circuit_id, e);
+            }
+        }
+    }
+    
+    let mut circuits = lock(&CIRCUITS, "circuits")?;
+    // An ID may have been taken with arti_create_circuit during warmup; never replace it
+    if let Some(id) = ids.iter().find(|id| circuits.contains_key(*id)) {
+        return Err(anyhow!("Circuit ID already exists: {}", id));
+    }
+    check_circuit_limit(&circuits, &ids)?;
+    circuits.extend(new_circuits);
+    drop(circuits);
+    for id in &ids {
+        emit_event(EVENT_CIRCUIT_CREATED, id);
+    }
+    
+    Ok(ids)
+}
+
+fn destroy_circuit(circuit_id: String) -> Result<()> {
+    // Remove the circuit ID from the registry
+    let mut circuits = lock(&CIRCUITS, "circuits")?;
+    if circuits.remove(&circuit_id).is_none() {
+        return Err(anyhow!("Circuit not found: {}", circuit_id));
+    }
+    drop(circuits);
+    emit_event(EVENT_CIRCUIT_DESTROYED, &circuit_id);
+    stop_circuit_proxies(Some(&circuit_id));
+    if let Ok(mut times) = CIRCUIT_BUILD_TIMES.lock() {
+        times.remove(&circuit_id);
+    }
+
+    // Close the streams too, rather than leave them running on a forgotten client
+    let closed = close_circuit_streams(&circuit_id)?;
+    if closed > 0 {
+        log_debug!("Closed {} streams of destroyed circuit {}", closed, circuit_id);
+    }
+    Ok(())
+}
+
+// Give a circuit a fresh isolation group so its next stream is built on a new path
+fn rebuild_circuit(circuit_id: &str) -> Result<()> {
+    let mut circuits = lock(&CIRCUITS, "circuits")?;
+    let client = circuits.get_mut(circuit_id)
+        .ok_or_else(|| anyhow!("Circuit not found: {}", circuit_id))?;
+    *client = Arc::new(client.isolated_client());
+    if let Ok(mut times) = CIRCUIT_BUILD_TIMES.lock() {
+        times.remove(circuit_id);
+    }
+    Ok(())
+}
+
+// Helper function to get or create the runtime
+fn get_or_create_runtime() -> Result<&'static Mutex<Option<Runtime>>> {
+    // Check if runtime exists
+    let runtime = lock(&RUNTIME, "runtime")?;
+    if runtime.is_none() {
+        // Release lock before modifying
+        drop(runtime);
+        
+        // Get lock again and check once more (double-check locking pattern)
+        let mut runtime = lock(&RUNTIME, "runtime")?;
+        if runtime.is_none() {
+            // Create a new runtime
+            let created = Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| anyhow!("Failed to create Tokio runtime: {}", e))?;
+            *RUNTIME_HANDLE.write()
+                .map_err(|_| anyhow!("Failed to lock runtime handle"))? = Some(created.handle().clone());
+            *runtime = Some(created);
+        }
+    }
+    
+    Ok(&RUNTIME)
+}
+
+// Helper function to build a "host:port" connect target. IPv6 literals, bare or
+// already bracketed, are written in the bracketed form Tor expects. Returns None
+// if the host or port is malformed.
+fn format_target(host: &str, port: i32) -> Option<String> {
+    if !(1..=65535).contains(&port) {
+        return None;
+    }
+
+    if let Some(inner) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return inner.parse::<Ipv6Addr>().ok().map(|ip| format!("[{}]:{}", ip, port));
+    }
+
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        return Some(format!("[{}]:{}", ip, port));
+    }
+
+    // Anything else containing IPv6 punctuation is a malformed address
+    if host.is_empty() || host.contains(|c: char| c == ':' || c == '[' || c == ']' || c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+
+    Some(format!("{}:{}", host, port))
+}
+
+// Explain why format_target rejected a host and port, pointing out the common
+// mistake of passing a URL or "host:port" as the host
+fn describe_invalid_target(host: &str, port: i32) -> String {
+    if !(1..=65535).contains(&port) {
+        return format!("port {} is out of range", port);
+    }
+    if let Some((scheme, _)) = host.split_once("://") {
+        return format!("host {:?} must not include a scheme; pass the bare hostname instead of a {}:// URL", host, scheme);
+    }
+    if let Some((name, host_port)) = host.rsplit_once(':') {
+        if !name.contains(':') && !host_port.is_empty() && host_port.chars().all(|c| c.is_ascii_digit()) {
+            return format!("host {:?} must not include a port; pass {:?} and the port separately", host, name);
+        }
+    }
+    format!("host {:?}, port {} is not a valid hostname or IP address", host, port)
+}
+
+// Helper function to map a caller-supplied isolation string to an Arti isolation token,
+// creating a new token the first time a string is seen
+fn isolation_token_for(token: &str) -> Result<IsolationToken> {
+    let mut tokens = ISOLATION_TOKENS.lock()
+        .map_err(|_| anyhow!("Failed to lock isolation tokens mutex"))?;
+    Ok(*tokens.entry(token.to_string()).or_insert_with(IsolationToken::new))
+}
+
+// Helper function to copy a string into a caller-provided buffer as a
+// null-terminated C string. Returns false if the buffer is too small.
+fn write_c_buffer(value: &str, buffer: *mut c_char, buffer_len: c_int) -> bool {
+    let bytes = value.as_bytes();
+    if buffer.is_null() || buffer_len <= 0 || bytes.len() + 1 > buffer_len as usize {
+        return false;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, buffer, bytes.len());
+        *buffer.add(bytes.len()) = 0;
+    }
+    true
+}
+
+// Helper function to run a future to completion on the global runtime
+fn block_on_runtime<F: std::future::Future>(future: F) -> Result<F::Output> {
+    Ok(runtime_handle()?.block_on(future))
+}
+
+// Helper function to get a handle to the global runtime for spawning tasks. Only
+// creating the runtime takes the RUNTIME lock, so this doesn't wait behind calls
+// that block on the runtime.
+fn runtime_handle() -> Result<tokio::runtime::Handle> {
+    let current = || -> Result<Option<tokio::runtime::Handle>> {
+        Ok(RUNTIME_HANDLE.read()
+            .map_err(|_| anyhow!("Failed to lock runtime handle"))?
+            .clone())
+    };
+    if let Some(handle) = current()? {
+        return Ok(handle);
+    }
+    get_or_create_runtime()?;
+    current()?.ok_or_else(|| anyhow!("Runtime not initialized"))
+}
+
+// Helper function to get the Tor client from a circuit ID
+fn get_tor_client_by_circuit(circuit_id: &str) -> Option<Arc<TorClient<PreferredRuntime>>> {
+    let circuits = CIRCUITS.lock().ok()?;
+    circuits.get(circuit_id).cloned()
+}
+
+/// Connect to a target through Tor with TLS (HTTPS)
+///
+/// @param circuit_id The circuit ID to use
+/// @param host The target hostname, without a scheme or port
+/// @param port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+#[no_mangle]
+pub extern "C" fn arti_connect_tls_stream(
+    circuit_id: *const c_char,
+    host: *const c_char,
+    port: c_int,
+    stream_id: *const c_char
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        connect_tls_stream(circuit_id, host, port, stream_id, false)
+    })
+}
+
+/// Connect to a target through Tor with TLS, WITHOUT verifying the server's certificate
+///
+/// DANGEROUS: any certificate is accepted, whoever issued it and whatever name it
+/// is for, so the connection is open to interception by anyone on the path after
+/// the exit, including the exit itself. Only use this for development servers or
+/// onion services with self-signed certificates, where the address itself
+/// authenticates the server. Always uses the default TLS versions, ignoring
+/// arti_set_tls_min_version. Otherwise behaves like arti_connect_tls_stream.
+///
+/// @param circuit_id The circuit ID to use
+/// @param host The target hostname, without a scheme or port
+/// @param port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+#[no_mangle]
+pub extern "C" fn arti_connect_tls_stream_insecure(
+    circuit_id: *const c_char,
+    host: *const c_char,
+    port: c_int,
+    stream_id: *const c_char
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        log_warn!("Connecting with TLS certificate verification disabled");
+        connect_tls_stream(circuit_id, host, port, stream_id, true)
+    })
+}
+
+// Accepts every server certificate. Only for arti_connect_tls_stream_insecure.
+struct NoCertificateVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+// TLS configuration that skips certificate verification
+fn insecure_tls_config() -> StdArc<ClientConfig> {
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(StdArc::new(NoCertificateVerification))
+        .with_no_client_auth();
+    StdArc::new(tls_config)
+}
+
+// Shared implementation of arti_connect_tls_stream and arti_connect_tls_stream_insecure
+fn connect_tls_stream(
+    circuit_id: *const c_char,
+    host: *const c_char,
+    port: c_int,
+    stream_id: *const c_char,
+    insecure: bool,
+) -> c_int {
+    if circuit_id.is_null() || host.is_null() || stream_id.is_null() || port <= 0 || port > 65535 {
+        log_error!("Invalid parameters in arti_connect_tls_stream");
+        return 0;
+    }
+
+    let circuit_id_str = unsafe {
+        match CStr::from_ptr(circuit_id).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        }
+    };
+
+    let host_str = unsafe {
+        match CStr::from_ptr(host).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                log_error!("Invalid host string");
+                return 0;
+            }
+        }
+    };
+
+    let stream_id_str = unsafe {
+        match CStr::from_ptr(stream_id).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        }
+    };
+
+    let target_port = port as u16;
+    if format_target(&host_str, port).is_none() {
+        log_error!("Invalid target address: {}", describe_invalid_target(&host_str, port));
+        return ERR_INVALID_PARAMS;
+    }
+
+    // Get the runtime
+    let runtime_mutex = match get_or_create_runtime() {
+        Ok(r) => r,
+        Err(e) => {
+            log_error!("Failed to get runtime for TLS connection: {:?}", e);
+            return 0;
+        }
+    };
+    let runtime_guard = match runtime_mutex.lock() {
+        Ok(g) => g,
+        Err(_) => {
+            log_error!("Failed to lock runtime mutex");
+            return ERR_INTERNAL;
+        }
+    };
+
+    if let Some(runtime) = &*runtime_guard {
+        // Get the circuit
+        let client = match get_tor_client_by_circuit(&circuit_id_str) {
+            Some(c) => c,
+            None => {
+                log_error!("Circuit not found: {}", circuit_id_str);
+                return 0;
+            }
+        };
+
+        // Connect to the target through Tor
+        log_debug!("Connecting to {}:{} through Tor with TLS", host_str, target_port);
+    
+        let result = runtime.block_on(async {
+            // First establish the basic Tor connection
+            let target = match format_target(&host_str, target_port as i32) {
+                Some(t) => t,
+                None => {
+                    log_error!("Invalid target address: {}", describe_invalid_target(&host_str, target_port as i32));
+                    return Err(anyhow!("Invalid target address"));
+                }
+            };
+            let started = Instant::now();
+            let stream = match record_connect(client.connect(&target).await) {
+                Ok(s) => s,
+                Err(e) => {
+                    log_error!("Failed to connect to target through Tor: {:?}", e);
+                    return Err(anyhow!("Connection failed"));
+                }
+            };
+            record_first_stream(&circuit_id_str, started.elapsed());
+        
+            // Now establish TLS connection over the Tor stream
+            let server_name = match rustls::ServerName::try_from(host_str.as_str()) {
+                Ok(n) => n,
+                Err(e) => {
+                    log_error!("Invalid server name for TLS: {:?}", e);
+                    return Err(anyhow!("Invalid server name"));
+                }
+            };
+        
+            let config = if insecure { insecure_tls_config() } else { tls_client_config()? };
+            let connector = TlsConnector::from(config);
+        
+            match connector.connect(server_name, stream).await {
+                Ok(tls_stream) => {
+                    // Store the TLS stream under the caller's stream ID
+                    let mut streams = TLS_STREAMS.lock()
+                        .map_err(|_| anyhow!("Failed to lock TLS streams mutex"))?;
+                    track_stream(&circuit_id_str, &stream_id_str, "");
+                    streams.insert(stream_id_str.clone(), StdArc::new(Mutex::new(tls_stream)));
+                    Ok(())
+                },
+                Err(e) => {
+                    log_error!("TLS handshake failed: {:?}", e);
+                    Err(anyhow!("TLS handshake failed"))
+                }
+            }
+        });
+
+        match result {
+            Ok(_) => {
+                log_debug!("TLS connection established: {}", stream_id_str);
+                1
+            },
+            Err(e) => {
+                log_error!("TLS connection failed: {:?}", e);
+                0
+            }
+        }
+    } else {
+        log_error!("Runtime not initialized");
+        0
+    }
+}
+
+// Largest buffer accepted by the TLS read/write functions; read counts are returned as c_int
+const MAX_TLS_IO_LEN: usize = c_int::MAX as usize;
+
+// Look up a TLS stream by ID
+fn tls_stream(stream_id: &str) -> Option<StdArc<Mutex<TlsStream<DataStream>>>> {
+    TLS_STREAMS.lock().ok().and_then(|streams| streams.get(stream_id).map(StdArc::clone))
+}
+
+/// Write data to a TLS stream
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @param data Pointer to the data to write
+/// @param data_len Length of the data, between 1 and INT_MAX
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_tls_write(
+    stream_id: *const c_char,
+    data: *const u8,
+    data_len: usize
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || data.is_null() || data_len == 0 || data_len > MAX_TLS_IO_LEN {
+            log_error!("Invalid parameters in arti_tls_write");
+            return 0;
+        }
+
+        let stream_id_str = unsafe {
+            match CStr::from_ptr(stream_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+    
+        let data_slice = unsafe { std::slice::from_raw_parts(data, data_len) };
+    
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(_) => return 0,
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => return ERR_INTERNAL,
+        };
+    
+        if let Some(runtime) = &*runtime_guard {
+            // Get the TLS stream
+            let stream_arc_option = tls_stream(&stream_id_str);
+        
+            if let Some(stream_arc) = stream_arc_option {
+                let result = runtime.block_on(async {
+                    // Get a lock on the TLS stream
+                    let mut stream = stream_arc.lock()
+                        .map_err(|_| anyhow!("Failed to lock TLS stream"))?;
+                
+                    // Write the data to the stream
+                    throttle(data_slice.len()).await;
+                    match stream.write_all(data_slice).await {
+                        Ok(_) => {
+                            BYTES_OUT.fetch_add(data_slice.len() as u64, Ordering::Relaxed);
+                            Ok(())
+                        },
+                        Err(e) => {
+                            log_error!("Failed to write to TLS stream: {:?}", e);
+                            Err(anyhow!("Write failed"))
+                        }
+                    }
+                });
+            
+                match result {
+                    Ok(_) => 1,
+                    Err(_) => 0,
+                }
+            } else {
+                0
+            }
+        } else {
+            0
+        }
+    })
+}
+
+/// Flush a TLS stream
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_flush_tls_stream(
+    stream_id: *const c_char
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_flush_tls_stream");
+            return 0;
+        }
+
+        let stream_id_str = unsafe {
+            match CStr::from_ptr(stream_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+    
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(_) => return 0,
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => return ERR_INTERNAL,
+        };
+    
+        if let Some(runtime) = &*runtime_guard {
+            // Get the TLS stream
+            let stream_arc_option = tls_stream(&stream_id_str);
+        
+            if let Some(stream_arc) = stream_arc_option {
+                let result = runtime.block_on(async {
+                    // Get a lock on the TLS stream
+                    let mut stream = stream_arc.lock()
+                        .map_err(|_| anyhow!("Failed to lock TLS stream"))?;
+                
+                    // Flush the stream
+                    match stream.flush().await {
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            log_error!("Failed to flush TLS stream: {:?}", e);
+                            Err(anyhow!("Flush failed"))
+                        }
+                    }
+                });
+            
+                match result {
+                    Ok(_) => 1,
+                    Err(_) => 0,
+                }
+            } else {
+                0
+            }
+        } else {
+            0
+        }
+    })
+}
+
+/// Read data from a TLS stream
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @param buffer Pointer to the buffer to store the read data
+/// @param buffer_len Length of the buffer, between 1 and INT_MAX
+/// @return The number of bytes read (0 at end of stream), or -1 on failure
+#[no_mangle]
+pub extern "C" fn arti_tls_read(
+    stream_id: *const c_char,
+    buffer: *mut u8,
+    buffer_len: usize
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || buffer.is_null() || buffer_len == 0 || buffer_len > MAX_TLS_IO_LEN {
+            log_error!("Invalid parameters in arti_tls_read");
+            return -1;
+        }
+
+        let stream_id_str = unsafe {
+            match CStr::from_ptr(stream_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return -1,
+            }
+        };
+    
+        let buffer_slice = unsafe { std::slice::from_raw_parts_mut(buffer, buffer_len) };
+    
+        // Get the runtime
+        let runtime_mutex = match get_or_create_runtime() {
+            Ok(r) => r,
+            Err(_) => return -1,
+        };
+        let runtime_guard = match runtime_mutex.lock() {
+            Ok(g) => g,
+            Err(_) => return ERR_INTERNAL,
+        };
+    
+        if let Some(runtime) = &*runtime_guard {
+            // Get the TLS stream
+            let stream_arc_option = tls_stream(&stream_id_str);
+        
+            if let Some(stream_arc) = stream_arc_option {
+                let result = runtime.block_on(async {
+                    // Get a lock on the TLS stream
+                    let mut stream = stream_arc.lock()
+                        .map_err(|_| anyhow!("Failed to lock TLS stream"))?;
+                
+                    // Read data into the buffer
+                    match stream.read(buffer_slice).await {
+                        Ok(n) => {
+                            BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+                            throttle(n).await;
+                            Ok(n)
+                        },
+                        Err(e) => {
+                            log_error!("Failed to read from TLS stream: {:?}", e);
+                            Err(anyhow!("Read failed"))
+                        }
+                    }
+                });
+            
+                match result {
+                    Ok(bytes_read) => bytes_read as c_int,
+                    Err(_) => -1,
+                }
+            } else {
+                -1
+            }
+        } else {
+            -1
+        }
+    })
+}
+
+/// Get details of the certificate presented by the peer of a TLS stream
+///
+/// Writes a JSON object with the leaf certificate's "subject", "issuer", "sans" (DNS
+/// names and IP addresses) and "not_before"/"not_after" as Unix timestamps in seconds.
+/// The certificate has already been validated against the web PKI roots for the host
+/// given to arti_connect_tls_stream; use this for additional checks of your own.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_tls_peer_cert_info(
+    stream_id: *const c_char,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_tls_peer_cert_info");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let stream_arc = match tls_stream(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("TLS Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        let leaf = match stream_arc.lock() {
+            Ok(stream) => stream.get_ref().1.peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.0.clone()),
+            Err(_) => {
+                log_error!("Failed to lock TLS stream");
+                return 0;
+            }
+        };
+
+        let leaf = match leaf {
+            Some(der) => der,
+            None => {
+                log_error!("No peer certificate on TLS stream: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        let info = match peer_cert_json(&leaf) {
+            Ok(info) => info,
+            Err(e) => {
+                log_error!("Failed to parse peer certificate: {:?}", e);
+                return 0;
+            }
+        };
+
+        if !write_c_buffer(&info.to_string(), out_json, out_len) {
+            log_error!("Output buffer too small in arti_tls_peer_cert_info");
+            return 0;
+        }
+        1
+    })
+}
+
+/// Get the parameters negotiated on a TLS stream
+///
+/// Writes a JSON object with the protocol "version" (e.g. "TLSv1.3"), the
+/// "cipher_suite" (e.g. "TLS13_AES_256_GCM_SHA384") and the "alpn" protocol, which
+/// is null when none was negotiated.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure or if the stream is not a TLS stream,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_tls_connection_info(
+    stream_id: *const c_char,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_tls_connection_info");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let stream_arc = match tls_stream(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("TLS Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        let info = match stream_arc.lock() {
+            Ok(stream) => {
+                let connection = stream.get_ref().1;
+                let version = connection.protocol_version().map(|v| match v {
+                    rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+                    rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+                    other => format!("{:?}", other),
+                });
+                serde_json::json!({
+                    "version": version,
+                    "cipher_suite": connection.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite())),
+                    "alpn": connection.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+                }).to_string()
+            },
+            Err(_) => {
+                log_error!("Failed to lock TLS stream");
+                return 0;
+            }
+        };
+
+        if !write_c_buffer(&info, out_json, out_len) {
+            log_error!("TLS connection info buffer too small: {} bytes needed", info.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+        1
+    })
+}
+
+// Summarize a DER-encoded certificate for arti_tls_peer_cert_info
+fn peer_cert_json(der: &[u8]) -> Result<serde_json::Value> {
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+    let (_, cert) = X509Certificate::from_der(der)
+        .map_err(|e| anyhow!("Invalid certificate: {}", e))?;
+
+    let mut sans = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(dns) => sans.push(dns.to_string()),
+                GeneralName::IPAddress(bytes) => {
+                    if let Ok(v4) = <[u8; 4]>::try_from(*bytes) {
+                        sans.push(Ipv4Addr::from(v4).to_string());
+                    } else if let Ok(v6) = <[u8; 16]>::try_from(*bytes) {
+                        sans.push(Ipv6Addr::from(v6).to_string());
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    Ok(serde_json::json!({
+        "subject": cert.subject().to_string(),
+        "issuer": cert.issuer().to_string(),
+        "sans": sans,
+        "not_before": cert.validity().not_before.timestamp(),
+        "not_after": cert.validity().not_after.timestamp(),
+    }))
+}
+
+/// Close a TLS stream
+///
+/// As with arti_close_stream, closing a stream that is already closed returns
+/// ERR_STREAM_NOT_FOUND.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if there is no such stream,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_close_tls_stream(
+    stream_id: *const c_char
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_close_tls_stream");
+            return 0;
+        }
+
+        let stream_id_str = unsafe {
+            match CStr::from_ptr(stream_id).to_str() {
+                Ok(s) => s.to_string(),
+                Err(_) => return 0,
+            }
+        };
+
+        // Remove the stream from the map
+        let removed = match TLS_STREAMS.lock() {
+            Ok(mut streams) => streams.remove(&stream_id_str).is_some(),
+            Err(_) => {
+                log_error!("Failed to lock TLS streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+    
+        if removed {
+            untrack_stream(&stream_id_str);
+            log_debug!("TLS Stream closed: {}", stream_id_str);
+            1
+        } else {
+            log_debug!("TLS Stream already closed or unknown: {}", stream_id_str);
+            ERR_STREAM_NOT_FOUND
+        }
+    })
+}
+
+// Queue a lifecycle event for the event callback. Events are delivered on a
+// dedicated thread, so this is safe to call while holding any of the map
+// mutexes and the callback is free to call back into the library.
+fn emit_event(event_type: c_int, id: &str) {
+    if EVENT_CALLBACK.lock().map(|cb| cb.is_none()).unwrap_or(true) {
+        return;
+    }
+
+    let mut sender = match EVENT_SENDER.lock() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    if sender.is_none() {
+        let (tx, rx) = std::sync::mpsc::channel::<(c_int, String)>();
+        let spawned = std::thread::Builder::new()
+            .name("torpc-events".to_string())
+            .spawn(move || {
+                for (event_type, id) in rx {
+                    // Read the callback per event so removing it takes effect immediately
+                    let callback = match EVENT_CALLBACK.lock() {
+                        Ok(cb) => *cb,
+                        Err(_) => None,
+                    };
+                    if let (Some(cb), Ok(c_id)) = (callback, CString::new(id.replace('\0', " "))) {
+                        cb(event_type, c_id.as_ptr());
+                    }
+                }
+            });
+        match spawned {
+            Ok(_) => *sender = Some(tx),
+            Err(e) => {
+                log_warn!("Failed to start event thread: {:?}", e);
+                return;
+            }
+        }
+    }
+    if let Some(tx) = sender.as_ref() {
+        let _ = tx.send((event_type, id.to_string()));
+    }
+}
+
+// Deliver a diagnostic message to the log callback if one is installed, or
+// print it to stderr otherwise. Nothing is ever written to stdout.
+fn log_message(level: c_int, msg: &str) {
+    if level == LOG_LEVEL_ERROR {
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg.to_string()));
+    }
+
+    let callback = match LOG_CALLBACK.lock() {
+        Ok(cb) => *cb,
+        Err(_) => None,
+    };
+
+    match callback {
+        Some(cb) => {
+            if level > LOG_LEVEL.load(Ordering::Relaxed) {
+                return;
+            }
+            // Interior NUL bytes would truncate the message, so replace them
+            if let Ok(c_msg) = CString::new(msg.replace('\0', " ")) {
+                cb(level, c_msg.as_ptr());
+            }
+        },
+        None => {
+            // Errors and warnings are always reported; anything chattier needs arti_set_verbose
+            if level <= LOG_LEVEL_WARN || level <= VERBOSE_LEVEL.load(Ordering::Relaxed) {
+                eprintln!("{}", msg);
+            }
+        }
+    }
+}
+
+// tracing layer that forwards Arti's events to the log callback
+struct CallbackLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CallbackLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        // Only forward Arti's events when the host asked for them
+        if LOG_CALLBACK.lock().map(|cb| cb.is_none()).unwrap_or(true) {
+            return;
+        }
+
+        let level = match *event.metadata().level() {
+            tracing::Level::ERROR => LOG_LEVEL_ERROR,
+            tracing::Level::WARN => LOG_LEVEL_WARN,
+            tracing::Level::INFO => LOG_LEVEL_INFO,
+            tracing::Level::DEBUG => LOG_LEVEL_DEBUG,
+            tracing::Level::TRACE => LOG_LEVEL_TRACE,
+        };
+        if level > LOG_LEVEL.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        log_message(level, &format!("{}: {}", event.metadata().target(), visitor.0));
+    }
+}
+
+// Collects the message and fields of a tracing event into a single line
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0.insert_str(0, &format!("{:?}", value));
+        } else {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Set a callback that receives log messages from torpc and Arti
+///
+/// Once a callback is installed, diagnostics are no longer printed to the console.
+/// Messages above the level set with arti_set_log_level are dropped. Passing NULL
+/// removes the callback and restores console output.
+///
+/// @param cb Function called with the level (1 = error ... 5 = trace) and a null-terminated message
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_log_callback(cb: Option<extern "C" fn(level: c_int, msg: *const c_char)>) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let mut callback = match LOG_CALLBACK.lock() {
+            Ok(c) => c,
+            Err(_) => return 0,
+        };
+        *callback = cb;
+        drop(callback);
+
+        if cb.is_some() {
+            TRACING_INIT.call_once(|| {
+                use tracing_subscriber::layer::SubscriberExt;
+                use tracing_subscriber::util::SubscriberInitExt;
+
+                if tracing_subscriber::registry().with(CallbackLayer).try_init().is_err() {
+                    log_warn!("A tracing subscriber is already installed; Arti events will not be forwarded");
+                }
+            });
+        }
+
+        1
+    })
+}
+
+/// Set a callback that is notified when circuits and streams are created or closed
+///
+/// The callback runs on a dedicated background thread, never while torpc holds
+/// its internal locks, so it may call other arti_* functions. Events are
+/// delivered in the order they happened. Passing NULL removes the callback.
+///
+/// @param cb Function called with the event type (1 = circuit created, 2 = circuit destroyed,
+///         3 = stream opened, 4 = stream closed) and the null-terminated circuit or stream ID
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_event_callback(cb: Option<extern "C" fn(event_type: c_int, id: *const c_char)>) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match EVENT_CALLBACK.lock() {
+            Ok(mut callback) => {
+                *callback = cb;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock event callback mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Set the maximum level of messages delivered to the log callback
+///
+/// @param level 0 = off, 1 = error, 2 = warn, 3 = info (default), 4 = debug, 5 = trace
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_log_level(level: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if !(LOG_LEVEL_OFF..=LOG_LEVEL_TRACE).contains(&level) {
+            log_error!("Invalid log level: {}", level);
+            return 0;
+        }
+
+        LOG_LEVEL.store(level, Ordering::Relaxed);
+        1
+    })
+}
+
+/// Set how much diagnostic output is printed when no log callback is installed
+///
+/// Errors and warnings are always printed to stderr. Informational and debug
+/// messages are printed to stderr only up to the given level. Nothing is written
+/// to stdout. When a log callback is installed, use arti_set_log_level instead.
+///
+/// @param level 0 = off (default), 3 = info, 4 = debug, 5 = trace
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_verbose(level: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if !(LOG_LEVEL_OFF..=LOG_LEVEL_TRACE).contains(&level) {
+            log_error!("Invalid verbosity level: {}", level);
+            return 0;
+        }
+
+        VERBOSE_LEVEL.store(level, Ordering::Relaxed);
+        1
+    })
+}
+
+/// Get the number of bytes read from and written to a stream
+///
+/// Counts are cumulative since the stream was opened. Peeked data is counted
+/// once it has been consumed by a read.
+///
+/// @param stream_id The stream ID
+/// @param out_read Output parameter that will receive the number of bytes read
+/// @param out_written Output parameter that will receive the number of bytes written
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_stream_stats(
+    stream_id: *const c_char,
+    out_read: *mut u64,
+    out_written: *mut u64,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_read.is_null() || out_written.is_null() {
+            log_error!("Invalid parameters in arti_stream_stats");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        match streams.get(stream_id_str) {
+            Some(stream) => {
+                unsafe {
+                    *out_read = stream.bytes_read;
+                    *out_written = stream.bytes_written;
+                }
+                1
+            },
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                0
+            }
+        }
+    })
+}
+
+/// Describe a stream ID, including whether it still exists
+///
+/// The result is a null-terminated JSON object:
+/// `{"exists": bool, "type": "plain" | "split" | "tls" | null, "circuit": string | null,
+///   "bytes_in": number | null, "bytes_out": number | null, "age_ms": number | null}`.
+/// An unknown ID is reported with `"exists": false` rather than as an error. Byte
+/// counts and age are only tracked for plain streams and are null for the others.
+///
+/// @param stream_id The stream ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_stream_info(
+    stream_id: *const c_char,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_json.is_null() {
+            log_error!("Invalid parameters in arti_stream_info");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Take each lock on its own so the stream maps are never held together
+        let plain = match STREAMS.lock() {
+            Ok(streams) => streams.get(stream_id_str).map(|stream| {
+                (stream.bytes_read, stream.bytes_written, stream.opened_at.elapsed().as_millis() as u64)
+            }),
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+        let kind = if plain.is_some() {
+            Some("plain")
+        } else if SPLIT_READERS.lock().map(|r| r.contains_key(stream_id_str)).unwrap_or(false)
+            || SPLIT_WRITERS.lock().map(|w| w.contains_key(stream_id_str)).unwrap_or(false) {
+            Some("split")
+        } else if TLS_STREAMS.lock().map(|t| t.contains_key(stream_id_str)).unwrap_or(false) {
+            Some("tls")
+        } else {
+            None
+        };
+
+        let circuit = match STREAM_GROUPS.lock() {
+            Ok(groups) => groups.iter()
+                .find(|(_, group)| group.contains_key(stream_id_str))
+                .map(|(circuit_id, _)| circuit_id.clone()),
+            Err(_) => {
+                log_error!("Failed to lock stream groups mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let json = serde_json::json!({
+            "exists": kind.is_some(),
+            "type": kind,
+            "circuit": kind.and(circuit),
+            "bytes_in": plain.map(|(bytes_in, _, _)| bytes_in),
+            "bytes_out": plain.map(|(_, bytes_out, _)| bytes_out),
+            "age_ms": plain.map(|(_, _, age_ms)| age_ms),
+        }).to_string();
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Stream info buffer too small: {} bytes needed", json.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Limit the combined throughput of all stream reads and writes
+///
+/// The limit is a token bucket shared by every plain and TLS stream, with a
+/// burst allowance of one second's worth of data.
+///
+/// @param bytes_per_sec Maximum bytes per second, or 0 to disable the limit
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_rate_limit(bytes_per_sec: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match RATE_LIMITER.lock() {
+            Ok(mut limiter) => {
+                *limiter = RateLimiter::new(bytes_per_sec);
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock rate limiter mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Report which optional capabilities this library was built with
+///
+/// Writes a JSON object such as
+/// `{"onion_client": false, "onion_service": false, "pt_bridges": false,
+///   "experimental_apis": false, "geoip": false}`.
+/// Arti features are chosen at compile time, so callers can use this to fail fast
+/// with a clear message instead of attempting an unsupported operation.
+///
+/// @param out_json Output buffer that will receive the null-terminated JSON
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_features(out_json: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let features = serde_json::json!({
+            "onion_client": cfg!(feature = "onion-client"),
+            "onion_service": cfg!(feature = "onion-service"),
+            "pt_bridges": cfg!(feature = "pt-bridges"),
+            "experimental_apis": cfg!(feature = "experimental-api"),
+            "geoip": cfg!(feature = "geoip"),
+        });
+
+        if !write_c_buffer(&features.to_string(), out_json, out_len) {
+            log_error!("Output buffer too small in arti_features");
+            return 0;
+        }
+        1
+    })
+}
+
+// Open a stream, send a request, and read the response until EOF or until the
+// response buffer is full. The stream is closed when this returns.
+fn request_once(circuit_id: &str, target: String, request: &[u8], response: &mut [u8]) -> Result<usize> {
+    let circuit = get_tor_client_by_circuit(circuit_id)
+        .ok_or_else(|| anyhow!("Circuit not found: {}", circuit_id))?;
+
+    block_on_runtime(async {
+        let mut stream = BufferedStream::new(record_connect(circuit.connect(target).await)?);
+
+        throttle(request.len()).await;
+        stream.write_all(request).await?;
+        stream.flush().await?;
+
+        let mut total = 0;
+        while total < response.len() {
+            let n = stream.read(&mut response[total..]).await?;
+            if n == 0 {
+                break;
+            }
+            throttle(n).await;
+            total += n;
+        }
+        Ok::<usize, anyhow::Error>(total)
+    })?
+}
+
+/// Connect to a target, send a request, and read the whole response in one call
+///
+/// Opens a stream on the circuit, writes and flushes the request, reads until the
+/// peer closes the connection, then closes the stream. If the response is larger
+/// than the output buffer, it is truncated to out_len bytes.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param request The request bytes to send
+/// @param request_len The length of the request
+/// @param out_buf The buffer to store the response
+/// @param out_len The maximum length of the response buffer
+/// @param bytes_written Output parameter that will receive the number of response bytes stored
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+#[no_mangle]
+pub extern "C" fn arti_request_once(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    request: *const c_char,
+    request_len: c_int,
+    out_buf: *mut c_char,
+    out_len: c_int,
+    bytes_written: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || target_host.is_null() || request.is_null() || request_len <= 0
+            || out_buf.is_null() || out_len <= 0 || bytes_written.is_null() {
+            log_error!("Invalid parameters in arti_request_once");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let host_str = match unsafe { CStr::from_ptr(target_host) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid host string");
+                return 0;
+            }
+        };
+
+        let target = match format_target(host_str, target_port) {
+            Some(t) => t,
+            None => {
+                log_error!("Invalid target address: {}", describe_invalid_target(host_str, target_port));
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let request_slice = unsafe {
+            std::slice::from_raw_parts(request as *const u8, request_len as usize)
+        };
+        let response_slice = unsafe {
+            std::slice::from_raw_parts_mut(out_buf as *mut u8, out_len as usize)
+        };
+
+        match request_once(circuit_id_str, target, request_slice, response_slice) {
+            Ok(n) => {
+                unsafe {
+                    *bytes_written = n as c_int;
+                }
+                1
+            },
+            Err(e) => {
+                log_error!("One-shot request failed: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+// Largest response head accepted by arti_http1_request
+const MAX_HTTP1_HEAD_LEN: usize = 64 * 1024;
+
+// Send a raw HTTP/1.1 request over a new stream on the circuit and read the response.
+// Returns the response head exactly as received, followed by the body with any
+// chunked transfer-encoding removed. The stream is closed when this returns.
+fn http1_request(circuit_id: &str, target: String, request: &[u8]) -> Result<Vec<u8>> {
+    let circuit = get_tor_client_by_circuit(circuit_id)
+        .ok_or_else(|| anyhow!("Circuit not found: {}", circuit_id))?;
+
+    // HEAD responses announce a body they never send
+    let is_head = request.split(|&b| b == b' ').next() == Some(&b"HEAD"[..]);
+
+    block_on_runtime(async {
+        let stream = record_connect(circuit.connect(target).await)?;
+        let mut stream = tokio::io::BufReader::new(BufferedStream::new(stream));
+
+        throttle(request.len()).await;
+        stream.get_mut().write_all(request).await?;
+        stream.get_mut().flush().await?;
+
+        let response = read_http1_response(&mut stream, is_head).await?;
+        throttle(response.len()).await;
+        Ok(response)
+    })?
+}
+
+// Read an HTTP/1.1 response for http1_request: the head exactly as received,
+// after any interim 1xx heads, followed by the body with any chunked
+// transfer-encoding removed. A response to HEAD has no body whatever it announces.
+async fn read_http1_response<R: AsyncBufRead + Unpin>(stream: &mut R, is_head: bool) -> Result<Vec<u8>> {
+    // Read heads until the final one, keeping any interim 1xx responses
+    let mut response = Vec::new();
+    let (status, head_start) = loop {
+        let head_start = response.len();
+        loop {
+            let n = stream.read_until(b'\n', &mut response).await?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed before the response head ended"));
+            }
+            if response.len() - head_start > MAX_HTTP1_HEAD_LEN {
+                return Err(anyhow!("Response head exceeds {} bytes", MAX_HTTP1_HEAD_LEN));
+            }
+            if response[head_start..].ends_with(b"\r\n\r\n") {
+                break;
+            }
+        }
+
+        let status = String::from_utf8_lossy(&response[head_start..])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Malformed HTTP status line"))?;
+        if !(100..200).contains(&status) || status == 101 {
+            break (status, head_start);
+        }
+    };
+
+    let head = String::from_utf8_lossy(&response[head_start..]).to_string();
+    let header = |name: &str| head.split("\r\n").skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .last();
+
+    if is_head || status < 200 || status == 204 || status == 304 {
+        return Ok(response);
+    }
+
+    let max_body = MAX_HTTP_BODY.load(Ordering::Relaxed);
+    let body_start = response.len();
+    let check_size = |body_len: usize| {
+        if max_body > 0 && body_len as u64 > max_body {
+            Err(anyhow::Error::from(BodyTooLarge(max_body)))
+        } else {
+            Ok(())
+        }
+    };
+
+    let chunked = header("transfer-encoding")
+        .map(|te| te.to_ascii_lowercase().trim_end().ends_with("chunked"))
+        .unwrap_or(false);
+    if chunked {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            stream.read_until(b'\n', &mut line).await?;
+            let size_str = std::str::from_utf8(&line)?;
+            let size_str = size_str.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size_str, 16)
+                .map_err(|_| anyhow!("Malformed chunk size {:?}", size_str))?;
+            if size == 0 {
+                break;
+            }
+
+            check_size(response.len() - body_start + size)?;
+
+            // Read through take() rather than allocating the announced size up front
+            let read = (&mut *stream).take(size as u64).read_to_end(&mut response).await?;
+            if read < size {
+                return Err(anyhow!("Connection closed inside a chunk"));
+            }
+
+            let mut crlf = [0u8; 2];
+            stream.read_exact(&mut crlf).await?;
+            if &crlf != b"\r\n" {
+                return Err(anyhow!("Missing CRLF after chunk"));
+            }
+        }
+
+        // Skip the trailer section
+        loop {
+            line.clear();
+            if stream.read_until(b'\n', &mut line).await? == 0 || line == b"\r\n" {
+                break;
+            }
+        }
+    } else if let Some(length) = header("content-length") {
+        let length = length.parse::<usize>()
+            .map_err(|_| anyhow!("Malformed Content-Length {:?}", length))?;
+        check_size(length)?;
+        let read = (&mut *stream).take(length as u64).read_to_end(&mut response).await?;
+        if read < length {
+            return Err(anyhow!("Connection closed after {} of {} body bytes", read, length));
+        }
+    } else {
+        let mut chunk = [0u8; 8192];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&chunk[..n]);
+            check_size(response.len() - body_start)?;
+        }
+    }
+
+    Ok(response)
+}
+
+// An HTTP request whose body is streamed in chunks by the caller. The request runs
+// on the global runtime and reads body chunks from a bounded channel, so writers
+// block once the channel is full until the request has consumed earlier chunks.
+struct HttpUpload {
+    sender: tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>,
+    task: tokio::task::JoinHandle<Result<String>>,
+}
+
+// Number of body chunks that may be queued before arti_http_upload_write blocks
+const HTTP_UPLOAD_QUEUE_DEPTH: usize = 4;
+
+/// Start an HTTP request whose body is streamed with arti_http_upload_write
+///
+/// The request is sent as soon as it starts; body chunks are passed on as they are
+/// written, so large bodies never need to be held in memory at once.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (POST, PUT or PATCH are typical)
+/// @param headers A JSON object of request headers
+/// @param content_length The total body length, or -1 to use chunked transfer encoding
+/// @return A non-zero upload handle on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_http_upload_begin(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    content_length: i64,
+) -> u64 {
+    ffi_boundary(0, || {
+        if circuit_id.is_null() || url.is_null() || method.is_null() || headers.is_null() {
+            log_error!("Invalid parameters in arti_http_upload_begin");
+            return 0;
+        }
+
+        let circuit_id_str = unsafe { CStr::from_ptr(circuit_id).to_str().unwrap_or("") };
+        let url_str = unsafe { CStr::from_ptr(url).to_str().unwrap_or("") };
+        let method_str = unsafe { CStr::from_ptr(method).to_str().unwrap_or("") };
+        let headers_str = unsafe { CStr::from_ptr(headers).to_str().unwrap_or("{}") };
+
+        let options = HttpOptions::default();
+        let mut request_builder = match build_http_request(circuit_id_str, url_str, method_str, headers_str, &options) {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to build HTTP upload request: {:?}", e);
+                return 0;
+            }
+        };
+
+        if content_length >= 0 {
+            request_builder = request_builder.header(reqwest::header::CONTENT_LENGTH, content_length);
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(HTTP_UPLOAD_QUEUE_DEPTH);
+        let body_stream = futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+        let request_builder = request_builder.body(reqwest::Body::wrap_stream(body_stream));
+
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+
+        let circuit = circuit_id_str.to_string();
+        let task = handle.spawn(async move {
+            let _permit = http_permit().await;
+            let response = send_http_request(&circuit, request_builder).await?;
+            let response = read_http_response(response, &options).await?;
+            Ok(serde_json::to_string(&response)?)
+        });
+
+        let upload_handle = NEXT_UPLOAD_HANDLE.fetch_add(1, Ordering::Relaxed);
+        match HTTP_UPLOADS.lock() {
+            Ok(mut uploads) => {
+                uploads.insert(upload_handle, HttpUpload { sender, task });
+                upload_handle
+            },
+            Err(_) => {
+                log_error!("Failed to lock HTTP uploads mutex");
+                task.abort();
+                0
+            }
+        }
+    })
+}
+
+/// Write a chunk of the request body for an HTTP upload
+///
+/// Blocks while earlier chunks are still waiting to be sent, so the caller is
+/// paced by the speed of the connection.
+///
+/// @param handle The upload handle returned by arti_http_upload_begin
+/// @param data The body data to send
+/// @param data_len The length of the data
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_http_upload_write(handle: u64, data: *const c_char, data_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if data.is_null() || data_len <= 0 {
+            log_error!("Invalid parameters in arti_http_upload_write");
+            return 0;
+        }
+
+        // Clone the sender so the uploads map isn't locked while we wait for capacity
+        let sender = match HTTP_UPLOADS.lock() {
+            Ok(uploads) => match uploads.get(&handle) {
+                Some(upload) => upload.sender.clone(),
+                None => {
+                    log_error!("HTTP upload not found: {}", handle);
+                    return 0;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock HTTP uploads mutex");
+                return 0;
+            }
+        };
+
+        let chunk = unsafe { std::slice::from_raw_parts(data as *const u8, data_len as usize) }.to_vec();
+        match sender.blocking_send(Ok(chunk)) {
+            Ok(_) => 1,
+            Err(_) => {
+                log_error!("HTTP upload {} is no longer accepting data", handle);
+                0
+            }
+        }
+    })
+}
+
+/// Finish an HTTP upload and wait for the response
+///
+/// Ends the request body, waits for the server's response, and releases the handle.
+/// The response is written as the same JSON object returned by arti_http_request.
+///
+/// @param handle The upload handle returned by arti_http_upload_begin
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @return 1 on success, 0 on failure, ERR_BODY_TOO_LARGE (-10) if the response body exceeds
+///         the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_upload_finish(handle: u64, response: *mut c_char, response_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let upload = match HTTP_UPLOADS.lock() {
+            Ok(mut uploads) => uploads.remove(&handle),
+            Err(_) => {
+                log_error!("Failed to lock HTTP uploads mutex");
+                return 0;
+            }
+        };
+
+        let HttpUpload { sender, task } = match upload {
+            Some(u) => u,
+            None => {
+                log_error!("HTTP upload not found: {}", handle);
+                return 0;
+            }
+        };
+
+        // Dropping the sender ends the body stream
+        drop(sender);
+
+        let result = match block_on_runtime(task) {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => Err(anyhow!("HTTP upload task failed: {}", e)),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(response_str) => {
+                if write_c_buffer(&response_str, response, response_len) {
+                    1
+                } else {
+                    log_error!("Response buffer too small in arti_http_upload_finish");
+                    0
+                }
+            },
+            Err(e) => {
+                log_error!("HTTP upload failed: {:?}", e);
+                http_error_code(&e)
+            }
+        }
+    })
+}
+
+// An HTTP response whose body is read in pieces with arti_http_stream_read
+struct HttpStream {
+    response: reqwest::Response,
+    // Part of the last chunk that didn't fit in the caller's buffer
+    pending: Vec<u8>,
+    // Keeps the request counted against arti_set_http_max_concurrency until closed
+    _permit: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+// Entry in HTTP_STREAMS. Removing it drops the response, which closes the
+// connection and its Tor stream, once any read in progress has been woken
+// through `closed` and given up its reference.
+struct HttpStreamHandle {
+    body: StdArc<tokio::sync::Mutex<HttpStream>>,
+    closed: StdArc<tokio::sync::Notify>,
+    // Status line and headers as JSON for arti_http_stream_headers, captured when
+    // the headers arrive so they can be read while a body read holds `body`
+    head: String,
+}
+
+// An HTTP stream was closed while a read was waiting on it
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream was closed")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// Read the next piece of an HTTP response body into buf, returning 0 at the end.
+// Fails with ERR_CANCELLED if the stream is closed while waiting for data.
+fn read_http_stream(stream_id: &str, buf: &mut [u8]) -> Result<usize> {
+    let (body, closed) = match lock(&HTTP_STREAMS, "HTTP streams")?.get(stream_id) {
+        Some(handle) => (StdArc::clone(&handle.body), StdArc::clone(&handle.closed)),
+        None => return Err(anyhow!("HTTP stream not found: {}", stream_id)),
+    };
+
+    runtime_handle()?.block_on(async {
+        let mut stream = body.lock().await;
+        if stream.pending.is_empty() {
+            let chunk = tokio::select! {
+                chunk = stream.response.chunk() => chunk?,
+                _ = closed.notified() => return Err(Cancelled.into()),
+            };
+            match chunk {
+                Some(chunk) => stream.pending = chunk.to_vec(),
+                None => return Ok(0),
+            }
+        }
+
+        let n = stream.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&stream.pending[..n]);
+        stream.pending.drain(..n);
+        Ok(n)
+    })
+}
+
+/// Start an HTTP or HTTPS request through Tor and read its response body in pieces
+///
+/// Returns once the response headers have arrived, without waiting for the body.
+/// Read the body with arti_http_stream_read and release the response with
+/// arti_http_stream_close, which may be called at any time to abandon the download.
+/// Response bodies read this way are not subject to arti_set_max_http_body.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param stream_id Output buffer that will receive the null-terminated HTTP stream ID
+/// @param stream_id_len Length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+///         (no request is sent), ERR_TIMEOUT (-8) if the request timed out, ERR_INTERNAL (-5)
+///         if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_http_stream_open(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || url.is_null() || method.is_null() || headers.is_null() || body.is_null()
+            || stream_id.is_null() || stream_id_len <= 0 {
+            log_error!("Invalid parameters in arti_http_stream_open");
+            return 0;
+        }
+
+        let circuit_id_str = unsafe { CStr::from_ptr(circuit_id).to_str().unwrap_or("") };
+        let url_str = unsafe { CStr::from_ptr(url).to_str().unwrap_or("") };
+        let method_str = unsafe { CStr::from_ptr(method).to_str().unwrap_or("") };
+        let headers_str = unsafe { CStr::from_ptr(headers).to_str().unwrap_or("{}") };
+        let body_str = unsafe { CStr::from_ptr(body).to_str().unwrap_or("") };
+
+        // Check the ID fits before sending, but only hand it out once the stream is registered
+        let http_stream_id = format!("{}-http-{}", circuit_id_str, NEXT_HTTP_STREAM.fetch_add(1, Ordering::Relaxed));
+        if http_stream_id.len() >= stream_id_len as usize {
+            log_error!("Stream ID buffer too small: {} bytes needed", http_stream_id.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        let mut request_builder = match build_http_request(circuit_id_str, url_str, method_str, headers_str, &HttpOptions::default()) {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("Failed to build HTTP request: {:?}", e);
+                return error_code(&e, 0);
+            }
+        };
+        if !body_str.is_empty() {
+            request_builder = request_builder.body(body_str.to_string());
+        }
+
+        // Run on the shared runtime, which also drives the body reads
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+        let sent = handle.block_on(async {
+            let permit = http_permit().await;
+            send_http_request(circuit_id_str, request_builder).await.map(|response| (response, permit))
+        });
+        let (response, permit) = match sent {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("HTTP request failed: {}", e);
+                return http_error_code(&e);
+            }
+        };
+
+        let head = serde_json::json!({
+            "status": response.status().as_u16(),
+            "url": response.url().to_string(),
+            "headers": response.headers().iter()
+                .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or("")))
+                .collect::<Vec<_>>(),
+        }).to_string();
+
+        let http_stream = HttpStreamHandle {
+            head,
+            body: StdArc::new(tokio::sync::Mutex::new(HttpStream {
+                response,
+                pending: Vec::new(),
+                _permit: permit,
+            })),
+            closed: StdArc::new(tokio::sync::Notify::new()),
+        };
+        match HTTP_STREAMS.lock() {
+            Ok(mut streams) => {
+                streams.insert(http_stream_id.clone(), http_stream);
+            },
+            Err(_) => {
+                log_error!("Failed to lock HTTP streams mutex");
+                return ERR_INTERNAL;
+            }
+        }
+
+        if !write_c_buffer(&http_stream_id, stream_id, stream_id_len) {
+            log_error!("Stream ID buffer too small: {} bytes needed", http_stream_id.len() + 1);
+            if let Ok(mut streams) = HTTP_STREAMS.lock() {
+                streams.remove(&http_stream_id);
+            }
+            return ERR_BUFFER_TOO_SMALL;
+        }
+        1
+    })
+}
+
+/// Read the next piece of a response body opened with arti_http_stream_open
+///
+/// Blocks until some of the body is available. A read of 0 bytes marks the end
+/// of the body.
+///
+/// @param stream_id The HTTP stream ID
+/// @param buffer The buffer to store the data
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_read Output parameter that will receive the number of bytes read
+/// @return 1 on success, 0 on failure, ERR_CANCELLED (-6) if the stream was closed
+///         with arti_http_stream_close while reading
+#[no_mangle]
+pub extern "C" fn arti_http_stream_read(
+    stream_id: *const c_char,
+    buffer: *mut c_char,
+    buffer_len: c_int,
+    bytes_read: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || buffer.is_null() || buffer_len <= 0 || bytes_read.is_null() {
+            log_error!("Invalid parameters in arti_http_stream_read");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+        };
+        match read_http_stream(stream_id_str, buf) {
+            Ok(n) => {
+                unsafe { *bytes_read = n as c_int; }
+                1
+            },
+            Err(e) if e.is::<Cancelled>() => {
+                log_debug!("HTTP stream closed while reading: {}", stream_id_str);
+                ERR_CANCELLED
+            },
+            Err(e) => {
+                log_error!("Failed to read HTTP stream: {:?}", e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Close an HTTP stream opened with arti_http_stream_open
+///
+/// The rest of the body is abandoned and the connection and its Tor stream are
+/// released straight away. A read blocked on the stream in another thread
+/// returns ERR_CANCELLED (-6).
+///
+/// @param stream_id The HTTP stream ID
+/// @return 1 on success, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if the stream is already
+///         closed or unknown
+#[no_mangle]
+pub extern "C" fn arti_http_stream_close(stream_id: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_http_stream_close");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let removed = match HTTP_STREAMS.lock() {
+            Ok(mut streams) => streams.remove(stream_id_str),
+            Err(_) => {
+                log_error!("Failed to lock HTTP streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        match removed {
+            Some(http_stream) => {
+                // Wake a blocked read so it lets go of the response
+                http_stream.closed.notify_one();
+                log_debug!("HTTP stream closed: {}", stream_id_str);
+                1
+            },
+            None => {
+                log_debug!("HTTP stream already closed or unknown: {}", stream_id_str);
+                ERR_STREAM_NOT_FOUND
+            }
+        }
+    })
+}
+
+/// Report whether datagram (UDP) traffic can be carried over Tor
+///
+/// Tor only carries TCP streams, so this currently always returns 0. For DNS,
+/// use arti_resolve, which performs the lookup at the exit relay.
+///
+/// @return 1 if UDP is supported, 0 if not
+#[no_mangle]
+pub extern "C" fn arti_supports_udp() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        0
+    })
+}
+
+/// Resolve a hostname through Tor
+///
+/// The lookup is performed by an exit relay, so it does not leak to the local
+/// resolver. The addresses are written as a JSON array of strings, e.g.
+/// `["93.184.216.34", "2606:2800:220:1::"]`. At most 32 addresses are returned.
+/// The lookup gives up after the timeout set with arti_set_resolve_timeout.
+///
+/// @param circuit_id The circuit ID to use
+/// @param hostname The hostname to resolve
+/// @param out_json Output buffer that will receive the null-terminated JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if the lookup timed out
+#[no_mangle]
+pub extern "C" fn arti_resolve(
+    circuit_id: *const c_char,
+    hostname: *const c_char,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || hostname.is_null() || out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_resolve");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let hostname_str = match unsafe { CStr::from_ptr(hostname) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid hostname string");
+                return 0;
+            }
+        };
+
+        let circuit = match get_tor_client_by_circuit(circuit_id_str) {
+            Some(c) => c,
+            None => {
+                log_error!("Circuit not found: {}", circuit_id_str);
+                return 0;
+            }
+        };
+
+        let timeout = Duration::from_millis(RESOLVE_TIMEOUT_MS.load(Ordering::Relaxed));
+        let addresses = match block_on_runtime(tokio::time::timeout(timeout, circuit.resolve(hostname_str))) {
+            Ok(Ok(Ok(a))) => a,
+            Ok(Ok(Err(e))) => {
+                log_error!("Failed to resolve {}: {:?}", hostname_str, e);
+                return 0;
+            },
+            Ok(Err(_)) => {
+                log_error!("Timed out resolving {} after {:?}", hostname_str, timeout);
+                return ERR_TIMEOUT;
+            },
+            Err(e) => {
+                log_error!("Failed to run resolve: {:?}", e);
+                return 0;
+            }
+        };
+
+        if addresses.len() > MAX_RESOLVED_ADDRESSES {
+            log_debug!("Keeping {} of {} addresses for {}", MAX_RESOLVED_ADDRESSES, addresses.len(), hostname_str);
+        }
+        let addresses: Vec<String> = addresses.iter()
+            .take(MAX_RESOLVED_ADDRESSES)
+            .map(|ip| ip.to_string())
+            .collect();
+        let json = match serde_json::to_string(&addresses) {
+            Ok(j) => j,
+            Err(e) => {
+                log_error!("Failed to serialize addresses: {:?}", e);
+                return 0;
+            }
+        };
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Output buffer too small in arti_resolve");
+            return 0;
+        }
+        1
+    })
+}
+
+/// Set how long arti_resolve waits for an answer
+///
+/// @param timeout_ms The timeout in milliseconds; the default is 30 seconds
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) if timeout_ms is 0
+#[no_mangle]
+pub extern "C" fn arti_set_resolve_timeout(timeout_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if timeout_ms == 0 {
+            log_error!("Resolve timeout must be positive");
+            return ERR_INVALID_PARAMS;
+        }
+        RESOLVE_TIMEOUT_MS.store(timeout_ms, Ordering::Relaxed);
+        1
+    })
+}
+
+/// Set the directories Arti uses for its persistent state and directory cache
+///
+/// Arti holds a lock on its state directory while running, so two processes using
+/// the same directory conflict and initialization returns ERR_STATE_LOCKED (-7).
+/// Give each process its own state directory to run several side by side.
+/// Must be called before arti_init.
+///
+/// @param state_dir A null-terminated path for the state directory, or NULL for the default
+/// @param cache_dir A null-terminated path for the cache directory, or NULL for the default
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_storage_dirs(state_dir: *const c_char, cache_dir: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if is_connected().unwrap_or(false) {
+            log_error!("Storage directories must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        let to_option = |ptr: *const c_char| -> std::result::Result<Option<String>, ()> {
+            if ptr.is_null() {
+                return Ok(None);
+            }
+            match unsafe { CStr::from_ptr(ptr) }.to_str() {
+                Ok(s) => Ok(Some(s.to_string())),
+                Err(_) => Err(()),
+            }
+        };
+
+        let (state_dir, cache_dir) = match (to_option(state_dir), to_option(cache_dir)) {
+            (Ok(state), Ok(cache)) => (state, cache),
+            _ => {
+                log_error!("Invalid directory string in arti_set_storage_dirs");
+                return 0;
+            }
+        };
+
+        match CONFIG_OVERRIDES.lock() {
+            Ok(mut overrides) => {
+                overrides.state_dir = state_dir;
+                overrides.cache_dir = cache_dir;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock config overrides mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Set the circuit build timeout
+///
+/// This maps to Arti's circuit request timeout (`circuit_timing.request_timeout`):
+/// the total time spent building and retrying circuits for one request, so slow
+/// guards fail fast instead of stalling for tens of seconds. Arti does not let the
+/// timeout of a single build attempt be set; that one adapts to the observed
+/// network speed and still applies within this limit.
+/// Must be called before arti_init.
+///
+/// @param timeout_ms The timeout in milliseconds, or 0 to restore Arti's default
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_circuit_build_timeout_ms(timeout_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if is_connected().unwrap_or(false) {
+            log_error!("Circuit build timeout must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        match CONFIG_OVERRIDES.lock() {
+            Ok(mut overrides) => {
+                overrides.circuit_request_timeout = match timeout_ms {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                };
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock config overrides mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Set the target used by arti_check_connectivity
+///
+/// Defaults to www.torproject.org:443.
+///
+/// @param host The target hostname
+/// @param port The target port
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed
+#[no_mangle]
+pub extern "C" fn arti_set_connectivity_target(host: *const c_char, port: i32) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if host.is_null() {
+            log_error!("Invalid parameters in arti_set_connectivity_target");
+            return 0;
+        }
+
+        let host_str = match unsafe { CStr::from_ptr(host) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid host string");
+                return 0;
+            }
+        };
+
+        let target = match format_target(host_str, port) {
+            Some(t) => t,
+            None => {
+                log_error!("Invalid connectivity target: {}", describe_invalid_target(host_str, port));
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        match CONNECTIVITY_TARGET.lock() {
+            Ok(mut t) => {
+                *t = target;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock connectivity target mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Check that the Tor network is actually reachable
+///
+/// Opens a short-lived stream to the connectivity target over a fresh circuit and
+/// closes it again. Unlike arti_is_connected, this verifies end-to-end reachability.
+///
+/// @param timeout_ms Maximum time to wait for the stream, in milliseconds
+/// @return 1 if the target was reached, 0 on failure, ERR_NOT_INITIALIZED (-1) if the
+///         client is not initialized, ERR_TIMEOUT (-8) if the timeout expired
+#[no_mangle]
+pub extern "C" fn arti_check_connectivity(timeout_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let client = match CLIENT.lock() {
+            Ok(c) => match &*c {
+                Some(client) => client.isolated_client(),
+                None => {
+                    log_error!("Tor client not initialized");
+                    return ERR_NOT_INITIALIZED;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock client mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let target = match CONNECTIVITY_TARGET.lock() {
+            Ok(t) => t.clone(),
+            Err(_) => {
+                log_error!("Failed to lock connectivity target mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        log_debug!("Checking connectivity to {}", target);
+        let check_result = block_on_runtime(tokio::time::timeout(
+            Duration::from_millis(timeout_ms),
+            client.connect(target.as_str()),
+        ));
+
+        // The stream, if any, is dropped here, closing it
+        match check_result {
+            Ok(Ok(Ok(_))) => 1,
+            Ok(Ok(Err(e))) => {
+                log_warn!("Connectivity check to {} failed: {:?}", target, e);
+                0
+            },
+            Ok(Err(_)) => {
+                log_warn!("Connectivity check to {} timed out", target);
+                ERR_TIMEOUT
+            },
+            Err(e) => {
+                log_error!("Failed to run connectivity check: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Set which IP address family exit connections use
+///
+/// Applies to every stream opened by the client, including those on circuits and
+/// isolated clients created later. Arti chooses guard and relay addresses itself;
+/// this only controls how exits connect to targets.
+/// Must be called before arti_init.
+///
+/// @param preference 0 = auto (prefer IPv4), 1 = IPv4 only, 2 = IPv6 only, 3 = prefer IPv6
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown preference
+#[no_mangle]
+pub extern "C" fn arti_set_address_family_preference(preference: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if !(ADDRESS_FAMILY_AUTO..=ADDRESS_FAMILY_PREFER_IPV6).contains(&preference) {
+            log_error!("Invalid address family preference: {}", preference);
+            return ERR_INVALID_PARAMS;
+        }
+
+        if is_connected().unwrap_or(false) {
+            log_error!("Address family preference must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        match CONFIG_OVERRIDES.lock() {
+            Ok(mut overrides) => {
+                overrides.address_family = preference;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock config overrides mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Get the validity window of the client's current network consensus
+///
+/// Writes a JSON object with "valid_after", "fresh_until" and "valid_until" as Unix
+/// timestamps in seconds, and "live" telling whether the consensus is currently valid.
+/// Requires a build with the `experimental-api` feature.
+///
+/// @param out_json Output buffer that will receive the null-terminated JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not
+///         initialized or has no usable directory yet, ERR_NOT_SUPPORTED (-11) if built without
+///         the experimental-api feature
+#[no_mangle]
+pub extern "C" fn arti_get_directory_info(out_json: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_get_directory_info");
+            return 0;
+        }
+
+        #[cfg(feature = "experimental-api")]
+        {
+            let netdir = match CLIENT.lock() {
+                Ok(client) => match &*client {
+                    Some(c) => c.dirmgr().timely_netdir(),
+                    None => {
+                        log_error!("Tor client not initialized");
+                        return ERR_NOT_INITIALIZED;
+                    }
+                },
+                Err(_) => {
+                    log_error!("Failed to lock client mutex");
+                    return 0;
+                }
+            };
+
+            let netdir = match netdir {
+                Ok(n) => n,
+                Err(e) => {
+                    log_error!("Directory not ready: {:?}", e);
+                    return ERR_NOT_INITIALIZED;
+                }
+            };
+
+            let unix_secs = |t: std::time::SystemTime| {
+                t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+            };
+            let lifetime = netdir.lifetime();
+            let now = std::time::SystemTime::now();
+            let info = serde_json::json!({
+                "live": lifetime.valid_after() <= now && now < lifetime.valid_until(),
+                "valid_after": unix_secs(lifetime.valid_after()),
+                "fresh_until": unix_secs(lifetime.fresh_until()),
+                "valid_until": unix_secs(lifetime.valid_until()),
+            });
+
+            if !write_c_buffer(&info.to_string(), out_json, out_len) {
+                log_error!("Output buffer too small in arti_get_directory_info");
+                return 0;
+            }
+            1
+        }
+
+        #[cfg(not(feature = "experimental-api"))]
+        {
+            log_error!("Cannot read directory info: built without the experimental-api feature");
+            ERR_NOT_SUPPORTED
+        }
+    })
+}
+
+/// Close streams that have been idle for too long
+///
+/// A background task closes any stream with no reads or writes for longer than the
+/// timeout, so streams the caller forgot to close don't leak. Applies to streams
+/// opened with the arti_connect_stream family.
+///
+/// @param timeout_ms The idle timeout in milliseconds, or 0 to disable reaping
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_idle_timeout_ms(timeout_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        IDLE_TIMEOUT_MS.store(timeout_ms, Ordering::Relaxed);
+        if timeout_ms == 0 {
+            return 1;
+        }
+
+        let mut reaper = match IDLE_REAPER.lock() {
+            Ok(r) => r,
+            Err(_) => {
+                log_error!("Failed to lock idle reaper mutex");
+                return 0;
+            }
+        };
+        if reaper.is_some() {
+            return 1;
+        }
+
+        match runtime_handle() {
+            Ok(handle) => {
+                *reaper = Some(handle.spawn(reap_idle_streams()));
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+// Periodically drop streams idle for longer than IDLE_TIMEOUT_MS. A stream in the
+// middle of a blocking read or write holds the streams lock, so a busy map is
+// simply skipped until the next check.
+async fn reap_idle_streams() {
+    loop {
+        let timeout_ms = IDLE_TIMEOUT_MS.load(Ordering::Relaxed);
+        if timeout_ms == 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_millis((timeout_ms / 2).clamp(50, 1000))).await;
+
+        if let Ok(mut streams) = STREAMS.try_lock() {
+            let timeout = Duration::from_millis(timeout_ms);
+            streams.retain(|stream_id, stream| {
+                let keep = stream.last_activity.elapsed() <= timeout;
+                if !keep {
+                    log_info!("Closing idle stream: {}", stream_id);
+                    untrack_stream(stream_id);
+                }
+                keep
+            });
+        }
+    }
+}
+
+/// Write as much data to a stream as it accepts right now
+///
+/// Unlike arti_write_stream, this makes a single write attempt and reports how many
+/// bytes were accepted, which may be fewer than data_len or 0 if the stream cannot
+/// take more data at the moment. Retry with the remainder for custom flow control.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @param bytes_written Output parameter that will receive the number of bytes accepted
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_write_stream_partial(
+    stream_id: *const c_char,
+    data: *const c_char,
+    data_len: c_int,
+    bytes_written: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || data.is_null() || data_len <= 0 || bytes_written.is_null() {
+            log_error!("Invalid parameters in arti_write_stream_partial");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let data_slice = unsafe {
+            std::slice::from_raw_parts(data as *const u8, data_len as usize)
+        };
+
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        // Poll the write once; a stream that can't take data yet counts as 0 bytes written
+        let write_result = block_on_runtime(async {
+            let n = match futures::FutureExt::now_or_never(stream.write(data_slice)) {
+                Some(result) => result?,
+                None => 0,
+            };
+            throttle(n).await;
+            Ok::<usize, std::io::Error>(n)
+        });
+
+        match write_result {
+            Ok(Ok(n)) => {
+                unsafe {
+                    *bytes_written = n as c_int;
+                }
+                1
+            },
+            Ok(Err(e)) => {
+                log_error!("Failed to write to stream: {:?}", e);
+                0
+            },
+            Err(e) => {
+                log_error!("Failed to run write: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Start a SOCKS5 proxy backed by the initialized Tor client
+///
+/// Lets external tools such as curl or browsers use the same bootstrapped client.
+/// The proxy listens on 127.0.0.1 only and accepts SOCKS5 CONNECT requests. As with
+/// Tor's SocksPort, a username and password are accepted but not checked: connections
+/// with different credentials are isolated onto different circuits, while those with
+/// the same credentials may share one. Only one proxy can run at a time.
+///
+/// @param port The port to listen on, or 0 to pick a free port
+/// @return The port the proxy is listening on, 0 on failure, ERR_NOT_INITIALIZED (-1) if
+///         the client is not initialized, ERR_INVALID_PARAMS (-4) for an invalid port
+#[no_mangle]
+pub extern "C" fn arti_start_socks_proxy(port: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if !(0..=65535).contains(&port) {
+            log_error!("Invalid SOCKS proxy port: {}", port);
+            return ERR_INVALID_PARAMS;
+        }
+
+        if !is_connected().unwrap_or(false) {
+            log_error!("Tor client not initialized");
+            return ERR_NOT_INITIALIZED;
+        }
+
+        let mut proxy = match SOCKS_PROXY.lock() {
+            Ok(p) => p,
+            Err(_) => {
+                log_error!("Failed to lock SOCKS proxy mutex");
+                return 0;
+            }
+        };
+        if let Some(running) = &*proxy {
+            log_error!("SOCKS proxy already running on port {}", running.port);
+            return 0;
+        }
+
+        match start_socks_proxy(port as u16, None, None, None, SocksAuth::IsolateByCredentials) {
+            Ok(started) => {
+                let bound_port = started.port;
+                log_info!("SOCKS proxy listening on 127.0.0.1:{}", bound_port);
+                *proxy = Some(started);
+                bound_port as c_int
+            },
+            Err(e) => {
+                log_error!("Failed to start SOCKS proxy: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Stop the SOCKS5 proxy started with arti_start_socks_proxy
+///
+/// Connections already relayed through the proxy are not interrupted.
+///
+/// @return 1 if a proxy was stopped, 0 if none was running
+#[no_mangle]
+pub extern "C" fn arti_stop_socks_proxy() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match SOCKS_PROXY.lock() {
+            Ok(mut proxy) => match proxy.take() {
+                Some(running) => {
+                    running.task.abort();
+                    log_info!("SOCKS proxy on port {} stopped", running.port);
+                    1
+                },
+                None => 0,
+            },
+            Err(_) => {
+                log_error!("Failed to lock SOCKS proxy mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Put the Tor client to sleep or wake it up
+///
+/// In soft dormant mode the client stops building circuits preemptively and
+/// reduces background activity, but streams can still be opened on demand.
+/// Call with normal mode to resume. The mode applies to every circuit.
+///
+/// @param mode 0 = normal, 1 = soft dormant, 2 = hard dormant (not supported by arti-client 0.10)
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not
+///         initialized, ERR_INVALID_PARAMS (-4) for an unknown or unsupported mode
+#[no_mangle]
+pub extern "C" fn arti_set_dormant(mode: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let dormant_mode = match mode {
+            DORMANT_NORMAL => arti_client::DormantMode::Normal,
+            DORMANT_SOFT => arti_client::DormantMode::Soft,
+            DORMANT_HARD => {
+                log_error!("Hard dormant mode is not supported by this version of Arti");
+                return ERR_INVALID_PARAMS;
+            },
+            _ => {
+                log_error!("Invalid dormant mode: {}", mode);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        match CLIENT.lock() {
+            Ok(client) => match &*client {
+                // Circuits are clones of this client and share its dormant state
+                Some(c) => {
+                    c.set_dormant(dormant_mode);
+                    log_debug!("Dormant mode set to {}", mode);
+                    1
+                },
+                None => {
+                    log_error!("Tor client not initialized");
+                    ERR_NOT_INITIALIZED
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock client mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Write data to a stream, waiting for the peer to make room, up to a timeout
+///
+/// Tor flow control stops accepting data while the receiver isn't reading. This
+/// waits for the data to be accepted and flushed, reporting how much got through
+/// if the timeout expires first, so callers don't buffer without bound.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @param timeout_ms Maximum time to wait, in milliseconds
+/// @param bytes_written Output parameter that will receive the number of bytes accepted
+/// @return 1 if all data was written, 0 on failure, ERR_TIMEOUT (-8) if the timeout expired first
+#[no_mangle]
+pub extern "C" fn arti_write_stream_blocking(
+    stream_id: *const c_char,
+    data: *const c_char,
+    data_len: c_int,
+    timeout_ms: u64,
+    bytes_written: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || data.is_null() || data_len <= 0 || bytes_written.is_null() {
+            log_error!("Invalid parameters in arti_write_stream_blocking");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let data_slice = unsafe {
+            std::slice::from_raw_parts(data as *const u8, data_len as usize)
+        };
+
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return 0;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        // Count progress outside the timed future so a timeout still reports it
+        let mut written = 0usize;
+        let write_result = block_on_runtime(tokio::time::timeout(Duration::from_millis(timeout_ms), async {
+            while written < data_slice.len() {
+                let n = stream.write(&data_slice[written..]).await?;
+                if n == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::WriteZero));
+                }
+                throttle(n).await;
+                written += n;
+            }
+            stream.flush().await
+        }));
+
+        unsafe {
+            *bytes_written = written as c_int;
+        }
+
+        match write_result {
+            Ok(Ok(Ok(()))) => 1,
+            Ok(Ok(Err(e))) => {
+                log_error!("Failed to write to stream: {:?}", e);
+                0
+            },
+            Ok(Err(_)) => {
+                log_debug!("Timed out writing to stream {} after {} of {} bytes", stream_id_str, written, data_len);
+                ERR_TIMEOUT
+            },
+            Err(e) => {
+                log_error!("Failed to run write: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Create an additional, independent Tor client
+///
+/// Each client has its own configuration, guards and circuits, so an application
+/// can run e.g. a bridged and a direct client side by side. Clients must not share
+/// a state directory. Create circuits on a client with arti_client_create_circuit;
+/// the stream functions then work on those circuits as usual. Handle 0 always
+/// refers to the global client set up by arti_init.
+///
+/// @param config_path Path to an Arti TOML configuration file, or NULL for the settings
+///                    made with the arti_set_* configuration functions
+/// @return A non-zero client handle on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_client_new(config_path: *const c_char) -> u64 {
+    ffi_boundary(0, || {
+        let config = if config_path.is_null() {
+            build_client_config()
+        } else {
+            match unsafe { CStr::from_ptr(config_path) }.to_str() {
+                Ok(path) => load_client_config(path),
+                Err(_) => {
+                    log_error!("Failed to convert config path to string");
+                    return 0;
+                }
+            }
+        };
+
+        let config = match config {
+            Ok(c) => c,
+            Err(e) => {
+                log_error!("Invalid client configuration: {:?}", e);
+                return 0;
+            }
+        };
+
+        log_info!("Bootstrapping additional Tor client...");
+        let mut client = match block_on_runtime(TorClient::create_bootstrapped(config)) {
+            Ok(Ok(c)) => c,
+            Ok(Err(e)) => {
+                log_error!("Failed to bootstrap Tor client: {:?}", e);
+                return 0;
+            },
+            Err(e) => {
+                log_error!("Failed to run bootstrap: {:?}", e);
+                return 0;
+            }
+        };
+        client.set_stream_prefs(default_stream_prefs());
+
+        let handle = NEXT_CLIENT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        match CLIENTS.lock() {
+            Ok(mut clients) => {
+                clients.insert(handle, client);
+                log_info!("Tor client {} bootstrapped successfully", handle);
+                handle
+            },
+            Err(_) => {
+                log_error!("Failed to lock clients mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Creates a new Tor circuit with the given ID on a specific client
+///
+/// Circuit IDs share one namespace across all clients.
+///
+/// @param client_handle A handle from arti_client_new, or 0 for the global client
+/// @param circuit_id A null-terminated string representing a unique circuit ID
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client does not exist,
+///         ERR_CIRCUIT_LIMIT (-12) if the limit set with arti_set_max_circuits is reached
+#[no_mangle]
+pub extern "C" fn arti_client_create_circuit(client_handle: u64, circuit_id: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if client_handle == 0 {
+            return arti_create_circuit(circuit_id);
+        }
+
+        if circuit_id.is_null() {
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let circuit = match CLIENTS.lock() {
+            Ok(clients) => match clients.get(&client_handle) {
+                Some(c) => Arc::new(c.isolated_client()),
+                None => {
+                    log_error!("Unknown client handle: {}", client_handle);
+                    return ERR_NOT_INITIALIZED;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock clients mutex");
+                return 0;
+            }
+        };
+
+        match CIRCUITS.lock() {
+            Ok(mut circuits) => {
+                if let Err(e) = check_circuit_limit(&circuits, [&circuit_id_str]) {
+                    log_error!("Cannot create circuit {}: {}", circuit_id_str, e);
+                    return ERR_CIRCUIT_LIMIT;
+                }
+                circuits.insert(circuit_id_str.clone(), circuit);
+                drop(circuits);
+                emit_event(EVENT_CIRCUIT_CREATED, &circuit_id_str);
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock circuits mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Release a client created with arti_client_new
+///
+/// Circuits created on the client keep working until they are destroyed.
+///
+/// @param client_handle A handle from arti_client_new
+/// @return 1 on success, 0 if the handle is unknown
+#[no_mangle]
+pub extern "C" fn arti_client_free(client_handle: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match CLIENTS.lock() {
+            Ok(mut clients) => match clients.remove(&client_handle) {
+                Some(_) => 1,
+                None => {
+                    log_error!("Unknown client handle: {}", client_handle);
+                    0
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock clients mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Load country databases from files
+///
+/// The files use Tor's legacy geoip format (the `geoip` and `geoip6` files shipped
+/// with Tor). They are parsed and validated immediately, and used by
+/// arti_geoip_lookup. The Arti version this library is built on can't take them for
+/// exit selection, so arti_connect_stream_country returns ERR_NOT_SUPPORTED once they
+/// are loaded. Requires a build with the `geoip` feature.
+///
+/// @param ipv4_path Path to the IPv4 geoip file
+/// @param ipv6_path Path to the IPv6 geoip file
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if a file is missing or malformed,
+///         ERR_NOT_SUPPORTED (-11) if built without the geoip feature
+#[no_mangle]
+pub extern "C" fn arti_set_geoip_files(ipv4_path: *const c_char, ipv6_path: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if ipv4_path.is_null() || ipv6_path.is_null() {
+            log_error!("Invalid parameters in arti_set_geoip_files");
+            return 0;
+        }
+
+        let (v4_path, v6_path) = match (unsafe { CStr::from_ptr(ipv4_path) }.to_str(), unsafe { CStr::from_ptr(ipv6_path) }.to_str()) {
+            (Ok(v4), Ok(v6)) => (v4, v6),
+            _ => {
+                log_error!("Invalid path string in arti_set_geoip_files");
+                return 0;
+            }
+        };
+
+        #[cfg(feature = "geoip")]
+        {
+            let (v4_data, v6_data) = match (std::fs::read_to_string(v4_path), std::fs::read_to_string(v6_path)) {
+                (Ok(v4), Ok(v6)) => (v4, v6),
+                (Err(e), _) | (_, Err(e)) => {
+                    log_error!("Failed to read geoip files: {}", e);
+                    return ERR_INVALID_PARAMS;
+                }
+            };
+
+            let db = match tor_geoip::GeoipDb::new_from_legacy_format(&v4_data, &v6_data) {
+                Ok(db) => db,
+                Err(e) => {
+                    log_error!("Failed to parse geoip files: {:?}", e);
+                    return ERR_INVALID_PARAMS;
+                }
+            };
+
+            match GEOIP_DB.lock() {
+                Ok(mut current) => {
+                    *current = Some(Arc::new(db));
+                    log_info!("Loaded geoip databases from {} and {}", v4_path, v6_path);
+                    1
+                },
+                Err(_) => {
+                    log_error!("Failed to lock geoip mutex");
+                    0
+                }
+            }
+        }
+
+        #[cfg(not(feature = "geoip"))]
+        {
+            log_error!("Cannot load {} and {}: built without the geoip feature", v4_path, v6_path);
+            ERR_NOT_SUPPORTED
+        }
+    })
+}
+
+/// Look up the country of an IP address in the databases loaded with arti_set_geoip_files
+///
+/// @param ip A null-terminated IPv4 or IPv6 address
+/// @param out_country Output buffer that will receive the null-terminated two-letter country code
+/// @param out_len Length of the output buffer
+/// @return 1 if a country was found, 0 if not found or on failure, ERR_INVALID_PARAMS (-4) for
+///         a malformed address, ERR_NOT_INITIALIZED (-1) if no databases are loaded,
+///         ERR_NOT_SUPPORTED (-11) if built without the geoip feature
+#[no_mangle]
+pub extern "C" fn arti_geoip_lookup(ip: *const c_char, out_country: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if ip.is_null() || out_country.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_geoip_lookup");
+            return 0;
+        }
+
+        let addr = match unsafe { CStr::from_ptr(ip) }.to_str().ok().and_then(|s| s.parse::<std::net::IpAddr>().ok()) {
+            Some(a) => a,
+            None => {
+                log_error!("Invalid IP address in arti_geoip_lookup");
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let db = match GEOIP_DB.lock() {
+            Ok(db) => db.clone(),
+            Err(_) => {
+                log_error!("Failed to lock geoip mutex");
+                return 0;
+            }
+        };
+
+        let db = match db {
+            Some(db) => db,
+            None if !cfg!(feature = "geoip") => {
+                log_error!("Cannot look up {}: built without the geoip feature", addr);
+                return ERR_NOT_SUPPORTED;
+            },
+            None => {
+                log_error!("No geoip databases loaded; call arti_set_geoip_files first");
+                return ERR_NOT_INITIALIZED;
+            }
+        };
+
+        #[cfg(feature = "geoip")]
+        {
+            match db.lookup_country_code(addr) {
+                Some(country) => {
+                    if !write_c_buffer(country.as_ref(), out_country, out_len) {
+                        log_error!("Output buffer too small in arti_geoip_lookup");
+                        return 0;
+                    }
+                    1
+                },
+                None => 0,
+            }
+        }
+
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = (addr, db);
+            0
+        }
+    })
+}
+
+/// Get the most recent error message reported on the calling thread
+///
+/// Every failure that is logged at error level, including a caught internal panic,
+/// is also kept as the thread's last error. The message stays until a newer error
+/// replaces it.
+///
+/// @param out_buf Output buffer that will receive the null-terminated message
+/// @param out_len Length of the output buffer
+/// @return 1 if a message was written, 0 if there is no error to report,
+///         ERR_BUFFER_TOO_SMALL (-9) if the message does not fit
+#[no_mangle]
+pub extern "C" fn arti_last_error(out_buf: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let last = LAST_ERROR.with(|last| last.borrow().clone());
+        match last {
+            Some(msg) => {
+                if write_c_buffer(&msg, out_buf, out_len) {
+                    1
+                } else {
+                    ERR_BUFFER_TOO_SMALL
+                }
+            },
+            None => 0,
+        }
+    })
+}
+
+/// Limit how many HTTP requests run at the same time
+///
+/// Requests beyond the limit wait for a running one to finish; the wait counts
+/// towards the request's timeout. Applies to every HTTP function, including
+/// uploads. Requests already waiting when the limit changes keep the old limit.
+///
+/// @param max_concurrent Maximum number of requests in flight, or 0 for no limit
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_http_max_concurrency(max_concurrent: u32) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match HTTP_CONCURRENCY.lock() {
+            Ok(mut limit) => {
+                *limit = if max_concurrent == 0 {
+                    None
+                } else {
+                    Some(StdArc::new(tokio::sync::Semaphore::new(max_concurrent as usize)))
+                };
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock HTTP concurrency mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Limit the size of HTTP response bodies
+///
+/// Bodies are read in chunks and the request fails with ERR_BODY_TOO_LARGE (-10) as
+/// soon as the limit is exceeded, so a huge response can't exhaust memory. When a
+/// body is decompressed, the limit also applies to the decoded size.
+///
+/// @param max_bytes The largest body accepted, in bytes, or 0 for no limit
+/// @return 1 on success
+#[no_mangle]
+pub extern "C" fn arti_set_max_http_body(max_bytes: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        MAX_HTTP_BODY.store(max_bytes, Ordering::Relaxed);
+        1
+    })
+}
+
+/// Connect to a target through a circuit and tag the stream with a label
+///
+/// Every stream opened on a circuit belongs to that circuit's group; the label only
+/// identifies the stream in arti_get_stream_group. Labels need not be unique.
+///
+/// @param circuit_id The circuit ID to use
+/// @param label A null-terminated label for the stream
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_circuit_open_labeled_stream(
+    circuit_id: *const c_char,
+    label: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if label.is_null() {
+            log_error!("Invalid parameters in arti_circuit_open_labeled_stream");
+            return 0;
+        }
+
+        let label_str = match unsafe { CStr::from_ptr(label) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid label string");
+                return 0;
+            }
+        };
+
+        connect_stream_labeled(circuit_id, target_host, target_port, stream_id, stream_id_len, &default_stream_prefs(), label_str)
+    })
+}
+
+/// Get the open streams of a circuit and their labels
+///
+/// The result is a null-terminated JSON object mapping stream IDs to labels, with
+/// an empty label for streams opened without one, e.g. `{"c-stream-1": "video"}`.
+///
+/// @param circuit_id The circuit ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_get_stream_group(
+    circuit_id: *const c_char,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || out_json.is_null() {
+            log_error!("Invalid parameters in arti_get_stream_group");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let group = match STREAM_GROUPS.lock() {
+            Ok(groups) => groups.get(circuit_id_str).cloned().unwrap_or_default(),
+            Err(_) => {
+                log_error!("Failed to lock stream groups mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let json = match serde_json::to_string(&group) {
+            Ok(j) => j,
+            Err(e) => {
+                log_error!("Failed to serialize stream group: {:?}", e);
+                return 0;
+            }
+        };
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Stream group buffer too small: {} bytes needed", json.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Close every stream opened on a circuit
+///
+/// Closes plain, split and TLS streams alike. The circuit itself stays usable.
+///
+/// @param circuit_id The circuit ID
+/// @return The number of streams closed (0 if there were none), or ERR_INTERNAL (-5)
+///         if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_close_stream_group(circuit_id: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() {
+            log_error!("Invalid parameters in arti_close_stream_group");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        match close_circuit_streams(circuit_id_str) {
+            Ok(closed) => {
+                log_debug!("Closed {} streams of circuit {}", closed, circuit_id_str);
+                closed.min(c_int::MAX as usize) as c_int
+            },
+            Err(e) => {
+                log_error!("Failed to close streams of circuit {}: {:?}", circuit_id_str, e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Read data from a stream into a buffer allocated by the library
+///
+/// Saves the caller from sizing a buffer up front. On success, *out_ptr receives a
+/// buffer of exactly *out_len bytes that belongs to the caller and must be released
+/// with arti_free_bytes, passing the same length; it must not be freed with free()
+/// or any other allocator. At end of stream *out_ptr is null and *out_len is 0.
+///
+/// @param stream_id The stream ID
+/// @param max_len The maximum number of bytes to read
+/// @param out_ptr Output parameter that will receive the buffer
+/// @param out_len Output parameter that will receive the number of bytes read
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_read_stream_owned(
+    stream_id: *const c_char,
+    max_len: usize,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_ptr.is_null() || out_len.is_null() || max_len == 0 {
+            log_error!("Invalid parameters in arti_read_stream_owned");
+            return 0;
+        }
+
+        let mut buffer = vec![0u8; max_len.min(c_int::MAX as usize)];
+        let mut bytes_read: c_int = 0;
+        let result = arti_read_stream(stream_id, buffer.as_mut_ptr() as *mut c_char, buffer.len() as c_int, &mut bytes_read);
+        if result != 1 {
+            return result;
+        }
+
+        let (ptr, len) = if bytes_read > 0 {
+            buffer.truncate(bytes_read as usize);
+            let len = buffer.len();
+            (Box::into_raw(buffer.into_boxed_slice()) as *mut u8, len)
+        } else {
+            (std::ptr::null_mut(), 0)
+        };
+
+        unsafe {
+            *out_ptr = ptr;
+            *out_len = len;
+        }
+        1
+    })
+}
+
+/// Free a buffer returned by arti_read_stream_owned
+///
+/// @param ptr The buffer, or null (which is ignored)
+/// @param len The length reported alongside the buffer
+#[no_mangle]
+pub extern "C" fn arti_free_bytes(ptr: *mut u8, len: usize) {
+    ffi_boundary((), || {
+        if ptr.is_null() {
+            return;
+        }
+
+        unsafe {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+        }
+    })
+}
+
+/// Close all streams and circuits but keep the Tor client
+///
+/// Closes every plain, split and TLS stream, cancels pending background connects and
+/// destroys every circuit. The client stays bootstrapped, so new circuits and
+/// streams can be created right away. The SOCKS proxy started with
+/// arti_start_socks_proxy keeps running.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_reset_streams_and_circuits() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match reset_streams_and_circuits() {
+            Ok(()) => {
+                log_info!("Closed all streams and circuits");
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to reset streams and circuits: {:?}", e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Get connection and traffic counters
+///
+/// The result is a null-terminated JSON object:
+/// `{"attempts": n, "successes": n, "failures": n, "timeouts": n, "bytes_in": n,
+///   "bytes_out": n, "active_streams": n}`.
+/// The connect counters cover every stream opened through Tor, including those made
+/// for HTTP requests and by the SOCKS proxy; timeouts are also counted as failures.
+/// The byte counters cover all stream traffic, as described for arti_total_bandwidth,
+/// and active_streams counts plain streams, split halves and TLS streams. Counters
+/// are never reset.
+///
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_get_metrics(out_json: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_json.is_null() {
+            log_error!("Invalid parameters in arti_get_metrics");
+            return 0;
+        }
+
+        let active_streams = STREAMS.lock().map(|s| s.len()).unwrap_or(0)
+            + SPLIT_READERS.lock().map(|r| r.len()).unwrap_or(0)
+            + SPLIT_WRITERS.lock().map(|w| w.len()).unwrap_or(0)
+            + TLS_STREAMS.lock().map(|t| t.len()).unwrap_or(0);
+
+        let json = serde_json::json!({
+            "attempts": CONNECT_ATTEMPTS.load(Ordering::Relaxed),
+            "successes": CONNECT_SUCCESSES.load(Ordering::Relaxed),
+            "failures": CONNECT_FAILURES.load(Ordering::Relaxed),
+            "timeouts": CONNECT_TIMEOUTS.load(Ordering::Relaxed),
+            "bytes_in": BYTES_IN.load(Ordering::Relaxed),
+            "bytes_out": BYTES_OUT.load(Ordering::Relaxed),
+            "active_streams": active_streams,
+        }).to_string();
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Metrics buffer too small: {} bytes needed", json.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Get the total number of bytes sent and received over Tor since the library was loaded
+///
+/// Covers plain, split and TLS streams, HTTP requests and traffic relayed by the
+/// SOCKS proxy. Only stream payload is counted: TLS handshakes and record overhead,
+/// Tor cell overhead and the client's own directory traffic are not included, since
+/// Arti does not report them. The totals are never reset, so compare two readings
+/// to measure the traffic in between.
+///
+/// @param out_sent Output parameter that will receive the number of bytes sent
+/// @param out_received Output parameter that will receive the number of bytes received
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_total_bandwidth(out_sent: *mut u64, out_received: *mut u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_sent.is_null() || out_received.is_null() {
+            log_error!("Invalid parameters in arti_total_bandwidth");
+            return 0;
+        }
+
+        unsafe {
+            *out_sent = BYTES_OUT.load(Ordering::Relaxed);
+            *out_received = BYTES_IN.load(Ordering::Relaxed);
+        }
+        1
+    })
+}
+
+/// Set the headers sent with every HTTP request
+///
+/// Replaces the default headers, which initially hold only a User-Agent matching
+/// Tor Browser so requests are harder to tell apart from its traffic. Headers passed
+/// to an individual request take precedence over these, compared case-insensitively.
+/// Passing "{}" sends no default headers at all, not even a User-Agent.
+///
+/// @param headers A JSON object mapping header names to string values
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is not an
+///         object of valid header names and string values
+#[no_mangle]
+pub extern "C" fn arti_set_default_http_headers(headers: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if headers.is_null() {
+            log_error!("Invalid parameters in arti_set_default_http_headers");
+            return 0;
+        }
+
+        let headers_str = match unsafe { CStr::from_ptr(headers) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid headers string");
+                return 0;
+            }
+        };
+
+        let headers_map: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(headers_str) {
+            Ok(m) => m,
+            Err(e) => {
+                log_error!("Invalid default headers JSON: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let mut parsed = Vec::with_capacity(headers_map.len());
+        for (key, value) in headers_map {
+            let value = match value.as_str() {
+                Some(v) => v.to_string(),
+                None => {
+                    log_error!("Default header {} is not a string", key);
+                    return ERR_INVALID_PARAMS;
+                }
+            };
+            if reqwest::header::HeaderName::from_bytes(key.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(&value).is_err() {
+                log_error!("Invalid default header: {}", key);
+                return ERR_INVALID_PARAMS;
+            }
+            parsed.push((key, value));
+        }
+
+        match DEFAULT_HTTP_HEADERS.lock() {
+            Ok(mut defaults) => {
+                *defaults = parsed;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock default HTTP headers mutex");
+                ERR_INTERNAL
+            }
+        }
+    })
+}
+
+/// Connect to an IP address through Tor and return a stream ID
+///
+/// Unlike arti_connect_stream, the host must be an IPv4 or IPv6 literal (IPv6 with or
+/// without brackets), so the exit connects to exactly that address without a DNS
+/// lookup. This pins the server, but the exit still sees the address, and skipping
+/// DNS does not hide which service is reached: an IP often identifies it as well as
+/// a hostname does. Resolving the address yourself outside Tor would leak it, so
+/// obtain it through arti_resolve or a trusted source.
+///
+/// @param circuit_id The circuit ID to use
+/// @param ip The target IP address
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the IP address or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_ip(
+    circuit_id: *const c_char,
+    ip: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if ip.is_null() {
+            log_error!("Invalid parameters in arti_connect_stream_ip");
+            return 0;
+        }
+
+        let ip_str = match unsafe { CStr::from_ptr(ip) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid IP address string");
+                return 0;
+            }
+        };
+
+        let literal = ip_str.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(ip_str);
+        if literal.parse::<std::net::IpAddr>().is_err() {
+            log_error!("Not an IP address: {:?}", ip_str);
+            return ERR_INVALID_PARAMS;
+        }
+
+        connect_stream_with_prefs(circuit_id, ip, target_port, stream_id, stream_id_len, &default_stream_prefs())
+    })
+}
+
+/// Set the oldest TLS version accepted by arti_connect_tls_stream
+///
+/// Applies to connections made after the call. TLS 1.0 and 1.1 are insecure and are
+/// never accepted.
+///
+/// @param version "1.2" to accept TLS 1.2 and 1.3 (the default), or "1.3" to require TLS 1.3
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for any other version
+#[no_mangle]
+pub extern "C" fn arti_set_tls_min_version(version: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if version.is_null() {
+            log_error!("Invalid parameters in arti_set_tls_min_version");
+            return 0;
+        }
+
+        let versions: &[&'static rustls::SupportedProtocolVersion] = match unsafe { CStr::from_ptr(version) }.to_str() {
+            Ok("1.2") => rustls::DEFAULT_VERSIONS,
+            Ok("1.3") => &[&rustls::version::TLS13],
+            Ok(v) => {
+                log_error!("Unsupported minimum TLS version: {:?}", v);
+                return ERR_INVALID_PARAMS;
+            },
+            Err(_) => {
+                log_error!("Invalid TLS version string");
+                return 0;
+            }
+        };
+
+        let config = match create_tls_config(versions) {
+            Ok(c) => c,
+            Err(e) => {
+                log_error!("Failed to build TLS config: {:?}", e);
+                return 0;
+            }
+        };
+
+        match TLS_CLIENT_CONFIG.lock() {
+            Ok(mut current) => {
+                *current = Some(config);
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock TLS config mutex");
+                ERR_INTERNAL
+            }
+        }
+    })
+}
+
+/// Check whether a read from a stream would return without blocking
+///
+/// Useful for event-loop integration: polls the stream once without waiting. A
+/// stream that has reached end of stream counts as readable, since a read returns
+/// 0 immediately. Split halves are not supported.
+///
+/// @param stream_id The stream ID
+/// @return 1 if data (or end of stream) is ready, 0 if a read would block, -1 on error
+#[no_mangle]
+pub extern "C" fn arti_stream_readable(stream_id: *const c_char) -> c_int {
+    ffi_boundary(-1, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_stream_readable");
+            return -1;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return -1;
+            }
+        };
+
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return -1;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return -1;
+            }
+        };
+
+        if !stream.read_ahead.is_empty() {
+            return 1;
+        }
+
+        // Poll a one-byte peek once; anything it reads stays buffered for the next read
+        let mut probe = [0u8; 1];
+        match block_on_runtime(async { futures::FutureExt::now_or_never(stream.peek(&mut probe)) }) {
+            Ok(Some(Ok(_))) => 1,
+            Ok(None) => 0,
+            Ok(Some(Err(e))) => {
+                log_error!("Failed to poll stream: {:?}", e);
+                -1
+            },
+            Err(e) => {
+                log_error!("Failed to run poll: {:?}", e);
+                -1
+            }
+        }
+    })
+}
+
+/// Close a TLS stream cleanly, sending the TLS close_notify alert
+///
+/// arti_close_tls_stream drops the connection without close_notify, which some
+/// servers treat as a truncation attack. This flushes pending data and sends
+/// close_notify before the stream is released. The stream is removed even if
+/// sending fails.
+///
+/// @param stream_id A null-terminated string representing the stream ID
+/// @return 1 on a clean close, 0 on failure, ERR_STREAM_NOT_FOUND (-13) if the stream is
+///         already closed or unknown
+#[no_mangle]
+pub extern "C" fn arti_close_tls_stream_graceful(stream_id: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_close_tls_stream_graceful");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        // Take the stream out of the map so it is gone whatever happens below
+        let stream_arc = match TLS_STREAMS.lock() {
+            Ok(mut streams) => match streams.remove(stream_id_str) {
+                Some(s) => {
+                    untrack_stream(stream_id_str);
+                    s
+                },
+                None => {
+                    log_debug!("TLS Stream already closed or unknown: {}", stream_id_str);
+                    return ERR_STREAM_NOT_FOUND;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock TLS streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let close_result = block_on_runtime(async {
+            let mut stream = stream_arc.lock()
+                .map_err(|_| anyhow!("Failed to lock TLS stream"))?;
+            stream.flush().await?;
+            stream.shutdown().await?;
+            Ok::<(), anyhow::Error>(())
+        });
+
+        match close_result {
+            Ok(Ok(())) => {
+                log_debug!("TLS Stream closed gracefully: {}", stream_id_str);
+                1
+            },
+            Ok(Err(e)) => {
+                log_error!("Failed to close TLS stream {}: {:?}", stream_id_str, e);
+                0
+            },
+            Err(e) => {
+                log_error!("Failed to run graceful close: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Set how much connection padding the client sends to its guards
+///
+/// Padding cells hide when the client is idle and make traffic analysis on the
+/// link to the guard harder. Normal padding costs a few hundred bytes per minute
+/// for each open channel; reduced padding cuts that at the price of weaker
+/// protection, which suits mobile or metered connections. Disabling padding makes
+/// the client stand out and is not recommended.
+/// Must be called before arti_init.
+///
+/// @param mode 0 = normal (the default), 1 = reduced, 2 = none
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown mode
+#[no_mangle]
+pub extern "C" fn arti_set_padding(mode: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let level = match mode {
+            PADDING_NORMAL => tor_config::PaddingLevel::Normal,
+            PADDING_REDUCED => tor_config::PaddingLevel::Reduced,
+            PADDING_NONE => tor_config::PaddingLevel::None,
+            _ => {
+                log_error!("Invalid padding mode: {}", mode);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        if is_connected().unwrap_or(false) {
+            log_error!("Padding must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        match CONFIG_OVERRIDES.lock() {
+            Ok(mut overrides) => {
+                overrides.padding = Some(level);
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock config overrides mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Set the directory sources used to bootstrap, for private or test networks
+///
+/// Replaces the built-in fallback directory caches and directory authorities
+/// with the ones given. The JSON follows the [tor_network] section of Arti's
+/// configuration file:
+/// {"fallback_caches": [{"rsa_identity": "...", "ed_identity": "...", "orports": ["127.0.0.1:5000"]}],
+///  "authorities": [{"name": "test000a", "v3ident": "..."}]}
+/// Either key may be left out to keep the defaults for that list. For a chutney
+/// network, the arti.toml it generates can instead be passed to arti_init_with_config.
+/// Must be called before arti_init; settings loaded from a config file are not affected.
+///
+/// @param network_json JSON object describing the directory sources, or NULL to restore the defaults
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is malformed
+#[no_mangle]
+pub extern "C" fn arti_set_fallback_dirs(network_json: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let network = if network_json.is_null() {
+            None
+        } else {
+            let parsed = unsafe { CStr::from_ptr(network_json) }.to_str()
+                .map_err(|e| anyhow!(e))
+                .and_then(|s| Ok(serde_json::from_str::<arti_client::config::dir::NetworkConfigBuilder>(s)?));
+            match parsed {
+                Ok(n) => Some(n),
+                Err(e) => {
+                    log_error!("Invalid directory sources: {:?}", e);
+                    return ERR_INVALID_PARAMS;
+                }
+            }
+        };
+
+        // Catch bad identities or addresses now rather than at bootstrap
+        if let Some(n) = &network {
+            if let Err(e) = n.build() {
+                log_error!("Invalid directory sources: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        }
+
+        if is_connected().unwrap_or(false) {
+            log_error!("Directory sources must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        match CONFIG_OVERRIDES.lock() {
+            Ok(mut overrides) => {
+                overrides.tor_network = network;
+                1
+            },
+            Err(_) => {
+                log_error!("Failed to lock config overrides mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Start bootstrapping the Tor client without waiting for it to finish
+///
+/// An alternative to arti_init for UIs that show progress: returns straight away
+/// while the client bootstraps on the runtime, and arti_bootstrap_progress reports
+/// how far it got. The client is only installed, and arti_is_connected only
+/// reports 1, once bootstrap has completed. Calling this while a bootstrap is
+/// already running does nothing. Settings made before arti_init apply here too.
+///
+/// @return 1 on success, 0 on failure, ERR_STATE_LOCKED (-7) if another process holds the state lock,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_begin_bootstrap() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if is_connected().unwrap_or(false) {
+            log_error!("The Tor client is already initialized");
+            return 0;
+        }
+
+        match begin_bootstrap() {
+            Ok(()) => 1,
+            Err(e) => {
+                log_error!("Failed to start bootstrapping: {:?}", e);
+                init_error_code(&e)
+            }
+        }
+    })
+}
+
+/// Get the progress of a bootstrap started with arti_begin_bootstrap
+///
+/// @param out_percent Output parameter that will receive the progress, from 0 to 100.
+///         100 means the client is bootstrapped and ready to use.
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if no bootstrap was started,
+///         ERR_CONNECTION_FAILED (-2) if the bootstrap failed (see arti_last_error),
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_bootstrap_progress(out_percent: *mut c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_percent.is_null() {
+            log_error!("Invalid parameters in arti_bootstrap_progress");
+            return 0;
+        }
+
+        let state = match BACKGROUND_BOOTSTRAP.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock background bootstrap mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let percent = match &*state {
+            // Leave 100 for when the client is actually installed
+            Some(BackgroundBootstrap::Running { client, .. }) => {
+                ((client.bootstrap_status().as_frac() * 100.0) as c_int).clamp(0, 99)
+            },
+            Some(BackgroundBootstrap::Failed(reason)) => {
+                log_error!("Bootstrap failed: {}", reason);
+                return ERR_CONNECTION_FAILED;
+            },
+            None if is_connected().unwrap_or(false) => 100,
+            None => {
+                log_error!("No bootstrap in progress, call arti_begin_bootstrap first");
+                return ERR_NOT_INITIALIZED;
+            }
+        };
+
+        unsafe {
+            *out_percent = percent;
+        }
+        1
+    })
+}
+
+// Work out whether arti_connect_url needs TLS, and the host and port to connect to
+fn connect_url_target(url_str: &str) -> Result<(bool, String, u16)> {
+    let parsed = url::Url::parse(url_str)
+        .map_err(|e| anyhow!("Invalid URL {:?}: {}", url_str, e))?;
+
+    let (tls, default_port) = match parsed.scheme() {
+        "http" => (false, Some(80)),
+        "https" => (true, Some(443)),
+        "tcp" => (false, None),
+        scheme => return Err(anyhow!("Unsupported URL scheme: {}", scheme)),
+    };
+
+    let host = match parsed.host_str() {
+        Some(h) if !h.is_empty() => h.to_string(),
+        _ => return Err(anyhow!("URL has no host: {:?}", url_str)),
+    };
+
+    let port = parsed.port().or(default_port)
+        .ok_or_else(|| anyhow!("URL has no port: {:?}", url_str))?;
+    Ok((tls, host, port))
+}
+
+/// Connect to the host and port named by a URL, using TLS when the scheme calls for it
+///
+/// Supported schemes are http (plain, default port 80), https (TLS, default port
+/// 443) and tcp (plain, port required). Only the scheme, host and port are used;
+/// no request is sent. A TLS stream must be used with the arti_tls_* functions and
+/// arti_close_tls_stream, a plain one with arti_read_stream and friends.
+///
+/// @param circuit_id The circuit ID to use
+/// @param url The URL to connect to, e.g. "https://example.com" or "tcp://example.com:7000"
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param is_tls Output parameter that will receive 1 for a TLS stream, 0 for a plain one (may be null)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the URL is malformed or its scheme
+///         unsupported, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_url(
+    circuit_id: *const c_char,
+    url: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+    is_tls: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || url.is_null() || stream_id.is_null() {
+            log_error!("Invalid parameters in arti_connect_url");
+            return 0;
+        }
+
+        let url_str = match unsafe { CStr::from_ptr(url) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid URL string");
+                return 0;
+            }
+        };
+
+        let (tls, host, port) = match connect_url_target(url_str) {
+            Ok(t) => t,
+            Err(e) => {
+                log_error!("{}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+        let port = port as c_int;
+
+        let host_cstring = match CString::new(host) {
+            Ok(h) => h,
+            Err(_) => return ERR_INVALID_PARAMS,
+        };
+
+        if !is_tls.is_null() {
+            unsafe {
+                *is_tls = tls as c_int;
+            }
+        }
+
+        if !tls {
+            return connect_stream_with_prefs(circuit_id, host_cstring.as_ptr(), port, stream_id, stream_id_len, &default_stream_prefs());
+        }
+
+        // TLS streams take their ID from the caller, so make one up and hand it back
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+        let id = new_stream_id(circuit_id_str);
+        let id_cstring = match CString::new(id.clone()) {
+            Ok(i) => i,
+            Err(_) => return 0,
+        };
+
+        // Fail before connecting, so no stream is opened that the caller can't address
+        if stream_id_len <= 0 || id.len() + 1 > stream_id_len as usize {
+            log_error!("Stream ID buffer too small: {} bytes needed", id.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        let result = arti_connect_tls_stream(circuit_id, host_cstring.as_ptr(), port, id_cstring.as_ptr());
+        if result == 1 && !write_c_buffer(&id, stream_id, stream_id_len) {
+            return 0;
+        }
+        result
+    })
+}
+
+/// Write data to a stream and flush it in one call
+///
+/// Equivalent to arti_write_stream followed by arti_flush_stream, saving a call
+/// per message. Works on plain streams and split write halves.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_write_stream_flush(
+    stream_id: *const c_char,
+    data: *const c_char,
+    data_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let written = arti_write_stream(stream_id, data, data_len);
+        if written != 1 {
+            return written;
+        }
+        arti_flush_stream(stream_id)
+    })
+}
+
+/// Set the bridges the client connects through, replacing any set before
+///
+/// Requires a build with the `pt-bridges` feature. Each entry is a bridge line as
+/// used in torrc, without the leading "Bridge", e.g.
+/// "obfs4 192.0.2.1:443 <fingerprint> cert=... iat-mode=0". Lines that can't be
+/// parsed are skipped and logged. An empty array removes all bridges.
+/// Must be called before arti_init.
+///
+/// @param bridges_json A JSON array of bridge lines
+/// @return The number of bridges accepted, 0 on failure, ERR_INVALID_PARAMS (-4) if the JSON is not
+///         an array of strings or no line could be parsed, ERR_NOT_SUPPORTED (-11) if built without
+///         the pt-bridges feature
+#[no_mangle]
+pub extern "C" fn arti_set_bridges(bridges_json: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if bridges_json.is_null() {
+            log_error!("Invalid parameters in arti_set_bridges");
+            return 0;
+        }
+
+        if is_connected().unwrap_or(false) {
+            log_error!("Bridges must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        let json_str = match unsafe { CStr::from_ptr(bridges_json) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid bridges string");
+                return 0;
+            }
+        };
+
+        let lines: Vec<String> = match serde_json::from_str(json_str) {
+            Ok(l) => l,
+            Err(e) => {
+                log_error!("Bridges must be a JSON array of strings: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        #[cfg(feature = "pt-bridges")]
+        {
+            let total = lines.len();
+            let accepted: Vec<String> = lines.into_iter()
+                .map(|line| line.trim().to_string())
+                .filter(|line| match line.parse::<arti_client::config::BridgeConfigBuilder>().map(|b| b.build()) {
+                    Ok(Ok(_)) => true,
+                    Ok(Err(e)) => {
+                        log_warn!("Skipping invalid bridge line {:?}: {}", line, e);
+                        false
+                    },
+                    Err(e) => {
+                        log_warn!("Skipping invalid bridge line {:?}: {}", line, e);
+                        false
+                    }
+                })
+                .collect();
+
+            if accepted.is_empty() && total > 0 {
+                log_error!("None of the {} bridge lines could be parsed", total);
+                return ERR_INVALID_PARAMS;
+            }
+
+            let count = accepted.len();
+            match CONFIG_OVERRIDES.lock() {
+                Ok(mut overrides) => {
+                    overrides.bridges = accepted;
+                    log_info!("Configured {} of {} bridges", count, total);
+                    count.min(c_int::MAX as usize) as c_int
+                },
+                Err(_) => {
+                    log_error!("Failed to lock config overrides mutex");
+                    0
+                }
+            }
+        }
+
+        #[cfg(not(feature = "pt-bridges"))]
+        {
+            log_error!("Cannot set {} bridges: built without the pt-bridges feature", lines.len());
+            ERR_NOT_SUPPORTED
+        }
+    })
+}
+
+/// Shut down the library completely
+///
+/// Closes every stream, destroys all circuits, stops the SOCKS proxy and the idle
+/// stream reaper, drops the global client and every client from arti_client_new,
+/// and shuts down the async runtime, waiting up to 5 seconds for its tasks. The
+/// library can then be unloaded safely. To use it again, call arti_init (or
+/// arti_begin_bootstrap) and re-apply arti_set_idle_timeout_ms if needed.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_shutdown_all() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        match shutdown_all() {
+            Ok(()) => {
+                log_info!("Shut down the Tor client and runtime");
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to shut down: {:?}", e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Get how long a circuit took to build and its current round-trip time
+///
+/// The build time is how long the first stream on the circuit took to open, which
+/// includes building the path. The round-trip time is measured now by opening and
+/// closing a probe stream to the connectivity target (see
+/// arti_set_connectivity_target); since the path already exists, this is roughly
+/// one round trip to the exit plus the exit's connection to the target.
+///
+/// @param circuit_id The circuit ID
+/// @param out_build_ms Output parameter that will receive the build time in milliseconds
+/// @param out_rtt_ms Output parameter that will receive the round-trip time in milliseconds
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if no stream has been opened on
+///         the circuit yet, ERR_CONNECTION_FAILED (-2) if the probe stream failed
+#[no_mangle]
+pub extern "C" fn arti_circuit_latency_ms(
+    circuit_id: *const c_char,
+    out_build_ms: *mut u64,
+    out_rtt_ms: *mut u64,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || out_build_ms.is_null() || out_rtt_ms.is_null() {
+            log_error!("Invalid parameters in arti_circuit_latency_ms");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let client = match get_tor_client_by_circuit(circuit_id_str) {
+            Some(c) => c,
+            None => {
+                log_error!("Circuit not found: {}", circuit_id_str);
+                return 0;
+            }
+        };
+
+        let build_time = match CIRCUIT_BUILD_TIMES.lock() {
+            Ok(times) => match times.get(circuit_id_str) {
+                Some(t) => *t,
+                None => {
+                    log_error!("No stream has been opened on circuit {} yet", circuit_id_str);
+                    return ERR_NOT_INITIALIZED;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock circuit build times mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let target = match CONNECTIVITY_TARGET.lock() {
+            Ok(t) => t.clone(),
+            Err(_) => {
+                log_error!("Failed to lock connectivity target mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        // The probe stream is dropped as soon as it is open
+        let started = Instant::now();
+        let rtt = match block_on_runtime(client.connect(target.as_str())) {
+            Ok(Ok(_)) => started.elapsed(),
+            Ok(Err(e)) => {
+                log_warn!("Latency probe to {} failed: {:?}", target, e);
+                return ERR_CONNECTION_FAILED;
+            },
+            Err(e) => {
+                log_error!("Failed to run latency probe: {:?}", e);
+                return 0;
+            }
+        };
+
+        unsafe {
+            *out_build_ms = build_time.as_millis() as u64;
+            *out_rtt_ms = rtt.as_millis() as u64;
+        }
+        1
+    })
+}
+
+/// Check whether any exit relay in the current consensus accepts a port
+///
+/// Most exits only allow common ports, so streams to unusual ports often fail.
+/// Call this to warn before trying. Relays flagged as bad exits are not counted.
+/// Requires a build with the `experimental-api` feature.
+///
+/// @param port The target port
+/// @return 1 if at least one exit allows the port over IPv4 or IPv6, 0 if none does,
+///         ERR_INVALID_PARAMS (-4) for an invalid port, ERR_NOT_INITIALIZED (-1) if no
+///         directory is available (client not initialized or not bootstrapped),
+///         ERR_NOT_SUPPORTED (-11) if built without the experimental-api feature
+#[no_mangle]
+pub extern "C" fn arti_exit_allows_port(port: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if !(1..=65535).contains(&port) {
+            log_error!("Invalid port: {}", port);
+            return ERR_INVALID_PARAMS;
+        }
+
+        #[cfg(feature = "experimental-api")]
+        {
+            let netdir = match CLIENT.lock() {
+                Ok(client) => match &*client {
+                    Some(c) => c.dirmgr().timely_netdir(),
+                    None => {
+                        log_error!("Tor client not initialized");
+                        return ERR_NOT_INITIALIZED;
+                    }
+                },
+                Err(_) => {
+                    log_error!("Failed to lock client mutex");
+                    return ERR_INTERNAL;
+                }
+            };
+
+            let netdir = match netdir {
+                Ok(n) => n,
+                Err(e) => {
+                    log_error!("Directory not ready: {:?}", e);
+                    return ERR_NOT_INITIALIZED;
+                }
+            };
+
+            let port = port as u16;
+            let allowed = netdir.relays()
+                .any(|relay| relay.supports_exit_port_ipv4(port) || relay.supports_exit_port_ipv6(port));
+            allowed as c_int
+        }
+
+        #[cfg(not(feature = "experimental-api"))]
+        {
+            log_error!("Cannot check exit policies: built without the experimental-api feature");
+            ERR_NOT_SUPPORTED
+        }
+    })
+}
+
+/// Send a raw HTTP/1.1 request and read the response, without reqwest
+///
+/// The request bytes are sent exactly as given, so the request line and headers
+/// are fully under the caller's control; nothing is added, reordered or encoded.
+/// The response is returned as the status line and headers exactly as received,
+/// followed by the body. A chunked body is de-chunked (trailers are dropped) but
+/// the head still says so; compressed bodies are left as they are. The body ends
+/// where Content-Length says, or when the server closes the connection.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param request The raw request bytes, including the blank line ending the headers
+/// @param request_len The length of the request
+/// @param out_buf The buffer to store the response
+/// @param out_len The length of the response buffer
+/// @param bytes_written Output parameter that will receive the response length, or the
+///         required buffer length if the buffer is too small
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response does not fit, ERR_BODY_TOO_LARGE (-10) if
+///         the body exceeds the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http1_request(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    request: *const c_char,
+    request_len: c_int,
+    out_buf: *mut c_char,
+    out_len: c_int,
+    bytes_written: *mut c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || target_host.is_null() || request.is_null() || request_len <= 0
+            || out_buf.is_null() || out_len <= 0 || bytes_written.is_null() {
+            log_error!("Invalid parameters in arti_http1_request");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        let host_str = match unsafe { CStr::from_ptr(target_host) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid host string");
+                return 0;
+            }
+        };
+
+        let target = match format_target(host_str, target_port) {
+            Some(t) => t,
+            None => {
+                log_error!("Invalid target address: {}", describe_invalid_target(host_str, target_port));
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let request_slice = unsafe {
+            std::slice::from_raw_parts(request as *const u8, request_len as usize)
+        };
+
+        let response = match http1_request(circuit_id_str, target, request_slice) {
+            Ok(r) => r,
+            Err(e) => {
+                log_error!("HTTP/1.1 request failed: {:?}", e);
+                return http_error_code(&e);
+            }
+        };
+
+        if response.len() > out_len as usize {
+            log_error!("Response buffer too small: {} bytes needed, {} available", response.len(), out_len);
+            unsafe {
+                *bytes_written = response.len().min(c_int::MAX as usize) as c_int;
+            }
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(response.as_ptr(), out_buf as *mut u8, response.len());
+            *bytes_written = response.len() as c_int;
+        }
+        1
+    })
+}
+
+/// Make an HTTP or HTTPS request on whichever of several circuits answers first
+///
+/// The request is sent on every circuit at once. The first successful response is
+/// returned and the requests still in flight on the other circuits are cancelled.
+/// The response JSON has the same schema as arti_http_request. Note that a request
+/// with side effects may reach the server more than once.
+///
+/// @param circuit_ids_json A JSON array of circuit IDs to race, e.g. ["a", "b"]
+/// @param url The URL to request
+/// @param method The HTTP method (GET, POST, PUT, DELETE, HEAD or PATCH)
+/// @param headers A JSON object of request headers
+/// @param body The request body, or an empty string for none
+/// @param response Output buffer that will receive the JSON response
+/// @param response_len Length of the response buffer
+/// @param used_circuit Output buffer that will receive the ID of the circuit that won
+/// @param used_circuit_len Length of the used_circuit buffer
+/// @return 1 on success, 0 on failure or if every circuit failed, ERR_INVALID_PARAMS (-4)
+///         if the circuit list is empty or a buffer length is not positive,
+///         ERR_BUFFER_TOO_SMALL (-9) if the response or circuit ID does not fit,
+///         ERR_BODY_TOO_LARGE (-10) if the body exceeds the limit set with arti_set_max_http_body
+#[no_mangle]
+pub extern "C" fn arti_http_request_fastest(
+    circuit_ids_json: *const c_char,
+    url: *const c_char,
+    method: *const c_char,
+    headers: *const c_char,
+    body: *const c_char,
+    response: *mut c_char,
+    response_len: c_int,
+    used_circuit: *mut c_char,
+    used_circuit_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_ids_json.is_null() || url.is_null() || method.is_null() || headers.is_null()
+            || body.is_null() || response.is_null() || used_circuit.is_null() {
+            return 0;
+        }
+        if response_len <= 0 || used_circuit_len <= 0 {
+            log_error!("Invalid buffer lengths in arti_http_request_fastest");
+            return ERR_INVALID_PARAMS;
+        }
+
+        let circuit_ids: Vec<String> = match unsafe { CStr::from_ptr(circuit_ids_json) }.to_str()
+            .map_err(|e| anyhow!(e))
+            .and_then(|s| Ok(serde_json::from_str(s)?))
+        {
+            Ok(ids) => ids,
+            Err(e) => {
+                log_error!("Invalid circuit ID list: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+        if circuit_ids.is_empty() {
+            log_error!("No circuits given to arti_http_request_fastest");
+            return ERR_INVALID_PARAMS;
+        }
+
+        let url_str = unsafe { CStr::from_ptr(url).to_str().unwrap_or("") };
+        let method_str = unsafe { CStr::from_ptr(method).to_str().unwrap_or("") };
+        let headers_str = unsafe { CStr::from_ptr(headers).to_str().unwrap_or("{}") };
+        let body_str = unsafe { CStr::from_ptr(body).to_str().unwrap_or("") };
+
+        match http_request_fastest(&circuit_ids, url_str, method_str, headers_str, body_str, &HttpOptions::default()) {
+            Ok((circuit_id, response_str)) => {
+                if !write_c_buffer(&response_str, response, response_len) {
+                    log_error!("Response buffer too small: {} bytes needed, {} available", response_str.len() + 1, response_len);
+                    unsafe { *response = 0; }
+                    return ERR_BUFFER_TOO_SMALL;
+                }
+                if !write_c_buffer(&circuit_id, used_circuit, used_circuit_len) {
+                    log_error!("Circuit ID buffer too small in arti_http_request_fastest");
+                    return ERR_BUFFER_TOO_SMALL;
+                }
+                log_debug!("Fastest response came from circuit {}", circuit_id);
+                1
+            },
+            Err(e) => {
+                log_error!("HTTP request failed on every circuit: {:?}", e);
+                http_error_code(&e)
+            }
+        }
+    })
+}
+
+/// Export the IDs of the open circuits so they can be recreated after a restart
+///
+/// The result is a null-terminated JSON object listing every circuit with its
+/// isolation, e.g. `{"circuits": [{"id": "session-1", "isolation": "client"}]}`.
+/// Every circuit is currently its own isolated client, so isolation is always
+/// "client". Only the logical grouping is exported; the paths themselves are not,
+/// and circuits recreated with arti_import_circuits get new ones.
+///
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_export_circuits(out_json: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_json.is_null() {
+            log_error!("Invalid parameters in arti_export_circuits");
+            return 0;
+        }
+
+        let mut ids: Vec<String> = match CIRCUITS.lock() {
+            Ok(circuits) => circuits.keys().cloned().collect(),
+            Err(_) => {
+                log_error!("Failed to lock circuits mutex");
+                return ERR_INTERNAL;
+            }
+        };
+        ids.sort();
+
+        let circuits: Vec<serde_json::Value> = ids.into_iter()
+            .map(|id| serde_json::json!({ "id": id, "isolation": "client" }))
+            .collect();
+        let json = serde_json::json!({ "circuits": circuits }).to_string();
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Circuit export buffer too small: {} bytes needed", json.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Recreate circuits exported with arti_export_circuits
+///
+/// Each circuit is created as a new isolated client of the bootstrapped Tor client,
+/// so it gets a fresh path. Circuits that already exist are left untouched. The
+/// whole list is validated before any circuit is created.
+///
+/// @param json A null-terminated JSON object as produced by arti_export_circuits
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_INVALID_PARAMS (-4) if the JSON is malformed, ERR_CIRCUIT_LIMIT (-12) if the
+///         limit set with arti_set_max_circuits is reached (circuits imported before that are
+///         kept), ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_import_circuits(json: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if json.is_null() {
+            log_error!("Invalid parameters in arti_import_circuits");
+            return 0;
+        }
+
+        let value: serde_json::Value = match unsafe { CStr::from_ptr(json) }.to_str()
+            .map_err(|e| anyhow!(e))
+            .and_then(|s| Ok(serde_json::from_str(s)?))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                log_error!("Invalid circuit export JSON: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let ids: Option<Vec<String>> = value.get("circuits")
+            .and_then(|c| c.as_array())
+            .and_then(|entries| entries.iter()
+                .map(|entry| entry.get("id").and_then(|id| id.as_str()).map(|id| id.to_string()))
+                .collect());
+        let ids = match ids {
+            Some(ids) => ids,
+            None => {
+                log_error!("Circuit export JSON must be an object with a \"circuits\" array of {{\"id\": string}} entries");
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        if !client_ready() {
+            log_error!("Cannot import circuits: the Tor client is not bootstrapped, call arti_init first");
+            return ERR_NOT_INITIALIZED;
+        }
+
+        let existing: Vec<String> = match CIRCUITS.lock() {
+            Ok(circuits) => ids.iter().filter(|id| circuits.contains_key(*id)).cloned().collect(),
+            Err(_) => {
+                log_error!("Failed to lock circuits mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        for id in ids {
+            if existing.contains(&id) {
+                log_debug!("Circuit {} already exists, not importing it", id);
+                continue;
+            }
+            if let Err(e) = create_circuit(id.clone()) {
+                log_error!("Failed to import circuit {}: {:?}", id, e);
+                return error_code(&e, 0);
+            }
+        }
+
+        1
+    })
+}
+
+/// Upgrade an open plain stream to TLS, as in STARTTLS
+///
+/// For protocols such as SMTP and IMAP that negotiate TLS after a plaintext
+/// exchange. The stream is taken out of the plain stream map and a TLS handshake
+/// is performed over it with the settings used by arti_connect_tls_stream. On
+/// success the TLS stream is stored under a new ID, belonging to the same circuit,
+/// and the plain stream ID is no longer valid. The plain stream is closed if the
+/// handshake fails. Data peeked but not yet read prevents the upgrade, since it
+/// would be lost; read it first.
+///
+/// @param stream_id The plain stream to upgrade
+/// @param sni_host The server name to send and verify the certificate against
+/// @param out_tls_stream_id Output buffer that will receive the new TLS stream ID
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the stream has unread
+///         peeked data, ERR_BUFFER_TOO_SMALL (-9) if the output buffer is too small,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_upgrade_stream_to_tls(
+    stream_id: *const c_char,
+    sni_host: *const c_char,
+    out_tls_stream_id: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || sni_host.is_null() || out_tls_stream_id.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_upgrade_stream_to_tls");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let server_name = match unsafe { CStr::from_ptr(sni_host) }.to_str()
+            .map_err(|e| anyhow!(e))
+            .and_then(|host| Ok(rustls::ServerName::try_from(host)?))
+        {
+            Ok(n) => n,
+            Err(e) => {
+                log_error!("Invalid server name for TLS: {:?}", e);
+                return 0;
+            }
+        };
+
+        let circuit_id = match STREAM_GROUPS.lock() {
+            Ok(groups) => groups.iter()
+                .find(|(_, group)| group.contains_key(stream_id_str))
+                .map(|(circuit_id, _)| circuit_id.clone()),
+            Err(_) => {
+                log_error!("Failed to lock stream groups mutex");
+                return ERR_INTERNAL;
+            }
+        };
+        let circuit_id = match circuit_id {
+            Some(c) => c,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        // Check the buffer before giving up the plain stream, so a small buffer costs
+        // nothing; the ID is only copied out once the handshake has succeeded
+        let tls_stream_id = new_stream_id(&circuit_id);
+        if tls_stream_id.len() + 1 > out_len as usize {
+            log_error!("TLS stream ID buffer too small: {} bytes needed", tls_stream_id.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        // Take the stream out of the map so it is gone whatever happens below
+        let stream = match STREAMS.lock() {
+            Ok(mut streams) => match streams.get(stream_id_str) {
+                Some(stream) if !stream.read_ahead.is_empty() => {
+                    log_error!("Stream {} has {} bytes of unread peeked data", stream_id_str, stream.read_ahead.len());
+                    return ERR_INVALID_PARAMS;
+                },
+                Some(_) => {
+                    untrack_stream(stream_id_str);
+                    streams.remove(stream_id_str).map(|stream| stream.inner)
+                },
+                None => None,
+            },
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+        let stream = match stream {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        let config = match tls_client_config() {
+            Ok(c) => c,
+            Err(e) => {
+                log_error!("Failed to build TLS config: {:?}", e);
+                return error_code(&e, 0);
+            }
+        };
+
+        match block_on_runtime(TlsConnector::from(config).connect(server_name, stream)) {
+            Ok(Ok(tls_stream)) => {
+                match TLS_STREAMS.lock() {
+                    Ok(mut streams) => {
+                        track_stream(&circuit_id, &tls_stream_id, "");
+                        streams.insert(tls_stream_id.clone(), StdArc::new(Mutex::new(tls_stream)));
+                    },
+                    Err(_) => {
+                        log_error!("Failed to lock TLS streams mutex");
+                        return ERR_INTERNAL;
+                    }
+                }
+                write_c_buffer(&tls_stream_id, out_tls_stream_id, out_len);
+                log_debug!("Stream {} upgraded to TLS stream {}", stream_id_str, tls_stream_id);
+                1
+            },
+            Ok(Err(e)) => {
+                log_error!("TLS handshake failed: {:?}", e);
+                0
+            },
+            Err(e) => {
+                log_error!("Failed to run TLS handshake: {:?}", e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Build several isolated circuits and report the exit IP addresses they use
+///
+/// Each circuit asks check.torproject.org for the address its request came from.
+/// The result is a null-terminated JSON array of the distinct addresses with the
+/// number of circuits that used each, most common first, e.g.
+/// `[{"ip": "185.220.101.1", "count": 2}, {"ip": "192.42.116.16", "count": 1}]`.
+/// Circuits that fail or time out are left out, so the counts may add up to less
+/// than count. The temporary circuits are destroyed before returning.
+///
+/// @param count Number of circuits to build, between 1 and 64
+/// @param timeout_ms Time each circuit has to report its exit IP, in milliseconds
+/// @param out_json Output buffer that will receive the JSON array
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_NOT_INITIALIZED (-1) if the client is not bootstrapped,
+///         ERR_INVALID_PARAMS (-4) if count or timeout_ms is out of range,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_sample_exit_ips(
+    count: c_int,
+    timeout_ms: u64,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_sample_exit_ips");
+            return 0;
+        }
+        if !(1..=MAX_EXIT_IP_SAMPLES).contains(&count) || timeout_ms == 0 {
+            log_error!("Invalid count {} or timeout {} in arti_sample_exit_ips", count, timeout_ms);
+            return ERR_INVALID_PARAMS;
+        }
+        if !client_ready() {
+            log_error!("Cannot sample exit IPs: the Tor client is not bootstrapped, call arti_init first");
+            return ERR_NOT_INITIALIZED;
+        }
+
+        let counts = match sample_exit_ips(count as usize, Duration::from_millis(timeout_ms)) {
+            Ok(c) => c,
+            Err(e) => {
+                log_error!("Failed to sample exit IPs: {:?}", e);
+                return error_code(&e, 0);
+            }
+        };
+
+        let json = serde_json::Value::Array(counts.into_iter()
+            .map(|(ip, count)| serde_json::json!({ "ip": ip.to_string(), "count": count }))
+            .collect()).to_string();
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Exit IP buffer too small: {} bytes needed", json.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Start writing data to a stream in the background
+///
+/// Returns immediately with an operation ID; use arti_write_poll to learn when
+/// the write has finished. The data is copied, so the caller may free it as soon
+/// as this returns. Writes started on the same stream are sent one after another,
+/// in the order they were started.
+///
+/// @param stream_id The stream ID
+/// @param data The data to write
+/// @param data_len The length of the data
+/// @return A non-zero operation ID on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_write_stream_start(
+    stream_id: *const c_char,
+    data: *const c_char,
+    data_len: c_int,
+) -> u64 {
+    ffi_boundary(0, || {
+        if stream_id.is_null() || data.is_null() || data_len <= 0 {
+            log_error!("Invalid parameters in arti_write_stream_start");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let data = unsafe {
+            std::slice::from_raw_parts(data as *const u8, data_len as usize)
+        }.to_vec();
+
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+
+        // Queue behind the stream's previous write so writes never overtake each other
+        let op_id = NEXT_WRITE_OP.fetch_add(1, Ordering::Relaxed);
+        let (done, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let previous = match WRITE_QUEUES.lock() {
+            Ok(mut queues) => queues.insert(stream_id_str.clone(), (op_id, done_rx)).map(|(_, rx)| rx),
+            Err(_) => {
+                log_error!("Failed to lock write queues mutex");
+                return 0;
+            }
+        };
+
+        let task = handle.spawn(async move {
+            if let Some(previous) = previous {
+                // An error only means the previous write's task is gone, which also frees the queue
+                let _ = previous.await;
+            }
+
+            // The stream maps use blocking locks, so the write runs on a blocking thread
+            let queue_id = stream_id_str.clone();
+            let result = match tokio::task::spawn_blocking(move || write_stream_all(&stream_id_str, &data)).await {
+                Ok(result) => result,
+                Err(e) => Err(anyhow!("Write task failed: {}", e)),
+            };
+
+            if let Ok(mut queues) = WRITE_QUEUES.lock() {
+                if queues.get(&queue_id).map(|(id, _)| *id) == Some(op_id) {
+                    queues.remove(&queue_id);
+                }
+            }
+            drop(done);
+            result
+        });
+
+        match PENDING_WRITES.lock() {
+            Ok(mut pending) => {
+                pending.insert(op_id, task);
+                op_id
+            },
+            Err(_) => {
+                log_error!("Failed to lock pending writes mutex");
+                0
+            }
+        }
+    })
+}
+
+/// Check whether a write started with arti_write_stream_start has finished
+///
+/// Once the write is reported done the operation ID is released.
+///
+/// @param op_id The operation ID returned by arti_write_stream_start
+/// @param out_done Output parameter set to 1 if the write has finished, 0 if it is in progress
+/// @param out_err Output parameter set to 0 if the finished write succeeded, or to
+///         ERR_CONNECTION_FAILED (-2) if it failed
+/// @return 1 on success, ERR_INVALID_PARAMS (-4) for an unknown operation ID or null pointers,
+///         ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_write_poll(op_id: u64, out_done: *mut c_int, out_err: *mut c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if out_done.is_null() || out_err.is_null() {
+            log_error!("Invalid parameters in arti_write_poll");
+            return ERR_INVALID_PARAMS;
+        }
+
+        let task = {
+            let mut pending = match PENDING_WRITES.lock() {
+                Ok(p) => p,
+                Err(_) => {
+                    log_error!("Failed to lock pending writes mutex");
+                    return ERR_INTERNAL;
+                }
+            };
+
+            match pending.get(&op_id) {
+                Some(task) if !task.is_finished() => {
+                    unsafe {
+                        *out_done = 0;
+                        *out_err = 0;
+                    }
+                    return 1;
+                },
+                Some(_) => pending.remove(&op_id),
+                None => None,
+            }
+        };
+
+        let task = match task {
+            Some(t) => t,
+            None => {
+                log_error!("Unknown write operation: {}", op_id);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        let err = match block_on_runtime(task) {
+            Ok(Ok(Ok(()))) => 0,
+            Ok(Ok(Err(e))) => {
+                log_error!("Failed to write to stream: {:?}", e);
+                error_code(&e, ERR_CONNECTION_FAILED)
+            },
+            Ok(Err(e)) => {
+                log_error!("Write task failed: {}", e);
+                ERR_INTERNAL
+            },
+            Err(e) => {
+                log_error!("Failed to get write result: {:?}", e);
+                return ERR_INTERNAL;
+            }
+        };
+
+        unsafe {
+            *out_done = 1;
+            *out_err = err;
+        }
+        1
+    })
+}
+
+/// Route Tor's own connections to the network through an upstream proxy
+///
+/// Meant for networks where Tor can only get out through a proxy. arti-client
+/// 0.10 cannot connect to relays through a proxy, so after validating the URL
+/// this always returns ERR_NOT_SUPPORTED for now and nothing is configured. Must
+/// be called before arti_init.
+///
+/// @param socks_url The proxy, e.g. "socks5://127.0.0.1:1080" (socks4, socks4a and socks5 are accepted)
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the URL is malformed,
+///         ERR_NOT_SUPPORTED (-11) if upstream proxies are not supported
+#[no_mangle]
+pub extern "C" fn arti_set_upstream_proxy(socks_url: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if socks_url.is_null() {
+            log_error!("Invalid parameters in arti_set_upstream_proxy");
+            return 0;
+        }
+
+        let proxy = match unsafe { CStr::from_ptr(socks_url) }.to_str()
+            .map_err(|e| anyhow!(e))
+            .and_then(|s| Ok(url::Url::parse(s)?))
+        {
+            Ok(u) => u,
+            Err(e) => {
+                log_error!("Invalid upstream proxy URL: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        if !matches!(proxy.scheme(), "socks4" | "socks4a" | "socks5") || proxy.host_str().is_none() || proxy.port().is_none() {
+            log_error!("Upstream proxy must be a socks4://, socks4a:// or socks5:// URL with a host and port: {}", proxy);
+            return ERR_INVALID_PARAMS;
+        }
+
+        if is_connected().unwrap_or(false) {
+            log_error!("The upstream proxy must be set before the Tor client is initialized");
+            return 0;
+        }
+
+        log_error!("Cannot use upstream proxy {}: connecting to relays through a proxy is not supported by this version of Arti", proxy);
+        ERR_NOT_SUPPORTED
+    })
+}
+
+/// Connect to a target through Tor and describe how the connect went
+///
+/// Behaves like arti_connect_stream and also writes a null-terminated JSON object
+/// such as `{"stream_id": "c-stream-1", "connect_ms": 840, "reused_circuit": false,
+/// "exit_nickname": null}`. reused_circuit is true when an earlier stream on the same
+/// circuit ID had already built a path, so this connect didn't have to wait for one.
+/// Arti doesn't report relay nicknames, so exit_nickname is always null for now.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) if the host or port is malformed,
+///         ERR_BUFFER_TOO_SMALL (-9) if either buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_detailed(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+    out_json: *mut c_char,
+    out_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if circuit_id.is_null() || out_json.is_null() || out_len <= 0 {
+            log_error!("Invalid parameters in arti_connect_stream_detailed");
+            return 0;
+        }
+
+        let circuit_id_str = match unsafe { CStr::from_ptr(circuit_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid circuit ID string");
+                return 0;
+            }
+        };
+
+        // A recorded build time means an earlier stream already built this circuit's path
+        let reused_circuit = CIRCUIT_BUILD_TIMES.lock()
+            .map(|times| times.contains_key(circuit_id_str))
+            .unwrap_or(false);
+
+        let started = Instant::now();
+        let result = connect_stream_with_prefs(circuit_id, target_host, target_port, stream_id, stream_id_len, &default_stream_prefs());
+        if result != 1 {
+            return result;
+        }
+        let connect_ms = started.elapsed().as_millis() as u64;
+
+        let stream_id_str = unsafe { CStr::from_ptr(stream_id) }.to_string_lossy().into_owned();
+        let json = serde_json::json!({
+            "stream_id": stream_id_str,
+            "connect_ms": connect_ms,
+            "reused_circuit": reused_circuit,
+            "exit_nickname": serde_json::Value::Null,
+        }).to_string();
+
+        if !write_c_buffer(&json, out_json, out_len) {
+            log_error!("Connect result buffer too small: {} bytes needed", json.len() + 1);
+            // Don't leave behind a stream the caller doesn't know succeeded
+            arti_close_stream(stream_id);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+
+        1
+    })
+}
+
+/// Connect to a target through Tor over a given IP address family and return a stream ID
+///
+/// Overrides arti_set_address_family_preference for this stream only. When the
+/// target has no address in the requested family, the exit refuses the stream and
+/// the connect fails.
+///
+/// @param circuit_id The circuit ID to use
+/// @param target_host The target hostname
+/// @param target_port The target port
+/// @param family 4 for IPv4 only, 6 for IPv6 only, or 0 for the global preference
+/// @param stream_id Output parameter that will receive a null-terminated string representing the stream ID
+/// @param stream_id_len Maximum length of the stream ID buffer
+/// @return 1 on success, 0 on failure, ERR_INVALID_PARAMS (-4) for an unknown family or a
+///         malformed host or port, ERR_BUFFER_TOO_SMALL (-9) if the stream ID buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_connect_stream_family(
+    circuit_id: *const c_char,
+    target_host: *const c_char,
+    target_port: i32,
+    family: c_int,
+    stream_id: *mut c_char,
+    stream_id_len: c_int,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        let mut prefs = default_stream_prefs();
+        match family {
+            0 => {},
+            4 => { prefs.ipv4_only(); },
+            6 => { prefs.ipv6_only(); },
+            _ => {
+                log_error!("Invalid address family: {}", family);
+                return ERR_INVALID_PARAMS;
+            }
+        }
+
+        connect_stream_with_prefs(circuit_id, target_host, target_port, stream_id, stream_id_len, &prefs)
+    })
+}
+
+/// Read one line from a stream
+///
+/// Reads up to and including the next newline, so CRLF-terminated lines end in
+/// "\r\n". Data received past the line is kept for the next read, so lines split
+/// across network reads, or several lines in one, are handled. If the line doesn't
+/// fit, the buffer is filled and the rest of the line is returned by the next call.
+/// At the end of the stream, whatever is left is returned without a newline, and
+/// then 0 bytes. Not available for streams split with arti_split_stream. Closing
+/// the stream from another thread ends the wait.
+///
+/// @param stream_id The stream ID
+/// @param buffer The buffer to store the line
+/// @param buffer_len The maximum length of the buffer
+/// @param bytes_read Output parameter that will receive the length of the line
+/// @param timeout_ms Maximum time to wait for a complete line in milliseconds, or 0 for none
+/// @return 1 on success, 0 on failure, ERR_TIMEOUT (-8) if no complete line arrived in time;
+///         any partial line stays buffered for the next read
+#[no_mangle]
+pub extern "C" fn arti_read_line(
+    stream_id: *const c_char,
+    buffer: *mut c_char,
+    buffer_len: c_int,
+    bytes_read: *mut c_int,
+    timeout_ms: u64,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || buffer.is_null() || buffer_len <= 0 || bytes_read.is_null() {
+            log_error!("Invalid parameters in arti_read_line");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+
+        let (mut stream, closed) = match take_stream(stream_id_str) {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            },
+            Err(e) => {
+                log_error!("Failed to take stream: {:?}", e);
+                return ERR_INTERNAL;
+            }
+        };
+
+        let buffer_slice = unsafe {
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_len as usize)
+        };
+
+        // A timed-out read_line is dropped between network reads, so nothing is lost.
+        // Closing the stream ends the wait, even without a timeout.
+        let read_result = handle.block_on(async {
+            let read = async {
+                if timeout_ms > 0 {
+                    tokio::time::timeout(Duration::from_millis(timeout_ms), stream.read_line(buffer_slice)).await
+                } else {
+                    Ok(stream.read_line(buffer_slice).await)
+                }
+            };
+            tokio::select! {
+                result = read => Some(result),
+                _ = closed.notified() => None,
+            }
+        });
+        return_stream(stream_id_str, stream);
+
+        let read_result = match read_result {
+            Some(r) => r,
+            None => {
+                log_error!("Stream closed while reading a line: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        match read_result {
+            Ok(Ok(n)) => {
+                unsafe { *bytes_read = n as c_int; }
+                1
+            },
+            Ok(Err(e)) => {
+                log_error!("Failed to read line from stream: {:?}", e);
+                0
+            },
+            Err(_) => {
+                log_debug!("Timed out waiting for a line on stream {}", stream_id_str);
+                ERR_TIMEOUT
+            }
+        }
+    })
+}
+
+/// Read and discard the data waiting on a stream
+///
+/// Keeps reading until max_bytes have been discarded, the stream ends, or no new
+/// data arrives for timeout_ms; with a timeout of 0 only data that has already
+/// arrived is discarded. Data buffered by arti_peek_stream is discarded too. Useful
+/// to reset a protocol or to clean up before closing.
+///
+/// @param stream_id The stream ID
+/// @param max_bytes Most bytes to discard, or 0 for no limit
+/// @param timeout_ms How long to wait for more data before stopping, in milliseconds
+/// @param out_drained Output parameter that will receive the number of bytes discarded
+/// @return 1 on success, 0 on failure; out_drained is set even when a read fails
+#[no_mangle]
+pub extern "C" fn arti_drain_stream(
+    stream_id: *const c_char,
+    max_bytes: u64,
+    timeout_ms: u64,
+    out_drained: *mut u64,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_drained.is_null() {
+            log_error!("Invalid parameters in arti_drain_stream");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+
+        let (mut stream, closed) = match take_stream(stream_id_str) {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            },
+            Err(e) => {
+                log_error!("Failed to take stream: {:?}", e);
+                return ERR_INTERNAL;
+            }
+        };
+
+        // With a zero timeout only data that has already arrived is discarded.
+        // Closing the stream stops the drain.
+        let limit = if max_bytes == 0 { u64::MAX } else { max_bytes };
+        let idle = Duration::from_millis(timeout_ms);
+        let mut drained: u64 = 0;
+        let drain_result = handle.block_on(async {
+            let mut chunk = [0u8; 16 * 1024];
+            while drained < limit {
+                let want = (limit - drained).min(chunk.len() as u64) as usize;
+                let read = tokio::time::timeout(idle, stream.read(&mut chunk[..want]));
+                let result = tokio::select! {
+                    result = read => result,
+                    _ = closed.notified() => break,
+                };
+                match result {
+                    Ok(Ok(0)) | Err(_) => break,
+                    Ok(Ok(n)) => drained += n as u64,
+                    Ok(Err(e)) => return Err(e),
+                }
+            }
+            Ok(())
+        });
+        return_stream(stream_id_str, stream);
+
+        unsafe { *out_drained = drained; }
+        match drain_result {
+            Ok(()) => {
+                log_debug!("Drained {} bytes from stream {}", drained, stream_id_str);
+                1
+            },
+            Err(e) => {
+                log_error!("Failed to drain stream: {:?}", e);
+                0
+            }
+        }
+    })
+}
+
+/// Keep a long-lived stream from being treated as idle, and notice when it dies
+///
+/// Tor has no stream-level keepalive message and sending bytes would corrupt the
+/// application protocol, so nothing is sent. Instead, every interval_ms a background
+/// task probes the stream without consuming data, as arti_stream_readable does, and
+/// counts the probe as activity so arti_set_idle_timeout_ms leaves the stream open.
+/// When the probe finds the stream closed or failed, the stream is removed and the
+/// stream closed event is sent to the arti_set_event_callback callback. Data that
+/// arrives during a probe stays buffered for the next read. Streams that are busy in
+/// a blocking read or write are skipped until the next interval. Applies to streams
+/// opened with the arti_connect_stream family.
+///
+/// @param stream_id The stream ID
+/// @param interval_ms How often to probe the stream, in milliseconds, or 0 to stop probing
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_stream_keepalive(stream_id: *const c_char, interval_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() {
+            log_error!("Invalid parameters in arti_set_stream_keepalive");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let handle = match runtime_handle() {
+            Ok(h) => h,
+            Err(e) => {
+                log_error!("Failed to get runtime: {:?}", e);
+                return 0;
+            }
+        };
+
+        let mut streams = match STREAMS.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to lock streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        let stream = match streams.get_mut(stream_id_str) {
+            Some(s) => s,
+            None => {
+                log_error!("Stream not found: {}", stream_id_str);
+                return 0;
+            }
+        };
+
+        if let Some(previous) = stream.keepalive.take() {
+            previous.abort();
+        }
+        if interval_ms > 0 {
+            let interval = Duration::from_millis(interval_ms);
+            stream.keepalive = Some(handle.spawn(keep_stream_alive(stream_id_str.to_string(), interval)));
+        }
+        1
+    })
+}
+
+// Probe a stream every interval until it is closed, removing it when the probe
+// finds the other end gone. Like the idle reaper, a busy streams map is skipped.
+async fn keep_stream_alive(stream_id: String, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let mut streams = match STREAMS.try_lock() {
+            Ok(s) => s,
+            Err(std::sync::TryLockError::WouldBlock) => continue,
+            Err(_) => return,
+        };
+
+        let stream = match streams.get_mut(&stream_id) {
+            Some(s) => s,
+            // Taken by a call that is reading it, so it is alive and in use
+            None if TAKEN_STREAMS.lock().map(|t| t.contains_key(&stream_id)).unwrap_or(false) => continue,
+            None => return,
+        };
+        stream.last_activity = Instant::now();
+        if !stream.read_ahead.is_empty() {
+            continue;
+        }
+
+        let mut probe = [0u8; 1];
+        let gone = match futures::FutureExt::now_or_never(stream.peek(&mut probe)) {
+            Some(Ok(0)) => true,
+            Some(Err(e)) => {
+                log_debug!("Keepalive probe failed on stream {}: {:?}", stream_id, e);
+                true
+            },
+            _ => false,
+        };
+
+        if gone {
+            log_info!("Stream closed by the remote end: {}", stream_id);
+            if let Some(mut stream) = streams.remove(&stream_id) {
+                // This task is the keepalive; let it finish instead of aborting itself
+                drop(stream.keepalive.take());
+            }
+            untrack_stream(&stream_id);
+            return;
+        }
+    }
+}
+
+/// Close several streams in one call
+///
+/// Closes plain, split and TLS streams alike. IDs that don't name an open stream
+/// are skipped, so one stale ID doesn't stop the rest of the batch.
+///
+/// @param stream_ids_json A JSON array of stream IDs, e.g. ["c-stream-1", "c-stream-2"]
+/// @return The number of streams closed, ERR_INVALID_PARAMS (-4) if the JSON is not an
+///         array of strings, ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_close_streams(stream_ids_json: *const c_char) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_ids_json.is_null() {
+            log_error!("Invalid parameters in arti_close_streams");
+            return 0;
+        }
+
+        let stream_ids: Vec<String> = match unsafe { CStr::from_ptr(stream_ids_json) }.to_str()
+            .map_err(|e| anyhow!(e))
+            .and_then(|s| Ok(serde_json::from_str(s)?))
+        {
+            Ok(ids) => ids,
+            Err(e) => {
+                log_error!("Invalid stream ID list: {:?}", e);
+                return ERR_INVALID_PARAMS;
+            }
+        };
+
+        match close_streams(&stream_ids) {
+            Ok(closed) => {
+                log_debug!("Closed {} of {} streams", closed, stream_ids.len());
+                closed.min(c_int::MAX as usize) as c_int
+            },
+            Err(e) => {
+                log_error!("Failed to close streams: {:?}", e);
+                error_code(&e, 0)
+            }
+        }
+    })
+}
+
+/// Set how long idle HTTP connections are kept for reuse
+///
+/// HTTP requests keep their connection open after the response has been read, so
+/// the next request on the same circuit to the same host and port can skip opening
+/// a new Tor stream and, for HTTPS, the TLS handshake. Connections are pooled per
+/// circuit and isolation token, never shared between circuits, and are dropped
+/// with their circuit. A connection is only reused after a complete, successful
+/// response; when a request fails, the circuit's pool is discarded. Connections
+/// already pooled are dropped when the setting changes. The default is 30 seconds.
+///
+/// @param ttl_ms How long an idle connection is kept, in milliseconds, or 0 to open a
+///        new connection for every request
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_set_http_pool_ttl_ms(ttl_ms: u64) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        HTTP_POOL_TTL_MS.store(ttl_ms, Ordering::Relaxed);
+        discard_pooled_connections(None);
+        1
+    })
+}
+
+/// Check that a configuration file can be used, without bootstrapping
+///
+/// Reads and parses the file the same way arti_init_with_config does and builds
+/// the client configuration from it, catching both TOML syntax errors and settings
+/// Arti rejects. No client is created, nothing is written to disk and the network
+/// is not touched. Paths in the file are not checked for existence.
+///
+/// @param config_path A null-terminated path to the configuration file
+/// @param out_error Output buffer that will receive a null-terminated description of
+///        what is wrong with the file, or NULL if not needed; left untouched if valid
+/// @param out_len Length of the output buffer
+/// @return 1 if the configuration is valid, 0 if it is not or on failure,
+///         ERR_BUFFER_TOO_SMALL (-9) if the file is invalid and the description doesn't fit
+#[no_mangle]
+pub extern "C" fn arti_validate_config(config_path: *const c_char, out_error: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if config_path.is_null() {
+            log_error!("Invalid parameters in arti_validate_config");
+            return 0;
+        }
+
+        let config_path_str = match unsafe { CStr::from_ptr(config_path) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Failed to convert config path to string");
+                return 0;
+            }
+        };
+
+        let e = match load_client_config(config_path_str) {
+            Ok(_) => return 1,
+            Err(e) => e,
+        };
+
+        let message = format!("{:#}", e);
+        log_debug!("Invalid configuration {}: {}", config_path_str, message);
+        if !out_error.is_null() && !write_c_buffer(&message, out_error, out_len) {
+            log_error!("Error buffer too small: {} bytes needed", message.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+        0
+    })
+}
+
+/// One buffer of a scatter read, as used by arti_read_stream_vectored
+#[repr(C)]
+pub struct ArtiIoVec {
+    /// Start of the buffer
+    pub base: *mut u8,
+    /// Length of the buffer in bytes
+    pub len: usize,
+}
+
+/// Read data from a stream into several buffers at once
+///
+/// Makes a single read of up to the combined length of the buffers, but at most
+/// 64 KiB, and spreads the data across them in order, each one filled before the
+/// next is used, so a caller can keep e.g. a fixed-size header apart from the
+/// body. Like arti_read_stream this returns whatever data is available, which may
+/// fill only the first buffers or part of one; buffers past out_total_read are
+/// untouched.
+/// Works on plain streams and split read halves.
+///
+/// @param stream_id The stream ID
+/// @param iovecs Array of count buffers; zero-length entries are skipped
+/// @param count Number of entries in iovecs
+/// @param out_total_read Output parameter that will receive the number of bytes read, 0 at end of stream
+/// @return 1 on success, 0 on failure
+#[no_mangle]
+pub extern "C" fn arti_read_stream_vectored(
+    stream_id: *const c_char,
+    iovecs: *const ArtiIoVec,
+    count: usize,
+    out_total_read: *mut usize,
+) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || iovecs.is_null() || count == 0 || out_total_read.is_null() {
+            log_error!("Invalid parameters in arti_read_stream_vectored");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let iovecs = unsafe { std::slice::from_raw_parts(iovecs, count) };
+        if iovecs.iter().any(|iov| iov.base.is_null() && iov.len > 0) {
+            log_error!("Invalid parameters in arti_read_stream_vectored");
+            return 0;
+        }
+        let total_len = iovecs.iter().fold(0usize, |total, iov| total.saturating_add(iov.len));
+        if total_len == 0 {
+            log_error!("Invalid parameters in arti_read_stream_vectored");
+            return 0;
+        }
+
+        let mut chunk = vec![0u8; total_len.min(MAX_VECTORED_READ)];
+        let read_result = if let Some(reader) = split_half(&SPLIT_READERS, stream_id_str) {
+            read_split_half(&reader, &mut chunk)
+        } else {
+            let handle = match runtime_handle() {
+                Ok(h) => h,
+                Err(e) => {
+                    log_error!("Failed to get runtime: {:?}", e);
+                    return 0;
+                }
+            };
+
+            let mut streams = match STREAMS.lock() {
+                Ok(s) => s,
+                Err(_) => {
+                    log_error!("Failed to lock streams mutex");
+                    return ERR_INTERNAL;
+                }
+            };
+
+            let stream = match streams.get_mut(stream_id_str) {
+                Some(s) => s,
+                None => {
+                    log_error!("Stream not found: {}", stream_id_str);
+                    return 0;
+                }
+            };
+
+            handle.block_on(async {
+                let n = stream.read(&mut chunk).await?;
+                throttle(n).await;
+                Ok(n)
+            })
+        };
+
+        let n = match read_result {
+            Ok(n) => n,
+            Err(e) => {
+                log_error!("Failed to read from stream: {:?}", e);
+                return 0;
+            }
+        };
+
+        scatter_into_iovecs(&chunk[..n], iovecs);
+        unsafe { *out_total_read = n; }
+        log_debug!("Read {} bytes from stream into {} buffers", n, count);
+        1
+    })
+}
+
+// Copy data into the buffers in order, filling each before moving to the next.
+// The buffers must hold at least data.len() bytes between them.
+fn scatter_into_iovecs(data: &[u8], iovecs: &[ArtiIoVec]) {
+    let mut offset = 0;
+    for iov in iovecs {
+        if offset == data.len() {
+            break;
+        }
+        let part = iov.len.min(data.len() - offset);
+        if part > 0 {
+            unsafe { std::ptr::copy_nonoverlapping(data[offset..].as_ptr(), iov.base, part); }
+            offset += part;
+        }
+    }
+}
+
+/// Initialize the Arti Tor client with its state and cache in a temporary directory
+///
+/// Nothing is kept once the client shuts down, but this is not in-memory storage:
+/// arti-client 0.10 cannot hold its state and directory cache in memory and writes
+/// them to disk while the client runs. This puts both in a new directory under the
+/// system temp directory that only the current user can access, and deletes it
+/// when the client is shut down with arti_disconnect or arti_shutdown_all, or when
+/// initialization fails. Every session therefore starts with a fresh guard set and
+/// downloads the directory again, which makes bootstrap slower. For nothing to
+/// reach a disk at all, point TMPDIR (TMP on Windows) at a RAM-backed filesystem
+/// such as tmpfs. Storage directories set with arti_set_storage_dirs are ignored
+/// for the session and apply again afterwards; other settings made before init
+/// still apply.
+///
+/// @return 1 on success, 0 on failure, ERR_INTERNAL (-5) if internal state is broken
+#[no_mangle]
+pub extern "C" fn arti_init_temporary() -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if is_connected().unwrap_or(false) {
+            log_error!("The Tor client is already initialized");
+            return 0;
+        }
+
+        if let Err(e) = create_temporary_storage() {
+            log_error!("Failed to create temporary storage: {:?}", e);
+            return error_code(&e, 0);
+        }
+
+        match initialize_tor_client(None) {
+            Ok(()) => 1,
+            Err(e) => {
+                log_error!("Failed to initialize temporary Tor client: {:?}", e);
+                remove_temporary_storage();
+                init_error_code(&e)
+            }
+        }
+    })
+}
+
+/// Limit how many circuits can exist at once
+///
+/// Once the limit is reached, creating another circuit fails with ERR_CIRCUIT_LIMIT
+/// (-12) until one is destroyed with arti_destroy_circuit; each destroyed circuit
+/// frees a slot. Circuits on every client count towards the limit. Lowering the
+/// limit below the current number of circuits doesn't destroy any; it only stops
+/// new ones from being created.
+///
+/// @param max_circuits The most circuits allowed, or 0 for no limit (the default)
+/// @return 1 on success
+#[no_mangle]
+pub extern "C" fn arti_set_max_circuits(max_circuits: u32) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        MAX_CIRCUITS.store(max_circuits as u64, Ordering::Relaxed);
+        1
+    })
+}
+
+/// Get the status and headers of a response opened with arti_http_stream_open
+///
+/// The headers are available as soon as arti_http_stream_open returns, before
+/// any of the body has been read, so a caller can look at the status, length or
+/// type and close the stream without downloading the body. Reading the body
+/// doesn't change the result, and it can be called while a read is in progress.
+/// The result is a null-terminated JSON object:
+/// `{"status": 200, "url": "<final url>", "headers": [["name", "value"], ...]}`,
+/// with headers in wire order and duplicates such as Set-Cookie kept.
+///
+/// @param stream_id The HTTP stream ID
+/// @param out_json Output buffer that will receive the JSON object
+/// @param out_len Length of the output buffer
+/// @return 1 on success, 0 on failure or if the stream is unknown,
+///         ERR_BUFFER_TOO_SMALL (-9) if the buffer is too small
+#[no_mangle]
+pub extern "C" fn arti_http_stream_headers(stream_id: *const c_char, out_json: *mut c_char, out_len: c_int) -> c_int {
+    ffi_boundary(ERR_INTERNAL, || {
+        if stream_id.is_null() || out_json.is_null() {
+            log_error!("Invalid parameters in arti_http_stream_headers");
+            return 0;
+        }
+
+        let stream_id_str = match unsafe { CStr::from_ptr(stream_id) }.to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                log_error!("Invalid stream ID string");
+                return 0;
+            }
+        };
+
+        let head = match HTTP_STREAMS.lock() {
+            Ok(streams) => match streams.get(stream_id_str) {
+                Some(handle) => handle.head.clone(),
+                None => {
+                    log_error!("HTTP stream not found: {}", stream_id_str);
+                    return 0;
+                }
+            },
+            Err(_) => {
+                log_error!("Failed to lock HTTP streams mutex");
+                return ERR_INTERNAL;
+            }
+        };
+
+        if !write_c_buffer(&head, out_json, out_len) {
+            log_error!("Header buffer too small: {} bytes needed", head.len() + 1);
+            return ERR_BUFFER_TOO_SMALL;
+        }
+        1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that change global settings hold this so they don't see each other's changes
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn global_state() -> MutexGuard<'static, ()> {
+        TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn circuit_build_timeout_reaches_client_config() {
+        let _guard = global_state();
+
+        assert_eq!(arti_set_circuit_build_timeout_ms(12_345), 1);
+        let configured = build_client_config();
+        assert_eq!(arti_set_circuit_build_timeout_ms(0), 1);
+        let restored = build_client_config().unwrap();
+
+        assert!(format!("{:?}", configured.unwrap()).contains("request_timeout: 12.345s"));
+        assert!(!format!("{:?}", restored).contains("request_timeout: 12.345s"));
+    }
+
+    #[tokio::test]
+    async fn peek_then_read_returns_the_same_bytes() {
+        let (mut remote, local) = tokio::io::duplex(64);
+        let mut stream = BufferedStream::new(local);
+        remote.write_all(b"hello").await.unwrap();
+
+        let mut peeked = [0u8; 5];
+        assert_eq!(stream.peek(&mut peeked).await.unwrap(), 5);
+        assert_eq!(&peeked, b"hello");
+
+        // With data buffered, a larger peek returns what's there instead of waiting for more
+        let mut larger = [0u8; 16];
+        let n = futures::FutureExt::now_or_never(stream.peek(&mut larger))
+            .expect("peek waited although data was buffered")
+            .unwrap();
+        assert_eq!(&larger[..n], b"hello");
+
+        remote.write_all(b" world").await.unwrap();
+        let mut read = [0u8; 16];
+        let n = stream.read(&mut read).await.unwrap();
+        assert_eq!(&read[..n], b"hello");
+        let n = stream.read(&mut read).await.unwrap();
+        assert_eq!(&read[..n], b" world");
+    }
+
+    #[tokio::test]
+    async fn read_line_joins_split_lines_and_cuts_long_ones() {
+        let (mut remote, local) = tokio::io::duplex(64);
+        let mut stream = BufferedStream::new(local);
+        let mut buf = [0u8; 8];
+
+        // The first line arrives in two pieces
+        remote.write_all(b"ab").await.unwrap();
+        let (n, written) = tokio::join!(stream.read_line(&mut buf), async {
+            tokio::task::yield_now().await;
+            remote.write_all(b"c\n0123456789\nrest").await
+        });
+        written.unwrap();
+        assert_eq!(&buf[..n.unwrap()], b"abc\n");
+
+        // A line longer than the buffer comes back in buffer-sized pieces
+        let n = stream.read_line(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"01234567");
+        let n = stream.read_line(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"89\n");
+
+        // An unterminated last line is returned at the end of the stream
+        drop(remote);
+        let n = stream.read_line(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"rest");
+        assert_eq!(stream.read_line(&mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn tls_io_rejects_null_and_bad_lengths() {
+        let stream_id = CString::new("no-such-tls-stream").unwrap();
+        let mut buf = [0u8; 16];
+
+        assert_eq!(arti_tls_read(std::ptr::null(), buf.as_mut_ptr(), buf.len()), -1);
+        assert_eq!(arti_tls_read(stream_id.as_ptr(), std::ptr::null_mut(), buf.len()), -1);
+        assert_eq!(arti_tls_read(stream_id.as_ptr(), buf.as_mut_ptr(), 0), -1);
+        assert_eq!(arti_tls_read(stream_id.as_ptr(), buf.as_mut_ptr(), MAX_TLS_IO_LEN + 1), -1);
+
+        assert_eq!(arti_tls_write(std::ptr::null(), buf.as_ptr(), buf.len()), 0);
+        assert_eq!(arti_tls_write(stream_id.as_ptr(), std::ptr::null(), buf.len()), 0);
+        assert_eq!(arti_tls_write(stream_id.as_ptr(), buf.as_ptr(), 0), 0);
+        assert_eq!(arti_tls_write(stream_id.as_ptr(), buf.as_ptr(), MAX_TLS_IO_LEN + 1), 0);
+    }
+
+    #[test]
+    fn closing_a_stream_twice_reports_not_found() {
+        let stream_id = CString::new("test-http-close-twice").unwrap();
+        let response = reqwest::Response::from(http::Response::new("body"));
+        HTTP_STREAMS.lock().unwrap().insert(stream_id.to_str().unwrap().to_string(), HttpStreamHandle {
+            body: StdArc::new(tokio::sync::Mutex::new(HttpStream {
+                response,
+                pending: Vec::new(),
+                _permit: None,
+            })),
+            closed: StdArc::new(tokio::sync::Notify::new()),
+            head: "{}".to_string(),
+        });
+
+        assert_eq!(arti_http_stream_close(stream_id.as_ptr()), 1);
+        assert_eq!(arti_http_stream_close(stream_id.as_ptr()), ERR_STREAM_NOT_FOUND);
+
+        let stream_id = CString::new("test-stream-close-twice").unwrap();
+        assert_eq!(arti_close_stream(stream_id.as_ptr()), ERR_STREAM_NOT_FOUND);
+        assert_eq!(arti_close_stream(stream_id.as_ptr()), ERR_STREAM_NOT_FOUND);
+        assert_eq!(arti_close_tls_stream(stream_id.as_ptr()), ERR_STREAM_NOT_FOUND);
+    }
+
+    #[test]
+    fn circuit_limit_counts_only_new_ids() {
+        let _guard = global_state();
+        let circuits: HashMap<String, ()> = [("a".to_string(), ()), ("b".to_string(), ())].into_iter().collect();
+        let existing = ["a".to_string(), "b".to_string()];
+        let new = ["c".to_string(), "d".to_string()];
+
+        assert_eq!(arti_set_max_circuits(0), 1);
+        assert!(check_circuit_limit(&circuits, &new).is_ok());
+
+        assert_eq!(arti_set_max_circuits(3), 1);
+        assert!(check_circuit_limit(&circuits, &existing).is_ok());
+        assert!(check_circuit_limit(&circuits, &new[..1]).is_ok());
+        let err = check_circuit_limit(&circuits, &new).unwrap_err();
+        assert_eq!(error_code(&err, 0), ERR_CIRCUIT_LIMIT);
+
+        assert_eq!(arti_set_max_circuits(0), 1);
+    }
+
+    #[test]
+    fn vectored_read_fills_buffers_in_order() {
+        let mut header = [0u8; 4];
+        let mut empty = [0u8; 0];
+        let mut body = [0xffu8; 8];
+        let iovecs = [
+            ArtiIoVec { base: header.as_mut_ptr(), len: header.len() },
+            ArtiIoVec { base: empty.as_mut_ptr(), len: 0 },
+            ArtiIoVec { base: body.as_mut_ptr(), len: body.len() },
+        ];
+
+        scatter_into_iovecs(b"HEADbody", &iovecs);
+        assert_eq!(&header, b"HEAD");
+        // Only the bytes read are written; the rest of the buffer is untouched
+        assert_eq!(&body, b"body\xff\xff\xff\xff");
+
+        scatter_into_iovecs(b"he", &iovecs);
+        assert_eq!(&header, b"heAD");
+        assert_eq!(&body, b"body\xff\xff\xff\xff");
+    }
+
+    #[test]
+    fn validate_config_accepts_good_toml_and_explains_bad() {
+        let dir = std::env::temp_dir().join(format!("torpc-test-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let check = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            let path = CString::new(path.to_str().unwrap()).unwrap();
+            let mut error = [0 as c_char; 512];
+            let result = arti_validate_config(path.as_ptr(), error.as_mut_ptr(), error.len() as c_int);
+            (result, unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy().into_owned())
+        };
+
+        assert_eq!(check("empty.toml", ""), (1, String::new()));
+        assert_eq!(check("good.toml", "[circuit_timing]\nrequest_timeout = \"30s\"\n"), (1, String::new()));
+
+        let (result, error) = check("syntax.toml", "[circuit_timing\n");
+        assert_eq!(result, 0);
+        assert!(!error.is_empty());
+
+        let (result, error) = check("value.toml", "[circuit_timing]\nrequest_timeout = \"soon\"\n");
+        assert_eq!(result, 0);
+        assert!(!error.is_empty());
+
+        let missing = CString::new(dir.join("missing.toml").to_str().unwrap()).unwrap();
+        assert_eq!(arti_validate_config(missing.as_ptr(), std::ptr::null_mut(), 0), 0);
+        assert_eq!(arti_validate_config(std::ptr::null(), std::ptr::null_mut(), 0), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn format_target_brackets_ipv6_and_rejects_malformed_hosts() {
+        assert_eq!(format_target("example.com", 443).as_deref(), Some("example.com:443"));
+        assert_eq!(format_target("192.0.2.1", 80).as_deref(), Some("192.0.2.1:80"));
+        assert_eq!(format_target("2001:db8::1", 443).as_deref(), Some("[2001:db8::1]:443"));
+        assert_eq!(format_target("[2001:db8::1]", 443).as_deref(), Some("[2001:db8::1]:443"));
+
+        assert_eq!(format_target("example.com", 0), None);
+        assert_eq!(format_target("example.com", 65536), None);
+        assert_eq!(format_target("", 443), None);
+        assert_eq!(format_target("[example.com]", 443), None);
+        assert_eq!(format_target("example.com:443", 443), None);
+        assert_eq!(format_target("exa mple.com", 443), None);
+    }
+
+    #[test]
+    fn describe_invalid_target_explains_common_mistakes() {
+        assert_eq!(describe_invalid_target("example.com", 0), "port 0 is out of range");
+        assert!(describe_invalid_target("https://example.com", 443).contains("must not include a scheme"));
+        assert!(describe_invalid_target("example.com:8080", 443).contains("pass \"example.com\" and the port separately"));
+        assert!(describe_invalid_target("", 443).contains("is not a valid hostname or IP address"));
+    }
+
+    #[test]
+    fn panic_becomes_internal_error_with_last_error() {
+        let code = ffi_boundary(ERR_INTERNAL, || -> c_int { panic!("test panic at the FFI boundary") });
+        assert_eq!(code, ERR_INTERNAL);
+
+        let mut message = [0 as c_char; 128];
+        assert_eq!(arti_last_error(message.as_mut_ptr(), message.len() as c_int), 1);
+        let message = unsafe { CStr::from_ptr(message.as_ptr()) }.to_str().unwrap();
+        assert!(message.contains("test panic at the FFI boundary"), "{}", message);
+
+        let mut tiny = [0 as c_char; 4];
+        assert_eq!(arti_last_error(tiny.as_mut_ptr(), tiny.len() as c_int), ERR_BUFFER_TOO_SMALL);
+    }
+
+    #[test]
+    fn poisoned_lock_maps_to_internal_error() {
+        let mutex = Mutex::new(0);
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _held = mutex.lock().unwrap();
+            panic!("test panic while holding a lock");
+        }));
+
+        let err = lock(&mutex, "test").unwrap_err();
+        assert_eq!(err.to_string(), "Failed to lock test mutex");
+        assert_eq!(error_code(&err, 0), ERR_INTERNAL);
+        assert_eq!(error_code(&anyhow!("other failure"), 0), 0);
+    }
+
+    #[test]
+    fn unavailable_features_report_not_supported() {
+        #[cfg(not(feature = "onion-client"))]
+        {
+            let circuit = CString::new("test-circuit").unwrap();
+            let mut stream_id = [0 as c_char; 128];
+            let stream_id_len = stream_id.len() as c_int;
+            let onion = CString::new("duskgytldkxiuqc6.onion").unwrap();
+            assert_eq!(arti_connect_onion_stream(circuit.as_ptr(), onion.as_ptr(), 80, std::ptr::null(),
+                stream_id.as_mut_ptr(), stream_id_len), ERR_NOT_SUPPORTED);
+            assert_eq!(arti_onion_reachable(circuit.as_ptr(), onion.as_ptr(), 80, 1000), ERR_NOT_SUPPORTED);
+        }
+
+        #[cfg(not(feature = "experimental-api"))]
+        {
+            let mut info = [0 as c_char; 256];
+            assert_eq!(arti_get_directory_info(info.as_mut_ptr(), info.len() as c_int), ERR_NOT_SUPPORTED);
+            assert_eq!(arti_exit_allows_port(443), ERR_NOT_SUPPORTED);
+        }
+
+        #[cfg(not(feature = "pt-bridges"))]
+        {
+            let bridges = CString::new("[]").unwrap();
+            assert_eq!(arti_set_bridges(bridges.as_ptr()), ERR_NOT_SUPPORTED);
+        }
+
+        #[cfg(not(feature = "geoip"))]
+        {
+            let circuit = CString::new("test-circuit").unwrap();
+            let host = CString::new("example.com").unwrap();
+            let mut stream_id = [0 as c_char; 128];
+            let stream_id_len = stream_id.len() as c_int;
+            let country = CString::new("DE").unwrap();
+            assert_eq!(arti_connect_stream_country(circuit.as_ptr(), host.as_ptr(), 443, country.as_ptr(),
+                stream_id.as_mut_ptr(), stream_id_len), ERR_NOT_SUPPORTED);
+            let path = CString::new("/nonexistent/geoip").unwrap();
+            assert_eq!(arti_set_geoip_files(path.as_ptr(), path.as_ptr()), ERR_NOT_SUPPORTED);
+            let ip = CString::new("192.0.2.1").unwrap();
+            let mut country = [0 as c_char; 8];
+            assert_eq!(arti_geoip_lookup(ip.as_ptr(), country.as_mut_ptr(), country.len() as c_int), ERR_NOT_SUPPORTED);
+        }
+
+        // Not available with arti-client 0.10 whatever the features
+        let proxy = CString::new("socks5://127.0.0.1:1080").unwrap();
+        assert_eq!(arti_set_upstream_proxy(proxy.as_ptr()), ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn default_http_headers_are_validated() {
+        let _guard = global_state();
+        let original = DEFAULT_HTTP_HEADERS.lock().unwrap().clone();
+        let set = |json: &str| {
+            let json = CString::new(json).unwrap();
+            arti_set_default_http_headers(json.as_ptr())
+        };
+
+        assert_eq!(set(r#"{"X-Test": "1"}"#), 1);
+        let expected = vec![("X-Test".to_string(), "1".to_string())];
+        assert_eq!(*DEFAULT_HTTP_HEADERS.lock().unwrap(), expected);
+
+        // Rejected headers leave the defaults unchanged
+        assert_eq!(set("not json"), ERR_INVALID_PARAMS);
+        assert_eq!(set(r#"["X-Test", "1"]"#), ERR_INVALID_PARAMS);
+        assert_eq!(set(r#"{"X-Test": 1}"#), ERR_INVALID_PARAMS);
+        assert_eq!(set(r#"{"Bad Name": "1"}"#), ERR_INVALID_PARAMS);
+        assert_eq!(set(r#"{"X-Test": "line\nbreak"}"#), ERR_INVALID_PARAMS);
+        assert_eq!(*DEFAULT_HTTP_HEADERS.lock().unwrap(), expected);
+        assert_eq!(arti_set_default_http_headers(std::ptr::null()), 0);
+
+        assert_eq!(set("{}"), 1);
+        assert!(DEFAULT_HTTP_HEADERS.lock().unwrap().is_empty());
+
+        *DEFAULT_HTTP_HEADERS.lock().unwrap() = original;
+    }
+
+    // Feed a SOCKS client's bytes to serve_socks_connection and collect the replies
+    async fn socks_exchange(circuit_id: Option<&str>, auth: &SocksAuth, request: &[u8]) -> (Result<()>, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let result = serve_socks_connection(circuit_id, None, None, auth, server).await;
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        (result, replies)
+    }
+
+    #[tokio::test]
+    async fn internal_socks_proxy_requires_its_credentials() {
+        let auth = SocksAuth::Required { username: "user".to_string(), password: "secret".to_string() };
+        let circuit = Some("test-no-such-circuit");
+
+        // No authentication offered
+        let (result, replies) = socks_exchange(circuit, &auth, &[5, 1, 0]).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 0xff]);
+
+        // Wrong password
+        let (result, replies) = socks_exchange(circuit, &auth, b"\x05\x01\x02\x01\x04user\x05wrong").await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 2, 1, 1]);
+
+        // The right credentials get as far as connecting, which fails for lack of a circuit
+        let (result, replies) = socks_exchange(circuit, &auth,
+            b"\x05\x01\x02\x01\x04user\x06secret\x05\x01\x00\x03\x0bexample.com\x00\x50").await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 2, 1, 0, 5, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    // Start a background task that owns a connect token, the way arti_connect_stream_cancelable does
+    fn start_fake_connect(outcome: ConnectOutcome, finish: bool) -> u64 {
+        let handle = runtime_handle().unwrap();
+        let mut pending = PENDING_CONNECTS.lock().unwrap();
+        let token = NEXT_CANCEL_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let guard = PendingConnect { token, outcome: ConnectOutcome::Cancelled };
+        let task = handle.spawn(async move {
+            let mut guard = guard;
+            if !finish {
+                futures::future::pending::<()>().await;
+            }
+            guard.outcome = outcome;
+        });
+        pending.insert(token, task.abort_handle());
+        token
+    }
+
+    fn poll_until_done(token: u64, buffer: &mut [c_char]) -> c_int {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let code = arti_connect_poll(token, buffer.as_mut_ptr(), buffer.len() as c_int);
+            if code != 0 || Instant::now() > deadline {
+                return code;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn background_connects_clean_up_after_themselves() {
+        let _state = global_state();
+        let mut buffer = [0 as c_char; 64];
+
+        // A cancelled connect leaves PENDING_CONNECTS as soon as it is aborted
+        let token = start_fake_connect(ConnectOutcome::Cancelled, false);
+        assert_eq!(arti_connect_poll(token, buffer.as_mut_ptr(), 64), 0);
+        assert_eq!(arti_cancel(token), 1);
+        assert_eq!(poll_until_done(token, &mut buffer), ERR_CANCELLED);
+        assert!(!PENDING_CONNECTS.lock().unwrap().contains_key(&token));
+        assert!(!FINISHED_CONNECTS.lock().unwrap().contains_key(&token));
+        assert_eq!(arti_cancel(token), 0);
+
+        // The stream ID is only handed out once connected, and a short buffer keeps the token
+        let token = start_fake_connect(ConnectOutcome::Connected("circuit-1-stream-1".to_string()), true);
+        let mut short = [0 as c_char; 4];
+        assert_eq!(poll_until_done(token, &mut short), ERR_BUFFER_TOO_SMALL);
+        assert_eq!(poll_until_done(token, &mut buffer), 1);
+        assert_eq!(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap(), "circuit-1-stream-1");
+        assert_eq!(arti_connect_poll(token, buffer.as_mut_ptr(), 64), ERR_INVALID_PARAMS);
+
+        let token = start_fake_connect(ConnectOutcome::Failed("refused".to_string()), true);
+        assert_eq!(poll_until_done(token, &mut buffer), ERR_CONNECTION_FAILED);
+        assert!(!PENDING_CONNECTS.lock().unwrap().contains_key(&token));
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[tokio::test]
+    async fn gzip_bodies_survive_with_and_without_decompression() {
+        let text = b"hello hello hello hello";
+        let compressed = gzip(text);
+        let gzip_response = || {
+            let response = http::Response::builder()
+                .header("Content-Encoding", "gzip")
+                .header("Content-Length", compressed.len().to_string())
+                .body(compressed.clone())
+                .unwrap();
+            reqwest::Response::from(response)
+        };
+
+        // Untouched: the compressed bytes come back base64-encoded, matching Content-Length
+        let options = HttpOptions::default();
+        let response = read_http_response(gzip_response(), &options).await.unwrap();
+        assert_eq!(response.body_encoding, "base64");
+        assert_eq!(response.content_encoding, None);
+        let body = base64::engine::general_purpose::STANDARD.decode(&response.body).unwrap();
+        assert_eq!(body, compressed);
+        assert_eq!(header(&response, "content-length"), Some(compressed.len().to_string().as_str()));
+        assert_eq!(header(&response, "content-encoding"), Some("gzip"));
+
+        // Decompressed: the text comes back as is and the headers describe it
+        let options = HttpOptions { decompress: true, ..HttpOptions::default() };
+        let response = read_http_response(gzip_response(), &options).await.unwrap();
+        assert_eq!(response.body_encoding, "utf-8");
+        assert_eq!(response.content_encoding.as_deref(), Some("gzip"));
+        assert_eq!(response.body.as_bytes(), text);
+        assert_eq!(header(&response, "content-length"), Some(text.len().to_string().as_str()));
+        assert_eq!(header(&response, "content-encoding"), None);
+    }
+
+    #[tokio::test]
+    async fn repeated_and_non_ascii_headers_are_kept() {
+        let response = http::Response::builder()
+            .header("Set-Cookie", "a=1")
+            .header("X-Name", http::HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap())
+            .header("Set-Cookie", "b=2")
+            .body("")
+            .unwrap();
+
+        let response = read_http_response(reqwest::Response::from(response), &HttpOptions::default()).await.unwrap();
+        let cookies: Vec<&str> = response.headers.iter()
+            .filter(|(name, _)| name == "set-cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(header(&response, "x-name"), Some("café"));
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn geoip_files_load_for_lookups() {
+        let _state = global_state();
+        let dir = std::env::temp_dir().join(format!("arti-ffi-geoip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            CString::new(path.to_str().unwrap()).unwrap()
+        };
+        let v4 = path("geoip", "# comment\n16777216,16777471,AU\n3221225984,3221226239,DE\n");
+        let v6 = path("geoip6", "2001:db8::,2001:db8::ffff,NL\n");
+        let bad = path("bad", "1,2\n");
+        let missing = CString::new(dir.join("missing").to_str().unwrap()).unwrap();
+
+        let lookup = |ip: &str| {
+            let ip = CString::new(ip).unwrap();
+            let mut country = [0 as c_char; 8];
+            let code = arti_geoip_lookup(ip.as_ptr(), country.as_mut_ptr(), country.len() as c_int);
+            (code, unsafe { CStr::from_ptr(country.as_ptr()) }.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(arti_set_geoip_files(bad.as_ptr(), v6.as_ptr()), ERR_INVALID_PARAMS);
+        assert_eq!(arti_set_geoip_files(missing.as_ptr(), v6.as_ptr()), ERR_INVALID_PARAMS);
+        assert_eq!(arti_set_geoip_files(v4.as_ptr(), v6.as_ptr()), 1);
+
+        assert_eq!(lookup("1.0.0.7"), (1, "AU".to_string()));
+        assert_eq!(lookup("192.0.2.7"), (1, "DE".to_string()));
+        assert_eq!(lookup("2001:db8::42"), (1, "NL".to_string()));
+        assert_eq!(lookup("1.0.1.0").0, 0);
+        assert_eq!(lookup("not an ip").0, ERR_INVALID_PARAMS);
+
+        // Exits can't be picked with the loaded files, so country selection is refused up front
+        let circuit = CString::new("circuit-1").unwrap();
+        let host = CString::new("example.com").unwrap();
+        let country = CString::new("DE").unwrap();
+        let mut stream_id = [0 as c_char; 64];
+        assert_eq!(arti_connect_stream_country(circuit.as_ptr(), host.as_ptr(), 80, country.as_ptr(),
+            stream_id.as_mut_ptr(), stream_id.len() as c_int), ERR_NOT_SUPPORTED);
+
+        *GEOIP_DB.lock().unwrap() = None;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temporary_storage_leaves_nothing_behind() {
+        let _state = global_state();
+        let (state_dir, cache_dir) = {
+            let overrides = CONFIG_OVERRIDES.lock().unwrap();
+            (overrides.state_dir.clone(), overrides.cache_dir.clone())
+        };
+
+        create_temporary_storage().unwrap();
+        let dir = TEMPORARY_STORAGE.lock().unwrap().as_ref().unwrap().dir.clone();
+        assert!(dir.is_dir());
+        {
+            let overrides = CONFIG_OVERRIDES.lock().unwrap();
+            assert!(overrides.state_dir.as_deref().unwrap().starts_with(dir.to_str().unwrap()));
+            assert!(overrides.cache_dir.as_deref().unwrap().starts_with(dir.to_str().unwrap()));
+        }
+        // Stand in for what Arti writes while the client runs
+        std::fs::create_dir_all(dir.join("state")).unwrap();
+        std::fs::write(dir.join("state").join("state.json"), "{}").unwrap();
+
+        remove_temporary_storage();
+        assert!(!dir.exists());
+        assert!(TEMPORARY_STORAGE.lock().unwrap().is_none());
+        let overrides = CONFIG_OVERRIDES.lock().unwrap();
+        assert_eq!(overrides.state_dir, state_dir);
+        assert_eq!(overrides.cache_dir, cache_dir);
+    }
+
+    #[test]
+    fn rate_limit_slows_writes_down() {
+        let _state = global_state();
+        assert_eq!(arti_set_rate_limit(1000), 1);
+
+        // The bucket starts empty, so 500 bytes at 1000 bytes/s take at least half a second
+        let started = Instant::now();
+        Runtime::new().unwrap().block_on(async {
+            for _ in 0..5 {
+                throttle(100).await;
+            }
+        });
+        let elapsed = started.elapsed();
+
+        assert_eq!(arti_set_rate_limit(0), 1);
+        assert!(elapsed >= Duration::from_millis(450), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+    }
+
+    // Feed a raw response to read_http1_response through an in-memory pipe
+    fn parse_http1(raw: &[u8], is_head: bool) -> Result<Vec<u8>> {
+        Runtime::new().unwrap().block_on(async {
+            let (mut server, client) = tokio::io::duplex(raw.len().max(1));
+            server.write_all(raw).await.unwrap();
+            drop(server);
+            read_http1_response(&mut tokio::io::BufReader::new(client), is_head).await
+        })
+    }
+
+    #[test]
+    fn http1_responses_are_parsed_from_raw_bytes() {
+        let _state = global_state();
+
+        // Chunk extensions are ignored and trailers are dropped
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let raw = format!("{}4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Checksum: abc\r\n\r\n", head);
+        assert_eq!(parse_http1(raw.as_bytes(), false).unwrap(), format!("{}Wikipedia", head).into_bytes());
+
+        // Interim responses are kept ahead of the final one
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+        assert_eq!(parse_http1(raw, false).unwrap(), raw.to_vec());
+
+        // HEAD and 204 responses end with the head, whatever they announce
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(parse_http1(raw, true).unwrap(), raw.to_vec());
+        let raw = b"HTTP/1.1 204 No Content\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(parse_http1(raw, false).unwrap(), raw.to_vec());
+
+        // A connection that ends inside a chunk or before the last chunk is an error
+        let truncated = format!("{}a\r\nabc", head);
+        let err = parse_http1(truncated.as_bytes(), false).unwrap_err();
+        assert!(err.to_string().contains("inside a chunk"), "{}", err);
+        let unterminated = format!("{}3\r\nabc\r\n", head);
+        assert!(parse_http1(unterminated.as_bytes(), false).is_err());
+        let short = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(parse_http1(short, false).is_err());
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected() {
+        let _state = global_state();
+        let runtime = Runtime::new().unwrap();
+        let read = |body: Vec<u8>, encoding: Option<&str>, decompress: bool| {
+            let mut response = http::Response::builder();
+            if let Some(encoding) = encoding {
+                response = response.header("Content-Encoding", encoding);
+            }
+            let response = reqwest::Response::from(response.body(body).unwrap());
+            let options = HttpOptions { decompress, ..HttpOptions::default() };
+            runtime.block_on(read_http_response(response, &options))
+        };
+        assert_eq!(arti_set_max_http_body(64), 1);
+
+        assert!(read(vec![b'a'; 64], None, false).is_ok());
+        let err = read(vec![b'a'; 65], None, false).unwrap_err();
+        assert_eq!(http_error_code(&err), ERR_BODY_TOO_LARGE);
+
+        // A small compressed body that inflates past the limit
+        let compressed = gzip(&[b'a'; 1000]);
+        assert!(compressed.len() <= 64);
+        assert!(read(compressed.clone(), Some("gzip"), false).is_ok());
+        let err = read(compressed.clone(), Some("gzip"), true).unwrap_err();
+        assert_eq!(http_error_code(&err), ERR_BODY_TOO_LARGE);
+
+        let err = decode_body(Some("gzip"), compressed.clone(), 999).unwrap_err();
+        assert_eq!(http_error_code(&err), ERR_BODY_TOO_LARGE);
+        let (decoded, encoding) = decode_body(Some("gzip"), compressed, 1000).unwrap();
+        assert_eq!(decoded.len(), 1000);
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+
+        assert_eq!(arti_set_max_http_body(0), 1);
+    }
+
+    #[test]
+    fn state_lock_conflicts_map_to_state_locked() {
+        assert_eq!(init_kind_code(ErrorKind::LocalResourceAlreadyInUse), Some(ERR_STATE_LOCKED));
+        assert_eq!(init_kind_code(ErrorKind::TorNetworkTimeout), None);
+
+        // Errors that don't come from Arti keep their usual codes
+        assert_eq!(init_error_code(&anyhow!("no config")), 0);
+        assert_eq!(init_error_code(&LockPoisoned("client").into()), ERR_INTERNAL);
+    }
+
+    static LOGGED: Mutex<Vec<(c_int, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record_log(level: c_int, msg: *const c_char) {
+        let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned();
+        // Other tests log at the same time; keep only this test's messages
+        if msg.starts_with("callback-check") {
+            LOGGED.lock().unwrap().push((level, msg));
+        }
+    }
+
+    #[test]
+    fn log_callback_receives_messages_up_to_its_level() {
+        let _state = global_state();
+        assert_eq!(arti_set_log_callback(Some(record_log)), 1);
+        assert_eq!(arti_set_log_level(LOG_LEVEL_WARN), 1);
+        log_message(LOG_LEVEL_ERROR, "callback-check error");
+        log_message(LOG_LEVEL_WARN, "callback-check warn");
+        log_message(LOG_LEVEL_INFO, "callback-check info");
+
+        assert_eq!(arti_set_log_level(LOG_LEVEL_DEBUG), 1);
+        log_message(LOG_LEVEL_DEBUG, "callback-check debug");
+        log_message(LOG_LEVEL_TRACE, "callback-check trace");
+
+        assert_eq!(arti_set_log_callback(None), 1);
+        assert_eq!(arti_set_log_level(LOG_LEVEL_INFO), 1);
+        log_message(LOG_LEVEL_ERROR, "callback-check after removal");
+
+        let logged = std::mem::take(&mut *LOGGED.lock().unwrap());
+        assert_eq!(logged, [
+            (LOG_LEVEL_ERROR, "callback-check error".to_string()),
+            (LOG_LEVEL_WARN, "callback-check warn".to_string()),
+            (LOG_LEVEL_DEBUG, "callback-check debug".to_string()),
+        ]);
+    }
+
+    // Runs only inside default_verbosity_keeps_stdout_clean, in a fresh process
+    #[test]
+    fn log_at_every_level_in_child_process() {
+        if std::env::var_os("ARTI_FFI_LOG_CHILD").is_none() {
+            return;
+        }
+        for level in [LOG_LEVEL_ERROR, LOG_LEVEL_WARN, LOG_LEVEL_INFO, LOG_LEVEL_DEBUG, LOG_LEVEL_TRACE] {
+            log_message(level, &format!("stdout-check level {}", level));
+        }
+    }
+
+    #[test]
+    fn default_verbosity_keeps_stdout_clean() {
+        // The test harness captures output within the process, so log from a child
+        // process whose stdout and stderr can be inspected
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::log_at_every_level_in_child_process", "--nocapture", "--test-threads=1"])
+            .env("ARTI_FFI_LOG_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stdout.contains("stdout-check"), "{}", stdout);
+        assert!(stderr.contains("stdout-check level 1"), "{}", stderr);
+        assert!(stderr.contains("stdout-check level 2"), "{}", stderr);
+        assert!(!stderr.contains("stdout-check level 3"), "{}", stderr);
+        assert!(!stderr.contains("stdout-check level 4"), "{}", stderr);
+    }
+
+    #[test]
+    fn padding_reaches_client_config() {
+        let _guard = global_state();
+
+        assert_eq!(arti_set_padding(PADDING_REDUCED), 1);
+        let configured = build_client_config();
+        CONFIG_OVERRIDES.lock().unwrap().padding = None;
+
+        assert!(format!("{:?}", configured.unwrap()).contains("padding: Reduced"));
+        assert_eq!(arti_set_padding(7), ERR_INVALID_PARAMS);
+    }
+
+    #[test]
+    fn address_family_reaches_stream_prefs() {
+        let _guard = global_state();
+
+        // The preference applies to the prefs every connect starts from, not the client config
+        assert_eq!(arti_set_address_family_preference(ADDRESS_FAMILY_IPV6_ONLY), 1);
+        let configured = default_stream_prefs();
+        assert_eq!(arti_set_address_family_preference(ADDRESS_FAMILY_AUTO), 1);
+        let restored = default_stream_prefs();
+
+        assert!(format!("{:?}", configured).contains("Ipv6Only"));
+        assert!(!format!("{:?}", restored).contains("Ipv6Only"));
+        assert_eq!(arti_set_address_family_preference(9), ERR_INVALID_PARAMS);
+    }
+
+    #[cfg(feature = "pt-bridges")]
+    #[test]
+    fn bridges_reach_client_config() {
+        let _guard = global_state();
+        let bridges = CString::new(r#"["192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567", "not a bridge"]"#).unwrap();
+        let none = CString::new("[]").unwrap();
+
+        assert_eq!(arti_set_bridges(bridges.as_ptr()), 1);
+        let configured = build_client_config();
+        assert_eq!(arti_set_bridges(none.as_ptr()), 0);
+        let restored = build_client_config().unwrap();
+
+        assert!(format!("{:?}", configured.unwrap()).contains("192.0.2.1:443"));
+        assert!(!format!("{:?}", restored).contains("192.0.2.1:443"));
+    }
+
+    #[test]
+    fn fallback_dirs_reach_client_config() {
+        let _guard = global_state();
+        let network = CString::new(r#"{"fallback_caches": [{
+            "rsa_identity": "0123456789ABCDEF0123456789ABCDEF01234567",
+            "ed_identity": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            "orports": ["127.0.0.1:5000"]}]}"#).unwrap();
+        let malformed = CString::new(r#"{"fallback_caches": [{"orports": "nowhere"}]}"#).unwrap();
+
+        assert_eq!(arti_set_fallback_dirs(network.as_ptr()), 1);
+        let configured = build_client_config();
+        assert_eq!(arti_set_fallback_dirs(std::ptr::null()), 1);
+        let restored = build_client_config().unwrap();
+
+        assert!(format!("{:?}", configured.unwrap()).contains("127.0.0.1:5000"));
+        assert!(!format!("{:?}", restored).contains("127.0.0.1:5000"));
+        assert_eq!(arti_set_fallback_dirs(malformed.as_ptr()), ERR_INVALID_PARAMS);
+    }
+
+    #[test]
+    fn connect_url_picks_tls_and_default_ports() {
+        let target = |url: &str| connect_url_target(url).map_err(|e| e.to_string());
+        assert_eq!(target("http://example.com/index.html").unwrap(), (false, "example.com".to_string(), 80));
+        assert_eq!(target("https://example.com").unwrap(), (true, "example.com".to_string(), 443));
+        assert_eq!(target("https://example.com:8443").unwrap(), (true, "example.com".to_string(), 8443));
+        assert_eq!(target("tcp://example.com:7000").unwrap(), (false, "example.com".to_string(), 7000));
+
+        assert!(target("tcp://example.com").unwrap_err().contains("no port"));
+        assert!(target("ftp://example.com").unwrap_err().contains("Unsupported URL scheme"));
+        assert!(target("example.com:80").is_err());
+
+        let circuit = CString::new("test-circuit").unwrap();
+        let url = CString::new("tcp://example.com").unwrap();
+        let mut stream_id = [0 as c_char; 64];
+        assert_eq!(arti_connect_url(circuit.as_ptr(), url.as_ptr(), stream_id.as_mut_ptr(),
+            stream_id.len() as c_int, std::ptr::null_mut()), ERR_INVALID_PARAMS);
+    }
+
+    #[test]
+    fn malformed_circuit_imports_are_rejected() {
+        let _guard = global_state();
+        for json in ["not json", r#"["c1"]"#, r#"{"circuits": "c1"}"#, r#"{"circuits": [{"id": 7}]}"#] {
+            let json = CString::new(json).unwrap();
+            assert_eq!(arti_import_circuits(json.as_ptr()), ERR_INVALID_PARAMS);
+        }
+        assert_eq!(arti_import_circuits(std::ptr::null()), 0);
+        assert!(CIRCUITS.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_streams_are_reported_as_missing() {
+        let _guard = global_state();
+        let stream_id = CString::new("no-such-stream").unwrap();
+        let mut json = [0 as c_char; 256];
+
+        assert_eq!(arti_stream_info(stream_id.as_ptr(), json.as_mut_ptr(), json.len() as c_int), 1);
+        let info: serde_json::Value = serde_json::from_str(
+            unsafe { CStr::from_ptr(json.as_ptr()) }.to_str().unwrap()).unwrap();
+        assert_eq!(info["exists"], false);
+        assert!(info["type"].is_null());
+        assert!(info["circuit"].is_null());
+
+        let mut tiny = [0 as c_char; 4];
+        assert_eq!(arti_stream_info(stream_id.as_ptr(), tiny.as_mut_ptr(), tiny.len() as c_int), ERR_BUFFER_TOO_SMALL);
+    }
+
+    #[test]
+    fn closing_streams_counts_only_known_ids() {
+        let _guard = global_state();
+        let waiting = StdArc::new(tokio::sync::Notify::new());
+        TAKEN_STREAMS.lock().unwrap().insert("taken-stream".to_string(), waiting.clone());
+
+        let ids = CString::new(r#"["no-such-1", "taken-stream", "no-such-2", "taken-stream"]"#).unwrap();
+        assert_eq!(arti_close_streams(ids.as_ptr()), 1);
+        assert!(!TAKEN_STREAMS.lock().unwrap().contains_key("taken-stream"));
+        Runtime::new().unwrap().block_on(async {
+            tokio::time::timeout(Duration::from_secs(1), waiting.notified()).await
+                .expect("the waiting call should be told its stream was closed");
+        });
+
+        let unknown = CString::new(r#"["no-such-1", "no-such-2"]"#).unwrap();
+        assert_eq!(arti_close_streams(unknown.as_ptr()), 0);
+        for json in ["not json", r#"{"ids": []}"#, "[1, 2]"] {
+            let json = CString::new(json).unwrap();
+            assert_eq!(arti_close_streams(json.as_ptr()), ERR_INVALID_PARAMS);
+        }
     }
 }